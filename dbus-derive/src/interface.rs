@@ -0,0 +1,288 @@
+// Implementation of the #[dbus_interface] attribute.
+
+use crate::{attr_flag, attr_value, dbus_attrs, parse_dbus_attrs, rename, result_inner, DBusAttr};
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_quote, AttributeArgs, Error, FnArg, Ident, ImplItem, ImplItemMethod, ItemImpl, LitStr, Pat,
+    ReturnType, Stmt, Type};
+
+const ITEM_ATTRS: &[&str] = &["name", "property", "signal", "skip", "emits_changed"];
+
+struct Arg {
+    name: LitStr,
+    var: Ident,
+    ty: Type,
+}
+
+// The value type of a method, getter or setter result, and whether it is wrapped in a Result.
+struct Output {
+    ty: Option<Type>,
+    is_result: bool,
+}
+
+struct Prop {
+    name: LitStr,
+    getter: Option<(Ident, Output)>,
+    setter: Option<(Ident, Type, Output)>,
+    emits: Option<LitStr>,
+}
+
+#[derive(Default)]
+struct Iface {
+    methods: Vec<TokenStream2>,
+    props: Vec<Prop>,
+    signals: Vec<TokenStream2>,
+}
+
+fn member_name(attrs: &[DBusAttr], ident: &Ident) -> Result<LitStr, Error> {
+    let rule = LitStr::new("PascalCase", Span::call_site());
+    let s = ident.to_string();
+    let s = s.trim_start_matches("r#");
+    Ok(attr_value(attrs, "name")?.unwrap_or_else(|| LitStr::new(&rename(s, &rule).unwrap(), ident.span())))
+}
+
+fn unit(t: &Type) -> bool {
+    match *t { Type::Tuple(ref t) => t.elems.is_empty(), _ => false }
+}
+
+fn output(r: &ReturnType) -> Output {
+    let t = match *r { ReturnType::Default => return Output { ty: None, is_result: false }, ReturnType::Type(_, ref t) => t };
+    match result_inner(t) {
+        Some(t) => Output { ty: if unit(t) { None } else { Some(t.clone()) }, is_result: true },
+        None => Output { ty: if unit(t) { None } else { Some((**t).clone()) }, is_result: false },
+    }
+}
+
+// The arguments of a method, except for self.
+fn args(m: &ImplItemMethod) -> Result<Vec<Arg>, Error> {
+    let mut r = vec!();
+    for a in m.sig.inputs.iter() {
+        let a = match *a { FnArg::Typed(ref a) => a, FnArg::Receiver(_) => continue };
+        let name = match *a.pat {
+            Pat::Ident(ref p) => p.ident.to_string().trim_start_matches("r#").to_string(),
+            ref p => return Err(Error::new_spanned(p, "expected an identifier")),
+        };
+        let var = Ident::new(&format!("a{}", r.len()), Span::call_site());
+        r.push(Arg { name: LitStr::new(&name, a.pat.span()), var, ty: (*a.ty).clone() });
+    }
+    Ok(r)
+}
+
+// Makes the body of a signal method, which returns the signal message.
+fn signal(iface: &LitStr, m: &mut ImplItemMethod, attrs: &[DBusAttr], i: &mut Iface) -> Result<(), Error> {
+    let member = member_name(attrs, &m.sig.ident)?;
+    let mut a = args(m)?;
+    if a.is_empty() { return Err(Error::new_spanned(&m.sig, "the first argument of a signal must be the object path")) }
+    let path = a.remove(0).var;
+    let has_body = match m.block.stmts.first() { Some(Stmt::Item(syn::Item::Verbatim(_))) => false, s => s.is_some() };
+    if has_body { return Err(Error::new_spanned(&m.block, "signals should be declared without a body")) }
+    if let ReturnType::Default = m.sig.output { m.sig.output = parse_quote!(-> ::dbus::Message); }
+
+    let mut vars = m.sig.inputs.iter_mut().filter_map(|a| match *a { FnArg::Typed(ref mut a) => Some(a), _ => None });
+    for (pt, v) in (&mut vars).zip(Some(&path).into_iter().chain(a.iter().map(|a| &a.var))) {
+        *pt.pat = parse_quote!(#v);
+    }
+    let v: Vec<_> = a.iter().map(|a| &a.var).collect();
+    m.block = parse_quote!({
+        let m = ::dbus::Message::signal(#path, &#iface.into(), &#member.into());
+        #( let m = m.append1(#v); )*
+        m
+    });
+
+    let n: Vec<_> = a.iter().map(|a| &a.name).collect();
+    let t: Vec<_> = a.iter().map(|a| &a.ty).collect();
+    i.signals.push(quote! {
+        let s = factory.signal(#member, Default::default());
+        #( let s = s.arg((#n, <#t as ::dbus::arg::Arg>::signature())); )*
+        let i = i.add_s(s);
+    });
+    Ok(())
+}
+
+fn method(m: &ImplItemMethod, attrs: &[DBusAttr], i: &mut Iface) -> Result<(), Error> {
+    let member = member_name(attrs, &m.sig.ident)?;
+    let a = args(m)?;
+    let out = output(&m.sig.output);
+    let ident = &m.sig.ident;
+    let (n, v, t): (Vec<_>, Vec<_>, Vec<_>) = (a.iter().map(|a| &a.name).collect(), a.iter().map(|a| &a.var).collect(), a.iter().map(|a| &a.ty).collect());
+    let q = if out.is_result { quote!(?) } else { quote!() };
+    let iter = if a.is_empty() { quote!() } else { quote!(let mut i = minfo.msg.iter_init();) };
+    let outs: Vec<Type> = match out.ty {
+        None => vec!(),
+        Some(Type::Tuple(ref t)) => t.elems.iter().cloned().collect(),
+        Some(ref t) => vec!(t.clone()),
+    };
+    let o: Vec<_> = (0..outs.len()).map(|i| Ident::new(&format!("r{}", i), Span::call_site())).collect();
+    let call = match out.ty {
+        None => quote! { d.#ident(#(#v),*)#q; },
+        Some(Type::Tuple(_)) => quote! { let (#(#o),*) = d.#ident(#(#v),*)#q; },
+        Some(_) => quote! { let r0 = d.#ident(#(#v),*)#q; },
+    };
+    i.methods.push(quote! {
+        let fclone = f.clone();
+        let h = move |minfo: &::dbus::tree::MethodInfo<M, D>| {
+            #iter
+            #( let #v: #t = i.read()?; )*
+            let d = fclone(minfo);
+            #call
+            let rm = minfo.msg.method_return();
+            #( let rm = rm.append1(#o); )*
+            Ok(vec!(rm))
+        };
+        let m = factory.method_sync(#member, Default::default(), h);
+        #( let m = m.in_arg((#n, <#t as ::dbus::arg::Arg>::signature())); )*
+        #( let m = m.out_arg(<#outs as ::dbus::arg::Arg>::signature()); )*
+        let i = i.add_m(m);
+    });
+    Ok(())
+}
+
+fn property(m: &ImplItemMethod, attrs: &[DBusAttr], i: &mut Iface) -> Result<(), Error> {
+    let ident = m.sig.ident.clone();
+    let mut a = args(m)?;
+    let out = output(&m.sig.output);
+    let s = ident.to_string();
+    let (setter, base) = match (a.len(), &out.ty, s.starts_with("set_")) {
+        (0, Some(_), _) => (None, ident.clone()),
+        (1, None, true) => (Some(a.remove(0).ty), Ident::new(&s["set_".len()..], ident.span())),
+        _ => return Err(Error::new_spanned(&m.sig, "properties need a getter like \"fn foo(&self) -> T\" \
+            and/or a setter like \"fn set_foo(&self, value: T)\"")),
+    };
+    let name = member_name(attrs, &base)?;
+    let emits = attr_value(attrs, "emits_changed")?;
+    let idx = match i.props.iter().position(|p| p.name.value() == name.value()) {
+        Some(idx) => idx,
+        None => { i.props.push(Prop { name: name.clone(), getter: None, setter: None, emits: None }); i.props.len() - 1 },
+    };
+    let p = &mut i.props[idx];
+    if emits.is_some() { p.emits = emits; }
+    let dup = match setter {
+        Some(t) => p.setter.replace((ident, t, out)).is_some(),
+        None => p.getter.replace((ident, out)).is_some(),
+    };
+    if dup { return Err(Error::new_spanned(&m.sig, format!("property {} is declared twice", name.value()))) }
+    Ok(())
+}
+
+fn property_tokens(p: &Prop) -> Result<TokenStream2, Error> {
+    let name = &p.name;
+    let (ty, access) = match (&p.getter, &p.setter) {
+        (Some((_, o)), None) => (o.ty.as_ref().unwrap(), quote!(Read)),
+        (Some((_, o)), Some(_)) => (o.ty.as_ref().unwrap(), quote!(ReadWrite)),
+        (None, Some((_, t, _))) => (t, quote!(Write)),
+        (None, None) => unreachable!(),
+    };
+    let emits = match p.emits {
+        None => quote!(),
+        Some(ref e) => {
+            let e = match &*e.value() {
+                "true" => quote!(True),
+                "invalidates" => quote!(Invalidates),
+                "const" => quote!(Const),
+                "false" => quote!(False),
+                _ => return Err(Error::new_spanned(e, "expected one of: true, invalidates, const, false")),
+            };
+            quote!(let p = p.emits_changed(::dbus::tree::EmitsChangedSignal::#e);)
+        }
+    };
+    let get = p.getter.as_ref().map(|(ident, o)| {
+        let q = if o.is_result { quote!(?) } else { quote!() };
+        quote! {
+            let fclone = f.clone();
+            let p = p.on_get_sync(move |a, pinfo| {
+                let minfo = pinfo.to_method_info();
+                let d = fclone(&minfo);
+                a.append(d.#ident()#q);
+                Ok(())
+            });
+        }
+    });
+    let set = p.setter.as_ref().map(|(ident, t, o)| {
+        let q = if o.is_result { quote!(?) } else { quote!() };
+        quote! {
+            let fclone = f.clone();
+            let p = p.on_set_sync(move |iter, pinfo| {
+                let minfo = pinfo.to_method_info();
+                let d = fclone(&minfo);
+                let v: #t = iter.read()?;
+                d.#ident(v)#q;
+                Ok(())
+            });
+        }
+    });
+    Ok(quote! {
+        let p = factory.property::<#ty, _>(#name, Default::default());
+        let p = p.access(::dbus::tree::Access::#access);
+        #emits
+        #get
+        #set
+        let i = i.add_p(p);
+    })
+}
+
+pub fn dbus_interface(args: AttributeArgs, mut item: ItemImpl) -> Result<TokenStream2, Error> {
+    let args = parse_dbus_attrs(args, &["name", "server"])?;
+    let iface = attr_value(&args, "name")?.ok_or_else(||
+        Error::new(Span::call_site(), "missing name, expected #[dbus_interface(name = \"...\")]"))?;
+    if !iface.value().contains('.') { return Err(Error::new_spanned(&iface, "invalid D-Bus interface name")) }
+    let server = match attr_value(&args, "server")? {
+        Some(s) => s.parse()?,
+        None => Ident::new("dbus_interface", Span::call_site()),
+    };
+    if let Some(ref t) = item.trait_ { return Err(Error::new_spanned(&t.1, "expected an inherent impl block")) }
+
+    let mut i = Iface::default();
+    for it in item.items.iter_mut() {
+        let m = match *it { ImplItem::Method(ref mut m) => m, _ => continue };
+        let attrs = dbus_attrs(&m.attrs, ITEM_ATTRS)?;
+        m.attrs.retain(|a| !a.path.is_ident("dbus"));
+        if attr_flag(&attrs, "skip")? { continue }
+        if attr_flag(&attrs, "signal")? {
+            signal(&iface, m, &attrs, &mut i)?;
+            continue;
+        }
+        match m.sig.receiver() {
+            Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_none() => {},
+            Some(r) => return Err(Error::new_spanned(r, "D-Bus methods and properties must take &self")),
+            None => continue,
+        }
+        if !m.sig.generics.params.is_empty() {
+            return Err(Error::new_spanned(&m.sig.generics, "D-Bus methods and properties cannot be generic"))
+        }
+        if attr_flag(&attrs, "property")? { property(m, &attrs, &mut i)? } else { method(m, &attrs, &mut i)? }
+    }
+
+    let mut wheres = vec!(quote!(D::Method: Default));
+    if !i.props.is_empty() { wheres.push(quote!(D::Property: Default)) }
+    if !i.signals.is_empty() { wheres.push(quote!(D::Signal: Default)) }
+    let methods = &i.methods;
+    let signals = &i.signals;
+    let props = i.props.iter().map(property_tokens).collect::<Result<Vec<_>, _>>()?;
+    let f = if methods.is_empty() && props.is_empty() { quote!(let _ = f;) } else { quote!(let f = ::std::sync::Arc::new(f);) };
+    let doc = format!("Creates a tree interface for \"{}\".\n\n`f` returns the instance to call methods on, \
+        typically from the object path's data.", iface.value());
+
+    let (impl_g, _, where_c) = item.generics.split_for_impl();
+    let self_ty = &item.self_ty;
+    let generated = quote! {
+        impl #impl_g #self_ty #where_c {
+            #[doc = #doc]
+            pub fn #server<M, D, F>(factory: &::dbus::tree::Factory<M, D>, data: D::Interface, f: F) -> ::dbus::tree::Interface<M, D>
+            where
+                M: ::dbus::tree::MethodType<D>,
+                D: ::dbus::tree::DataType,
+                #( #wheres, )*
+                F: 'static + Send + Sync + for<'z> Fn(&'z ::dbus::tree::MethodInfo<M, D>) -> &'z Self,
+            {
+                let i = factory.interface(#iface, data);
+                #f
+                #( #methods )*
+                #( #props )*
+                #( #signals )*
+                i
+            }
+        }
+    };
+    Ok(quote!(#item #generated))
+}
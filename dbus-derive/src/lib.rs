@@ -0,0 +1,452 @@
+//! Derive macros for the [dbus](https://docs.rs/dbus) crate.
+//!
+//! * `DBusArgs` - implements `Arg`, `Append` and `Get` for a struct, which is sent as a D-Bus struct.
+//! * `DBusDict` - implements `Arg`, `Append` and `Get` for a struct, which is sent as a dict of
+//!   variants (`a{sv}`), like the property bags used by many D-Bus services.
+//! * `SignalArgs` - implements `SignalArgs` for a struct, the same way as `dbus-codegen` does for
+//!   the signals it generates.
+//! * `#[dbus_interface]` - an attribute for an `impl` block, which generates a function that creates
+//!   a `tree::Interface` with the methods, properties and signals of the block.
+//!
+//! # Example
+//!
+//! ```rust
+//! use dbus_derive::{DBusArgs, DBusDict, SignalArgs};
+//! use dbus::{Message, SignalArgs};
+//!
+//! #[derive(DBusArgs, Debug, PartialEq)]
+//! struct Point { x: i32, y: i32 }
+//!
+//! #[derive(DBusDict, Debug, PartialEq)]
+//! #[dbus(rename_all = "PascalCase")]
+//! struct Settings {
+//!     volume: u8,
+//!     #[dbus(rename = "Output-Device")]
+//!     device: Option<String>,
+//! }
+//!
+//! #[derive(SignalArgs, Debug, Default)]
+//! #[dbus(interface = "com.example.Drawing", name = "Moved")]
+//! struct DrawingMoved {
+//!     from: (i32, i32),
+//!     to: (i32, i32),
+//! }
+//!
+//! let m = Message::new_signal("/", "com.example", "Test").unwrap()
+//!     .append2(Point { x: 1, y: 2 }, Settings { volume: 7, device: None });
+//! let (p, s): (Option<Point>, Option<Settings>) = m.get2();
+//! assert_eq!(p, Some(Point { x: 1, y: 2 }));
+//! assert_eq!(s, Some(Settings { volume: 7, device: None }));
+//!
+//! let m = DrawingMoved { from: (1, 2), to: (3, 4) }.to_emit_message(&"/drawing".into());
+//! assert_eq!(&*m.member().unwrap(), "Moved");
+//! assert_eq!(DrawingMoved::from_message(&m).unwrap().to, (3, 4));
+//! ```
+
+extern crate proc_macro;
+
+mod interface;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, AttributeArgs, Data, DeriveInput, Error, Fields, GenericArgument, Generics, Ident,
+    ItemImpl, Lifetime, LifetimeDef, Lit, LitStr, Member, Meta, NestedMeta, PathArguments, Type};
+
+/// Implements `Arg`, `Append` and `Get` for a struct, so that it is sent as a D-Bus struct.
+///
+/// The fields are appended in declaration order, and need to implement the same traits.
+#[proc_macro_derive(DBusArgs, attributes(dbus))]
+pub fn derive_dbus_args(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    dbus_args(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Implements `Arg`, `Append` and `Get` for a struct with named fields, so that it is sent
+/// as a dict with string keys and variant values (`a{sv}`).
+///
+/// The keys are the field names, unless changed with `#[dbus(rename = "...")]` on the field, or
+/// `#[dbus(rename_all = "...")]` on the struct, which accepts `PascalCase`, `camelCase` and `kebab-case`.
+///
+/// Fields of type `Option<T>` are left out of the dict if `None`, and are `None` if missing
+/// when reading. Other fields must be present. Unknown keys are ignored when reading.
+#[proc_macro_derive(DBusDict, attributes(dbus))]
+pub fn derive_dbus_dict(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    dbus_dict(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Implements `SignalArgs` for a struct, whose fields are the arguments of the signal.
+///
+/// The interface must be given with `#[dbus(interface = "...")]`. The signal name defaults to the
+/// name of the struct and can be changed with `#[dbus(name = "...")]`. The struct needs to implement
+/// `Default`, and the fields need to implement `RefArg`, `Arg` and `Get`.
+#[proc_macro_derive(SignalArgs, attributes(dbus))]
+pub fn derive_signal_args(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    signal_args(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Exposes the methods of an `impl` block as a D-Bus interface.
+///
+/// The attribute takes the name of the interface, `#[dbus_interface(name = "com.example.Foo")]`,
+/// and generates an associated function which creates a `tree::Interface` for it:
+///
+/// ```ignore
+/// pub fn dbus_interface<M, D, F>(factory: &Factory<M, D>, data: D::Interface, f: F) -> Interface<M, D>
+/// where F: 'static + Send + Sync + for<'z> Fn(&'z MethodInfo<M, D>) -> &'z Self, ...
+/// ```
+///
+/// `f` is called for every incoming call to get the instance the call should be made on,
+/// typically from the object path's data. The function is generic over the method type, so it
+/// works with `MTFn`, `MTFnMut` and `MTSync` trees. Use `server = "..."` to give it another name,
+/// e g if a type implements several interfaces.
+///
+/// The items of the block are mapped like this:
+///
+/// * Methods taking `&self` become D-Bus methods, named in PascalCase unless changed with
+///   `#[dbus(name = "...")]`. The arguments must implement `Arg` and `Get`, and the return value
+///   `Arg` and `Append`. A tuple is returned as several output arguments, and a `Result<T, E>`
+///   replies with an error if `MethodErr` implements `From<E>`.
+/// * Methods marked `#[dbus(property)]` become properties; `fn foo(&self) -> T` is the getter and
+///   `fn set_foo(&self, value: T)` the setter. Properties with both are read-write. The
+///   PropertiesChanged behaviour can be set with `#[dbus(property, emits_changed = "...")]`
+///   ("true", "invalidates", "const" or "false").
+/// * Methods marked `#[dbus(signal)]` are declared without a body. The first argument is the
+///   object path, and the body is generated to return the signal message, e g
+///   `fn moved(path: &Path, x: i32) -> Message;`.
+/// * Methods marked `#[dbus(skip)]`, and functions that do not take `self`, are left alone.
+///
+/// # Example
+///
+/// ```rust
+/// use dbus::{tree, Path, Message};
+/// use dbus_derive::dbus_interface;
+/// use std::sync::atomic::{AtomicU32, Ordering};
+///
+/// #[derive(Debug, Default)]
+/// struct Counter(AtomicU32);
+///
+/// #[dbus_interface(name = "com.example.Counter")]
+/// impl Counter {
+///     fn add(&self, n: u32) -> Result<u32, tree::MethodErr> {
+///         if n == 0 { return Err(tree::MethodErr::invalid_arg(&n)) }
+///         Ok(self.0.fetch_add(n, Ordering::SeqCst) + n)
+///     }
+///
+///     #[dbus(property)]
+///     fn value(&self) -> u32 { self.0.load(Ordering::SeqCst) }
+///
+///     #[dbus(property)]
+///     fn set_value(&self, v: u32) { self.0.store(v, Ordering::SeqCst) }
+///
+///     #[dbus(signal)]
+///     fn overflowed(path: &Path, value: u32) -> Message;
+/// }
+///
+/// #[derive(Debug, Default)]
+/// struct Data;
+/// impl tree::DataType for Data {
+///     type Tree = ();
+///     type ObjectPath = Counter;
+///     type Property = ();
+///     type Interface = ();
+///     type Method = ();
+///     type Signal = ();
+/// }
+///
+/// let f = tree::Factory::new_sync::<Data>();
+/// let iface = Counter::dbus_interface(&f, (), |m| m.path.get_data());
+/// let tree = f.tree(()).add(f.object_path("/counter", Counter::default()).introspectable().add(iface));
+/// # let _ = tree;
+/// let signal = Counter::overflowed(&"/counter".into(), 5);
+/// assert_eq!(&*signal.member().unwrap(), "Overflowed");
+/// ```
+#[proc_macro_attribute]
+pub fn dbus_interface(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemImpl);
+    interface::dbus_interface(args, item).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+struct DBusAttr {
+    key: Ident,
+    value: Option<LitStr>,
+}
+
+// Parses the contents of #[dbus(key = "value", flag, ...)], rejecting keys that are not allowed.
+fn parse_dbus_attrs<I: IntoIterator<Item=NestedMeta>>(nested: I, allowed: &[&str]) -> Result<Vec<DBusAttr>, Error> {
+    let mut r = vec!();
+    for n in nested {
+        let (path, value) = match n {
+            NestedMeta::Meta(Meta::NameValue(nv)) => match nv.lit {
+                Lit::Str(s) => (nv.path, Some(s)),
+                l => return Err(Error::new_spanned(l, "expected a string")),
+            },
+            NestedMeta::Meta(Meta::Path(p)) => (p, None),
+            n => return Err(Error::new_spanned(n, "expected key = \"value\"")),
+        };
+        match path.get_ident() {
+            Some(key) if allowed.contains(&&*key.to_string()) => r.push(DBusAttr { key: key.clone(), value }),
+            _ => return Err(Error::new_spanned(&path, format!("unknown dbus attribute, expected one of: {}", allowed.join(", ")))),
+        }
+    }
+    Ok(r)
+}
+
+fn dbus_attrs(attrs: &[syn::Attribute], allowed: &[&str]) -> Result<Vec<DBusAttr>, Error> {
+    let mut r = vec!();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("dbus")) {
+        match attr.parse_meta()? {
+            Meta::List(l) => r.extend(parse_dbus_attrs(l.nested, allowed)?),
+            m => return Err(Error::new_spanned(m, "expected #[dbus(key = \"value\")]")),
+        }
+    }
+    Ok(r)
+}
+
+// The value of "key = value", if present.
+fn attr_value(attrs: &[DBusAttr], key: &str) -> Result<Option<LitStr>, Error> {
+    match attrs.iter().filter(|a| a.key == key).last() {
+        Some(DBusAttr { value: Some(v), .. }) => Ok(Some(v.clone())),
+        Some(a) => Err(Error::new_spanned(&a.key, format!("expected {} = \"...\"", key))),
+        None => Ok(None),
+    }
+}
+
+// Whether the flag "key" is present.
+fn attr_flag(attrs: &[DBusAttr], key: &str) -> Result<bool, Error> {
+    match attrs.iter().find(|a| a.key == key) {
+        Some(DBusAttr { value: Some(v), .. }) => Err(Error::new_spanned(v, format!("{} does not take a value", key))),
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+fn dbus_attr(attrs: &[syn::Attribute], allowed: &[&str], key: &str) -> Result<Option<LitStr>, Error> {
+    attr_value(&dbus_attrs(attrs, allowed)?, key)
+}
+
+fn struct_fields<'a>(input: &'a DeriveInput, what: &str) -> Result<&'a Fields, Error> {
+    match input.data {
+        Data::Struct(ref s) => Ok(&s.fields),
+        _ => Err(Error::new_spanned(&input.ident, format!("{} can only be derived for structs", what))),
+    }
+}
+
+fn members(fields: &Fields) -> Vec<Member> {
+    fields.iter().enumerate().map(|(i, f)| match f.ident {
+        Some(ref id) => Member::Named(id.clone()),
+        None => Member::Unnamed(i.into()),
+    }).collect()
+}
+
+fn vars(fields: &Fields) -> Vec<Ident> {
+    (0..fields.len()).map(|i| Ident::new(&format!("f{}", i), Span::call_site())).collect()
+}
+
+// Adds "T: bounds" for every field type, but only for generic structs; for others the
+// compiler will check the field types anyway.
+fn with_bounds<'a, I: IntoIterator<Item=&'a Type>>(g: &Generics, tys: I, bounds: TokenStream2) -> Generics {
+    let mut g = g.clone();
+    if g.type_params().next().is_some() {
+        let w = g.make_where_clause();
+        for t in tys { w.predicates.push(parse_quote!(#t: #bounds)); }
+    }
+    g
+}
+
+// The lifetime to use for Get<'a>: the first lifetime of the struct, or a new one.
+fn get_lifetime(g: &Generics) -> (Lifetime, Generics) {
+    if let Some(l) = g.lifetimes().next() { return (l.lifetime.clone(), g.clone()) }
+    let l = Lifetime::new("'dbus_derive", Span::call_site());
+    let mut g = g.clone();
+    g.params.insert(0, LifetimeDef::new(l.clone()).into());
+    (l, g)
+}
+
+fn dbus_args(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = struct_fields(input, "DBusArgs")?;
+    if fields.is_empty() { return Err(Error::new_spanned(&input.ident, "D-Bus structs must have at least one field")) }
+    dbus_attrs(&input.attrs, &[])?;
+    let name = &input.ident;
+    let m = members(fields);
+    let v = vars(fields);
+    let tys: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let (_, ty_g, _) = input.generics.split_for_impl();
+
+    let g = with_bounds(&input.generics, tys.iter().cloned(), quote!(::dbus::arg::Arg));
+    let (impl_g, _, where_c) = g.split_for_impl();
+    let arg = quote! {
+        impl #impl_g ::dbus::arg::Arg for #name #ty_g #where_c {
+            const ARG_TYPE: ::dbus::arg::ArgType = ::dbus::arg::ArgType::Struct;
+            fn signature() -> ::dbus::Signature<'static> {
+                let mut s = String::from("(");
+                #( s.push_str(&<#tys as ::dbus::arg::Arg>::signature()); )*
+                s.push(')');
+                ::dbus::Signature::from(s)
+            }
+        }
+    };
+
+    let g = with_bounds(&input.generics, tys.iter().cloned(), quote!(::dbus::arg::Append));
+    let (impl_g, _, where_c) = g.split_for_impl();
+    let append = quote! {
+        impl #impl_g ::dbus::arg::Append for #name #ty_g #where_c {
+            fn append(self, i: &mut ::dbus::arg::IterAppend) {
+                let Self { #( #m: #v ),* } = self;
+                i.append_struct(|s| { #( s.append(#v); )* });
+            }
+        }
+    };
+
+    let (lt, g) = get_lifetime(&input.generics);
+    let g = with_bounds(&g, tys.iter().cloned(), quote!(::dbus::arg::Get<#lt>));
+    let (impl_g, _, where_c) = g.split_for_impl();
+    let get = quote! {
+        impl #impl_g ::dbus::arg::Get<#lt> for #name #ty_g #where_c {
+            fn get(i: &mut ::dbus::arg::Iter<#lt>) -> Option<Self> {
+                let mut s = i.recurse(::dbus::arg::ArgType::Struct)?;
+                #( let #v = s.get()?; s.next(); )*
+                Some(Self { #( #m: #v ),* })
+            }
+        }
+    };
+
+    Ok(quote!(#arg #append #get))
+}
+
+// Returns T if the type is Option<T>.
+fn option_inner(t: &Type) -> Option<&Type> { generic_inner(t, "Option", 1) }
+
+// Returns T if the type is Result<T, E> (or an alias like io::Result<T>).
+fn result_inner(t: &Type) -> Option<&Type> {
+    generic_inner(t, "Result", 1).or_else(|| generic_inner(t, "Result", 2))
+}
+
+// Returns the first type argument of "name<T, ...>", if it has "count" type arguments.
+fn generic_inner<'a>(t: &'a Type, name: &str, count: usize) -> Option<&'a Type> {
+    let p = match *t { Type::Path(ref p) if p.qself.is_none() => &p.path, _ => return None };
+    let seg = p.segments.last()?;
+    if seg.ident != name { return None }
+    match seg.arguments {
+        PathArguments::AngleBracketed(ref a) if a.args.len() == count => match a.args[0] {
+            GenericArgument::Type(ref t) => Some(t),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn rename(s: &str, rule: &LitStr) -> Result<String, Error> {
+    let words = s.split('_').filter(|w| !w.is_empty());
+    let capitalize = |w: &str| { let mut c = w.chars(); c.next().map(|f| f.to_uppercase().chain(c).collect()).unwrap_or_default() };
+    Ok(match &*rule.value() {
+        "PascalCase" => words.map(capitalize).collect::<Vec<String>>().concat(),
+        "camelCase" => words.enumerate().map(|(i, w)| if i == 0 { w.into() } else { capitalize(w) }).collect::<Vec<String>>().concat(),
+        "kebab-case" => words.collect::<Vec<_>>().join("-"),
+        _ => return Err(Error::new_spanned(rule, "expected one of: PascalCase, camelCase, kebab-case")),
+    })
+}
+
+fn dbus_dict(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match struct_fields(input, "DBusDict")? {
+        f @ Fields::Named(_) => f,
+        _ => return Err(Error::new_spanned(&input.ident, "DBusDict can only be derived for structs with named fields")),
+    };
+    let rename_all = dbus_attr(&input.attrs, &["rename_all"], "rename_all")?;
+    let name = &input.ident;
+    let m = members(fields);
+    let v = vars(fields);
+    let mut keys = vec!();
+    for f in fields.iter() {
+        let id = f.ident.as_ref().unwrap().to_string();
+        let id = id.trim_start_matches("r#");
+        keys.push(match (dbus_attr(&f.attrs, &["rename"], "rename")?, &rename_all) {
+            (Some(r), _) => r.value(),
+            (None, Some(rule)) => rename(id, rule)?,
+            (None, None) => id.into(),
+        });
+    }
+    let inner: Vec<_> = fields.iter().map(|f| option_inner(&f.ty).unwrap_or(&f.ty)).collect();
+    let (_, ty_g, _) = input.generics.split_for_impl();
+
+    let (impl_g, _, where_c) = input.generics.split_for_impl();
+    let arg = quote! {
+        impl #impl_g ::dbus::arg::Arg for #name #ty_g #where_c {
+            const ARG_TYPE: ::dbus::arg::ArgType = ::dbus::arg::ArgType::Array;
+            fn signature() -> ::dbus::Signature<'static> { ::dbus::Signature::from("a{sv}") }
+        }
+    };
+
+    let entries: Vec<_> = fields.iter().zip(keys.iter()).zip(v.iter()).map(|((f, k), v)| {
+        let entry = quote! { d.append_dict_entry(|e| { e.append(#k); e.append(::dbus::arg::Variant(#v)); }); };
+        if option_inner(&f.ty).is_some() { quote! { if let Some(#v) = #v { #entry } } } else { entry }
+    }).collect();
+    let g = with_bounds(&input.generics, inner.iter().cloned(), quote!(::dbus::arg::Arg + ::dbus::arg::Append));
+    let (impl_g, _, where_c) = g.split_for_impl();
+    let append = quote! {
+        impl #impl_g ::dbus::arg::Append for #name #ty_g #where_c {
+            fn append(self, i: &mut ::dbus::arg::IterAppend) {
+                let Self { #( #m: #v ),* } = self;
+                let ks = <&str as ::dbus::arg::Arg>::signature();
+                let vs = <::dbus::arg::Variant<u8> as ::dbus::arg::Arg>::signature();
+                i.append_dict(&ks, &vs, |d| { #( #entries )* });
+            }
+        }
+    };
+
+    let results: Vec<_> = fields.iter().zip(v.iter()).map(|(f, v)| {
+        if option_inner(&f.ty).is_some() { quote!(#v) } else { quote!(#v?) }
+    }).collect();
+    let (lt, g) = get_lifetime(&input.generics);
+    let g = with_bounds(&g, inner.iter().cloned(), quote!(::dbus::arg::Get<#lt>));
+    let (impl_g, _, where_c) = g.split_for_impl();
+    let get = quote! {
+        impl #impl_g ::dbus::arg::Get<#lt> for #name #ty_g #where_c {
+            fn get(i: &mut ::dbus::arg::Iter<#lt>) -> Option<Self> {
+                let d: ::dbus::arg::Dict<#lt, &#lt str, ::dbus::arg::Variant<::dbus::arg::Iter<#lt>>, _> = i.get()?;
+                #( let mut #v: Option<#inner> = None; )*
+                for (k, mut v) in d {
+                    match k {
+                        #( #keys => #v = Some(v.0.get()?), )*
+                        _ => {},
+                    }
+                }
+                Some(Self { #( #m: #results ),* })
+            }
+        }
+    };
+
+    Ok(quote!(#arg #append #get))
+}
+
+fn signal_args(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = struct_fields(input, "SignalArgs")?;
+    const ALLOWED: &[&str] = &["interface", "name"];
+    let iface = dbus_attr(&input.attrs, ALLOWED, "interface")?.ok_or_else(||
+        Error::new_spanned(&input.ident, "missing #[dbus(interface = \"...\")] attribute"))?;
+    if !iface.value().contains('.') { return Err(Error::new_spanned(&iface, "invalid D-Bus interface name")) }
+    let member = dbus_attr(&input.attrs, ALLOWED, "name")?.unwrap_or_else(|| LitStr::new(&input.ident.to_string(), input.ident.span()));
+    if member.value().is_empty() || member.value().contains('.') { return Err(Error::new_spanned(&member, "invalid D-Bus member name")) }
+
+    let name = &input.ident;
+    let m = members(fields);
+    let i = if fields.is_empty() { quote!(_) } else { quote!(i) };
+    let g = with_bounds(&input.generics, fields.iter().map(|f| &f.ty),
+        quote!(::dbus::arg::RefArg + ::dbus::arg::Arg + for<'z> ::dbus::arg::Get<'z>));
+    let (impl_g, ty_g, where_c) = g.split_for_impl();
+    Ok(quote! {
+        impl #impl_g ::dbus::SignalArgs for #name #ty_g #where_c {
+            const NAME: &'static str = #member;
+            const INTERFACE: &'static str = #iface;
+            fn append(&self, #i: &mut ::dbus::arg::IterAppend) {
+                #( ::dbus::arg::RefArg::append(&self.#m, i); )*
+            }
+            fn get(&mut self, #i: &mut ::dbus::arg::Iter) -> Result<(), ::dbus::arg::TypeMismatchError> {
+                #( self.#m = i.read()?; )*
+                Ok(())
+            }
+        }
+    })
+}
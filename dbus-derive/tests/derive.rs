@@ -0,0 +1,103 @@
+use dbus::arg::{Arg, Dict, Variant, RefArg};
+use dbus::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved;
+use dbus::{Message, Path, SignalArgs};
+use dbus_derive::{DBusArgs, DBusDict, SignalArgs};
+use std::collections::HashMap;
+
+#[derive(DBusArgs, Debug, PartialEq, Clone)]
+struct Inner(u8, String);
+
+#[derive(DBusArgs, Debug, PartialEq, Clone)]
+struct Outer<'a> {
+    name: &'a str,
+    inner: Inner,
+    list: Vec<i64>,
+}
+
+#[derive(DBusArgs, Debug, PartialEq)]
+struct Wrapper<T> { value: T, count: u32 }
+
+#[derive(DBusDict, Debug, PartialEq, Default)]
+#[dbus(rename_all = "PascalCase")]
+struct Props {
+    display_name: String,
+    #[dbus(rename = "volume-level")]
+    volume: u8,
+    muted: Option<bool>,
+    r#type: Option<Path<'static>>,
+}
+
+#[derive(SignalArgs, Debug, Default)]
+#[dbus(interface = "org.freedesktop.DBus.ObjectManager", name = "InterfacesRemoved")]
+struct Removed {
+    object: Path<'static>,
+    interfaces: Vec<String>,
+}
+
+#[derive(SignalArgs, Debug, Default)]
+#[dbus(interface = "com.example.Test")]
+struct Ping;
+
+fn msg() -> Message { Message::new_signal("/", "com.example", "Test").unwrap() }
+
+#[test]
+fn args() {
+    assert_eq!(&*Outer::signature(), "(s(ys)ax)");
+    assert_eq!(&*Wrapper::<Inner>::signature(), "((ys)u)");
+
+    let o = Outer { name: "outer", inner: Inner(5, "inner".into()), list: vec!(-1, 2) };
+    let m = msg().append2(o.clone(), Wrapper { value: 1.5f64, count: 3 });
+    assert_eq!(m.get1(), Some(o));
+    let (a, b): (Option<(&str, (u8, &str), Vec<i64>)>, Option<Wrapper<f64>>) = m.get2();
+    assert_eq!(a, Some(("outer", (5, "inner"), vec!(-1, 2))));
+    assert_eq!(b, Some(Wrapper { value: 1.5, count: 3 }));
+    assert_eq!(m.get1::<Inner>(), None);
+}
+
+#[test]
+fn dict() {
+    assert_eq!(&*Props::signature(), "a{sv}");
+    let p = Props { display_name: "Speaker".into(), volume: 80, muted: None, r#type: Some("/sink".into()) };
+    let m = msg().append1(p);
+    let d: HashMap<&str, Variant<Box<dyn RefArg>>> = m.get1::<Dict<&str, Variant<Box<dyn RefArg>>, _>>().unwrap().collect();
+    assert_eq!(d.len(), 3);
+    assert_eq!(d["DisplayName"].0.as_str(), Some("Speaker"));
+    assert_eq!(d["volume-level"].0.as_u64(), Some(80));
+    assert_eq!(d["Type"].0.as_str(), Some("/sink"));
+
+    let p: Props = m.get1().unwrap();
+    assert_eq!(p, Props { display_name: "Speaker".into(), volume: 80, muted: None, r#type: Some("/sink".into()) });
+
+    // Unknown keys are ignored, missing non-optional ones fail
+    let m = msg().append1(Dict::new(vec!(("DisplayName", Variant("x")), ("volume-level", Variant("y")))));
+    assert_eq!(m.get1::<Props>(), None);
+    let m = msg().append1(Dict::new(vec!(
+        ("DisplayName", Variant(Box::new("x".to_string()) as Box<dyn RefArg>)),
+        ("volume-level", Variant(Box::new(3u8) as Box<dyn RefArg>)),
+        ("Muted", Variant(Box::new(true) as Box<dyn RefArg>)),
+        ("Extra", Variant(Box::new(0.5f64) as Box<dyn RefArg>)),
+    )));
+    assert_eq!(m.get1(), Some(Props { display_name: "x".into(), volume: 3, muted: Some(true), r#type: None }));
+}
+
+#[test]
+fn signal() {
+    // Same shape as the hand written (and dbus-codegen generated) signal structs
+    let r = Removed { object: "/obj".into(), interfaces: vec!("com.example.A".into(), "com.example.B".into()) };
+    let m = r.to_emit_message(&"/".into());
+    let ir = ObjectManagerInterfacesRemoved::from_message(&m).unwrap();
+    assert_eq!(ir.object, r.object);
+    assert_eq!(ir.interfaces, r.interfaces);
+
+    let m = ObjectManagerInterfacesRemoved { object: "/obj2".into(), interfaces: vec!("x.y".into()) }.to_emit_message(&"/".into());
+    let r = Removed::from_message(&m).unwrap();
+    assert_eq!(&*r.object, "/obj2");
+    assert_eq!(r.interfaces, vec!("x.y".to_string()));
+    assert_eq!(Removed::match_str(None, None), ObjectManagerInterfacesRemoved::match_str(None, None));
+
+    assert_eq!(Ping::NAME, "Ping");
+    let m = Ping.to_emit_message(&"/".into());
+    assert_eq!(&*m.interface().unwrap(), "com.example.Test");
+    assert!(Ping::from_message(&m).is_some());
+    assert!(Removed::from_message(&m).is_none());
+}
@@ -0,0 +1,162 @@
+use dbus::stdintf::org_freedesktop_dbus::{Introspectable, Properties};
+use dbus::testing::TestBus;
+use dbus::tree::{self, Factory, MethodErr};
+use dbus::{Connection, Message, Path};
+use dbus_derive::dbus_interface;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Default)]
+struct Counter {
+    value: AtomicU32,
+    limit: AtomicU32,
+    name: Mutex<String>,
+}
+
+#[dbus_interface(name = "com.example.Counter")]
+impl Counter {
+    fn new(name: &str) -> Self {
+        Counter { value: AtomicU32::new(0), limit: AtomicU32::new(100), name: Mutex::new(name.into()) }
+    }
+
+    fn add(&self, n: u32) -> Result<u32, MethodErr> {
+        let v = self.value.load(Ordering::SeqCst) + n;
+        if v > self.limit.load(Ordering::SeqCst) { return Err(("com.example.Error.TooLarge", "Limit exceeded").into()) }
+        self.value.store(v, Ordering::SeqCst);
+        Ok(v)
+    }
+
+    #[dbus(name = "DivMod")]
+    fn div_mod(&self, divisor: u32) -> (u32, u32) {
+        let v = self.value.load(Ordering::SeqCst);
+        (v / divisor, v % divisor)
+    }
+
+    fn reset(&self) { self.value.store(0, Ordering::SeqCst) }
+
+    #[dbus(skip)]
+    fn helper(&self) -> u32 { 0 }
+
+    #[dbus(property, emits_changed = "false")]
+    fn value(&self) -> u32 { self.value.load(Ordering::SeqCst) }
+
+    #[dbus(property)]
+    fn set_value(&self, v: u32) { self.value.store(v, Ordering::SeqCst) }
+
+    #[dbus(property)]
+    fn set_limit(&self, v: u32) -> Result<(), MethodErr> {
+        if v == 0 { return Err(MethodErr::invalid_arg(&v)) }
+        self.limit.store(v, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[dbus(property, name = "DisplayName")]
+    fn display_name(&self) -> String { self.name.lock().unwrap().clone() }
+
+    #[dbus(signal)]
+    fn limit_reached(path: &Path, value: u32, name: &str) -> Message;
+}
+
+#[derive(Debug, Default)]
+struct Data;
+
+impl tree::DataType for Data {
+    type Tree = ();
+    type ObjectPath = Arc<Counter>;
+    type Property = ();
+    type Interface = ();
+    type Method = ();
+    type Signal = ();
+}
+
+#[test]
+fn method_types() {
+    let f = Factory::new_fn::<Data>();
+    let _ = Counter::dbus_interface(&f, (), |m| &**m.path.get_data());
+    let f = Factory::new_fnmut::<Data>();
+    let _ = Counter::dbus_interface(&f, (), |m| &**m.path.get_data());
+    let f = Factory::new_sync::<Data>();
+    let i = Counter::dbus_interface(&f, (), |m| &**m.path.get_data());
+    assert_eq!(&**i.get_name(), "com.example.Counter");
+    assert_eq!(Counter::default().helper(), 0);
+}
+
+#[test]
+fn signal() {
+    let m = Counter::limit_reached(&"/counter".into(), 5, "c");
+    assert_eq!(&*m.interface().unwrap(), "com.example.Counter");
+    assert_eq!(&*m.member().unwrap(), "LimitReached");
+    assert_eq!(&*m.path().unwrap(), "/counter");
+    assert_eq!(m.get2(), (Some(5u32), Some("c")));
+}
+
+fn call(c: &Connection, method: &str, args: Option<u32>) -> Result<Message, dbus::Error> {
+    let m = Message::new_method_call("com.example.counter", "/counter", "com.example.Counter", method).unwrap();
+    c.send_with_reply_and_block(args.into_iter().fold(m, |m, a| m.append1(a)), 2000)
+}
+
+#[test]
+fn server() {
+    let bus = TestBus::new().unwrap();
+    let address = bus.address().to_string();
+    let quit = Arc::new(AtomicBool::new(false));
+    let quit2 = quit.clone();
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let c = Connection::open_private(&address).unwrap();
+        c.register().unwrap();
+        c.register_name("com.example.counter", 0).unwrap();
+        let f = Factory::new_fn::<Data>();
+        let tree = f.tree(()).add(f.object_path("/counter", Arc::new(Counter::new("First"))).introspectable()
+            .add(Counter::dbus_interface(&f, (), |m| &**m.path.get_data())));
+        tree.set_registered(&c, true).unwrap();
+        c.add_handler(tree);
+        tx.send(()).unwrap();
+        while !quit2.load(Ordering::SeqCst) { for _ in c.incoming(100) {} }
+    });
+    rx.recv().unwrap();
+
+    let c = bus.connection().unwrap();
+    assert_eq!(call(&c, "Add", Some(5)).unwrap().get1(), Some(5u32));
+    assert_eq!(call(&c, "Add", Some(10)).unwrap().get1(), Some(15u32));
+    assert_eq!(call(&c, "DivMod", Some(4)).unwrap().get2(), (Some(3u32), Some(3u32)));
+    assert!(call(&c, "Add", None).is_err());
+
+    let p = c.with_path("com.example.counter", "/counter", 2000);
+    assert_eq!(p.get::<u32>("com.example.Counter", "Value").unwrap(), 15);
+    assert_eq!(p.get::<String>("com.example.Counter", "DisplayName").unwrap(), "First");
+    assert!(p.get::<u32>("com.example.Counter", "Limit").is_err());
+    p.set("com.example.Counter", "Limit", 20u32).unwrap();
+    assert!(p.set("com.example.Counter", "Limit", 0u32).is_err());
+    assert!(p.set("com.example.Counter", "DisplayName", "Second").is_err());
+    let e = call(&c, "Add", Some(10)).unwrap_err();
+    assert_eq!(e.name(), Some("com.example.Error.TooLarge"));
+
+    assert_eq!(call(&c, "Reset", None).unwrap().get1::<u32>(), None);
+    assert_eq!(p.get::<u32>("com.example.Counter", "Value").unwrap(), 0);
+    p.set("com.example.Counter", "Value", 7u32).unwrap();
+    assert_eq!(call(&c, "Add", Some(1)).unwrap().get1(), Some(8u32));
+    assert!(call(&c, "Helper", None).is_err());
+    assert!(call(&c, "New", None).is_err());
+
+    let xml = p.introspect().unwrap();
+    for s in &[
+        "<interface name=\"com.example.Counter\">",
+        "<method name=\"Add\">\n      <arg name=\"n\" type=\"u\" direction=\"in\"/>\n      <arg type=\"u\" direction=\"out\"/>\n    </method>",
+        "<method name=\"DivMod\">\n      <arg name=\"divisor\" type=\"u\" direction=\"in\"/>\n      \
+            <arg type=\"u\" direction=\"out\"/>\n      <arg type=\"u\" direction=\"out\"/>\n    </method>",
+        "<method name=\"Reset\"/>",
+        "<property name=\"Value\" type=\"u\" access=\"readwrite\">\n      \
+            <annotation name=\"org.freedesktop.DBus.Property.EmitsChangedSignal\" value=\"false\"/>",
+        "<property name=\"Limit\" type=\"u\" access=\"write\"/>",
+        "<property name=\"DisplayName\" type=\"s\" access=\"read\"/>",
+        "<signal name=\"LimitReached\">\n      <arg name=\"value\" type=\"u\"/>\n      <arg name=\"name\" type=\"s\"/>\n    </signal>",
+    ] {
+        assert!(xml.contains(s), "{} not found in {}", s, xml);
+    }
+    assert!(!xml.contains("Helper"));
+
+    quit.store(true, Ordering::SeqCst);
+    t.join().unwrap();
+}
@@ -0,0 +1,432 @@
+use std::sync::{Arc, Mutex, Once};
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{Context, Poll, Waker};
+use std::os::unix::io::RawFd;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{io, thread};
+
+use crate::{Error, ConnHandle, Command};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::FutureExt;
+use futures::Stream;
+
+#[derive(Debug)]
+struct Replies;
+
+impl dbus::MessageDispatcherConfig for Replies {
+    type Reply = oneshot::Sender<dbus::Message>;
+    fn call_reply(sender: Self::Reply, msg: dbus::Message) {
+        let _ = sender.send(msg); // If the ReplyMessage was dropped, just ignore that.
+    }
+}
+
+/// Replies to calls to org.freedesktop.DBus.Peer, and with an error to other method calls.
+pub(crate) fn default_reply(msg: &dbus::Message) -> Option<dbus::Message> {
+    dbus::MessageDispatcher::<Replies>::default_dispatch(msg)
+}
+
+#[derive(Debug)]
+struct Entry {
+    // Keeps the fd open while it is being polled.
+    txrx: Arc<dbus::TxRx>,
+    fd: RawFd,
+    writable: bool,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<usize, Entry>,
+    next_id: usize,
+}
+
+// Waits for the D-Bus file descriptors of all connections in one background thread, and wakes
+// up a ConnTxRx when there is something to read or write. This is what makes ConnTxRx
+// independent of the executor it runs on.
+#[derive(Debug)]
+struct Reactor {
+    entries: Mutex<Entries>,
+    // Writing to this pipe interrupts a blocking poll, so that changed entries are picked up.
+    pipe: [RawFd; 2],
+}
+
+impl Reactor {
+    fn get() -> io::Result<&'static Reactor> {
+        static START: Once = Once::new();
+        static mut REACTOR: Option<Result<&'static Reactor, io::ErrorKind>> = None;
+        START.call_once(|| unsafe { REACTOR = Some(Reactor::start().map_err(|e| e.kind())) });
+        unsafe { REACTOR }.unwrap().map_err(|k| io::Error::new(k, "Starting the D-Bus watcher thread failed"))
+    }
+
+    fn start() -> io::Result<&'static Reactor> {
+        let p = pipe()?;
+        let r: &'static Reactor = Box::leak(Box::new(Reactor { entries: Default::default(), pipe: p }));
+        thread::Builder::new().name("dbus-futures watcher".into()).spawn(move || r.run())?;
+        Ok(r)
+    }
+
+    fn interrupt(&self) {
+        let b = 0u8;
+        // If the pipe is full, the poll is interrupted already.
+        unsafe { libc::write(self.pipe[1], &b as *const _ as *const libc::c_void, 1) };
+    }
+
+    fn wake(&self, id: usize) {
+        let waker = self.entries.lock().unwrap().map.get_mut(&id).and_then(|e| e.waker.take());
+        if let Some(waker) = waker { waker.wake() };
+    }
+
+    fn run(&self) {
+        loop {
+            // Connections that are busy (i e, not waiting for their fd) are left out.
+            let (ids, mut fds): (Vec<_>, Vec<_>) = {
+                let e = self.entries.lock().unwrap();
+                e.map.iter().filter(|(_, x)| x.waker.is_some()).map(|(&id, x)| {
+                    let events = libc::POLLIN | if x.writable { libc::POLLOUT } else { 0 };
+                    ((id, x.txrx.clone()), libc::pollfd { fd: x.fd, events, revents: 0 })
+                }).unzip()
+            };
+            fds.push(libc::pollfd { fd: self.pipe[0], events: libc::POLLIN, revents: 0 });
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted { continue; }
+                // Let every ConnTxRx find out whether its connection is still fine, and try again in a while.
+                for &(id, _) in &ids { self.wake(id) };
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            if fds[ids.len()].revents != 0 {
+                let mut b = [0u8; 64];
+                while unsafe { libc::read(self.pipe[0], b.as_mut_ptr() as *mut libc::c_void, b.len()) } > 0 {}
+            }
+            for (&(id, _), pfd) in ids.iter().zip(&fds) {
+                if pfd.revents != 0 { self.wake(id) };
+            }
+        }
+    }
+}
+
+// A non-blocking pipe, which is not inherited by child processes.
+#[cfg(target_os = "linux")]
+fn pipe() -> io::Result<[RawFd; 2]> {
+    let mut p = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe2(p.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 { return Err(io::Error::last_os_error()) };
+    Ok(p)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pipe() -> io::Result<[RawFd; 2]> {
+    let mut p = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(p.as_mut_ptr()) } < 0 { return Err(io::Error::last_os_error()) };
+    for &fd in &p {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK);
+        }
+    }
+    Ok(p)
+}
+
+// A connection's entry in the Reactor.
+#[derive(Debug)]
+struct Watcher {
+    reactor: &'static Reactor,
+    id: usize,
+}
+
+impl Watcher {
+    fn new(txrx: Arc<dbus::TxRx>, fd: RawFd) -> io::Result<Watcher> {
+        let reactor = Reactor::get()?;
+        let mut e = reactor.entries.lock().unwrap();
+        let id = e.next_id;
+        e.next_id += 1;
+        e.map.insert(id, Entry { txrx, fd, writable: false, waker: None });
+        Ok(Watcher { reactor, id })
+    }
+
+    // Wake up the waker when the fd becomes readable, or writable if "writable" is set.
+    fn arm(&self, waker: &Waker, writable: bool) {
+        let mut e = self.reactor.entries.lock().unwrap();
+        let x = e.map.get_mut(&self.id).unwrap();
+        // Unless the poll already waits for the same thing, it needs to start over.
+        let restart = x.waker.is_none() || x.writable != writable;
+        x.waker = Some(waker.clone());
+        x.writable = writable;
+        drop(e);
+        if restart { self.reactor.interrupt() };
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.reactor.entries.lock().unwrap().map.remove(&self.id);
+        self.reactor.interrupt();
+    }
+}
+
+/// This is the reactor independent part of the Connection.
+///
+/// It is a future that reads and dispatches incoming messages, and writes outgoing messages.
+/// You need to spawn it on an executor - any executor will do - or nothing will happen.
+/// Before you do that, you will probably want to get a handle or two.
+///
+/// The future resolves when one of its handles calls `quit`, or when the connection is closed.
+#[derive(Debug)]
+pub struct ConnTxRx {
+    txrx: Arc<dbus::TxRx>,
+    watcher: Watcher,
+    command_sender: mpsc::UnboundedSender<Command>,
+    command_receiver: mpsc::UnboundedReceiver<Command>,
+    replies: dbus::MessageDispatcher<Replies>,
+    server: Option<mpsc::UnboundedSender<dbus::Message>>,
+    streams: Vec<(Option<dbus::MatchRule<'static>>, mpsc::UnboundedSender<dbus::Message>)>,
+    done: Option<oneshot::Sender<()>>,
+    done_receiver: futures::future::Shared<oneshot::Receiver<()>>,
+    // Held by ConnHandle::send_with_reply while sending and registering a reply.
+    reply_lock: Arc<Mutex<()>>,
+    quit: bool,
+}
+
+impl ConnTxRx {
+    /// Creates a new D-Bus connection and connects it to the Session bus.
+    ///
+    /// Blocking: until the connection is up and running.
+    pub fn new_session() -> Result<Self, Error> {
+        Self::new(dbus::TxRx::get_private(dbus::BusType::Session)?)
+    }
+
+    /// Takes over an existing TxRx connection.
+    pub fn new(mut x: dbus::TxRx) -> Result<Self, Error> {
+        let watches = x.watch_fds().map_err(|_| Error::failed(&"Getting D-Bus file descriptors failed"))?;
+        let fd = match watches.into_iter().find(|w| w.readable()) {
+            Some(w) => w.fd(),
+            None => return Err(Error::failed(&"D-Bus connection has no readable file descriptor")),
+        };
+        let txrx = Arc::new(x);
+        let watcher = Watcher::new(txrx.clone(), fd).map_err(|e| Error::failed(&e))?;
+        let (s, r) = mpsc::unbounded();
+        let (ds, dr) = oneshot::channel();
+        Ok(ConnTxRx { txrx, watcher, command_sender: s, command_receiver: r, replies: dbus::MessageDispatcher::new(),
+            server: None, streams: vec!(), done: Some(ds), done_receiver: dr.shared(), reply_lock: Default::default(), quit: false })
+    }
+
+    pub fn handle(&self) -> ConnHandle {
+        ConnHandle(self.txrx.clone(), self.command_sender.clone(), self.done_receiver.clone(), self.reply_lock.clone())
+    }
+
+    fn check_cmd(&mut self, cx: &mut Context) -> bool {
+        if let Poll::Ready(cmd) = Pin::new(&mut self.command_receiver).poll_next(cx) {
+            match cmd {
+                None | Some(Command::Quit) =>  { self.quit = true; },
+                Some(Command::AddReply(serial, sender)) => { self.replies.add_reply(serial, sender); },
+                Some(Command::CancelReply(serial)) => { self.replies.cancel_reply(serial); },
+                Some(Command::Serve(sender)) => { self.server = Some(sender); },
+                Some(Command::AddStream(filter, sender)) => { self.streams.push((filter.map(|f| *f), sender)); },
+                Some(Command::Wake) => {},
+            };
+            true
+        } else { false }
+    }
+
+    fn check_msg(&mut self, cx: &mut Context) -> bool {
+        let lock = self.reply_lock.clone();
+        let guard = lock.lock().unwrap();
+        // Every reply that can be read now belongs to a call whose AddReply is already queued.
+        while self.check_cmd(cx) {}
+        let msg = self.txrx.pop_message();
+        drop(guard);
+        if let Some(msg) = msg {
+            if msg.msg_type() == dbus::MessageType::MethodCall {
+                self.dispatch_method_call(msg);
+            } else if let Some(msg) = self.replies.dispatch_reply(msg) {
+                self.send_streams(msg);
+            }
+            true
+        } else { false }
+    }
+
+    fn dispatch_method_call(&mut self, msg: dbus::Message) {
+        let msg = match &self.server {
+            Some(server) => match server.unbounded_send(msg) {
+                Ok(()) => return,
+                Err(e) => { self.server = None; e.into_inner() },
+            },
+            None => msg,
+        };
+        let msg = match self.send_streams(msg) {
+            Some(msg) => msg,
+            None => return,
+        };
+        // Nobody is serving method calls, so reply like a good D-Bus citizen.
+        if let Some(reply) = default_reply(&msg) {
+            let _ = self.txrx.send(reply);
+        }
+    }
+
+    // Gives every matching stream its own copy of the message. Returns the message if no stream matched.
+    fn send_streams(&mut self, msg: dbus::Message) -> Option<dbus::Message> {
+        self.streams.retain(|(_, s)| !s.is_closed());
+        let targets: Vec<_> = self.streams.iter().filter(|(filter, _)| {
+            filter.as_ref().map(|f| f.matches_delivered(&msg)).unwrap_or(true)
+        }).map(|(_, s)| s).collect();
+        let (last, rest) = match targets.split_last() {
+            Some(x) => x,
+            None => return Some(msg),
+        };
+        for s in rest {
+            // Duplicating only fails if we're out of memory.
+            if let Ok(m) = msg.duplicate() { let _ = s.unbounded_send(m); }
+        }
+        let _ = last.unbounded_send(msg);
+        None
+    }
+}
+
+impl Future for ConnTxRx {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut has_rw = false;
+        loop {
+            if self.quit {
+                self.replies = dbus::MessageDispatcher::new();
+                if let Some(done) = self.done.take() { let _ = done.send(()); }
+                return Poll::Ready(())
+            };
+            if self.check_cmd(cx) { continue; }
+            if self.check_msg(cx) {
+                has_rw = false;
+                continue;
+            }
+            if !has_rw {
+                if self.txrx.read_write(Some(0)).is_err() || !self.txrx.is_connected() { self.quit = true; }
+                has_rw = true;
+                continue;
+            }
+            self.watcher.arm(cx.waker(), self.txrx.has_messages_to_send());
+            return Poll::Pending;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::{block_on, LocalPool};
+    use futures::task::LocalSpawnExt;
+    use dbus::testing::TestBus;
+    use super::ConnTxRx;
+    use crate::ReplyMessage;
+    use std::sync::Arc;
+
+    fn test_conn(bus: &TestBus) -> ConnTxRx {
+        let mut x = dbus::TxRx::open_private(bus.address()).unwrap();
+        x.register().unwrap();
+        ConnTxRx::new(x).unwrap()
+    }
+
+    #[test]
+    fn basic_conn() {
+        let bus = TestBus::new().unwrap();
+        let ctr = test_conn(&bus);
+        let c = ctr.handle();
+        let mut pool = LocalPool::new();
+        pool.spawner().spawn_local(ctr).unwrap();
+
+        pool.run_until(async {
+            let remote_path = c.with_path("org.freedesktop.DBus", "/");
+            let reply_future: ReplyMessage = remote_path.method_call_with_args(
+                &"org.freedesktop.DBus".into(), &"ListNames".into(), |_| {});
+            let msg = reply_future.await.unwrap();
+            let reply: Vec<String> = msg.read1().unwrap();
+            assert!(reply.iter().any(|t| t == c.unique_name()));
+            c.quit().unwrap();
+            c.clone().await;
+        });
+    }
+
+    #[test]
+    fn cancel_calls() {
+        use crate::stdintf::org_freedesktop::DBus;
+        use std::task::Context;
+        use std::future::Future;
+        use std::pin::Pin;
+        let bus = TestBus::new().unwrap();
+        let mut ctr = test_conn(&bus);
+        let c = ctr.handle();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // Catch the method calls to ourselves, so nobody answers them.
+        let mut calls = c.message_stream(Some(dbus::MatchRule::new().with_type(dbus::MessageType::MethodCall)));
+        let p = c.with_path(c.unique_name().to_string(), "/");
+        for _ in 0..50 {
+            drop(p.method_call_with_args(&"com.example.test".into(), &"Hang".into(), |_| {}));
+            drop(p.get_id());
+        }
+        p.method_call_with_args(&"com.example.test".into(), &"Hang".into(), |_| {}).cancel();
+        p.get_id().cancel();
+        let _kept = p.method_call_with_args(&"com.example.test".into(), &"Hang".into(), |_| {});
+
+        let mut received = 0;
+        for _ in 0..500 {
+            assert!(Pin::new(&mut ctr).poll(&mut cx).is_pending());
+            while calls.0.try_recv().is_ok() { received += 1; }
+            if received == 103 { break; }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(received, 103);
+        assert_eq!(ctr.replies.waiting_replies(), 1);
+    }
+
+    #[test]
+    fn shared_watcher() {
+        let bus = TestBus::new().unwrap();
+        let ctrs: Vec<_> = (0..3).map(|_| test_conn(&bus)).collect();
+        let r = super::Reactor::get().unwrap();
+        assert!(ctrs.iter().all(|c| std::ptr::eq(c.watcher.reactor, r)));
+        for &fd in &r.pipe {
+            assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0);
+        }
+
+        let handles: Vec<_> = ctrs.iter().map(|c| c.handle()).collect();
+        let mut pool = LocalPool::new();
+        for c in ctrs { pool.spawner().spawn_local(c).unwrap(); }
+        pool.run_until(async {
+            for c in &handles {
+                let reply = c.with_path("org.freedesktop.DBus", "/").method_call_with_args(
+                    &"org.freedesktop.DBus".into(), &"GetId".into(), |_| {}).await;
+                assert!(reply.is_ok());
+                c.quit().unwrap();
+            }
+        });
+        pool.run();
+        assert!(!r.entries.lock().unwrap().map.values().any(|e| handles.iter().any(|c| Arc::ptr_eq(&e.txrx, &c.0))));
+    }
+
+    #[test]
+    fn gen_conn_threaded() {
+        use crate::stdintf::org_freedesktop::DBus;
+        let bus = TestBus::new().unwrap();
+        let ctr = test_conn(&bus);
+        let c = ctr.handle();
+        let t = std::thread::spawn(move || block_on(ctr));
+
+        let remote_path = c.with_path("org.freedesktop.DBus", "/org/freedesktop/DBus");
+        let id = block_on(async { remote_path.get_id().await }).unwrap();
+        assert!(!id.is_empty());
+        let e = block_on(remote_path.get_connection_unix_user("com.example.nonexistent")).unwrap_err();
+        assert!(!e.errorname().is_empty());
+
+        // Too large to be written at once, so the driver has to wait for the fd to become writable.
+        let big = vec!["x".repeat(1024); 8192];
+        let e = block_on(remote_path.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetId".into(), |m| {
+            dbus::arg::IterAppend::new(m).append(&big);
+        }));
+        assert!(e.is_ok());
+
+        c.quit().unwrap();
+        block_on(c);
+        t.join().unwrap();
+    }
+}
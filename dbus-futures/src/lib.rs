@@ -1,71 +1,112 @@
-#![feature(futures_api)]
-
 use dbus;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::pin::Pin;
+use std::future::Future;
+use std::task::{Context, Poll};
 use futures::channel::{oneshot, mpsc};
-
-use futures::task;
-use futures::future::ready;
+use futures::future::{FutureExt, Shared};
 
 pub type Error = dbus::tree::MethodErr;
 
 pub mod stdintf;
 
+mod driver;
+
+pub mod tree;
+
+mod stream;
+
+pub use crate::driver::ConnTxRx;
+pub use crate::stream::{MessageStream, SignalStream};
+
 // To be sent to the backend
 #[derive(Debug)]
 enum Command {
     AddReply(u32, oneshot::Sender<dbus::Message>),
+    CancelReply(u32),
+    Serve(mpsc::UnboundedSender<dbus::Message>),
+    AddStream(Option<Box<dbus::MatchRule<'static>>>, mpsc::UnboundedSender<dbus::Message>),
+    Wake,
     Quit,
 }
 
 #[derive(Debug)]
-pub struct ReplyMessage(Result<oneshot::Receiver<dbus::Message>, Option<Error>>);
-
-impl futures::TryFuture for ReplyMessage {
-    type Ok = dbus::Message;
-    type Error = Error;
-    fn try_poll(mut self: Pin<&mut Self>, lw: &task::LocalWaker) -> task::Poll<Result<Self::Ok, Self::Error>> {
-        match &mut self.0 {
-            Err(e) => task::Poll::Ready(Err(e.take().unwrap())),
-            Ok(ref mut recv) => {
-                use futures::Future;
-                let p: Pin<&mut oneshot::Receiver<dbus::Message>> = Pin::new(recv);
-                let mut r: dbus::Message = futures::try_ready!(p.poll(lw).map_err(|e| { Error::failed(&e) }));
-                task::Poll::Ready((|| { r.as_result()?; Ok(r) })())
-            }
-        }
+/// A future method reply.
+///
+/// Dropping it before the reply has arrived cancels the method call, i e, the reply is ignored.
+pub struct ReplyMessage {
+    inner: Result<oneshot::Receiver<dbus::Message>, Option<Error>>,
+    cancel: Option<(u32, mpsc::UnboundedSender<Command>)>,
+}
+
+impl Future for ReplyMessage {
+    type Output = Result<dbus::Message, Error>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let r = match &mut self.inner {
+            Err(e) => Poll::Ready(Err(e.take().unwrap())),
+            Ok(ref mut recv) => Pin::new(recv).poll(cx).map(|r| {
+                let mut r = r.map_err(|e| Error::failed(&e))?;
+                r.as_result()?;
+                Ok(r)
+            }),
+        };
+        // The driver has already forgotten about the call, no need to cancel it.
+        if r.is_ready() { self.cancel = None; }
+        r
     }
 }
 
 impl ReplyMessage {
+    /// Waits for the reply to an already sent method call.
+    ///
+    /// If the call was sent from another thread than the one running the `ConnTxRx`, the reply
+    /// might already have been handled, prefer `ConnHandle::send_with_reply` for that reason.
     pub fn new(serial: u32, handle: &ConnHandle) -> Self {
         let (s, r) = oneshot::channel();
-        ReplyMessage(
-            handle.1.unbounded_send(Command::AddReply(serial, s))
-                .map_err(|e| { Some(Error::failed(&e)) })
-                .map(|_| r)
-        )
+        let inner = handle.1.unbounded_send(Command::AddReply(serial, s))
+            .map_err(|e| { Some(Error::failed(&e)) })
+            .map(|_| r);
+        let cancel = if inner.is_ok() { Some((serial, handle.1.clone())) } else { None };
+        ReplyMessage { inner, cancel }
     }
+
+    fn from_err(e: Error) -> Self { ReplyMessage { inner: Err(Some(e)), cancel: None } }
+
+    /// Cancels the method call. This is the same as dropping it.
+    pub fn cancel(self) {}
 }
 
+impl Drop for ReplyMessage {
+    fn drop(&mut self) {
+        if let Some((serial, sender)) = self.cancel.take() {
+            let _ = sender.unbounded_send(Command::CancelReply(serial));
+        }
+    }
+}
+
+/// A future method reply, parsed into the method's return value.
+///
+/// Dropping it before the reply has arrived cancels the method call.
+/// It is `Send`, so it can be awaited on a multi-threaded executor.
 pub struct MethodReply<T> {
-    f: Pin<Box<futures::Future<Output=Result<T, Error>>>>,
+    f: Pin<Box<dyn Future<Output=Result<T, Error>> + Send>>,
 }
 
-impl<T> futures::TryFuture for MethodReply<T> {
-    type Ok = T;
-    type Error = Error;
-    fn try_poll(mut self: Pin<&mut Self>, lw: &task::LocalWaker) -> task::Poll<Result<Self::Ok, Self::Error>> {
-        let p = Pin::new(&mut self.f);
-        p.try_poll(lw)
+impl<T> Future for MethodReply<T> {
+    type Output = Result<T, Error>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.f.as_mut().poll(cx)
     }
 }
 
+impl<T> MethodReply<T> {
+    /// Cancels the method call. This is the same as dropping it.
+    pub fn cancel(self) {}
+}
+
 impl<T: 'static> MethodReply<T> {
-    pub fn from_msg<F: FnOnce(dbus::Message) -> Result<T, Error> + 'static>(msg: ReplyMessage, parse_fn: F) -> Self {
-        use futures::TryFutureExt;
-        MethodReply { f: Box::pin(msg.and_then(|m| ready(parse_fn(m))).into_future()) }
+    pub fn from_msg<F: FnOnce(dbus::Message) -> Result<T, Error> + Send + 'static>(msg: ReplyMessage, parse_fn: F) -> Self {
+        MethodReply { f: Box::pin(msg.map(|r| r.and_then(parse_fn))) }
     }
 }
 
@@ -87,10 +128,7 @@ impl<'a> ConnPath<'a> {
     {
         let mut msg = dbus::Message::method_call(&self.dest, &self.path, i, m);
         f(&mut msg);
-        match self.conn.send(msg) {
-            Ok(serial) => ReplyMessage::new(serial, &self.conn),
-            Err(e) => ReplyMessage(Err(Some(e))),
-        }
+        self.conn.send_with_reply(msg)
     }
 
     /// Emit a D-Bus signal, where you can append arguments inside the closure.
@@ -107,8 +145,25 @@ impl<'a> ConnPath<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct ConnHandle(Arc<dbus::TxRx>, mpsc::UnboundedSender<Command>);
+#[derive(Clone)]
+/// A handle to a connection, which can be cloned and sent between threads.
+///
+/// The handle is also a future, which resolves once the connection's `ConnTxRx` has quit.
+pub struct ConnHandle(Arc<dbus::TxRx>, mpsc::UnboundedSender<Command>, Shared<oneshot::Receiver<()>>, Arc<Mutex<()>>);
+
+impl std::fmt::Debug for ConnHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("ConnHandle").field(&self.0).field(&self.1).finish()
+    }
+}
+
+impl Future for ConnHandle {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // Canceled means that the ConnTxRx was dropped, which is just as final as quitting.
+        Pin::new(&mut self.2).poll(cx).map(|_| ())
+    }
+}
 
 impl ConnHandle {
     /// Get the connection's unique name.
@@ -121,7 +176,21 @@ impl ConnHandle {
     /// Returns a serial number than can be used to match against a reply.
     /// This does not flush the out queue, the messages are likely to be written the next time the main loop runs.
     pub fn send(&self, msg: dbus::Message) -> Result<u32, Error> {
-        self.0.send(msg).map_err(|_| Error::from((dbus::ErrorName::from("org.freedesktop.DBus.Error.Failed"), "Sending message failed")))
+        let serial = self.0.send(msg).map_err(|_| Error::from((dbus::ErrorName::from("org.freedesktop.DBus.Error.Failed"), "Sending message failed")))?;
+        // libdbus could not write everything right away, so wake up the ConnTxRx to write the rest.
+        if self.0.has_messages_to_send() { let _ = self.1.unbounded_send(Command::Wake); }
+        Ok(serial)
+    }
+
+    /// Sends a method call, and returns a future for its reply.
+    pub fn send_with_reply(&self, msg: dbus::Message) -> ReplyMessage {
+        // Hold the lock until the reply is registered, so the ConnTxRx cannot read the reply
+        // before it knows who is waiting for it.
+        let _guard = self.3.lock().unwrap();
+        match self.send(msg) {
+            Ok(serial) => ReplyMessage::new(serial, self),
+            Err(e) => ReplyMessage::from_err(e),
+        }
     }
 
     /// Create a convenience struct for easier calling of many methods on the same destination and path.
@@ -129,14 +198,42 @@ impl ConnHandle {
         ConnPath { conn: self.clone(), dest: dest.into(), path: path.into() }
     }
 
+    /// Returns a stream of incoming messages.
+    ///
+    /// This includes signals, method calls not handled by a `tree::ATreeServer`, and method
+    /// replies that no `ReplyMessage` is waiting for. The bus only sends signals you have added
+    /// a match rule for, see `signals` for an easy way to do that.
+    pub fn messages(&self) -> MessageStream { self.message_stream(None) }
+
+    /// Like `messages`, but only messages matching the filter are included.
+    ///
+    /// A method call included in at least one stream does not get any default reply, so
+    /// make sure to reply to it yourself.
+    pub fn message_stream(&self, filter: Option<dbus::MatchRule>) -> MessageStream {
+        let (s, r) = mpsc::unbounded();
+        // If the ConnTxRx has already quit, the stream will just end.
+        let _ = self.1.unbounded_send(Command::AddStream(filter.map(|f| Box::new(f.into_static())), s));
+        MessageStream(r)
+    }
+
+    /// Adds a match rule for the signal, and returns a stream of matching signals once the bus has replied.
+    ///
+    /// The match rule is removed when the stream is dropped.
+    pub fn signals<S: dbus::SignalArgs + 'static>(&self, sender: Option<&dbus::BusName>, path: Option<&dbus::Path>) -> MethodReply<SignalStream<S>> {
+        use crate::stdintf::org_freedesktop::DBus;
+        let rule = S::match_rule(sender, path).into_static();
+        let rulestr = rule.to_string();
+        let stream = SignalStream::new(self.message_stream(Some(rule)), self.clone(), rulestr.clone());
+        let reply = self.with_path("org.freedesktop.DBus", "/org/freedesktop/DBus").add_match(&rulestr);
+        MethodReply { f: Box::pin(reply.map(move |r| r.map(|_| stream))) }
+    }
+
     /// Tells the TxRx part to quit from the event loop.
     pub fn quit(&self) -> Result<(), ()> {
          self.1.unbounded_send(Command::Quit).map_err(|_| ())
     }
 }
 
-#[cfg(feature = "tml")]
-pub mod thin_main_loop;
 
 
 
@@ -0,0 +1,139 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::marker::PhantomData;
+use futures::channel::mpsc;
+use futures::Stream;
+
+use crate::ConnHandle;
+use crate::stdintf::org_freedesktop::DBus;
+
+#[derive(Debug)]
+/// A stream of incoming messages.
+///
+/// Created by `ConnHandle::messages` or `ConnHandle::message_stream`. The stream ends when the connection quits.
+pub struct MessageStream(pub(crate) mpsc::UnboundedReceiver<dbus::Message>);
+
+impl Stream for MessageStream {
+    type Item = dbus::Message;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+#[derive(Debug)]
+/// A stream of signals, together with their sender and object path.
+///
+/// Created by `ConnHandle::signals`. The match rule is removed from the bus when the stream is dropped.
+pub struct SignalStream<S> {
+    inner: MessageStream,
+    conn: ConnHandle,
+    rule: String,
+    _dummy: PhantomData<fn() -> S>,
+}
+
+impl<S> SignalStream<S> {
+    pub(crate) fn new(inner: MessageStream, conn: ConnHandle, rule: String) -> Self {
+        SignalStream { inner, conn, rule, _dummy: PhantomData }
+    }
+}
+
+impl<S: dbus::SignalArgs> Stream for SignalStream<S> {
+    type Item = (S, dbus::BusName<'static>, dbus::Path<'static>);
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            let m = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let (Some(s), Some(sender), Some(path)) = (S::from_message(&m), m.sender(), m.path()) {
+                return Poll::Ready(Some((s, sender.into_static(), path.into_static())));
+            }
+        }
+    }
+}
+
+impl<S> Drop for SignalStream<S> {
+    fn drop(&mut self) {
+        // The call is sent right away, we don't need to wait for the reply.
+        drop(self.conn.with_path("org.freedesktop.DBus", "/org/freedesktop/DBus").remove_match(&self.rule));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    use futures::StreamExt;
+    use dbus::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved as IR;
+    use crate::{ConnTxRx, ConnHandle};
+
+    fn test_conn(bus: &dbus::testing::TestBus, pool: &LocalPool) -> ConnHandle {
+        let mut x = dbus::TxRx::open_private(bus.address()).unwrap();
+        x.register().unwrap();
+        let ctr = ConnTxRx::new(x).unwrap();
+        let c = ctr.handle();
+        pool.spawner().spawn_local(ctr).unwrap();
+        c
+    }
+
+    #[test]
+    fn signal_stream() {
+        let bus = dbus::testing::TestBus::new().unwrap();
+        let mut pool = LocalPool::new();
+        let c = test_conn(&bus, &pool);
+        let c2 = test_conn(&bus, &pool);
+
+        pool.run_until(async {
+            let mut signals = c.signals::<IR>(None, Some(&"/hello".into())).await.unwrap();
+            let mut all = c.messages();
+            let ir = IR { object: "/a".into(), interfaces: vec!("com.example.A".into()) };
+            c2.with_path("com.example", "/other").emit(&ir).unwrap();
+            c2.with_path("com.example", "/hello").emit(&ir).unwrap();
+
+            let (ir2, sender, path) = signals.next().await.unwrap();
+            assert_eq!(ir2.interfaces, ir.interfaces);
+            assert_eq!(&*sender, c2.unique_name());
+            assert_eq!(&*path, "/hello");
+
+            // The messages stream gets everything, which might include the NameAcquired signal.
+            let mut m = all.next().await.unwrap();
+            if &*m.member().unwrap() == "NameAcquired" { m = all.next().await.unwrap(); }
+            assert_eq!(&*m.path().unwrap(), "/hello");
+
+            drop(signals);
+            c.quit().unwrap();
+            assert!(all.next().await.is_none());
+            c2.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn method_call_stream() {
+        let bus = dbus::testing::TestBus::new().unwrap();
+        let mut pool = LocalPool::new();
+        let c = test_conn(&bus, &pool);
+        let c2 = test_conn(&bus, &pool);
+
+        let filter = dbus::MatchRule::new().with_type(dbus::MessageType::MethodCall).with_interface("com.example.test");
+        let mut calls = c.message_stream(Some(filter));
+        let c1 = c.clone();
+        pool.spawner().spawn_local(async move {
+            while let Some(m) = calls.next().await {
+                let x: i32 = m.read1().unwrap();
+                c1.send(m.method_return().append1(x + 1)).unwrap();
+            }
+        }).unwrap();
+
+        pool.run_until(async {
+            let p = c2.with_path(c.unique_name().to_string(), "/");
+            let r = p.method_call_with_args(&"com.example.test".into(), &"Inc".into(), |m| { dbus::arg::IterAppend::new(m).append(5i32) }).await;
+            assert_eq!(r.unwrap().read1::<i32>().unwrap(), 6);
+            // Not matched by any stream, so we get the default reply.
+            let e = p.method_call_with_args(&"com.example.other".into(), &"Inc".into(), |_| {}).await.unwrap_err();
+            assert_eq!(&**e.errorname(), "org.freedesktop.DBus.Error.UnknownMethod");
+            c.quit().unwrap();
+            c2.quit().unwrap();
+        });
+    }
+}
@@ -0,0 +1,267 @@
+//! Async server-side trees
+
+use std::{ops, fmt, mem};
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{Context, Poll};
+use std::marker::PhantomData;
+use std::cell::RefCell;
+use std::ffi::CString;
+use dbus::tree::{Factory, Tree, MethodType, DataType, MTFn, Method, MethodInfo, MethodErr};
+use dbus::{Member, Message};
+use futures::channel::mpsc;
+use futures::Stream;
+
+use crate::{ConnHandle, Command};
+use crate::driver::default_reply;
+
+pub trait ADataType: fmt::Debug + Sized + Default {
+    type ObjectPath: fmt::Debug;
+    type Property: fmt::Debug;
+    type Interface: fmt::Debug + Default;
+    type Method: fmt::Debug + Default;
+    type Signal: fmt::Debug;
+}
+
+#[derive(Debug, Default)]
+/// A Tree that allows both synchronous and asynchronous methods.
+pub struct ATree<D: ADataType>(RefCell<Option<AMethodResult>>, PhantomData<*const D>);
+
+impl<D: ADataType> ATree<D> {
+    pub fn new() -> Self { Default::default() }
+    fn push(&self, a: AMethodResult) {
+        let mut z = self.0.borrow_mut();
+        assert!(z.is_none(), "Same message handled twice");
+        *z = Some(a);
+    }
+}
+
+impl<D: ADataType> DataType for ATree<D> {
+    type Tree = ATree<D>;
+    type ObjectPath = D::ObjectPath;
+    type Property = D::Property;
+    type Interface = D::Interface;
+    type Method = D::Method;
+    type Signal = D::Signal;
+}
+
+impl ADataType for () {
+    type ObjectPath = ();
+    type Property = ();
+    type Interface = ();
+    type Method = ();
+    type Signal = ();
+}
+
+/// A Tree factory that allows both synchronous and asynchronous methods.
+pub struct AFactory<M: MethodType<D>, D: DataType = ()>(Factory<M, D>);
+
+impl AFactory<MTFn<()>, ()> {
+    pub fn new_afn<D: ADataType>() -> AFactory<MTFn<ATree<D>>, ATree<D>> { AFactory(Factory::new_fn()) }
+}
+
+impl<M: MethodType<D>, D: DataType> ops::Deref for AFactory<M, D> {
+    type Target = Factory<M, D>;
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl<D: ADataType> AFactory<MTFn<ATree<D>>, ATree<D>> {
+    /// Creates an async method, for methods whose result cannot be returned immediately.
+    ///
+    /// The method handler supplied to amethod returns a future, which resolves into the method result.
+    /// If it resolves into an error, an error reply is sent back to the caller.
+    pub fn amethod<H, R, T>(&self, t: T, data: D::Method, handler: H) -> Method<MTFn<ATree<D>>, ATree<D>>
+    where H: 'static + Fn(&MethodInfo<MTFn<ATree<D>>, ATree<D>>) -> R, T: Into<Member<'static>>,
+        R: 'static + Future<Output=Result<Vec<Message>, MethodErr>> {
+        self.0.method(t, data, move |minfo| {
+            let r = handler(minfo);
+            minfo.tree.get_data().push(AMethodResult::new(r));
+            Ok(Vec::new())
+        })
+    }
+}
+
+/// A Future method result
+///
+/// When method results cannot be returned right away, the AMethodResult holds it temporarily
+struct AMethodResult(Pin<Box<dyn Future<Output=Result<Vec<Message>, MethodErr>>>>, Option<Message>);
+
+impl fmt::Debug for AMethodResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "AMethodResult({:?})", self.1) }
+}
+
+impl AMethodResult {
+    fn new<F: 'static + Future<Output=Result<Vec<Message>, MethodErr>>>(f: F) -> Self {
+        AMethodResult(Box::pin(f), None)
+    }
+}
+
+impl Future for AMethodResult {
+    type Output = Result<Vec<Message>, MethodErr>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+#[derive(Debug)]
+/// Handles incoming method calls in the tree, and sends back the replies.
+///
+/// Once created, the ConnTxRx hands all incoming method calls over to this server. The server is a
+/// future which needs to be spawned on an executor. It resolves when the connection quits.
+///
+/// There is no blocking connection to look up callers with, so `MethodInfo::caller_credentials` and
+/// methods with `require_polkit` fail with an error, unless the tree has been given a credentials cache
+/// of a `dbus::Connection` to the same bus through `Tree::set_credentials_cache`.
+pub struct ATreeServer<T, D>
+where T: ops::Deref<Target=Tree<MTFn<ATree<D>>, ATree<D>>>,
+      D: ADataType {
+    conn: ConnHandle,
+    tree: T,
+    calls: mpsc::UnboundedReceiver<Message>,
+    pendingresults: Vec<AMethodResult>,
+}
+
+// Nothing inside the server is structurally pinned.
+impl<T, D> Unpin for ATreeServer<T, D>
+where T: ops::Deref<Target=Tree<MTFn<ATree<D>>, ATree<D>>>,
+      D: ADataType {}
+
+impl<T, D> ATreeServer<T, D>
+where T: ops::Deref<Target=Tree<MTFn<ATree<D>>, ATree<D>>>,
+      D: ADataType {
+    /// Creates a server for the tree, which replaces any earlier server on the same connection.
+    pub fn new(conn: ConnHandle, tree: T) -> Self {
+        let (s, r) = mpsc::unbounded();
+        // If the ConnTxRx has already quit, the receiver will tell us when polled.
+        let _ = conn.1.unbounded_send(Command::Serve(s));
+        ATreeServer { conn, tree, calls: r, pendingresults: vec![] }
+    }
+
+    fn send(&self, msg: Message) {
+        // If this fails, the connection is going down and there is nobody to reply to anyway.
+        let _ = self.conn.send(msg);
+    }
+
+    fn handle_call(&mut self, msg: Message) {
+        match self.tree.handle(&msg) {
+            Some(v) => {
+                if let Some(mut r) = self.tree.get_data().0.borrow_mut().take() {
+                    r.1 = Some(msg);
+                    self.pendingresults.push(r);
+                }
+                for m in v { self.send(m) }
+            }
+            None => {
+                // Not in our tree, so reply with the default reply.
+                if let Some(m) = default_reply(&msg) { self.send(m) }
+            }
+        }
+    }
+
+    fn check_pending_results(&mut self, cx: &mut Context) {
+        let v = mem::take(&mut self.pendingresults);
+        for mut mr in v {
+            match Pin::new(&mut mr).poll(cx) {
+                Poll::Pending => self.pendingresults.push(mr),
+                Poll::Ready(Ok(t)) => for msg in t { self.send(msg) },
+                Poll::Ready(Err(e)) => {
+                    let m = mr.1.take().unwrap();
+                    // A D-Bus string cannot contain NULs, so drop them rather than panic.
+                    let text = CString::new(e.description().replace('\0', "")).unwrap();
+                    let msg = m.error(e.errorname(), &text);
+                    self.send(msg);
+                }
+            }
+        }
+    }
+}
+
+impl<T, D> Future for ATreeServer<T, D>
+where T: ops::Deref<Target=Tree<MTFn<ATree<D>>, ATree<D>>>,
+      D: ADataType {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        loop {
+            match Pin::new(&mut self.calls).poll_next(cx) {
+                Poll::Ready(Some(m)) => self.handle_call(m),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+        self.check_pending_results(cx);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    use futures::channel::oneshot;
+    use dbus::tree::MethodErr;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use crate::ConnTxRx;
+    use super::{AFactory, ATree, ATreeServer};
+
+    #[test]
+    fn async_server() {
+        let bus = dbus::testing::TestBus::new().unwrap();
+        let mut x = dbus::TxRx::open_private(bus.address()).unwrap();
+        x.register().unwrap();
+        let ctr = ConnTxRx::new(x).unwrap();
+        let c = ctr.handle();
+        let mut x2 = dbus::TxRx::open_private(bus.address()).unwrap();
+        x2.register().unwrap();
+        let ctr2 = ConnTxRx::new(x2).unwrap();
+        let c2 = ctr2.handle();
+
+        // The reply to "Later" is sent when we say so.
+        let later: Rc<RefCell<Option<oneshot::Sender<i32>>>> = Default::default();
+        let later2 = later.clone();
+        let f = AFactory::new_afn::<()>();
+        let tree = Rc::new(f.tree(ATree::new()).add(f.object_path("/test", ()).add(f.interface("com.example.test", ())
+            .add_m(f.method("Sync", (), |m| Ok(vec!(m.msg.method_return().append1(1i32)))))
+            .add_m(f.amethod("Later", (), move |m| {
+                let (s, r) = oneshot::channel();
+                *later2.borrow_mut() = Some(s);
+                let reply = m.msg.method_return();
+                async move { Ok(vec!(reply.append1(r.await.unwrap()))) }
+            }))
+            .add_m(f.amethod("Fail", (), |_| async { Err(MethodErr::failed(&"Oops")) }))
+            .add_m(f.amethod("FailNul", (), |_| async { Err(MethodErr::failed(&"Oops\0again")) }))
+        )));
+
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        spawner.spawn_local(ctr).unwrap();
+        spawner.spawn_local(ctr2).unwrap();
+        spawner.spawn_local(ATreeServer::new(c.clone(), tree)).unwrap();
+
+        pool.run_until(async {
+            let p = c2.with_path(c.unique_name().to_string(), "/test");
+            let call = |name: &'static str| p.method_call_with_args(&"com.example.test".into(), &name.into(), |_| {});
+
+            let r: i32 = call("Sync").await.unwrap().read1().unwrap();
+            assert_eq!(r, 1);
+
+            let e = call("Fail").await.unwrap_err();
+            assert_eq!(&**e.errorname(), "org.freedesktop.DBus.Error.Failed");
+            assert!(e.description().contains("Oops"));
+
+            let e = call("FailNul").await.unwrap_err();
+            assert_eq!(&**e.errorname(), "org.freedesktop.DBus.Error.Failed");
+            assert!(e.description().contains("Oopsagain"));
+
+            let pending = call("Later");
+            let e = call("Missing").await.unwrap_err();
+            assert_eq!(&**e.errorname(), "org.freedesktop.DBus.Error.UnknownMethod");
+            later.borrow_mut().take().unwrap().send(5).unwrap();
+            let r: i32 = pending.await.unwrap().read1().unwrap();
+            assert_eq!(r, 5);
+
+            c.quit().unwrap();
+            c2.quit().unwrap();
+        });
+    }
+}
@@ -1,21 +1,61 @@
 use mio::{self, unix, Ready};
 use mio::unix::UnixReady;
 use std::io;
-use dbus::{Connection, ConnMsgs, Watch, WatchEvent, Message, MessageType, Error as DBusError};
-use futures::{Async, Future, Stream, Poll};
+use dbus::{Connection, ConnMsgs, Watch, WatchEvent, Timeout, Message, MessageType, Error as DBusError};
+use dbus::{BusName, Path, MatchRule, SignalArgs, NameWatchCache};
+use futures::{future, Async, Future, Stream, Poll, task};
 use futures::sync::{oneshot, mpsc};
 use tokio::reactor::Handle as CoreHandle;
 use tokio::reactor::PollEvented2;
 use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Delay;
 use std::rc::Rc;
+use std::fmt;
+use std::ffi::CString;
 use std::os::raw::c_uint;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
 use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
 type MCallMap = Rc<RefCell<HashMap<u32, oneshot::Sender<Message>>>>;
 
-type MStream = Rc<RefCell<Option<mpsc::UnboundedSender<Message>>>>;
+type MStreams = Rc<RefCell<Vec<(Option<MatchRule<'static>>, Rc<RefCell<StreamQueue>>)>>>;
+
+// Every watch update libdbus has told us about on this thread, so tests can check the driver sees them.
+#[cfg(test)]
+thread_local!(static WATCH_UPDATES: RefCell<Vec<Watch>> = RefCell::new(vec!()));
+
+/// A reasonable capacity for a stream created by `AConnection::message_stream`.
+pub const DEFAULT_STREAM_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub(crate) struct StreamQueue {
+    pub(crate) queue: VecDeque<Message>,
+    capacity: Option<usize>,
+    pub(crate) dropped: u64,
+    pub(crate) task: Option<task::Task>,
+}
+
+impl StreamQueue {
+    /// None means unbounded.
+    pub(crate) fn new(capacity: Option<usize>) -> StreamQueue {
+        StreamQueue { queue: VecDeque::new(), capacity: capacity, dropped: 0, task: None }
+    }
+
+    /// Gives the message back if the queue is full.
+    pub(crate) fn push(&mut self, m: Message) -> Result<(), Message> {
+        if let Some(t) = self.task.take() { t.notify(); }
+        if self.capacity.map(|c| self.queue.len() >= c).unwrap_or(false) {
+            self.dropped += 1;
+            warn!("AMessageStream is full, dropping message {:?}", m);
+            return Err(m);
+        }
+        self.queue.push_back(m);
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 /// A Tokio enabled D-Bus connection.
@@ -23,10 +63,10 @@ type MStream = Rc<RefCell<Option<mpsc::UnboundedSender<Message>>>>;
 /// While an AConnection exists, it will consume all incoming messages.
 /// Creating more than one AConnection for the same Connection is not recommended.
 pub struct AConnection {
-    conn: Rc<Connection>,
+    pub(crate) conn: Rc<Connection>,
     quit: Option<Rc<oneshot::Sender<()>>>,
     callmap: MCallMap,
-    msgstream: MStream,
+    msgstreams: MStreams,
 }
 
 impl AConnection {
@@ -37,48 +77,126 @@ impl AConnection {
     pub fn new(c: Rc<Connection>, h: CoreHandle, e: &mut Runtime) -> io::Result<AConnection> {
         let (tx, rx) = oneshot::channel();
         let map: MCallMap = Default::default();
-        let istream: MStream = Default::default();
+        let istreams: MStreams = Default::default();
+        let (ttx, trx) = mpsc::unbounded();
+        let (wtx, wrx) = mpsc::unbounded();
         let mut d = ADriver {
             conn: c.clone(),
             fds: HashMap::new(),
+            watch_updates: wrx,
+            timeouts: HashMap::new(),
+            timeout_updates: trx,
             core: h.clone(),
             quit: rx,
             callmap: map.clone(),
-            msgstream: istream.clone(),
+            msgstreams: istreams.clone(),
         };
         let i = AConnection {
             conn: c,
             quit: Some(Rc::new(tx)),
             callmap: map,
-            msgstream: istream,
+            msgstreams: istreams,
         };
-        i.conn.set_watch_callback(Box::new(|_| unimplemented!("Watch handling is very rare and not implemented yet")));
+        i.conn.set_watch_callback(Box::new(move |w| {
+            #[cfg(test)]
+            WATCH_UPDATES.with(|u| u.borrow_mut().push(w));
+            let _ = wtx.unbounded_send(w);
+        }));
         for w in i.conn.watch_fds() { d.modify_watch(w, false)?; }
+        i.conn.set_timeout_callback(Box::new(move |t| { let _ = ttx.unbounded_send(t); }));
+        for t in i.conn.timeouts() { d.modify_timeout(t); }
         e.spawn(Box::new(d));
         Ok(i)
     }
 
     /// Sends a method call message, and returns a Future for the method return.
     pub fn method_call(&self, m: Message) -> Result<AMethodCall, &'static str> {
+        self.caller().method_call(m)
+    }
+
+    pub(crate) fn caller(&self) -> ACaller {
+        ACaller { conn: self.conn.clone(), callmap: self.callmap.clone() }
+    }
+
+    /// Returns a stream of all incoming messages.
+    ///
+    /// The stream is unbounded, so make sure to keep reading from it.
+    /// This function currently never fails.
+    pub fn messages(&self) -> Result<AMessageStream, &'static str> {
+        Ok(self.new_stream(None, None))
+    }
+
+    /// Returns a bounded stream of incoming messages, optionally only the ones matching "filter".
+    ///
+    /// Any number of streams can exist at the same time; every stream gets its own copy of
+    /// each message it matches. The filter is matched locally, so a sender in the filter
+    /// must be the unique name of the sender to match.
+    ///
+    /// If more than "capacity" messages are waiting in the stream, incoming messages are
+    /// dropped instead of added to the stream. See `AMessageStream::dropped`. A method call
+    /// that could not be added to any stream is replied to with a LimitsExceeded error.
+    pub fn message_stream(&self, filter: Option<MatchRule>, capacity: usize) -> AMessageStream {
+        self.new_stream(filter, Some(capacity))
+    }
+
+    pub(crate) fn new_stream(&self, filter: Option<MatchRule>, capacity: Option<usize>) -> AMessageStream {
+        let q = Rc::new(RefCell::new(StreamQueue::new(capacity)));
+        self.msgstreams.borrow_mut().push((filter.map(|f| f.into_static()), q.clone()));
+        AMessageStream { queue: q, streams: self.msgstreams.clone(), quit: self.quit.as_ref().map(|q| q.clone()) }
+    }
+
+    /// Returns a stream of incoming signals of type S, together with their sender and path.
+    ///
+    /// The future yields the stream once the match rule has been added to the bus; it is removed
+    /// again when the stream is dropped. Like `Connection::subscribe`, a well-known sender name
+    /// in the rule is resolved to its owner. The signals are still present in the stream returned by `messages`.
+    pub fn subscribe<S: SignalArgs + 'static>(&self, rule: MatchRule) -> Box<Future<Item=ASignalStream<S>, Error=DBusError>> {
+        let rule = rule.into_static();
+        let owner = match rule.sender() {
+            Some(s) if !s.starts_with(':') => Some(NameWatchCache::new(s.clone())),
+            _ => None,
+        };
+        let mut rules: Vec<String> = owner.iter().map(|w| w.match_rule()).collect();
+        rules.push(rule.to_string());
+        // Created before any reply arrives, so that no signal gets lost. If adding a match rule
+        // fails, dropping the stream removes the rules again.
+        let stream = self.new_stream(Some(MatchRule::new().with_type(MessageType::Signal)), None);
+        // The bus handles the calls in order, so no owner change is missed between the reply and the match rule.
+        let add_matches: Result<Vec<_>, _> = rules.iter().map(|r| self.method_call(bus_call("AddMatch", r))).collect();
+        let get_owner = owner.as_ref().map(|w| self.method_call(w.get_name_owner()));
+        let mut s = ASignalStream { stream: stream, rule: rule, owner: owner, rules: rules, conn: self.conn.clone(), _s: PhantomData };
+        let (add_matches, get_owner) = match (add_matches, get_owner) {
+            (Ok(a), None) => (a, None),
+            (Ok(a), Some(Ok(g))) => (a, Some(g)),
+            (Err(e), _) | (_, Some(Err(e))) => return Box::new(future::err(DBusError::new_custom("org.freedesktop.DBus.Failed", e))),
+        };
+        let get_owner = match get_owner {
+            Some(g) => future::Either::A(g.then(|r| Ok::<_, DBusError>(Some(r)))),
+            None => future::Either::B(future::ok::<_, DBusError>(None)),
+        };
+        Box::new(future::join_all(add_matches).join(get_owner).and_then(move |(_, r)| {
+            if let (Some(w), Some(r)) = (s.owner.as_mut(), r) { w.handle_reply(r)?; }
+            Ok(s)
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+// Makes method calls like AConnection::method_call, for types that outlive the borrow of the AConnection.
+pub(crate) struct ACaller {
+    conn: Rc<Connection>,
+    callmap: MCallMap,
+}
+
+impl ACaller {
+    pub(crate) fn method_call(&self, m: Message) -> Result<AMethodCall, &'static str> {
         let r = self.conn.send(m).map_err(|_| "D-Bus send error")?;
         let (tx, rx) = oneshot::channel();
         let mut map = self.callmap.borrow_mut();
         map.insert(r, tx); // TODO: error check for duplicate entries. Should not happen, but if it does...
-        let mc = AMethodCall { serial: r, callmap: self.callmap.clone(), inner: rx };
+        let mc = AMethodCall { serial: r, callmap: self.callmap.clone(), inner: rx, timeout: None };
         Ok(mc)
     }
-
-    /// Returns a stream of incoming messages.
-    ///
-    /// Creating more than one stream for the same AConnection is not supported; this function will
-    /// fail with an error if you try. Drop the first stream if you need to create a second one.
-    pub fn messages(&self) -> Result<AMessageStream, &'static str> {
-        let mut i = self.msgstream.borrow_mut();
-        if i.is_some() { return Err("Another instance of AMessageStream already exists"); }
-        let (tx, rx) = mpsc::unbounded();
-        *i = Some(tx);
-        Ok(AMessageStream { inner: rx, stream: self.msgstream.clone(), quit: self.quit.as_ref().map(|q| q.clone()) })
-    }
 }
 
 impl Drop for AConnection {
@@ -89,18 +207,50 @@ impl Drop for AConnection {
             let _ = x.send(());
         }
         self.conn.set_watch_callback(Box::new(|_| {}));
+        self.conn.set_timeout_callback(Box::new(|_| {}));
+    }
+}
+
+// Gives every target its own copy of the message.
+pub(crate) fn broadcast<T, F: FnMut(&T, Message)>(targets: &[T], m: Message, mut f: F) {
+    let mut m = Some(m);
+    for (i, t) in targets.iter().enumerate() {
+        let msg = if i + 1 == targets.len() { m.take().unwrap() } else {
+            match m.as_ref().unwrap().duplicate() {
+                Ok(d) => d,
+                Err(e) => { warn!("Dropping message for message stream: {}", e); continue },
+            }
+        };
+        f(t, msg);
     }
 }
 
+// Like broadcast, for queues that can be full. Returns an error reply if the message
+// is a method call that none of the queues had room for.
+pub(crate) fn deliver<T, F: FnMut(&T, Message) -> Result<(), Message>>(targets: &[T], m: Message, mut push: F) -> Option<Message> {
+    let (mut accepted, mut rejected) = (false, None);
+    broadcast(targets, m, |t, msg| match push(t, msg) {
+        Ok(()) => accepted = true,
+        Err(msg) => rejected = Some(msg),
+    });
+    if accepted { return None }
+    rejected.filter(|m| m.msg_type() == MessageType::MethodCall).map(|m| {
+        m.error(&"org.freedesktop.DBus.Error.LimitsExceeded".into(), &CString::new("Too many unhandled messages").unwrap())
+    })
+}
+
 #[derive(Debug)]
 // Internal struct; this is the future spawned on the core.
 struct ADriver {
     conn: Rc<Connection>,
     fds: HashMap<RawFd, PollEvented2<AWatch>>,
+    watch_updates: mpsc::UnboundedReceiver<Watch>,
+    timeouts: HashMap<usize, (Timeout, Delay)>,
+    timeout_updates: mpsc::UnboundedReceiver<Timeout>,
     core: CoreHandle,
     quit: oneshot::Receiver<()>,
     callmap: MCallMap,
-    msgstream: MStream,
+    msgstreams: MStreams,
 }
 
 impl ADriver {
@@ -128,15 +278,53 @@ impl ADriver {
         Ok(())
     }
 
+    fn handle_watches(&mut self) -> Result<(), ()> {
+        while let Async::Ready(Some(w)) = self.watch_updates.poll()? {
+            self.modify_watch(w, true).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    fn modify_timeout(&mut self, t: Timeout) {
+        debug!("Modify_timeout: {:?}", t);
+        if !t.enabled() {
+            self.timeouts.remove(&t.id());
+        } else {
+            self.timeouts.insert(t.id(), (t, Delay::new(Instant::now() + t.interval())));
+        }
+    }
+
+    fn handle_timeouts(&mut self) -> Result<(), ()> {
+        while let Async::Ready(Some(t)) = self.timeout_updates.poll()? { self.modify_timeout(t); }
+
+        let mut expired = vec!();
+        for &mut (t, ref mut d) in self.timeouts.values_mut() {
+            if d.poll().map_err(|_| ())?.is_ready() {
+                expired.push(t);
+                // Libdbus timeouts are periodic until removed or disabled.
+                d.reset(Instant::now() + t.interval());
+                if d.poll().map_err(|_| ())?.is_ready() { task::current().notify(); }
+            }
+        }
+        for t in expired {
+            debug!("D-Bus timeout expired: {:?}", t);
+            self.conn.timeout_handle(t);
+        }
+        Ok(())
+    }
+
     fn send_stream(&self, m: Message) {
-        self.msgstream.borrow().as_ref().map(|z| { z.unbounded_send(m).unwrap() });
+        let targets: Vec<_> = self.msgstreams.borrow().iter()
+            .filter(|&&(ref f, _)| f.as_ref().map(|f| f.matches(&m)).unwrap_or(true))
+            .map(|&(_, ref q)| q.clone()).collect();
+        if let Some(r) = deliver(&targets, m, |q, msg| q.borrow_mut().push(msg)) { let _ = self.conn.send(r); }
     }
 
     fn handle_msgs(&mut self) {
         let msgs = ConnMsgs { conn: &*self.conn, timeout_ms: None };
         for m in msgs {
             debug!("handle_msgs: {:?}", m);
-            if m.msg_type() == MessageType::MethodReturn {
+            if m.msg_type() == MessageType::MethodReturn || m.msg_type() == MessageType::Error {
                 let mut map = self.callmap.borrow_mut();
                 let serial = m.get_reply_serial().unwrap();
                 let r = map.remove(&serial);
@@ -156,6 +344,7 @@ impl Future for ADriver {
         let q = self.quit.poll();
         if q != Ok(Async::NotReady) { return Ok(Async::Ready(())); }
 
+        self.handle_watches()?;
         for w in self.fds.values() {
             let mut mask = UnixReady::hup() | UnixReady::error();
             if w.get_ref().0.readable() { mask = mask | Ready::readable().into(); }
@@ -187,13 +376,14 @@ impl Future for ADriver {
             if ur.is_readable() { w.clear_read_ready(Ready::readable()).map_err(|_| ())?; };
             if ur.is_writable() { w.clear_write_ready().map_err(|_| ())?; };
         };
+        self.handle_timeouts()?;
         self.handle_msgs();
         Ok(Async::NotReady)
     }
 }
 
 #[derive(Debug)]
-struct AWatch(Watch);
+pub(crate) struct AWatch(pub(crate) Watch);
 
 impl mio::Evented for AWatch {
     fn register(&self,
@@ -233,6 +423,18 @@ pub struct AMethodCall {
     serial: u32,
     callmap: MCallMap,
     inner: oneshot::Receiver<Message>,
+    timeout: Option<Delay>,
+}
+
+impl AMethodCall {
+    /// Fails the method call with `org.freedesktop.DBus.Error.NoReply` unless a reply
+    /// has been received within the given duration.
+    ///
+    /// Without a timeout, the future will wait for a reply forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> AMethodCall {
+        self.timeout = Some(Delay::new(Instant::now() + timeout));
+        self
+    }
 }
 
 impl Future for AMethodCall {
@@ -243,8 +445,15 @@ impl Future for AMethodCall {
         let x = self.inner.poll().map_err(|_| DBusError::new_custom("org.freedesktop.DBus.Failed", "Tokio cancelled future"))?;
         if let Async::Ready(mut m) = x {
             m.as_result()?;
-            Ok(Async::Ready(m))
-        } else { Ok(Async::NotReady) }
+            return Ok(Async::Ready(m));
+        }
+        if let Some(ref mut d) = self.timeout {
+            let t = d.poll().map_err(|_| DBusError::new_custom("org.freedesktop.DBus.Failed", "Tokio timer error"))?;
+            if t.is_ready() {
+                return Err(DBusError::new_custom("org.freedesktop.DBus.Error.NoReply", "Method call timed out"));
+            }
+        }
+        Ok(Async::NotReady)
     }
 }
 
@@ -261,9 +470,14 @@ impl Drop for AMethodCall {
 /// Messages already processed (method returns for AMethodCall)
 /// are already consumed and will not be present in the stream.
 pub struct AMessageStream {
-    inner: mpsc::UnboundedReceiver<Message>,
+    queue: Rc<RefCell<StreamQueue>>,
     quit: Option<Rc<oneshot::Sender<()>>>,
-    stream: MStream,
+    streams: MStreams,
+}
+
+impl AMessageStream {
+    /// The number of messages that have been dropped because the stream was full.
+    pub fn dropped(&self) -> u64 { self.queue.borrow().dropped }
 }
 
 impl Stream for AMessageStream {
@@ -271,15 +485,17 @@ impl Stream for AMessageStream {
     type Error = ();
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         debug!("Polling message stream");
-        let r = self.inner.poll();
+        let mut q = self.queue.borrow_mut();
+        let r = q.queue.pop_front();
         debug!("msgstream found {:?}", r);
-        r
+        if r.is_none() { q.task = Some(task::current()); return Ok(Async::NotReady) }
+        Ok(Async::Ready(r))
     }
 }
 
 impl Drop for AMessageStream {
     fn drop(&mut self) {
-        *self.stream.borrow_mut() = None;
+        self.streams.borrow_mut().retain(|&(_, ref q)| !Rc::ptr_eq(q, &self.queue));
         debug!("Dropping AMessageStream");
         if let Ok(x) = Rc::try_unwrap(self.quit.take().unwrap()) {
             debug!("AMessageStream telling ADriver to quit");
@@ -288,9 +504,64 @@ impl Drop for AMessageStream {
     }
 }
 
+// A method call to the bus with a match rule as its argument.
+fn bus_call(member: &str, rule: &str) -> Message {
+    Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", member).unwrap().append1(rule)
+}
+
+/// A Stream of incoming signals, see `AConnection::subscribe`.
+pub struct ASignalStream<S> {
+    stream: AMessageStream,
+    rule: MatchRule<'static>,
+    owner: Option<NameWatchCache>,
+    rules: Vec<String>,
+    conn: Rc<Connection>,
+    _s: PhantomData<fn() -> S>,
+}
+
+impl<S> fmt::Debug for ASignalStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ASignalStream({})", self.rule)
+    }
+}
+
+impl<S: SignalArgs> Stream for ASignalStream<S> {
+    type Item = (S, BusName<'static>, Path<'static>);
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let m = match self.stream.poll()? {
+                Async::Ready(Some(m)) => m,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            let matches = match self.owner {
+                Some(ref mut w) => { w.handle_message(&m); self.rule.matches_owner(&m, w) },
+                None => self.rule.matches(&m),
+            };
+            if !matches { continue }
+            if let (Some(s), Some(sender), Some(path)) = (S::from_message(&m), m.sender(), m.path()) {
+                return Ok(Async::Ready(Some((s, sender.into_static(), path.into_static()))));
+            }
+        }
+    }
+}
+
+impl<S> Drop for ASignalStream<S> {
+    fn drop(&mut self) {
+        // Nobody waits for the replies.
+        for r in self.rules.iter() {
+            let m = bus_call("RemoveMatch", r);
+            m.set_no_reply(true);
+            let _ = self.conn.send(m);
+        }
+    }
+}
+
 #[test]
 fn aconnection_test() {
-    let conn = Rc::new(Connection::get_private(::dbus::BusType::Session).unwrap());
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
     let mut rt = Runtime::new().unwrap();
     let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
 
@@ -301,9 +572,28 @@ fn aconnection_test() {
     assert!(z.iter().any(|v| *v == "org.freedesktop.DBus"));
 }
 
+#[test]
+fn amethodcall_timeout_test() {
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+
+    // Nobody reads the messages of the other connection, so the call should time out.
+    let c2 = bus.connection().unwrap();
+    let m = ::dbus::Message::new_method_call(&*c2.unique_name(), "/", "com.example.dbustokio", "Hang").unwrap();
+    let mc = aconn.method_call(m).unwrap().with_timeout(Duration::from_millis(100));
+    let start = Instant::now();
+    let e = rt.block_on(mc).unwrap_err();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.NoReply"));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(aconn.callmap.borrow().is_empty());
+}
+
 #[test]
 fn astream_test() {
-    let conn = Rc::new(Connection::get_private(::dbus::BusType::Session).unwrap());
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
     let mut rt = Runtime::new().unwrap();
     let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
 
@@ -314,3 +604,115 @@ fn astream_test() {
     assert_eq!(firstsig.unwrap().msg_type(), ::dbus::MessageType::Signal);
 }
 
+
+#[test]
+fn asignal_test() {
+    use dbus::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved as IR;
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
+    let conn2 = bus.connection().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+
+    let signals = rt.block_on(aconn.subscribe::<IR>(IR::match_rule(None, Some(&"/hello".into())))).unwrap();
+    conn2.send(Message::new_signal("/hello", "com.example", "Other").unwrap()).unwrap();
+    let ir = IR { object: "/a".into(), interfaces: vec!("com.example.A".into()) };
+    conn2.send(ir.to_emit_message(&"/hello".into())).unwrap();
+    let (s, _) = rt.block_on(signals.into_future()).map_err(|(e, _)| e).unwrap();
+    let (ir2, sender, path) = s.unwrap();
+    assert_eq!(ir2.interfaces, ir.interfaces);
+    assert_eq!(&*sender, &*conn2.unique_name());
+    assert_eq!(&*path, "/hello");
+
+    // A well-known sender name only matches signals from its owner.
+    conn2.register_name("com.example.asignal", 0).unwrap();
+    let conn3 = bus.connection().unwrap();
+    let rule = IR::match_rule(Some(&"com.example.asignal".into()), None);
+    let signals = rt.block_on(aconn.subscribe::<IR>(rule)).unwrap();
+    conn3.send(ir.to_emit_message(&"/other".into())).unwrap();
+    conn2.send(ir.to_emit_message(&"/owner".into())).unwrap();
+    let (s, signals) = rt.block_on(signals.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(&*s.unwrap().2, "/owner");
+    drop(signals);
+    let r = conn.remove_match(&IR::match_rule(Some(&"com.example.asignal".into()), None).to_string());
+    assert!(r.is_err());
+}
+
+#[test]
+fn amultistream_test() {
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
+    let conn2 = bus.connection().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+    conn.add_match("type='signal',interface='com.example'").unwrap();
+
+    let all = aconn.messages().unwrap();
+    let all2 = aconn.messages().unwrap();
+    let foo = aconn.message_stream(Some(MatchRule::new_signal("com.example", "Foo")), 10);
+    let small = aconn.message_stream(Some(MatchRule::new().with_interface("com.example")), 1);
+    for member in &["Foo", "Bar", "Foo"] {
+        conn2.send(Message::new_signal("/", "com.example", *member).unwrap()).unwrap();
+    }
+    let members = |rt: &mut Runtime, s: AMessageStream, n| {
+        let s = s.filter(|m| m.interface().map(|i| &*i == "com.example").unwrap_or(false));
+        let v = rt.block_on(s.take(n).collect()).unwrap();
+        v.iter().map(|m: &Message| m.member().unwrap().to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(members(&mut rt, foo, 2), vec!("Foo", "Foo"));
+    assert_eq!(members(&mut rt, all, 3), vec!("Foo", "Bar", "Foo"));
+    assert_eq!(members(&mut rt, all2, 3), vec!("Foo", "Bar", "Foo"));
+    assert_eq!(small.dropped(), 2);
+    assert_eq!(aconn.msgstreams.borrow().len(), 1);
+    drop(small);
+    assert!(aconn.msgstreams.borrow().is_empty());
+}
+
+#[test]
+fn afull_stream_test() {
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+
+    let calls = aconn.message_stream(Some(MatchRule::new().with_type(MessageType::MethodCall)), 1);
+    let call = |member: &str| {
+        let m = Message::new_method_call(&*conn.unique_name(), "/", "com.example.dbustokio", member).unwrap();
+        aconn.method_call(m).unwrap().with_timeout(Duration::from_secs(5))
+    };
+    let _first = call("First");
+    // The stream is full, so the second call is not dropped silently but gets an error reply.
+    let e = rt.block_on(call("Second")).unwrap_err();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.LimitsExceeded"));
+    assert_eq!(calls.dropped(), 1);
+}
+
+#[test]
+fn alarge_message_test() {
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+
+    // Too large to be written in one go, so libdbus has to enable the writable watch
+    // and wait for ADriver to tell it the socket is writable again.
+    let payload = vec![7u8; 8 * 1024 * 1024];
+    let items = aconn.message_stream(Some(MatchRule::new().with_type(MessageType::MethodCall)
+        .with_interface("com.example.dbustokio")), 4);
+    for _ in 0..2 {
+        let m = Message::new_method_call(&*conn.unique_name(), "/", "com.example.dbustokio", "Big").unwrap()
+            .append1(&payload);
+        conn.send(m).unwrap();
+    }
+    let received: Vec<Message> = rt.block_on(items.take(2).collect()).unwrap();
+    for m in received {
+        let z: Vec<u8> = m.get1().unwrap();
+        assert_eq!(z, payload);
+    }
+
+    // The writable watch was turned on, and off again once everything had been written.
+    let updates = WATCH_UPDATES.with(|u| u.borrow().clone());
+    let on = updates.iter().position(|w| w.writable()).expect("writable watch never enabled");
+    assert!(updates[on..].iter().any(|w| w.fd() == updates[on].fd() && !w.writable()),
+        "writable watch never disabled: {:?}", updates);
+}
@@ -0,0 +1,142 @@
+// The plumbing shared by ANameWatcher and AObjectManagerClient: one of dbus' caches of remote
+// state, fetched with a method call and then kept up to date from a stream of signals.
+
+use dbus::{Connection, Message, MatchRule, MessageType, Error as DBusError};
+use dbus::{NameWatchCache, NameWatchEvent, ObjectManagerCache, ObjectManagerEvent};
+use futures::{future, Async, Future, Stream, Poll};
+use std::rc::Rc;
+use std::time::Duration;
+use adriver::{AConnection, AMessageStream, AMethodCall, ACaller};
+
+pub(crate) trait RemoteCache {
+    type Event;
+
+    // Match rules for the signals that update the state.
+    fn rules(&self) -> Vec<String>;
+
+    // The method call that fetches the state.
+    fn fetch_call(&self) -> Message;
+
+    // Takes the state from the reply to "fetch_call".
+    fn fetched(&mut self, reply: Result<Message, DBusError>) -> Result<(), DBusError>;
+
+    // Updates the state from an incoming message, if it is one of ours.
+    fn update(&mut self, m: &Message) -> Option<Self::Event>;
+
+    // What to do about fetching after this event.
+    fn refetch(_: &Self::Event) -> Refetch { Refetch::Keep }
+}
+
+pub(crate) enum Refetch {
+    // Nothing changes.
+    Keep,
+    // A fetch in progress is out of date, so forget about it.
+    Cancel,
+    // Fetch the state again.
+    Fetch,
+}
+
+impl RemoteCache for NameWatchCache {
+    type Event = NameWatchEvent;
+    fn rules(&self) -> Vec<String> { vec!(self.match_rule()) }
+    fn fetch_call(&self) -> Message { self.get_name_owner() }
+    fn fetched(&mut self, reply: Result<Message, DBusError>) -> Result<(), DBusError> { self.handle_reply(reply) }
+    fn update(&mut self, m: &Message) -> Option<NameWatchEvent> { self.handle_message(m) }
+}
+
+impl RemoteCache for ObjectManagerCache {
+    type Event = ObjectManagerEvent;
+    fn rules(&self) -> Vec<String> { self.match_rules() }
+    fn fetch_call(&self) -> Message { self.get_managed_objects() }
+    fn fetched(&mut self, reply: Result<Message, DBusError>) -> Result<(), DBusError> { self.handle_reply(&mut reply?) }
+    fn update(&mut self, m: &Message) -> Option<ObjectManagerEvent> { self.handle_message(m) }
+    fn refetch(e: &ObjectManagerEvent) -> Refetch {
+        match *e {
+            ObjectManagerEvent::OwnerChanged(Some(_)) => Refetch::Fetch,
+            ObjectManagerEvent::OwnerChanged(None) => Refetch::Cancel,
+            _ => Refetch::Keep,
+        }
+    }
+}
+
+#[derive(Debug)]
+// A RemoteCache on an AConnection, and a Stream of its events. The match rules are removed when dropped.
+pub(crate) struct CacheClient<T> {
+    conn: Rc<Connection>,
+    cache: T,
+    rules: Vec<String>,
+    stream: AMessageStream,
+    caller: ACaller,
+    timeout: Duration,
+    refreshing: Option<AMethodCall>,
+}
+
+impl<T: RemoteCache + 'static> CacheClient<T> {
+    // Resolves once the state has been fetched.
+    pub fn new(aconn: &AConnection, cache: T, timeout: Duration) -> Box<Future<Item=CacheClient<T>, Error=DBusError>> {
+        let msg = cache.fetch_call();
+        let stream = aconn.new_stream(Some(MatchRule::new().with_type(MessageType::Signal)), None);
+        let mut c = CacheClient { conn: aconn.conn.clone(), cache: cache, rules: vec!(), stream: stream,
+            caller: aconn.caller(), timeout: timeout, refreshing: None };
+        for r in c.cache.rules() {
+            if let Err(e) = c.conn.add_match(&r) { return Box::new(future::err(e)) }
+            c.rules.push(r);
+        }
+        let mc = match aconn.method_call(msg) {
+            Ok(mc) => mc.with_timeout(timeout),
+            Err(e) => return Box::new(future::err(DBusError::new_custom("org.freedesktop.DBus.Failed", e))),
+        };
+        Box::new(mc.then(move |reply| {
+            c.cache.fetched(reply)?;
+            Ok(c)
+        }))
+    }
+
+    pub fn cache(&self) -> &T { &self.cache }
+
+    fn refetch(&mut self) {
+        match self.caller.method_call(self.cache.fetch_call()) {
+            Ok(mc) => { self.refreshing = Some(mc.with_timeout(self.timeout)); self.poll_refresh(); },
+            Err(e) => warn!("Could not fetch the remote state again: {}", e),
+        }
+    }
+
+    fn poll_refresh(&mut self) {
+        let r = match self.refreshing.as_mut().map(|r| r.poll()) {
+            Some(Ok(Async::Ready(m))) => self.cache.fetched(Ok(m)),
+            Some(Err(e)) => Err(e),
+            _ => return,
+        };
+        self.refreshing = None;
+        if let Err(e) = r { warn!("Could not fetch the remote state again: {}", e); }
+    }
+}
+
+impl<T: RemoteCache + 'static> Stream for CacheClient<T> {
+    type Item = T::Event;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.poll_refresh();
+        loop {
+            let m = match self.stream.poll()? {
+                Async::Ready(Some(m)) => m,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            if let Some(e) = self.cache.update(&m) {
+                match T::refetch(&e) {
+                    Refetch::Keep => {},
+                    Refetch::Cancel => self.refreshing = None,
+                    Refetch::Fetch => self.refetch(),
+                }
+                return Ok(Async::Ready(Some(e)))
+            }
+        }
+    }
+}
+
+impl<T> Drop for CacheClient<T> {
+    fn drop(&mut self) {
+        for r in self.rules.iter() { let _ = self.conn.remove_match(r); }
+    }
+}
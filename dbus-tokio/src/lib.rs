@@ -3,7 +3,13 @@
 //! What's currently working is:
 //!
 //!  * Client: Make method calls and wait asynchronously for them to be replied to - see `AConnection::method_call`
-//!  * Get a stream of incoming messages (so you can listen to signals etc) - see `AConnection::messages`
+//!    (optionally with a timeout - see `AMethodCall::with_timeout`)
+//!  * Get streams of incoming messages (so you can listen to signals etc), optionally filtered -
+//!    see `AConnection::messages` and `AConnection::message_stream`
+//!  * Get a stream of decoded signals of a specific type - see `AConnection::subscribe`
+//!  * A thread-safe connection, for use with multi-threaded runtimes - see `SyncConnection`
+//!  * Client: Keep track of the objects of a remote object manager - see `AObjectManagerClient`
+//!  * Client: Know when a remote service appears or disappears from the bus - see `ANameWatcher`
 //!  * Server: Make a tree handle that stream of incoming messages - see `tree::ATreeServer`
 //!  * Server: Add asynchronous methods to the tree - in case you cannot reply right away,
 //!    you can return a future that will reply when that future resolves - see `tree::AFactory::amethod`
@@ -21,5 +27,12 @@ extern crate log;
 pub mod tree;
 
 mod adriver;
+mod cacheclient;
+mod namewatcher;
+mod objectmanager;
+mod syncconn;
 
-pub use adriver::{AConnection, AMessageStream, AMethodCall};
+pub use adriver::{AConnection, AMessageStream, AMethodCall, ASignalStream, DEFAULT_STREAM_CAPACITY};
+pub use namewatcher::ANameWatcher;
+pub use objectmanager::AObjectManagerClient;
+pub use syncconn::{SyncConnection, SyncDriver, SyncMethodCall, SyncMessageStream};
@@ -0,0 +1,63 @@
+use dbus::{BusName, Error as DBusError, NameWatchCache, NameWatchEvent};
+use futures::{Future, Stream, Poll};
+use std::time::Duration;
+use adriver::AConnection;
+use cacheclient::CacheClient;
+
+#[derive(Debug)]
+/// Tells when a bus name gets, loses or changes its owner, the async way.
+///
+/// Polling this Stream yields the changes, and also keeps `owner` up to date; the
+/// NameOwnerChanged signals it waits for are not delivered to `owner` any other way.
+///
+/// The bus stops sending those signals when the watcher is dropped.
+pub struct ANameWatcher(CacheClient<NameWatchCache>);
+
+impl ANameWatcher {
+    /// Starts watching "name". The future yields the watcher when the bus has said who owns the name now.
+    pub fn new<N>(aconn: &AConnection, name: N, timeout: Duration) -> Box<Future<Item=ANameWatcher, Error=DBusError>>
+    where N: Into<BusName<'static>> {
+        Box::new(CacheClient::new(aconn, NameWatchCache::new(name), timeout).map(ANameWatcher))
+    }
+
+    /// The watched name.
+    pub fn name(&self) -> &BusName<'static> { self.0.cache().name() }
+
+    /// The unique name of the owner as of the last poll, or None if it had no owner.
+    pub fn owner(&self) -> Option<&BusName<'static>> { self.0.cache().owner() }
+}
+
+impl Stream for ANameWatcher {
+    type Item = NameWatchEvent;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> { self.0.poll() }
+}
+
+#[test]
+fn anamewatcher_test() {
+    use dbus::testing::TestBus;
+    use std::rc::Rc;
+    use tokio::reactor::Handle as CoreHandle;
+    use tokio::runtime::current_thread::Runtime;
+
+    let bus = TestBus::new().unwrap();
+    let conn = Rc::new(bus.connection().unwrap());
+    let c2 = bus.connection().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+    let w = rt.block_on(ANameWatcher::new(&aconn, "com.example.awatched", Duration::from_secs(2))).unwrap();
+    assert_eq!(w.owner(), None);
+
+    c2.register_name("com.example.awatched", 0).unwrap();
+    let (e, w) = rt.block_on(w.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(e, Some(NameWatchEvent::Appeared(c2.unique_name().into())));
+    assert_eq!(w.owner().map(|o| &**o), Some(&*c2.unique_name()));
+
+    drop(c2);
+    let (e, w) = rt.block_on(w.into_future()).map_err(|(e, _)| e).unwrap();
+    match e {
+        Some(NameWatchEvent::Vanished(_)) => {},
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert_eq!(w.owner(), None);
+}
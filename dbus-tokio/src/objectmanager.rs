@@ -0,0 +1,90 @@
+use dbus::{Path, BusName, Error as DBusError, ObjectManagerCache, ObjectManagerEvent, ManagedObjects};
+use futures::{Future, Stream, Poll};
+use std::time::Duration;
+use adriver::AConnection;
+use cacheclient::CacheClient;
+
+#[derive(Debug)]
+/// Client side of a remote object manager, for Tokio.
+///
+/// As a Stream, it yields what happens to the objects, and applies each change to `objects`
+/// before yielding it. When another process takes over the object manager's bus name, the
+/// objects are requested from it anew; they show up in `objects` from some later poll on.
+///
+/// The bus stops sending the object manager's signals when the client is dropped.
+pub struct AObjectManagerClient(CacheClient<ObjectManagerCache>);
+
+impl AObjectManagerClient {
+    /// Asks the object manager at "path" of "dest" for its objects. The future yields the
+    /// client once they have arrived; "timeout" also applies when they are requested anew.
+    pub fn new<D, P>(aconn: &AConnection, dest: D, path: P, timeout: Duration) -> Box<Future<Item=AObjectManagerClient, Error=DBusError>>
+    where D: Into<BusName<'static>>, P: Into<Path<'static>> {
+        Box::new(CacheClient::new(aconn, ObjectManagerCache::new(dest, path), timeout).map(AObjectManagerClient))
+    }
+
+    /// The objects, with all changes yielded so far applied.
+    pub fn objects(&self) -> &ManagedObjects { self.0.cache().objects() }
+}
+
+impl Stream for AObjectManagerClient {
+    type Item = ObjectManagerEvent;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> { self.0.poll() }
+}
+
+#[test]
+fn aobjectmanager_test() {
+    use dbus::tree::Factory;
+    use dbus::testing::TestBus;
+    use futures::{future, Async};
+    use std::rc::Rc;
+    use tokio::reactor::Handle as CoreHandle;
+    use tokio::runtime::current_thread::Runtime;
+
+    let bus = TestBus::new().unwrap();
+    let server = bus.serve("com.example.aobjmgr", || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/mgr", ()).object_manager())
+    }).unwrap();
+
+    let conn = Rc::new(bus.connection().unwrap());
+    let mut rt = Runtime::new().unwrap();
+    let aconn = AConnection::new(conn.clone(), CoreHandle::current(), &mut rt).unwrap();
+    let omc = rt.block_on(AObjectManagerClient::new(&aconn, "com.example.aobjmgr", "/mgr", Duration::from_secs(2))).unwrap();
+    assert_eq!(omc.objects().keys().collect::<Vec<_>>(), vec!(&Path::from("/mgr")));
+
+    server.run(|c, tree| {
+        let f = Factory::new_fn::<()>();
+        tree.insert(f.object_path("/mgr/a", ()).add(f.interface("com.example.Thing", ())));
+        c.register_object_path("/mgr/a").unwrap();
+    });
+    let (e, omc) = rt.block_on(omc.into_future()).map_err(|(e, _)| e).unwrap();
+    match e.unwrap() {
+        ObjectManagerEvent::InterfacesAdded(p, i) => {
+            assert_eq!(&*p, "/mgr/a");
+            assert!(i.contains_key("com.example.Thing"));
+        },
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert!(omc.objects()[&"/mgr/a".into()].contains_key("com.example.Thing"));
+
+    // The objects go away with the owner, and are fetched again from the new one.
+    server.run(|c, _| { c.release_name("com.example.aobjmgr").unwrap(); });
+    let (e, omc) = rt.block_on(omc.into_future()).map_err(|(e, _)| e).unwrap();
+    match e.unwrap() {
+        ObjectManagerEvent::OwnerChanged(None) => {},
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert!(omc.objects().is_empty());
+    server.run(|c, _| { c.register_name("com.example.aobjmgr", 0).unwrap(); });
+    let (e, mut omc) = rt.block_on(omc.into_future()).map_err(|(e, _)| e).unwrap();
+    match e.unwrap() {
+        ObjectManagerEvent::OwnerChanged(Some(_)) => {},
+        e => panic!("Unexpected event {:?}", e),
+    }
+    rt.block_on(future::poll_fn(|| {
+        omc.poll()?;
+        Ok(if omc.objects().is_empty() { Async::NotReady } else { Async::Ready(()) })
+    })).unwrap();
+    assert!(omc.objects()[&"/mgr/a".into()].contains_key("com.example.Thing"));
+}
@@ -0,0 +1,427 @@
+use mio::{self, Ready};
+use mio::unix::{self, UnixReady};
+use dbus::{TxRx, Message, MessageType, MatchRule, Timeout, Error as DBusError};
+use futures::{future, Async, Future, Stream, Poll, task};
+use futures::task::AtomicTask;
+use futures::sync::{oneshot, mpsc};
+use tokio::reactor::PollEvented2;
+use tokio::timer::Delay;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use adriver::{StreamQueue, deliver};
+
+type SCallMap = Mutex<HashMap<u32, oneshot::Sender<Message>>>;
+
+type SStreams = Mutex<Vec<(Option<MatchRule<'static>>, Arc<Mutex<StreamQueue>>)>>;
+
+fn failed(s: &str) -> DBusError { DBusError::new_custom("org.freedesktop.DBus.Failed", s) }
+
+#[derive(Debug)]
+struct SInner {
+    txrx: TxRx,
+    callmap: SCallMap,
+    streams: SStreams,
+    // The driver, woken up to write messages queued by send and method_call.
+    task: AtomicTask,
+}
+
+#[derive(Debug)]
+// Tells the driver to quit when the last connection handle or stream is dropped.
+struct SQuit(Mutex<Option<oneshot::Sender<()>>>);
+
+impl Drop for SQuit {
+    fn drop(&mut self) {
+        debug!("SyncConnection telling SyncDriver to quit");
+        if let Some(tx) = self.0.lock().unwrap().take() { let _ = tx.send(()); }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A thread-safe, Tokio enabled D-Bus connection.
+///
+/// Unlike AConnection, this is built on `TxRx`, and is `Clone + Send + Sync`, so it can be
+/// used from a multi-threaded runtime. All clones refer to the same connection.
+///
+/// Incoming messages are read by a `SyncDriver`, which needs to be spawned on the runtime.
+/// It quits when the last clone of the connection (and all streams created from it) are dropped.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// extern crate dbus;
+/// extern crate dbus_tokio;
+/// extern crate tokio;
+/// use dbus::{TxRx, BusType, Message};
+/// use dbus_tokio::SyncConnection;
+///
+/// fn main() {
+///     let (conn, driver) = SyncConnection::new(TxRx::get_private(BusType::Session).unwrap()).unwrap();
+///     let mut rt = tokio::runtime::Runtime::new().unwrap();
+///     rt.spawn(driver);
+///     let m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "ListNames").unwrap();
+///     let reply = rt.block_on(conn.method_call(m).unwrap()).unwrap();
+///     let names: Vec<&str> = reply.read1().unwrap();
+///     println!("{:?}", names);
+/// }
+/// ```
+pub struct SyncConnection {
+    inner: Arc<SInner>,
+    quit: Arc<SQuit>,
+}
+
+impl SyncConnection {
+    /// Creates a SyncConnection, and the driver that handles its incoming messages.
+    ///
+    /// The driver needs to be spawned on a Tokio runtime, e g with `tokio::spawn`.
+    pub fn new(mut c: TxRx) -> Result<(SyncConnection, SyncDriver), DBusError> {
+        let watches = c.watch_fds().map_err(|_| failed("Getting D-Bus file descriptors failed"))?;
+        let w = match watches.into_iter().find(|w| w.readable()) {
+            Some(w) => w,
+            None => return Err(failed("D-Bus connection has no readable file descriptor")),
+        };
+        let (ttx, trx) = mpsc::unbounded();
+        c.set_timeout_callback(Box::new(move |t| { let _ = ttx.unbounded_send(t); }));
+        let timeouts = c.timeouts().into_iter().map(|t| (t.id(), (t, Delay::new(Instant::now() + t.interval())))).collect();
+        let (tx, rx) = oneshot::channel();
+        let inner = Arc::new(SInner { txrx: c, callmap: Default::default(), streams: Default::default(), task: AtomicTask::new() });
+        let conn = SyncConnection { inner: inner.clone(), quit: Arc::new(SQuit(Mutex::new(Some(tx)))) };
+        let d = SyncDriver { inner: inner, fd: w.fd(), evented: None, timeouts: timeouts, timeout_updates: trx, quit: rx };
+        Ok((conn, d))
+    }
+
+    /// Get the connection's unique name.
+    pub fn unique_name(&self) -> Option<String> { self.inner.txrx.unique_name().map(|s| s.to_string()) }
+
+    /// Sends a message, and returns its serial number.
+    ///
+    /// The message is queued, and written to the connection by the driver.
+    pub fn send(&self, m: Message) -> Result<u32, DBusError> {
+        let r = self.inner.txrx.send(m).map_err(|_| failed("D-Bus send error"))?;
+        self.inner.task.notify();
+        Ok(r)
+    }
+
+    /// Sends a method call message, and returns a Future for the method return.
+    pub fn method_call(&self, m: Message) -> Result<SyncMethodCall, DBusError> {
+        // Hold the lock while sending, so the driver cannot see the reply before it is waited for.
+        let mut map = self.inner.callmap.lock().unwrap();
+        let r = self.inner.txrx.send(m).map_err(|_| failed("D-Bus send error"))?;
+        let (tx, rx) = oneshot::channel();
+        map.insert(r, tx);
+        drop(map);
+        self.inner.task.notify();
+        Ok(SyncMethodCall { serial: r, inner: self.inner.clone(), rx: rx, timeout: None })
+    }
+
+    fn bus_call(&self, member: &str, rule: &MatchRule) -> Box<Future<Item=(), Error=DBusError> + Send> {
+        let m = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", member)
+            .unwrap().append1(rule.to_string());
+        match self.method_call(m) {
+            Ok(mc) => Box::new(mc.map(|_| ())),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Adds a match rule to the bus; the returned future resolves when the bus has replied.
+    pub fn add_match(&self, rule: &MatchRule) -> Box<Future<Item=(), Error=DBusError> + Send> {
+        self.bus_call("AddMatch", rule)
+    }
+
+    /// Removes a match rule from the bus; the returned future resolves when the bus has replied.
+    pub fn remove_match(&self, rule: &MatchRule) -> Box<Future<Item=(), Error=DBusError> + Send> {
+        self.bus_call("RemoveMatch", rule)
+    }
+
+    /// Returns a stream of all incoming messages.
+    ///
+    /// The stream is unbounded, so make sure to keep reading from it.
+    pub fn messages(&self) -> SyncMessageStream { self.new_stream(None, None) }
+
+    /// Returns a bounded stream of incoming messages, optionally only the ones matching "filter".
+    ///
+    /// This works like `AConnection::message_stream`.
+    pub fn message_stream(&self, filter: Option<MatchRule>, capacity: usize) -> SyncMessageStream {
+        self.new_stream(filter, Some(capacity))
+    }
+
+    fn new_stream(&self, filter: Option<MatchRule>, capacity: Option<usize>) -> SyncMessageStream {
+        let q = Arc::new(Mutex::new(StreamQueue::new(capacity)));
+        self.inner.streams.lock().unwrap().push((filter.map(|f| f.into_static()), q.clone()));
+        SyncMessageStream { queue: q, inner: self.inner.clone(), _quit: self.quit.clone() }
+    }
+}
+
+#[derive(Debug)]
+/// The future that reads and dispatches incoming messages of a SyncConnection.
+///
+/// It resolves when the connection is closed, or when all handles to the connection have been dropped.
+pub struct SyncDriver {
+    inner: Arc<SInner>,
+    fd: RawFd,
+    evented: Option<PollEvented2<SWatch>>,
+    timeouts: HashMap<usize, (Timeout, Delay)>,
+    timeout_updates: mpsc::UnboundedReceiver<Timeout>,
+    quit: oneshot::Receiver<()>,
+}
+
+impl SyncDriver {
+    fn handle_timeouts(&mut self) -> Result<(), ()> {
+        while let Async::Ready(Some(t)) = self.timeout_updates.poll()? {
+            debug!("SyncDriver modify timeout: {:?}", t);
+            if !t.enabled() { self.timeouts.remove(&t.id()); }
+            else { self.timeouts.insert(t.id(), (t, Delay::new(Instant::now() + t.interval()))); }
+        }
+
+        let mut expired = vec!();
+        for &mut (t, ref mut d) in self.timeouts.values_mut() {
+            if d.poll().map_err(|_| ())?.is_ready() {
+                expired.push(t);
+                // Libdbus timeouts are periodic until removed or disabled.
+                d.reset(Instant::now() + t.interval());
+                if d.poll().map_err(|_| ())?.is_ready() { task::current().notify(); }
+            }
+        }
+        for t in expired {
+            debug!("SyncDriver: D-Bus timeout expired: {:?}", t);
+            self.inner.txrx.timeout_handle(t);
+        }
+        Ok(())
+    }
+
+    // Reads and writes what can be done without blocking. Returns false if the connection is closed.
+    fn read_write(&self) -> bool {
+        if self.inner.txrx.read_write(Some(0)).is_err() || !self.inner.txrx.is_connected() {
+            debug!("SyncDriver: D-Bus connection closed");
+            return false;
+        }
+        true
+    }
+
+    fn dispatch(&self, m: Message) {
+        let t = m.msg_type();
+        if t == MessageType::MethodReturn || t == MessageType::Error {
+            let r = m.get_reply_serial().and_then(|s| self.inner.callmap.lock().unwrap().remove(&s));
+            if let Some(r) = r { let _ = r.send(m); return }
+        }
+        let targets: Vec<_> = self.inner.streams.lock().unwrap().iter()
+            .filter(|&&(ref f, _)| f.as_ref().map(|f| f.matches(&m)).unwrap_or(true))
+            .map(|&(_, ref q)| q.clone()).collect();
+        if let Some(r) = deliver(&targets, m, |q, msg| q.lock().unwrap().push(msg)) { let _ = self.inner.txrx.send(r); }
+    }
+}
+
+impl Future for SyncDriver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let q = self.quit.poll();
+        if q != Ok(Async::NotReady) { return Ok(Async::Ready(())); }
+        self.inner.task.register();
+
+        self.handle_timeouts()?;
+        // Queued messages are written as far as the socket takes them; the rest when it becomes writable.
+        if self.inner.txrx.has_messages_to_send() && !self.read_write() { return Ok(Async::Ready(())); }
+        let want_write = self.inner.txrx.has_messages_to_send();
+
+        // Registered here, so that it ends up on the reactor of the runtime the driver is spawned on.
+        // Tokio cannot change the interest of a registration, so the fd is registered again when needed.
+        if self.evented.as_ref().map(|e| e.get_ref().write) != Some(want_write) {
+            self.evented = None;
+            self.evented = Some(PollEvented2::new(SWatch { fd: self.fd, write: want_write }));
+        }
+        let mask = *(UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error());
+        let read = self.evented.as_ref().unwrap().poll_read_ready(mask).map_err(|_| ())?;
+        let write = if want_write { self.evented.as_ref().unwrap().poll_write_ready().map_err(|_| ())? } else { Async::NotReady };
+        if read.is_ready() || write.is_ready() {
+            debug!("SyncDriver i/o ready: {:?} {:?}", read, write);
+            if !self.read_write() { return Ok(Async::Ready(())); }
+            let e = self.evented.as_ref().unwrap();
+            if read.is_ready() { e.clear_read_ready(Ready::readable()).map_err(|_| ())?; }
+            if write.is_ready() { e.clear_write_ready().map_err(|_| ())?; }
+        }
+
+        let mut dispatched = false;
+        while let Some(m) = self.inner.txrx.pop_message() {
+            debug!("SyncDriver dispatching: {:?}", m);
+            self.dispatch(m);
+            dispatched = true;
+        }
+        // Error replies to unhandled method calls need writing, too.
+        if dispatched && self.inner.txrx.has_messages_to_send() { task::current().notify(); }
+        Ok(Async::NotReady)
+    }
+}
+
+#[derive(Debug)]
+// The connection's fd, with readable interest, and writable interest while messages are waiting to be written.
+struct SWatch {
+    fd: RawFd,
+    write: bool,
+}
+
+impl SWatch {
+    fn interest(&self, mut interest: mio::Ready, mut opts: mio::PollOpt) -> (mio::Ready, mio::PollOpt) {
+        if !self.write { interest.remove(mio::Ready::writable()) };
+        opts.remove(mio::PollOpt::edge());
+        opts.insert(mio::PollOpt::level());
+        (interest, opts)
+    }
+}
+
+impl mio::Evented for SWatch {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        let (interest, opts) = self.interest(interest, opts);
+        unix::EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> io::Result<()> {
+        let (interest, opts) = self.interest(interest, opts);
+        unix::EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        unix::EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+#[derive(Debug)]
+/// A Future that resolves when a method call is replied to, see `SyncConnection::method_call`.
+pub struct SyncMethodCall {
+    serial: u32,
+    inner: Arc<SInner>,
+    rx: oneshot::Receiver<Message>,
+    timeout: Option<Delay>,
+}
+
+impl SyncMethodCall {
+    /// Fails the method call with `org.freedesktop.DBus.Error.NoReply` unless a reply
+    /// has been received within the given duration.
+    ///
+    /// Without a timeout, the future will wait for a reply forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> SyncMethodCall {
+        self.timeout = Some(Delay::new(Instant::now() + timeout));
+        self
+    }
+}
+
+impl Future for SyncMethodCall {
+    type Item = Message;
+    type Error = DBusError;
+
+    fn poll(&mut self) -> Poll<Message, DBusError> {
+        let x = self.rx.poll().map_err(|_| failed("Tokio cancelled future"))?;
+        if let Async::Ready(mut m) = x {
+            m.as_result()?;
+            return Ok(Async::Ready(m));
+        }
+        if let Some(ref mut d) = self.timeout {
+            if d.poll().map_err(|_| failed("Tokio timer error"))?.is_ready() {
+                return Err(DBusError::new_custom("org.freedesktop.DBus.Error.NoReply", "Method call timed out"));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for SyncMethodCall {
+    fn drop(&mut self) {
+        self.inner.callmap.lock().unwrap().remove(&self.serial);
+    }
+}
+
+#[derive(Debug)]
+/// A Stream of incoming messages, see `SyncConnection::message_stream`.
+pub struct SyncMessageStream {
+    queue: Arc<Mutex<StreamQueue>>,
+    inner: Arc<SInner>,
+    _quit: Arc<SQuit>,
+}
+
+impl SyncMessageStream {
+    /// The number of messages that have been dropped because the stream was full.
+    pub fn dropped(&self) -> u64 { self.queue.lock().unwrap().dropped }
+}
+
+impl Stream for SyncMessageStream {
+    type Item = Message;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Message>, ()> {
+        let mut q = self.queue.lock().unwrap();
+        match q.queue.pop_front() {
+            Some(m) => Ok(Async::Ready(Some(m))),
+            None => { q.task = Some(task::current()); Ok(Async::NotReady) },
+        }
+    }
+}
+
+impl Drop for SyncMessageStream {
+    fn drop(&mut self) {
+        self.inner.streams.lock().unwrap().retain(|&(_, ref q)| !Arc::ptr_eq(q, &self.queue));
+    }
+}
+
+#[test]
+fn sync_connection_test() {
+    use dbus::testing::TestBus;
+    use tokio::runtime::Runtime;
+    use std::thread;
+
+    fn is_send_sync<T: Send + Sync>(_: &T) {}
+    fn is_send<T: Send>(_: &T) {}
+
+    let bus = TestBus::new().unwrap();
+    let mut txrx = TxRx::open_private(bus.address()).unwrap();
+    txrx.register().unwrap();
+    let (conn, driver) = SyncConnection::new(txrx).unwrap();
+    is_send_sync(&conn);
+    is_send(&driver);
+    let mut rt = Runtime::new().unwrap();
+    rt.spawn(driver);
+
+    // Method calls from several threads at once
+    let threads: Vec<_> = (0..4).map(|_| {
+        let conn = conn.clone();
+        thread::spawn(move || {
+            let m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "ListNames").unwrap();
+            let mc = conn.method_call(m).unwrap();
+            is_send(&mc);
+            let reply = mc.wait().unwrap();
+            let names: Vec<String> = reply.read1().unwrap();
+            assert!(names.contains(&conn.unique_name().unwrap()));
+        })
+    }).collect();
+    for t in threads { t.join().unwrap(); }
+
+    let m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "NoSuchMethod").unwrap();
+    let e = rt.block_on(conn.method_call(m).unwrap().with_timeout(Duration::from_secs(2))).unwrap_err();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.UnknownMethod"));
+
+    let rule = MatchRule::new_signal("com.example", "Foo");
+    let stream = conn.message_stream(Some(rule.clone()), 10);
+    is_send_sync(&stream);
+    rt.block_on(conn.add_match(&rule)).unwrap();
+    let c2 = bus.connection().unwrap();
+    c2.send(Message::new_signal("/", "com.example", "Bar").unwrap()).unwrap();
+    c2.send(Message::new_signal("/", "com.example", "Foo").unwrap()).unwrap();
+    let (m, stream) = rt.block_on(stream.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(&*m.unwrap().member().unwrap(), "Foo");
+    rt.block_on(conn.remove_match(&rule)).unwrap();
+    drop(stream);
+    assert!(conn.inner.streams.lock().unwrap().is_empty());
+
+    // Sending returns right away; the driver writes what does not fit into the socket later.
+    let big = vec![7u8; 64 * 1024];
+    for _ in 0..20 {
+        let m = Message::new_method_call(c2.unique_name(), "/", "com.example", "Big").unwrap().append1(big.clone());
+        conn.send(m).unwrap();
+    }
+    let n = c2.incoming(2000).filter(|m| m.member().map(|mm| &*mm == "Big").unwrap_or(false)).take(20).count();
+    assert_eq!(n, 20);
+
+    drop(conn);
+    rt.shutdown_on_idle().wait().unwrap();
+}
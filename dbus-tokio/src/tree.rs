@@ -117,7 +117,12 @@ where C: ops::Deref<Target=Connection>,
       T: ops::Deref<Target=Tree<MTFn<ATree<D>>, ATree<D>>>,
       S: Stream<Item=Message, Error=()>,
       D: ADataType {
+    /// Creates a server for the tree on the connection.
+    ///
+    /// Unless the tree already has a credentials cache, it gets the one of the connection,
+    /// so that `MethodInfo::caller_credentials` and `Method::require_polkit` work.
     pub fn new(c: C, t: T, stream: S) -> Self {
+        if t.credentials_cache().is_none() { t.set_credentials_cache(Some(c.credentials_cache())) }
         ATreeServer { conn: c, tree: t, stream: stream, pendingresults: vec![] }
     }
 
@@ -174,3 +179,16 @@ where C: ops::Deref<Target=Connection>,
     }
 }
 
+
+#[test]
+fn atreeserver_credentials() {
+    use std::rc::Rc;
+    let bus = ::dbus::testing::TestBus::new().unwrap();
+    let c = Rc::new(bus.connection().unwrap());
+    let f = AFactory::new_afn::<()>();
+    let tree = f.tree(ATree::new());
+    assert!(tree.credentials_cache().is_none());
+    let _server = ATreeServer::new(c.clone(), &tree, ::futures::stream::empty());
+    let cache = tree.credentials_cache().unwrap();
+    assert_eq!(cache.get(&c.unique_name()).unwrap().process_id, Some(::std::process::id()));
+}
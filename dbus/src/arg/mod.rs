@@ -57,6 +57,12 @@
 //!
 //! `OwnedFd` - a file descriptor sent from the remote side.
 //!
+//! **Serde**:
+//!
+//! With the `serde` feature enabled, `Serde<T> where T: Serialize + Deserialize` can be both
+//! appended and read. See `Serializer` for how serde types are mapped to D-Bus types,
+//! and `serde_signature` for getting the signature of a type.
+//!
 
 mod msgarg;
 mod basic_impl;
@@ -67,6 +73,11 @@ pub use self::msgarg::{Arg, FixedArray, Get, DictKey, Append, RefArg, cast, cast
 pub use self::array_impl::{Array, Dict};
 pub use self::variantstruct_impl::Variant;
 
+#[cfg(feature = "serde")]
+mod serde_impl;
+#[cfg(feature = "serde")]
+pub use self::serde_impl::{Serde, Serializer, Deserializer, to_iter, from_iter, serde_signature};
+
 use std::{fmt, mem, ptr, error};
 use {ffi, Message, Signature, Path, OwnedFd};
 use std::ffi::{CStr, CString};
@@ -92,16 +103,30 @@ impl<'a> IterAppend<'a> {
     /// Appends the argument.
     pub fn append<T: Append>(&mut self, a: T) { a.append(self) }
 
-    fn append_container<F: FnOnce(&mut IterAppend<'a>)>(&mut self, arg_type: ArgType, sig: Option<&CStr>, f: F) {
+    fn open_container(&mut self, arg_type: ArgType, sig: Option<&CStr>) -> IterAppend<'a> {
         let mut s = IterAppend(ffi_iter(), self.1);
         let p = sig.map(|s| s.as_ptr()).unwrap_or(ptr::null());
         check("dbus_message_iter_open_container",
             unsafe { ffi::dbus_message_iter_open_container(&mut self.0, arg_type as c_int, p, &mut s.0) });
-        f(&mut s);
+        s
+    }
+
+    fn close_container(&mut self, mut s: IterAppend<'a>) {
         check("dbus_message_iter_close_container",
             unsafe { ffi::dbus_message_iter_close_container(&mut self.0, &mut s.0) });
     }
 
+    // After this, the message is in an undefined state and must not be sent.
+    fn abandon_container(&mut self, mut s: IterAppend<'a>) {
+        unsafe { ffi::dbus_message_iter_abandon_container(&mut self.0, &mut s.0) };
+    }
+
+    fn append_container<F: FnOnce(&mut IterAppend<'a>)>(&mut self, arg_type: ArgType, sig: Option<&CStr>, f: F) {
+        let mut s = self.open_container(arg_type, sig);
+        f(&mut s);
+        self.close_container(s);
+    }
+
     /// Low-level function to append a variant.
     ///
     /// Use in case the `Variant` struct is not flexible enough -
@@ -206,14 +206,16 @@ deref_impl!(Arc, self, Arc::get_mut(self).unwrap());
 mod test {
     extern crate tempdir;
 
-    use {Connection, ConnectionItem, Message, BusType, Path, Signature};
+    use {ConnectionItem, Message, Path, Signature};
+    use testing::TestBus;
     use arg::{Array, Variant, Dict, Iter, ArgType, TypeMismatchError, RefArg, cast};
 
     use std::collections::HashMap;
 
     #[test]
     fn refarg() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         c.register_object_path("/mooh").unwrap();
         let m = Message::new_method_call(&c.unique_name(), "/mooh", "com.example.hello", "Hello").unwrap();
 
@@ -271,7 +273,8 @@ mod test {
 
     #[test]
     fn message_types() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         c.register_object_path("/hello").unwrap();
         let m = Message::new_method_call(&c.unique_name(), "/hello", "com.example.hello", "Hello").unwrap();
         let m = m.append1(2000u16);
@@ -0,0 +1,1209 @@
+use serde::ser::{self, Serialize};
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use {Error, Signature, Path};
+use marshal::{split_signature, check_value, append_value, Value};
+use super::{Iter, IterAppend, ArgType, Append, Get, Arg, TypeMismatchError};
+
+fn invalid(s: &str) -> Error { Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", s) }
+
+fn mismatch(sig: &[u8], what: &str) -> Error {
+    invalid(&format!("Cannot serialize {} as D-Bus type '{}'", what, String::from_utf8_lossy(sig)))
+}
+
+fn sig_str(sig: &[u8]) -> String { String::from_utf8_lossy(sig).into_owned() }
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self { invalid(&msg.to_string()) }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self { invalid(&msg.to_string()) }
+}
+
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+/// A wrapper that makes a serde type usable as a D-Bus argument, e g with `append1` and `get1`.
+///
+/// See `Serializer` for how serde types map to D-Bus types. The signature is the one
+/// returned by `serde_signature`, so it does not depend on the value.
+///
+/// Appending does not fail: if the value cannot be written with that signature (e g because
+/// of a string containing a nul character, or a skipped field), a zero value of the signature
+/// (zeroes, empty strings and empty arrays) is appended instead. Use `to_iter` to get an error.
+///
+/// Types that have no signature are appended as a variant, with the contents inferred from the value.
+pub struct Serde<T>(pub T);
+
+/// The signature is `v` for types that have no signature of their own.
+impl<T: Serialize + DeserializeOwned> Arg for Serde<T> {
+    // The real type depends on T, which a constant cannot express.
+    const ARG_TYPE: ArgType = ArgType::Invalid;
+    fn signature() -> Signature<'static> {
+        serde_signature::<T>().unwrap_or_else(|_| unsafe { Signature::from_slice_unchecked(b"v\0") })
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Append for Serde<T> {
+    fn append(self, i: &mut IterAppend) {
+        let v = match static_signature::<T>() {
+            Ok((sig, enums)) => {
+                let sig = sig.as_cstr().to_bytes();
+                put(sig, &self.0, Some(&enums)).and_then(|v| check_value(&v).map(|_| v)).unwrap_or_else(|_| zero_value(sig))
+            }
+            Err(_) => {
+                let v = infer(&self.0).and_then(|sig| put(&sig, &self.0, None)).and_then(|v| check_value(&v).map(|_| v));
+                Value::Variant(Box::new(v.unwrap_or_else(|_| Value::Str(String::new()))))
+            }
+        };
+        append_value(&mut i.0, &v);
+    }
+}
+
+impl<'a, T: Deserialize<'a>> Get<'a> for Serde<T> {
+    fn get(i: &mut Iter<'a>) -> Option<Self> {
+        T::deserialize(&mut Deserializer::new(*i)).ok().map(Serde)
+    }
+}
+
+/// Appends a value to the message using serde.
+///
+/// If `sig` is None, the signature is inferred from the value. This fails for empty sequences
+/// and maps, and for `None`, so prefer getting the signature from `serde_signature`.
+/// The contents of enum variants are always inferred from the value, so an empty sequence
+/// there fails as well; `Serde` does not have that limitation.
+///
+/// If an error is returned, nothing has been appended.
+pub fn to_iter<T: Serialize + ?Sized>(i: &mut IterAppend, value: &T, sig: Option<&Signature>) -> Result<(), Error> {
+    let sig = match sig {
+        Some(s) => s.as_cstr().to_bytes().to_vec(),
+        None => try!(infer(value)),
+    };
+    let v = try!(put(&sig, value, None));
+    try!(check_value(&v));
+    append_value(&mut i.0, &v);
+    Ok(())
+}
+
+/// Reads the current argument using serde, and moves the iterator to the next argument.
+pub fn from_iter<'a, T: Deserialize<'a>>(i: &mut Iter<'a>) -> Result<T, Error> {
+    let r = try!(T::deserialize(&mut Deserializer::new(*i)));
+    i.next();
+    Ok(r)
+}
+
+/// Returns the D-Bus signature of a serde type.
+///
+/// Types that need `deserialize_any` to be deserialized (e g untagged enums or flattened structs)
+/// do not have a signature.
+pub fn serde_signature<T: DeserializeOwned>() -> Result<Signature<'static>, Error> {
+    static_signature::<T>().map(|s| s.0)
+}
+
+fn static_signature<T: DeserializeOwned>() -> Result<(Signature<'static>, EnumSigs), Error> {
+    let (sig, enums) = try!(trace::<T>());
+    let sig = try!(Signature::new(sig).map_err(|e| invalid(&e)));
+    Ok((sig, enums))
+}
+
+// The signatures of the contents of enum variants, by enum name and variant name.
+type EnumSigs = BTreeMap<(&'static str, &'static str), Vec<u8>>;
+
+fn put<T: Serialize + ?Sized>(sig: &[u8], value: &T, enums: Option<&EnumSigs>) -> Result<Value, Error> {
+    // Without the signatures of the variants, an enum's contents get the signature of the value itself.
+    let content = if sig == b"v" && enums.is_none() { Some(try!(value.serialize(SigSerializer { content: true }))) } else { None };
+    value.serialize(Serializer { sig: sig, enums: enums, content: content })
+}
+
+// A value for a valid single complete type, as produced by the Tracer.
+fn zero_value(sig: &[u8]) -> Value {
+    match sig[0] {
+        b'b' => Value::Bool(false),
+        b'y' => Value::Byte(0),
+        b'n' => Value::Int16(0),
+        b'q' => Value::UInt16(0),
+        b'i' => Value::Int32(0),
+        b'u' => Value::UInt32(0),
+        b'x' => Value::Int64(0),
+        b't' => Value::UInt64(0),
+        b'd' => Value::Double(0.0),
+        b's' => Value::Str(String::new()),
+        b'o' => Value::ObjectPath(Path::from("/")),
+        b'g' => Value::Signature(String::new()),
+        b'a' => Value::Array(sig_str(&sig[1..]), vec!()),
+        b'(' => Value::Struct(split_signature(&sig[1..sig.len()-1]).unwrap_or(vec!()).into_iter().map(zero_value).collect()),
+        _ => Value::Variant(Box::new(Value::Str(String::new()))),
+    }
+}
+
+/// Serializes serde types into D-Bus arguments.
+///
+/// Created by `to_iter`. The mapping is as follows:
+///
+/// `bool, u8, u16, u32, u64, i16, i32, i64, f64` - the corresponding D-Bus basic type.
+/// `i8` is a D-Bus `i16` and `f32` is a D-Bus `f64`.
+///
+/// `str`, `char` - a D-Bus string.
+///
+/// Sequences - a D-Bus array. Maps - a D-Bus dict.
+///
+/// Structs and tuples - a D-Bus struct. Newtype structs are transparent.
+///
+/// `Option<T>` - a D-Bus array of T with zero or one elements.
+///
+/// Enums - a D-Bus variant, containing the name of the enum variant as a string
+/// for unit variants, or a struct of the name followed by the fields for other variants.
+///
+/// Unit types and skipped struct fields cannot be represented.
+pub struct Serializer<'a> {
+    sig: &'a [u8],
+    enums: Option<&'a EnumSigs>,
+    content: Option<Vec<u8>>,
+}
+
+impl<'a> Serializer<'a> {
+    fn expect(&self, t: &[u8], what: &str) -> Result<(), Error> {
+        if self.sig == t { Ok(()) } else { Err(mismatch(self.sig, what)) }
+    }
+
+    fn basic(&self, t: &[u8], what: &str, v: Value) -> Result<Value, Error> {
+        try!(self.expect(t, what));
+        Ok(v)
+    }
+
+    fn array_elem(&self, what: &str) -> Result<&'a [u8], Error> {
+        if self.sig.first() == Some(&b'a') { Ok(&self.sig[1..]) } else { Err(mismatch(self.sig, what)) }
+    }
+
+    fn inner_sig(&self, what: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let s = self.sig;
+        match s.first() {
+            Some(&b'(') | Some(&b'{') => Ok(try!(split_signature(&s[1..s.len()-1])).into_iter().map(|q| q.to_vec()).collect()),
+            _ => Err(mismatch(s, what)),
+        }
+    }
+
+    fn variant_content(&mut self, name: &'static str, variant: &'static str) -> Result<Vec<u8>, Error> {
+        try!(self.expect(b"v", name));
+        match self.enums {
+            Some(e) => e.get(&(name, variant)).cloned()
+                .ok_or_else(|| invalid(&format!("Enum variant {}::{} has no D-Bus signature", name, variant))),
+            None => self.content.take().ok_or_else(|| mismatch(self.sig, name)),
+        }
+    }
+
+    fn compound(&self, kind: Kind, sigs: Vec<Vec<u8>>) -> Compound<'a> {
+        Compound { kind: kind, enums: self.enums, sigs: sigs, items: vec!(), key: None }
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> { self.basic(b"b", "bool", Value::Bool(v)) }
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> { self.basic(b"n", "i8", Value::Int16(v as i16)) }
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> { self.basic(b"n", "i16", Value::Int16(v)) }
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> { self.basic(b"i", "i32", Value::Int32(v)) }
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> { self.basic(b"x", "i64", Value::Int64(v)) }
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> { self.basic(b"y", "u8", Value::Byte(v)) }
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> { self.basic(b"q", "u16", Value::UInt16(v)) }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> { self.basic(b"u", "u32", Value::UInt32(v)) }
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> { self.basic(b"t", "u64", Value::UInt64(v)) }
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> { self.basic(b"d", "f32", Value::Double(v as f64)) }
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> { self.basic(b"d", "f64", Value::Double(v)) }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        let mut b = [0; 4];
+        self.serialize_str(v.encode_utf8(&mut b))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        if v.contains('\0') { return Err(invalid("D-Bus strings cannot contain null characters")) }
+        self.basic(b"s", "string", Value::Str(v.into()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        self.basic(b"ay", "bytes", Value::Array("y".into(), v.iter().map(|&b| Value::Byte(b)).collect()))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        let elem = try!(self.array_elem("None"));
+        Ok(Value::Array(sig_str(elem), vec!()))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        let elem = try!(self.array_elem("Some"));
+        Ok(Value::Array(sig_str(elem), vec!(try!(put(elem, value, self.enums)))))
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> { Err(invalid("D-Bus has no unit type")) }
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Value, Error> { Err(mismatch(self.sig, name)) }
+
+    fn serialize_unit_variant(mut self, name: &'static str, _: u32, variant: &'static str) -> Result<Value, Error> {
+        let c = try!(self.variant_content(name, variant));
+        if c != b"s" { return Err(mismatch(&c, variant)) }
+        Ok(Value::Variant(Box::new(Value::Str(variant.into()))))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, name: &'static str, i: u32, variant: &'static str, value: &T) -> Result<Value, Error> {
+        let mut c = try!(self.serialize_tuple_variant(name, i, variant, 1));
+        try!(ser::SerializeTupleVariant::serialize_field(&mut c, value));
+        c.finish()
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound<'a>, Error> {
+        let elem = try!(self.array_elem("sequence"));
+        Ok(self.compound(Kind::Array(sig_str(elem)), vec!(elem.to_vec())))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, Error> {
+        let sigs = try!(self.inner_sig("tuple"));
+        if sigs.len() != len { return Err(mismatch(self.sig, &format!("tuple of length {}", len))) }
+        let kind = if self.sig[0] == b'{' { Kind::DictEntry } else { Kind::Struct };
+        Ok(self.compound(kind, sigs))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(mut self, name: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Compound<'a>, Error> {
+        let c = try!(self.variant_content(name, variant));
+        if c.first() != Some(&b'(') { return Err(mismatch(&c, variant)) }
+        let sigs = try!(split_signature(&c[1..c.len()-1])).into_iter().skip(1).map(|q| q.to_vec()).collect();
+        Ok(self.compound(Kind::Variant(variant), sigs))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Compound<'a>, Error> {
+        let s = self.sig;
+        if !s.starts_with(b"a{") { return Err(mismatch(s, "map")) }
+        let sigs = try!(split_signature(&s[2..s.len()-1])).into_iter().map(|q| q.to_vec()).collect();
+        Ok(self.compound(Kind::Map(sig_str(&s[1..])), sigs))
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_struct_variant(self, name: &'static str, i: u32, variant: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        self.serialize_tuple_variant(name, i, variant, len)
+    }
+}
+
+enum Kind { Array(String), Map(String), Struct, DictEntry, Variant(&'static str) }
+
+#[doc(hidden)]
+/// Serializer state for D-Bus containers.
+pub struct Compound<'a> {
+    kind: Kind,
+    enums: Option<&'a EnumSigs>,
+    sigs: Vec<Vec<u8>>,
+    items: Vec<Value>,
+    key: Option<Value>,
+}
+
+impl<'a> Compound<'a> {
+    fn element<T: Serialize + ?Sized>(&self, idx: usize, value: &T) -> Result<Value, Error> {
+        let sig = try!(self.sigs.get(idx).ok_or_else(|| invalid("Too many fields for D-Bus struct")));
+        put(sig, value, self.enums)
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let idx = if let Kind::Array(_) = self.kind { 0 } else { self.items.len() };
+        let v = try!(self.element(idx, value));
+        self.items.push(v);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let complete = self.items.len() == self.sigs.len();
+        let items = self.items;
+        match self.kind {
+            Kind::Array(elem) | Kind::Map(elem) => Ok(Value::Array(elem, items)),
+            _ if !complete => Err(invalid("Too few fields for D-Bus struct")),
+            Kind::DictEntry => {
+                let mut i = items.into_iter();
+                let k = i.next().unwrap();
+                Ok(Value::DictEntry(Box::new(k), Box::new(i.next().unwrap())))
+            }
+            Kind::Variant(name) => {
+                let mut v = vec!(Value::Str(name.into()));
+                v.extend(items);
+                Ok(Value::Variant(Box::new(Value::Struct(v))))
+            }
+            Kind::Struct => Ok(Value::Struct(items)),
+        }
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.field(value) }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.field(value) }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.field(value) }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.field(value) }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(try!(self.element(0, key)));
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let k = try!(self.key.take().ok_or_else(|| invalid("Map value serialized before key")));
+        let v = try!(self.element(1, value));
+        self.items.push(Value::DictEntry(Box::new(k), Box::new(v)));
+        Ok(())
+    }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), Error> { self.field(value) }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        Err(invalid(&format!("Cannot skip field '{}' of a D-Bus struct", key)))
+    }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), Error> { self.field(value) }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        Err(invalid(&format!("Cannot skip field '{}' of a D-Bus struct", key)))
+    }
+    fn end(self) -> Result<Value, Error> { self.finish() }
+}
+
+
+// Infers the signature of a value. If "content" is set and the value is an enum,
+// returns the signature of what's inside the variant instead of "v".
+struct SigSerializer { content: bool }
+
+fn infer<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> { value.serialize(SigSerializer { content: false }) }
+
+impl ser::Serializer for SigSerializer {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    type SerializeSeq = SigCompound;
+    type SerializeTuple = SigCompound;
+    type SerializeTupleStruct = SigCompound;
+    type SerializeTupleVariant = SigCompound;
+    type SerializeMap = SigCompound;
+    type SerializeStruct = SigCompound;
+    type SerializeStructVariant = SigCompound;
+
+    fn serialize_bool(self, _: bool) -> Result<Vec<u8>, Error> { Ok(b"b".to_vec()) }
+    fn serialize_i8(self, _: i8) -> Result<Vec<u8>, Error> { Ok(b"n".to_vec()) }
+    fn serialize_i16(self, _: i16) -> Result<Vec<u8>, Error> { Ok(b"n".to_vec()) }
+    fn serialize_i32(self, _: i32) -> Result<Vec<u8>, Error> { Ok(b"i".to_vec()) }
+    fn serialize_i64(self, _: i64) -> Result<Vec<u8>, Error> { Ok(b"x".to_vec()) }
+    fn serialize_u8(self, _: u8) -> Result<Vec<u8>, Error> { Ok(b"y".to_vec()) }
+    fn serialize_u16(self, _: u16) -> Result<Vec<u8>, Error> { Ok(b"q".to_vec()) }
+    fn serialize_u32(self, _: u32) -> Result<Vec<u8>, Error> { Ok(b"u".to_vec()) }
+    fn serialize_u64(self, _: u64) -> Result<Vec<u8>, Error> { Ok(b"t".to_vec()) }
+    fn serialize_f32(self, _: f32) -> Result<Vec<u8>, Error> { Ok(b"d".to_vec()) }
+    fn serialize_f64(self, _: f64) -> Result<Vec<u8>, Error> { Ok(b"d".to_vec()) }
+    fn serialize_char(self, _: char) -> Result<Vec<u8>, Error> { Ok(b"s".to_vec()) }
+    fn serialize_str(self, _: &str) -> Result<Vec<u8>, Error> { Ok(b"s".to_vec()) }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Vec<u8>, Error> { Ok(b"ay".to_vec()) }
+    fn serialize_none(self) -> Result<Vec<u8>, Error> { Err(invalid("Cannot infer the D-Bus signature of None")) }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, Error> {
+        let mut r = b"a".to_vec();
+        r.extend(try!(infer(value)));
+        Ok(r)
+    }
+    fn serialize_unit(self) -> Result<Vec<u8>, Error> { Err(invalid("D-Bus has no unit type")) }
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Vec<u8>, Error> { Err(mismatch(b"", name)) }
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<Vec<u8>, Error> {
+        Ok(if self.content { b"s".to_vec() } else { b"v".to_vec() })
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Vec<u8>, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str, value: &T) -> Result<Vec<u8>, Error> {
+        if !self.content { return Ok(b"v".to_vec()) }
+        let mut r = b"(s".to_vec();
+        r.extend(try!(infer(value)));
+        r.push(b')');
+        Ok(r)
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<SigCompound, Error> { Ok(SigCompound::new(SigKind::Array)) }
+    fn serialize_tuple(self, _: usize) -> Result<SigCompound, Error> { Ok(SigCompound::new(SigKind::Struct)) }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<SigCompound, Error> { Ok(SigCompound::new(SigKind::Struct)) }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<SigCompound, Error> {
+        Ok(SigCompound::new(SigKind::Variant(self.content)))
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<SigCompound, Error> { Ok(SigCompound::new(SigKind::Map)) }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<SigCompound, Error> { Ok(SigCompound::new(SigKind::Struct)) }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<SigCompound, Error> {
+        Ok(SigCompound::new(SigKind::Variant(self.content)))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum SigKind { Array, Struct, Map, Variant(bool) }
+
+#[doc(hidden)]
+/// Signature inference state for D-Bus containers.
+pub struct SigCompound { kind: SigKind, sigs: Vec<Vec<u8>> }
+
+impl SigCompound {
+    fn new(kind: SigKind) -> Self { SigCompound { kind: kind, sigs: vec!() } }
+
+    fn add<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if self.kind == SigKind::Variant(false) { return Ok(()) }
+        let s = try!(infer(value));
+        self.sigs.push(s);
+        Ok(())
+    }
+
+    fn same(&self, start: usize) -> Result<Vec<u8>, Error> {
+        let mut it = self.sigs.iter().skip(start).step_by(if self.kind == SigKind::Map { 2 } else { 1 });
+        let first = try!(it.next().ok_or_else(|| invalid("Cannot infer the D-Bus signature of an empty sequence or map")));
+        if it.any(|s| s != first) { return Err(invalid("Elements of a D-Bus array must have the same signature")) }
+        Ok(first.clone())
+    }
+
+    fn finish(self) -> Result<Vec<u8>, Error> {
+        let mut r = vec!();
+        match self.kind {
+            SigKind::Array => { r.push(b'a'); r.extend(try!(self.same(0))); },
+            SigKind::Map => { r.extend(b"a{"); r.extend(try!(self.same(0))); r.extend(try!(self.same(1))); r.push(b'}'); },
+            SigKind::Struct => {
+                if self.sigs.len() == 0 { return Err(invalid("D-Bus structs must have at least one field")) }
+                r.push(b'('); for s in self.sigs { r.extend(s) }; r.push(b')');
+            },
+            SigKind::Variant(true) => { r.extend(b"(s"); for s in self.sigs { r.extend(s) }; r.push(b')'); },
+            SigKind::Variant(false) => r.push(b'v'),
+        }
+        Ok(r)
+    }
+}
+
+impl ser::SerializeSeq for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.add(value) }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+impl ser::SerializeTuple for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.add(value) }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+impl ser::SerializeTupleStruct for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.add(value) }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+impl ser::SerializeTupleVariant for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.add(value) }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+impl ser::SerializeMap for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> { self.add(key) }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> { self.add(value) }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+impl ser::SerializeStruct for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), Error> { self.add(value) }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        Err(invalid(&format!("Cannot skip field '{}' of a D-Bus struct", key)))
+    }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+impl ser::SerializeStructVariant for SigCompound {
+    type Ok = Vec<u8>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), Error> { self.add(value) }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        Err(invalid(&format!("Cannot skip field '{}' of a D-Bus struct", key)))
+    }
+    fn end(self) -> Result<Vec<u8>, Error> { self.finish() }
+}
+
+
+// Finds the signature of a type, and of the contents of all its enum variants. Deserializing
+// a dummy value only traces one variant of each enum, so that is repeated with other variants
+// until all of them (including those of enums found inside of variants) have been traced.
+fn trace<T: DeserializeOwned>() -> Result<(Vec<u8>, EnumSigs), Error> {
+    let mut enums = EnumTrace::default();
+    loop {
+        let traced = enums.sigs.len();
+        let mut sig = vec!();
+        try!(T::deserialize(&mut Tracer { sig: &mut sig, depth: 0, enums: &mut enums }));
+        if enums.variants.keys().all(|&e| enums.complete(e, &mut BTreeSet::new())) { return Ok((sig, enums.sigs)) }
+        if enums.sigs.len() == traced { return Err(invalid("Cannot find the D-Bus signatures of all enum variants")) }
+    }
+}
+
+type VariantKey = (&'static str, &'static str);
+
+#[derive(Default)]
+struct EnumTrace {
+    sigs: EnumSigs,
+    variants: BTreeMap<&'static str, &'static [&'static str]>,
+    // The enums found directly inside the contents of each variant
+    nested: BTreeMap<VariantKey, BTreeSet<&'static str>>,
+    // The variants whose contents are being traced, and the enums found in them so far
+    busy: BTreeSet<VariantKey>,
+    found: Vec<BTreeSet<&'static str>>,
+}
+
+impl EnumTrace {
+    fn complete(&self, name: &'static str, seen: &mut BTreeSet<&'static str>) -> bool {
+        if !seen.insert(name) { return true }
+        let variants = match self.variants.get(name) { Some(v) => *v, None => return false };
+        variants.iter().all(|&v| match self.nested.get(&(name, v)) {
+            Some(n) => n.iter().all(|&e| self.complete(e, seen)),
+            None => false,
+        })
+    }
+
+    // Prefers a variant that has not been traced, or that leads to an enum which has not been.
+    fn pick(&self, name: &'static str) -> usize {
+        let variants = self.variants[name];
+        variants.iter().position(|&v| {
+            let key = (name, v);
+            if self.busy.contains(&key) { return false }
+            match self.nested.get(&key) {
+                Some(n) => n.iter().any(|&e| !self.complete(e, &mut BTreeSet::new())),
+                None => true,
+            }
+        }).or_else(|| variants.iter().position(|&v| !self.busy.contains(&(name, v)))).unwrap_or(0)
+    }
+
+    fn record(&mut self, key: VariantKey, sig: Vec<u8>, found: BTreeSet<&'static str>) -> Result<(), Error> {
+        if self.sigs.get(&key).map(|s| *s != sig).unwrap_or(false) {
+            return Err(invalid(&format!("Enum variant {}::{} has more than one D-Bus signature (are there several enums named {}?)",
+                key.0, key.1, key.0)))
+        }
+        self.sigs.insert(key, sig);
+        self.nested.entry(key).or_insert_with(BTreeSet::new).extend(found);
+        Ok(())
+    }
+}
+
+struct Tracer<'a> { sig: &'a mut Vec<u8>, depth: usize, enums: &'a mut EnumTrace }
+
+impl<'a> Tracer<'a> {
+    fn push(&mut self, s: &[u8]) -> Result<(), Error> {
+        self.sig.extend(s);
+        if self.sig.len() > 255 { Err(invalid("D-Bus signature is too long (is the type recursive?)")) } else { Ok(()) }
+    }
+
+    fn nested_depth(&self) -> Result<usize, Error> {
+        if self.depth >= 32 { Err(invalid("D-Bus type is nested too deeply (is the type recursive?)")) } else { Ok(self.depth + 1) }
+    }
+}
+
+macro_rules! trace_basic {
+    ($f: ident, $visit: ident, $sig: expr, $v: expr) => {
+        fn $f<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            try!(self.push($sig));
+            visitor.$visit($v)
+        }
+    }
+}
+
+impl<'de, 'x, 'a> de::Deserializer<'de> for &'x mut Tracer<'a> {
+    type Error = Error;
+
+    trace_basic!(deserialize_bool, visit_bool, b"b", false);
+    trace_basic!(deserialize_i8, visit_i8, b"n", 1);
+    trace_basic!(deserialize_i16, visit_i16, b"n", 1);
+    trace_basic!(deserialize_i32, visit_i32, b"i", 1);
+    trace_basic!(deserialize_i64, visit_i64, b"x", 1);
+    trace_basic!(deserialize_u8, visit_u8, b"y", 1);
+    trace_basic!(deserialize_u16, visit_u16, b"q", 1);
+    trace_basic!(deserialize_u32, visit_u32, b"u", 1);
+    trace_basic!(deserialize_u64, visit_u64, b"t", 1);
+    trace_basic!(deserialize_f32, visit_f32, b"d", 0.0);
+    trace_basic!(deserialize_f64, visit_f64, b"d", 0.0);
+    trace_basic!(deserialize_char, visit_char, b"s", 'a');
+    trace_basic!(deserialize_str, visit_borrowed_str, b"s", "");
+    trace_basic!(deserialize_string, visit_borrowed_str, b"s", "");
+    trace_basic!(deserialize_bytes, visit_borrowed_bytes, b"ay", &[]);
+    trace_basic!(deserialize_byte_buf, visit_borrowed_bytes, b"ay", &[]);
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> {
+        Err(invalid("Type has no D-Bus signature, because it is not known until deserialization"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        try!(self.push(b"a"));
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> { Err(invalid("D-Bus has no unit type")) }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, name: &'static str, _: V) -> Result<V::Value, Error> {
+        Err(invalid(&format!("Unit struct {} has no D-Bus signature", name)))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        try!(self.push(b"a"));
+        visitor.visit_seq(TraceSeq(self, 1))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        if len == 0 { return Err(invalid("D-Bus structs must have at least one field")) }
+        try!(self.push(b"("));
+        let r = try!(visitor.visit_seq(TraceSeq(&mut *self, len)));
+        try!(self.push(b")"));
+        Ok(r)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        try!(self.push(b"a{"));
+        let r = try!(visitor.visit_map(TraceSeq(&mut *self, 2)));
+        try!(self.push(b"}"));
+        Ok(r)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        try!(self.push(b"v"));
+        if variants.is_empty() { return Err(invalid(&format!("Enum {} has no variants", name))) }
+        self.enums.variants.insert(name, variants);
+        if let Some(f) = self.enums.found.last_mut() { f.insert(name); }
+        let idx = self.enums.pick(name);
+        visitor.visit_enum(TraceEnum(self, name, variants[idx], idx as u32))
+    }
+}
+
+struct TraceSeq<'x, 'a: 'x>(&'x mut Tracer<'a>, usize);
+
+impl<'de, 'x, 'a> de::SeqAccess<'de> for TraceSeq<'x, 'a> {
+    type Error = Error;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.1 == 0 { return Ok(None) }
+        self.1 -= 1;
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+    fn size_hint(&self) -> Option<usize> { Some(self.1) }
+}
+
+impl<'de, 'x, 'a> de::MapAccess<'de> for TraceSeq<'x, 'a> {
+    type Error = Error;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.1 < 2 { return Ok(None) }
+        self.1 -= 1;
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Error> {
+        self.1 -= 1;
+        seed.deserialize(&mut *self.0)
+    }
+    fn size_hint(&self) -> Option<usize> { Some(self.1 / 2) }
+}
+
+struct TraceEnum<'x, 'a: 'x>(&'x mut Tracer<'a>, &'static str, &'static str, u32);
+
+impl<'x, 'a> TraceEnum<'x, 'a> {
+    fn content<R, F: FnOnce(&mut Tracer) -> Result<R, Error>>(self, f: F) -> Result<R, Error> {
+        let key = (self.1, self.2);
+        let depth = try!(self.0.nested_depth());
+        let enums = &mut *self.0.enums;
+        let mut sig = b"(s".to_vec();
+        enums.busy.insert(key);
+        enums.found.push(BTreeSet::new());
+        let r = f(&mut Tracer { sig: &mut sig, depth: depth, enums: &mut *enums });
+        enums.busy.remove(&key);
+        let found = enums.found.pop().unwrap();
+        let r = try!(r);
+        sig.push(b')');
+        try!(enums.record(key, sig, found));
+        Ok(r)
+    }
+}
+
+impl<'de, 'x, 'a> de::EnumAccess<'de> for TraceEnum<'x, 'a> {
+    type Error = Error;
+    type Variant = Self;
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let v = try!(seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.3)));
+        Ok((v, self))
+    }
+}
+
+impl<'de, 'x, 'a> de::VariantAccess<'de> for TraceEnum<'x, 'a> {
+    type Error = Error;
+    fn unit_variant(self) -> Result<(), Error> { self.0.enums.record((self.1, self.2), b"s".to_vec(), BTreeSet::new()) }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        self.content(|t| seed.deserialize(t))
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.content(|t| visitor.visit_seq(TraceSeq(t, len)))
+    }
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        self.tuple_variant(fields.len(), visitor)
+    }
+}
+
+
+/// Deserializes D-Bus arguments into serde types.
+///
+/// See `Serializer` for how D-Bus types map to serde types. In addition, variants are
+/// unwrapped when something else than an enum is expected, and structs can also
+/// be read from dicts with string keys, e g `a{sv}`.
+pub struct Deserializer<'a> {
+    iter: Iter<'a>,
+}
+
+impl<'a> Deserializer<'a> {
+    /// Creates a Deserializer that starts reading at the current argument of the Iter.
+    pub fn new(i: Iter<'a>) -> Self { Deserializer { iter: i } }
+
+    fn get<T: Arg + Get<'a>>(&mut self) -> Result<T, Error> {
+        self.iter.read().map_err(|e| invalid(&e.to_string()))
+    }
+
+    fn mismatch(&mut self, t: ArgType) -> Error {
+        invalid(&TypeMismatchError { expected: t, found: self.iter.arg_type(), position: self.iter.2 }.to_string())
+    }
+
+    fn container(&mut self, t: ArgType) -> Result<Deserializer<'a>, Error> {
+        let s = try!(self.iter.recurse(t).ok_or_else(|| self.mismatch(t)));
+        self.iter.next();
+        Ok(Deserializer { iter: s })
+    }
+
+    fn at_end(&mut self) -> bool { self.iter.arg_type() == ArgType::Invalid }
+
+    // Reads the rest of a container as a sequence, which must be read to the end.
+    fn seq<V: Visitor<'a>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let r = try!(visitor.visit_seq(DeSeq(&mut *self)));
+        if self.at_end() { Ok(r) } else { Err(invalid("D-Bus container has more values than expected")) }
+    }
+
+    fn enum_value(&mut self) -> Result<(&'a str, Option<Deserializer<'a>>), Error> {
+        match self.iter.arg_type() {
+            ArgType::Variant => try!(self.container(ArgType::Variant)).enum_value(),
+            ArgType::String => Ok((try!(self.get()), None)),
+            ArgType::Struct => {
+                let mut s = try!(self.container(ArgType::Struct));
+                Ok((try!(s.get()), Some(s)))
+            }
+            _ => Err(self.mismatch(ArgType::Variant)),
+        }
+    }
+}
+
+macro_rules! through_variant {
+    ($s: ident, $f: ident, $($a: expr),*) => {
+        if $s.iter.arg_type() == ArgType::Variant {
+            let mut d = try!($s.container(ArgType::Variant));
+            return (&mut d).$f($($a),*);
+        }
+    }
+}
+
+macro_rules! de_basic {
+    ($f: ident, $visit: ident, $t: ty) => {
+        fn $f<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            through_variant!(self, $f, visitor);
+            visitor.$visit(try!(self.get::<$t>()))
+        }
+    }
+}
+
+impl<'x, 'de> de::Deserializer<'de> for &'x mut Deserializer<'de> {
+    type Error = Error;
+
+    de_basic!(deserialize_bool, visit_bool, bool);
+    de_basic!(deserialize_i8, visit_i16, i16);
+    de_basic!(deserialize_i16, visit_i16, i16);
+    de_basic!(deserialize_i32, visit_i32, i32);
+    de_basic!(deserialize_i64, visit_i64, i64);
+    de_basic!(deserialize_u8, visit_u8, u8);
+    de_basic!(deserialize_u16, visit_u16, u16);
+    de_basic!(deserialize_u32, visit_u32, u32);
+    de_basic!(deserialize_u64, visit_u64, u64);
+    de_basic!(deserialize_f32, visit_f64, f64);
+    de_basic!(deserialize_f64, visit_f64, f64);
+    de_basic!(deserialize_bytes, visit_borrowed_bytes, &'de [u8]);
+    de_basic!(deserialize_byte_buf, visit_borrowed_bytes, &'de [u8]);
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.iter.arg_type() {
+            ArgType::Boolean => self.deserialize_bool(visitor),
+            ArgType::Byte => self.deserialize_u8(visitor),
+            ArgType::Int16 => self.deserialize_i16(visitor),
+            ArgType::UInt16 => self.deserialize_u16(visitor),
+            ArgType::Int32 => self.deserialize_i32(visitor),
+            ArgType::UInt32 => self.deserialize_u32(visitor),
+            ArgType::Int64 => self.deserialize_i64(visitor),
+            ArgType::UInt64 => self.deserialize_u64(visitor),
+            ArgType::Double => self.deserialize_f64(visitor),
+            ArgType::String | ArgType::ObjectPath | ArgType::Signature => self.deserialize_str(visitor),
+            ArgType::Array => if self.iter.signature().starts_with("a{") { self.deserialize_map(visitor) } else { self.deserialize_seq(visitor) },
+            ArgType::Struct | ArgType::DictEntry => self.deserialize_tuple(0, visitor),
+            ArgType::Variant => try!(self.container(ArgType::Variant)).deserialize_any(visitor),
+            ArgType::UnixFd => Err(invalid("Unix file descriptors are not supported by the serde Deserializer")),
+            ArgType::Invalid => Err(invalid("No more D-Bus arguments")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.at_end() { return Err(invalid("No more D-Bus arguments")) }
+        self.iter.next();
+        visitor.visit_unit()
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> { self.deserialize_str(visitor) }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.iter.arg_type() {
+            ArgType::Variant => try!(self.container(ArgType::Variant)).deserialize_str(visitor),
+            ArgType::ObjectPath => { let p: Path = try!(self.get()); visitor.visit_str(&p) },
+            ArgType::Signature => { let s: Signature = try!(self.get()); visitor.visit_str(&s) },
+            _ => visitor.visit_borrowed_str(try!(self.get::<&'de str>())),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> { self.deserialize_str(visitor) }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> { self.deserialize_str(visitor) }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        through_variant!(self, deserialize_option, visitor);
+        let mut s = try!(self.container(ArgType::Array));
+        if s.at_end() { visitor.visit_none() } else { visitor.visit_some(&mut s) }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> { Err(invalid("D-Bus has no unit type")) }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, name: &'static str, _: V) -> Result<V::Value, Error> {
+        Err(invalid(&format!("Unit struct {} cannot be read from D-Bus", name)))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        through_variant!(self, deserialize_seq, visitor);
+        let mut s = try!(self.container(ArgType::Array));
+        s.seq(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        through_variant!(self, deserialize_tuple, len, visitor);
+        let t = if self.iter.arg_type() == ArgType::DictEntry { ArgType::DictEntry } else { ArgType::Struct };
+        let mut s = try!(self.container(t));
+        s.seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        through_variant!(self, deserialize_map, visitor);
+        let mut s = try!(self.container(ArgType::Array));
+        visitor.visit_map(DeMap(&mut s, None))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        through_variant!(self, deserialize_struct, name, fields, visitor);
+        if self.iter.arg_type() == ArgType::Array { self.deserialize_map(visitor) }
+        else { self.deserialize_tuple(fields.len(), visitor) }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        let (name, payload) = try!(self.enum_value());
+        visitor.visit_enum(DeEnum(name, payload))
+    }
+}
+
+struct DeSeq<'x, 'a: 'x>(&'x mut Deserializer<'a>);
+
+impl<'x, 'de> de::SeqAccess<'de> for DeSeq<'x, 'de> {
+    type Error = Error;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.0.at_end() { return Ok(None) }
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+}
+
+struct DeMap<'x, 'a: 'x>(&'x mut Deserializer<'a>, Option<Deserializer<'a>>);
+
+impl<'x, 'de> de::MapAccess<'de> for DeMap<'x, 'de> {
+    type Error = Error;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.0.at_end() { return Ok(None) }
+        let mut e = try!(self.0.container(ArgType::DictEntry));
+        let k = try!(seed.deserialize(&mut e));
+        self.1 = Some(e);
+        Ok(Some(k))
+    }
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Error> {
+        let mut e = try!(self.1.take().ok_or_else(|| invalid("Dict value requested before key")));
+        seed.deserialize(&mut e)
+    }
+}
+
+struct DeEnum<'a>(&'a str, Option<Deserializer<'a>>);
+
+impl<'de> de::EnumAccess<'de> for DeEnum<'de> {
+    type Error = Error;
+    type Variant = Self;
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let v = try!(seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.0)));
+        Ok((v, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for DeEnum<'de> {
+    type Error = Error;
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.1 {
+            Some(mut d) => if d.at_end() { Ok(()) } else { Err(invalid("Expected a unit enum variant")) },
+            None => Ok(()),
+        }
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let mut d = try!(self.1.ok_or_else(|| invalid("Expected a newtype enum variant")));
+        seed.deserialize(&mut d)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        let mut d = try!(self.1.ok_or_else(|| invalid("Expected a tuple enum variant")));
+        d.seq(visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        let mut d = try!(self.1.ok_or_else(|| invalid("Expected a struct enum variant")));
+        d.seq(visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Serde, serde_signature, to_iter, from_iter};
+    use arg::{Arg, IterAppend, Variant, Dict};
+    use {Message, Signature};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Shape { Point, Circle(f64), Rect { w: u32, h: u32 }, Named(String, Vec<u8>) }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct Id(u64);
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct Thing {
+        id: Id,
+        name: String,
+        small: i8,
+        ratio: f32,
+        tags: Vec<String>,
+        attrs: BTreeMap<String, (i32, bool)>,
+        parent: Option<u32>,
+        shapes: Vec<Shape>,
+    }
+
+    fn thing() -> Thing {
+        let mut attrs = BTreeMap::new();
+        attrs.insert("a".into(), (-5, true));
+        attrs.insert("b".into(), (7, false));
+        Thing { id: Id(u64::max_value()), name: "thing".into(), small: -3, ratio: 0.5,
+            tags: vec!(), attrs: attrs, parent: None,
+            shapes: vec!(Shape::Point, Shape::Circle(2.0), Shape::Rect { w: 3, h: 4 }, Shape::Named("x".into(), vec!(1, 2)))}
+    }
+
+    #[test]
+    fn signature() {
+        assert_eq!(&*serde_signature::<Thing>().unwrap(), "(tsndasa{s(ib)}auav)");
+        assert_eq!(&*serde_signature::<HashMap<u8, Vec<Option<String>>>>().unwrap(), "a{yaas}");
+        assert_eq!(&*serde_signature::<(Shape, Id)>().unwrap(), "(vt)");
+        assert!(serde_signature::<()>().is_err());
+        assert_eq!(&*Serde::<Thing>::signature(), "(tsndasa{s(ib)}auav)");
+        assert_eq!(&*Serde::<()>::signature(), "v");
+    }
+
+    #[test]
+    fn static_signatures() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+        enum Tree { Leaf(Option<Shape>), Node(Vec<Tree>, u8) }
+
+        // The signatures of enum contents do not depend on the value
+        let shapes = vec!(Serde(Shape::Named("x".into(), vec!())), Serde(Shape::Point));
+        let m = Message::new_signal("/", "com.example", "Test").unwrap()
+            .append3(shapes.clone(), Serde(Vec::<Shape>::new()), Serde(Tree::Node(vec!(Tree::Leaf(None)), 1)));
+        assert_eq!(&*m.iter_init().signature(), "av");
+        let (a, b, c): (Option<Vec<Serde<Shape>>>, Option<Serde<Vec<Shape>>>, Option<Serde<Tree>>) = m.get3();
+        assert_eq!(a.unwrap(), shapes);
+        assert_eq!(b.unwrap().0, vec!());
+        assert_eq!(c.unwrap().0, Tree::Node(vec!(Tree::Leaf(None)), 1));
+        let mut i = m.iter_init();
+        let mut v: Vec<Variant<::arg::Iter>> = i.read().unwrap();
+        assert_eq!(&*v[0].0.signature(), "(ssay)");
+        i.next();
+        let mut t: Variant<::arg::Iter> = i.read().unwrap();
+        assert_eq!(&*t.0.signature(), "(savy)");
+        let mut leaves: Vec<Variant<::arg::Iter>> = t.0.get::<(&str, Vec<Variant<::arg::Iter>>, u8)>().unwrap().1;
+        assert_eq!(&*leaves[0].0.signature(), "(sav)");
+
+        // Values that do not fit their signature become zero values instead
+        #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+        struct Skips { a: u32, #[serde(skip_serializing_if = "Option::is_none")] b: Option<String> }
+        let m = Message::new_signal("/", "com.example", "Test").unwrap()
+            .append2(Serde(("a\0b".to_string(), 5u32)), Serde(Skips { a: 1, b: None }));
+        let (x, y): (Option<(&str, u32)>, Option<(u32, Vec<&str>)>) = m.get2();
+        assert_eq!(x, Some(("", 0)));
+        assert_eq!(y, Some((0, vec!())));
+
+        // Types without a signature become variants
+        #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+        #[serde(untagged)]
+        enum Untagged { Num(u32), Text(String) }
+        let m = Message::new_signal("/", "com.example", "Test").unwrap().append1(Serde(Untagged::Text("hi".into())));
+        assert_eq!(&*m.iter_init().signature(), "v");
+        assert_eq!(m.get1::<Serde<Untagged>>().unwrap().0, Untagged::Text("hi".into()));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut t = thing();
+        let m = Message::new_signal("/", "com.example", "Test").unwrap().append2(Serde(t.clone()), 5u8);
+        assert_eq!(&*m.iter_init().signature(), "(tsndasa{s(ib)}auav)");
+        let (r, five): (Option<Serde<Thing>>, Option<u8>) = m.get2();
+        assert_eq!(r.unwrap().0, t);
+        assert_eq!(five, Some(5));
+
+        // The contents of enum variants are self-describing
+        let mut i = m.iter_init();
+        let s: (u64, &str, i16, f64, Vec<&str>, Dict<&str, (i32, bool), _>, Vec<u32>, Vec<Variant<::arg::Iter>>) = i.read().unwrap();
+        assert_eq!(s.4.len(), 0);
+        let mut shapes = s.7;
+        assert_eq!(shapes[0].0.get::<&str>(), Some("Point"));
+        assert_eq!(shapes[1].0.get::<(&str, f64)>(), Some(("Circle", 2.0)));
+        assert_eq!(shapes[2].0.get::<(&str, u32, u32)>(), Some(("Rect", 3, 4)));
+        assert_eq!(&*shapes[3].0.signature(), "(ssay)");
+
+        t.tags.push("tag".into());
+        t.parent = Some(9);
+        let mut m = Message::new_signal("/", "com.example", "Test").unwrap();
+        to_iter(&mut IterAppend::new(&mut m), &t, None).unwrap();
+        assert_eq!(&*m.iter_init().signature(), "(tsndasa{s(ib)}auav)");
+        let mut i = m.iter_init();
+        assert_eq!(from_iter::<Thing>(&mut i).unwrap(), t);
+        assert!(!i.next());
+    }
+
+    #[test]
+    fn borrowed_and_dicts() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Props<'a> { #[serde(rename = "Name")] name: &'a str, #[serde(rename = "Count")] count: u32 }
+
+        let mut d = HashMap::new();
+        d.insert("Name", Variant(Box::new("hello".to_string()) as Box<::arg::RefArg>));
+        d.insert("Count", Variant(Box::new(42u32) as Box<::arg::RefArg>));
+        d.insert("Unknown", Variant(Box::new(1.5f64) as Box<::arg::RefArg>));
+        let m = Message::new_signal("/", "com.example", "Test").unwrap().append1(d);
+        let p: Serde<Props> = m.get1().unwrap();
+        assert_eq!(p.0, Props { name: "hello", count: 42 });
+    }
+
+    #[test]
+    fn errors() {
+        // Nothing is appended if to_iter fails
+        fn fails<T: ::serde::Serialize>(value: &T, sig: Option<&Signature>) -> bool {
+            let mut m = Message::new_signal("/", "com.example", "Test").unwrap();
+            let r = to_iter(&mut IterAppend::new(&mut m), value, sig).is_err();
+            IterAppend::new(&mut m).append(5u8);
+            assert_eq!(&*m.iter_init().signature(), "y");
+            r
+        }
+        assert!(fails(&(5u32, 6u32), Some(&Signature::new("(us)").unwrap())));
+        let map: BTreeMap<u32, u32> = vec!((5, 6)).into_iter().collect();
+        assert!(fails(&map, Some(&Signature::new("a{us}").unwrap())));
+        assert!(fails(&vec!((5u32, 6u32)), Some(&Signature::new("a(us)").unwrap())));
+        assert!(fails(&Some((5u32, 6u32)), Some(&Signature::new("a(us)").unwrap())));
+        assert!(fails(&Vec::<u32>::new(), None));
+        assert!(fails(&"a\0b", None));
+        // to_iter infers enum contents from the value, so empty sequences can't be written there
+        assert!(fails(&Shape::Named("x".into(), vec!()), Some(&serde_signature::<Shape>().unwrap())));
+
+        let m = Message::new_signal("/", "com.example", "Test").unwrap().append1((5u32, "x"));
+        let e = from_iter::<(u32, u32)>(&mut m.iter_init()).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+        assert!(m.get1::<Serde<Shape>>().is_none());
+
+        // All of a struct must be read
+        let m = Message::new_signal("/", "com.example", "Test").unwrap().append1((5u32, 6u32, 7u32));
+        assert!(from_iter::<(u32, u32)>(&mut m.iter_init()).is_err());
+        assert!(m.get1::<Serde<(u32, u32, u32)>>().is_some());
+    }
+}
@@ -0,0 +1,87 @@
+// The plumbing shared by NameWatcher and ObjectManagerClient: state of a remote service that
+// is fetched with a method call, and then kept up to date through the signals it sends.
+
+use {Connection, ConnMsgs, Message, Error};
+
+pub(crate) trait RemoteCache {
+    type Event;
+
+    // Match rules for the signals that update the state.
+    fn rules(&self) -> Vec<String>;
+
+    // The method call that fetches the state.
+    fn fetch_call(&self) -> Message;
+
+    // Takes the state from the reply to "fetch_call".
+    fn fetched(&mut self, reply: Result<Message, Error>) -> Result<(), Error>;
+
+    // Updates the state from an incoming message, if it is one of ours.
+    fn update(&mut self, m: &Message) -> Option<Self::Event>;
+
+    // Whether the state has to be fetched again after this event.
+    fn refetch(_: &Self::Event) -> bool { false }
+}
+
+// A RemoteCache on a blocking connection. The match rules are removed when dropped.
+pub(crate) struct CacheClient<'a, T: RemoteCache> {
+    conn: &'a Connection,
+    cache: T,
+    rules: Vec<String>,
+    timeout_ms: i32,
+}
+
+impl<'a, T: RemoteCache> CacheClient<'a, T> {
+    pub fn new(conn: &'a Connection, cache: T, timeout_ms: i32) -> Result<CacheClient<'a, T>, Error> {
+        let mut c = CacheClient { conn: conn, cache: cache, rules: vec!(), timeout_ms: timeout_ms };
+        for r in c.cache.rules() {
+            try!(conn.add_match(&r));
+            c.rules.push(r);
+        }
+        try!(c.refresh(timeout_ms));
+        Ok(c)
+    }
+
+    pub fn cache(&self) -> &T { &self.cache }
+
+    pub fn refresh(&mut self, timeout_ms: i32) -> Result<(), Error> {
+        let r = self.conn.send_with_reply_and_block(self.cache.fetch_call(), timeout_ms);
+        self.cache.fetched(r)
+    }
+
+    // If the state has to be fetched again, this is done right away, with the timeout given to "new".
+    // If that fails, the cache stays as "update" left it until the next refresh.
+    pub fn handle_message(&mut self, m: &Message) -> Option<T::Event> {
+        let e = self.cache.update(m);
+        if e.as_ref().map(T::refetch) == Some(true) {
+            let t = self.timeout_ms;
+            let _ = self.refresh(t);
+        }
+        e
+    }
+
+    pub fn events<'b>(&'b mut self, timeout_ms: u32) -> CacheEvents<'b, 'a, T> {
+        let msgs = self.conn.incoming(timeout_ms);
+        CacheEvents { client: self, msgs: msgs }
+    }
+}
+
+impl<'a, T: RemoteCache> Drop for CacheClient<'a, T> {
+    fn drop(&mut self) {
+        for r in self.rules.iter() { let _ = self.conn.remove_match(r); }
+    }
+}
+
+pub(crate) struct CacheEvents<'b, 'a: 'b, T: RemoteCache + 'b> {
+    client: &'b mut CacheClient<'a, T>,
+    msgs: ConnMsgs<&'a Connection>,
+}
+
+impl<'b, 'a, T: RemoteCache> Iterator for CacheEvents<'b, 'a, T> {
+    type Item = T::Event;
+    fn next(&mut self) -> Option<T::Event> {
+        loop {
+            let m = match self.msgs.next() { Some(m) => m, None => return None };
+            if let Some(e) = self.client.handle_message(&m) { return Some(e) }
+        }
+    }
+}
@@ -1,7 +1,13 @@
-use super::{Error, ffi, to_c_str, c_str_to_slice, Watch, Message, MessageType, BusName, Path, ConnPath};
+use super::{Error, ffi, to_c_str, c_str_to_slice, Watch, Timeout, Message, MessageType, BusName, Path, ConnPath};
+use super::{MatchRule, SignalArgs, NameOwner, NameWatcher, NameWatchCache, CredentialsCache};
 use super::{RequestNameReply, ReleaseNameReply, BusType};
-use super::watch::WatchList;
+use super::watch::{WatchList, TimeoutList};
+use super::stdintf::org_freedesktop_dbus::ConnectionCredentials;
 use std::{fmt, mem, ptr, thread, panic, ops};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::VecDeque;
 use std::cell::{Cell, RefCell};
 use std::os::unix::io::RawFd;
@@ -185,10 +191,69 @@ struct IConnection {
     conn: Cell<*mut ffi::DBusConnection>,
     pending_items: RefCell<VecDeque<Message>>,
     watches: Option<Box<WatchList>>,
-    handlers: RefCell<MsgHandlerList>,
+    timeouts: Option<Box<TimeoutList>>,
+    // Handlers added with add_handler_id have a nonzero id.
+    handlers: RefCell<Vec<(usize, Box<MsgHandler>)>>,
 
     filter_cb: RefCell<Option<MessageCallback>>,
     filter_cb_panic: RefCell<thread::Result<()>>,
+    conn_ref: RefCell<Option<ConnRef>>,
+    creds: RefCell<Option<CredentialsCache>>,
+}
+
+struct RawConn(*mut ffi::DBusConnection);
+
+// libdbus connections are thread safe, see dbus_threads_init_default.
+unsafe impl Send for RawConn {}
+unsafe impl Sync for RawConn {}
+
+/// A handle to a Connection that can be used from other threads.
+///
+/// It does not keep the connection open: once the Connection is dropped, using the handle fails.
+#[derive(Clone)]
+pub(crate) struct ConnRef(Arc<RwLock<RawConn>>);
+
+impl ConnRef {
+    /// Calls f with the libdbus connection, unless the Connection has been dropped.
+    ///
+    /// The lock is not held while f runs (which might block), so that dropping the Connection
+    /// does not have to wait for it; a libdbus reference keeps the pointer valid meanwhile,
+    /// and calls on it fail once the connection is closed.
+    pub(crate) fn with<R, F: FnOnce(*mut ffi::DBusConnection) -> Result<R, Error>>(&self, f: F) -> Result<R, Error> {
+        let c = {
+            let c = self.0.read().unwrap();
+            if c.0.is_null() { return Err(Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "The connection has been dropped")) }
+            unsafe { ffi::dbus_connection_ref(c.0) }
+        };
+        let r = f(c);
+        unsafe { ffi::dbus_connection_unref(c) };
+        r
+    }
+
+    /// Like Connection::send.
+    pub(crate) fn send(&self, msg: Message) -> Result<u32, Error> {
+        self.with(|c| {
+            let mut serial = 0u32;
+            if unsafe { ffi::dbus_connection_send(c, msg.ptr(), &mut serial) } == 0 {
+                return Err(Error::new_custom("org.freedesktop.DBus.Error.NoMemory", "Sending message failed"))
+            }
+            unsafe { ffi::dbus_connection_flush(c) };
+            Ok(serial)
+        })
+    }
+
+    /// Like Connection::send_with_reply_and_block.
+    pub(crate) fn send_with_reply_and_block(&self, msg: Message, timeout_ms: i32) -> Result<Message, Error> {
+        self.with(|c| {
+            let mut e = Error::empty();
+            let r = unsafe { ffi::dbus_connection_send_with_reply_and_block(c, msg.ptr(), timeout_ms as c_int, e.get_mut()) };
+            if r.is_null() { Err(e) } else { Ok(Message::from_ptr(r, false)) }
+        })
+    }
+}
+
+impl fmt::Debug for ConnRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "ConnRef") }
 }
 
 /// A D-Bus connection. Start here if you want to get on the D-Bus!
@@ -216,8 +281,10 @@ extern "C" fn filter_message_cb(conn: *mut ffi::DBusConnection, msg: *mut ffi::D
     }
 
     let fcb = panic::AssertUnwindSafe(&i.filter_cb);
+    let creds = panic::AssertUnwindSafe(&i.creds);
     let r = panic::catch_unwind(|| {
         let m = Message::from_ptr(msg, true);
+        if let Some(c) = creds.borrow().as_ref() { c.handle_message(&m) }
         let mut cb = fcb.borrow_mut().take().unwrap(); // Take the callback out while we call it.
         let r = cb(connref.0, m);
         let mut cb2 = fcb.borrow_mut(); // If the filter callback has not been replaced, put it back in.
@@ -259,9 +326,12 @@ impl Connection {
             conn: Cell::new(conn),
             pending_items: RefCell::new(VecDeque::new()),
             watches: None,
+            timeouts: None,
             handlers: RefCell::new(vec!()),
             filter_cb: RefCell::new(Some(Box::new(default_filter_callback))),
             filter_cb_panic: RefCell::new(Ok(())),
+            conn_ref: RefCell::new(None),
+            creds: RefCell::new(None),
         })};
 
         /* No, we don't want our app to suddenly quit if dbus goes down */
@@ -271,6 +341,7 @@ impl Connection {
         } != 0);
 
         c.i.watches = Some(WatchList::new(&c, Box::new(|_| {})));
+        c.i.timeouts = Some(TimeoutList::new(&c, Box::new(|_| {})));
         Ok(c)
     }
 
@@ -365,8 +436,8 @@ impl Connection {
     /// while !done.get() { c.incoming(100).next(); }
     /// ```
     pub fn add_handler<H: MsgHandler + 'static>(&self, h: H) {
-        let h = Box::new(h);
-        self.i.handlers.borrow_mut().push(h);
+        let h: Box<MsgHandler> = Box::new(h);
+        self.i.handlers.borrow_mut().push((0, h));
     }
 
     /// Removes a MsgHandler from the connection.
@@ -377,7 +448,106 @@ impl Connection {
     /// with the list of MsgHandler currently on the connection. If this would help you,
     /// please [file an issue](https://github.com/diwic/dbus-rs/issues). 
     pub fn extract_handler(&self) -> Option<Box<MsgHandler>> {
-        self.i.handlers.borrow_mut().pop()
+        self.i.handlers.borrow_mut().pop().map(|(_, h)| h)
+    }
+
+    /// Listens to a signal, calling "f" with its arguments, sender and path whenever it arrives.
+    ///
+    /// The match rule is added to the bus, and a MsgHandler is added to the connection;
+    /// both are removed when the returned Subscription is dropped. Signals are dispatched
+    /// while reading incoming messages, e g through `incoming`, and are still returned
+    /// from there after the callback has been called.
+    ///
+    /// If the rule has a well-known sender name, its owner is looked up when subscribing (which blocks)
+    /// and then followed through NameOwnerChanged, so that the callback only gets the signals
+    /// sent by the current owner of the name.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use dbus::{Connection, BusType, SignalArgs};
+    /// use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PC;
+    ///
+    /// let c = Connection::get_private(BusType::Session).unwrap();
+    /// let _s = c.subscribe(PC::match_rule(None, None), |pc: PC, sender, path| {
+    ///     println!("{} on {} changed {:?}", sender, path, pc.changed_properties.keys());
+    /// }).unwrap();
+    /// for _ in c.incoming(1000) {}
+    /// ```
+    pub fn subscribe<S, F>(&self, rule: MatchRule, f: F) -> Result<Subscription<&Connection>, Error>
+    where S: SignalArgs + 'static, F: FnMut(S, &BusName, &Path) + 'static {
+        Subscription::new(self, rule, f)
+    }
+
+    /// Requests a name, and keeps track of whether we own it.
+    ///
+    /// The returned NameOwner tells whether we are the primary owner, are waiting in the queue,
+    /// or have lost the name, and can call a callback when this changes. Changes are picked up
+    /// while reading incoming messages. The name is released when the NameOwner is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use dbus::{Connection, BusType, NameFlag, NameState};
+    ///
+    /// let c = Connection::get_private(BusType::Session).unwrap();
+    /// let owner = c.request_name_tracked("com.example.dbustest", NameFlag::AllowReplacement.value()).unwrap();
+    /// owner.on_change(|s| if s != NameState::Primary { println!("Somebody else took our name") });
+    /// for _ in c.incoming(1000) {}
+    /// ```
+    pub fn request_name_tracked(&self, name: &str, flags: u32) -> Result<NameOwner<&Connection>, Error> {
+        NameOwner::new(self, name, flags)
+    }
+
+    /// Watches the owner of a name, to know when a remote service appears or disappears.
+    ///
+    /// The returned NameWatcher knows the current owner, and reports changes to it as incoming
+    /// messages are read. This is similar to GDBus's `g_bus_watch_name`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use dbus::{Connection, BusType, NameWatchEvent};
+    ///
+    /// let c = Connection::get_private(BusType::System).unwrap();
+    /// let mut w = c.watch_name("org.freedesktop.NetworkManager", 2000).unwrap();
+    /// println!("NetworkManager is running as {:?}", w.owner());
+    /// for e in w.events(1000) {
+    ///     match e {
+    ///         NameWatchEvent::Vanished(_) => println!("NetworkManager went away"),
+    ///         e => println!("NetworkManager changed: {:?}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn watch_name<N: Into<BusName<'static>>>(&self, name: N, timeout_ms: i32) -> Result<NameWatcher, Error> {
+        NameWatcher::new(self, name, timeout_ms)
+    }
+
+    /// Gets the credentials (uid, pid etc) of a bus name from the bus.
+    ///
+    /// The credentials of unique names are cached until the name disconnects from the bus,
+    /// see `credentials_cache`.
+    ///
+    /// Blocking: while waiting for the reply from the bus, if not cached.
+    pub fn connection_credentials(&self, name: &str) -> Result<ConnectionCredentials, Error> { self.credentials_cache().get(name) }
+
+    /// Gets the credentials (uid, pid etc) of the sender of a message, e g to authorize a caller.
+    ///
+    /// Blocking: while waiting for the reply from the bus, if not cached.
+    pub fn caller_credentials(&self, msg: &Message) -> Result<ConnectionCredentials, Error> { self.credentials_cache().get_sender(msg) }
+
+    /// The cache used by `connection_credentials` and `caller_credentials`.
+    ///
+    /// The cache can be moved to other threads, and keeps working as long as the
+    /// connection is open and incoming messages are read from it.
+    pub fn credentials_cache(&self) -> CredentialsCache {
+        let c = self.conn_ref();
+        self.i.creds.borrow_mut().get_or_insert_with(|| CredentialsCache::new(c)).clone()
+    }
+
+    pub(crate) fn conn_ref(&self) -> ConnRef {
+        let c = self.conn();
+        self.i.conn_ref.borrow_mut().get_or_insert_with(|| ConnRef(Arc::new(RwLock::new(RawConn(c))))).clone()
     }
 
     /// Get the connection's unique name.
@@ -500,6 +670,18 @@ impl Connection {
         ConnectionItems::new(self, None, true)
     }
 
+    /// Async I/O: Get an up-to-date list of enabled timeouts.
+    pub fn timeouts(&self) -> Vec<Timeout> {
+        self.i.timeouts.as_ref().unwrap().get_enabled_timeouts()
+    }
+
+    /// Async I/O: Call this function whenever the interval of an enabled timeout has elapsed.
+    /// The returned iterator will return pending items only, never block for new events.
+    pub fn timeout_handle(&self, t: Timeout) -> ConnectionItems {
+        self.i.timeouts.as_ref().unwrap().timeout_handle(t.id());
+        ConnectionItems::new(self, None, true)
+    }
+
 
     /// Create a convenience struct for easier calling of many methods on the same destination and path.
     pub fn with_path<'a, D: Into<BusName<'a>>, P: Into<Path<'a>>>(&'a self, dest: D, path: P, timeout_ms: i32) ->
@@ -593,6 +775,34 @@ impl Connection {
     /// see https://github.com/diwic/dbus-rs/issues/99 for additional info.)
     pub fn set_watch_callback(&self, f: Box<Fn(Watch) + Send>) { self.i.watches.as_ref().unwrap().set_on_update(f); }
 
+    /// Sets a callback to be called when a timeout is added, removed or toggled.
+    ///
+    /// For async I/O. The callback has the same restrictions as the one given to `set_watch_callback`.
+    /// Timeouts that already exist are not reported; get them from `timeouts`.
+    pub fn set_timeout_callback(&self, f: Box<Fn(Timeout) + Send>) { self.i.timeouts.as_ref().unwrap().set_on_update(f); }
+
+    // Adds a MsgHandler, and returns an id that can be given to `remove_handler`.
+    //
+    // The ids are unique within the process, so that they stay unique when handlers are moved to another connection.
+    fn add_handler_id<H: MsgHandler + 'static>(&self, h: H) -> usize {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.i.handlers.borrow_mut().push((id, Box::new(h) as Box<MsgHandler>));
+        id
+    }
+
+    // Removes a MsgHandler added by `add_handler_id`. Handlers that are being processed
+    // (or were moved to another connection) are not found.
+    fn remove_handler(&self, id: usize) {
+        self.i.handlers.borrow_mut().retain(|&(i, _)| i != id);
+    }
+
+    // Moves all MsgHandlers over to another connection.
+    pub(crate) fn move_handlers(&self, to: &Connection) {
+        let mut v = mem::replace(&mut *self.i.handlers.borrow_mut(), vec!());
+        to.i.handlers.borrow_mut().append(&mut v);
+    }
+
     fn check_panic(&self) {
         let p = mem::replace(&mut *self.i.filter_cb_panic.borrow_mut(), Ok(()));
         if let Err(perr) = p { panic::resume_unwind(perr); }
@@ -600,7 +810,7 @@ impl Connection {
 
     fn next_msg(&self) -> Option<Message> {
         while let Some(msg) = self.i.pending_items.borrow_mut().pop_front() {
-            let mut v: MsgHandlerList = mem::replace(&mut *self.i.handlers.borrow_mut(), vec!());
+            let mut v = mem::replace(&mut *self.i.handlers.borrow_mut(), vec!());
             let b = msghandler_process(&mut v, &msg, self);
             let mut v2 = self.i.handlers.borrow_mut();
             v.append(&mut *v2);
@@ -614,6 +824,7 @@ impl Connection {
 
 impl Drop for Connection {
     fn drop(&mut self) {
+        if let Some(c) = self.i.conn_ref.borrow().as_ref() { c.0.write().unwrap().0 = ptr::null_mut() }
         unsafe {
             ffi::dbus_connection_close(self.conn());
             ffi::dbus_connection_unref(self.conn());
@@ -678,15 +889,26 @@ pub struct MsgHandlerResult {
 
 type MsgHandlerList = Vec<Box<MsgHandler>>;
 
-fn msghandler_process(v: &mut MsgHandlerList, m: &Message, c: &Connection) -> bool {
+// An entry in a list of MsgHandlers.
+trait HandlerEntry { fn handler(&mut self) -> &mut (MsgHandler + 'static); }
+
+impl HandlerEntry for Box<MsgHandler> {
+    fn handler(&mut self) -> &mut (MsgHandler + 'static) { &mut **self }
+}
+
+impl HandlerEntry for (usize, Box<MsgHandler>) {
+    fn handler(&mut self) -> &mut (MsgHandler + 'static) { &mut *self.1 }
+}
+
+fn msghandler_process<T: HandlerEntry>(v: &mut Vec<T>, m: &Message, c: &Connection) -> bool {
     let mut ii: isize = -1;
     loop {
         ii += 1; 
         let i = ii as usize;
         if i >= v.len() { return false };
 
-        if !v[i].handler_type().matches_msg(m) { continue; }
-        if let Some(r) = v[i].handle_msg(m) {
+        if !v[i].handler().handler_type().matches_msg(m) { continue; }
+        if let Some(r) = v[i].handler().handle_msg(m) {
             for msg in r.reply.into_iter() { c.send(msg).unwrap(); }
             if r.done { v.remove(i); ii -= 1; }
             if r.handled { return true; }
@@ -713,11 +935,184 @@ impl<'a, F: FnOnce(Result<&Message, Error>) + 'a> MsgHandler for MessageReply<F>
     }
 }
 
+/// A signal subscription, returned from `Connection::subscribe`.
+///
+/// The match rule and the MsgHandler are removed when the subscription is dropped.
+/// If the subscription is dropped from inside a MsgHandler other than its own,
+/// its MsgHandler is instead removed the next time the connection processes a signal.
+pub struct Subscription<C: ops::Deref<Target = Connection>> {
+    conn: C,
+    attached: Attached,
+}
+
+impl<C: ops::Deref<Target = Connection>> Subscription<C> {
+    /// Creates a subscription on a connection, or some reference to it.
+    ///
+    /// See `Connection::subscribe` for details.
+    pub fn new<S, F>(conn: C, rule: MatchRule, f: F) -> Result<Self, Error>
+    where S: SignalArgs + 'static, F: FnMut(S, &BusName, &Path) + 'static {
+        let a = try!(Attached::new(&conn, rule.into_static(), f));
+        Ok(Subscription { conn: conn, attached: a })
+    }
+
+    /// The match rule of this subscription.
+    pub fn match_rule(&self) -> &str { &self.attached.rule }
+}
+
+impl<C: ops::Deref<Target = Connection>> Drop for Subscription<C> {
+    fn drop(&mut self) { self.attached.detach(&self.conn) }
+}
+
+impl<C: ops::Deref<Target = Connection>> fmt::Debug for Subscription<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Subscription({})", self.attached.rule)
+    }
+}
+
+/// The match rules and the MsgHandler of a subscription, as added to a connection.
+#[derive(Debug)]
+pub(crate) struct Attached {
+    rule: String,
+    name_rule: Option<String>,
+    handler: usize,
+    active: Rc<Cell<bool>>,
+}
+
+impl Attached {
+    pub(crate) fn new<S, F>(conn: &Connection, rule: MatchRule<'static>, f: F) -> Result<Self, Error>
+    where S: SignalArgs + 'static, F: FnMut(S, &BusName, &Path) + 'static {
+        let text = rule.to_string();
+        // A well-known sender name is resolved to its unique owner, so that signals
+        // from other connections, delivered through other match rules, are not mixed in.
+        let owner = match rule.sender() {
+            Some(s) if !s.starts_with(':') => Some(NameWatchCache::new(s.clone())),
+            _ => None,
+        };
+        let name_rule = owner.as_ref().map(|w| w.match_rule());
+        if let Some(ref r) = name_rule { try!(conn.add_match(r)); }
+        let owner = match owner {
+            Some(mut w) => {
+                let r = conn.send_with_reply_and_block(w.get_name_owner(), -1);
+                if let Err(e) = w.handle_reply(r).and_then(|_| conn.add_match(&text)) {
+                    let _ = conn.remove_match(name_rule.as_ref().unwrap());
+                    return Err(e);
+                }
+                Some(w)
+            },
+            None => { try!(conn.add_match(&text)); None },
+        };
+        let active = Rc::new(Cell::new(true));
+        let handler = conn.add_handler_id(SignalHandler { active: active.clone(), rule: rule, owner: owner, f: f, _s: PhantomData::<fn(S)> });
+        Ok(Attached { rule: text, name_rule: name_rule, handler: handler, active: active })
+    }
+
+    /// Removes the MsgHandler and the match rules from the connection.
+    pub(crate) fn detach(&self, conn: &Connection) {
+        self.active.set(false);
+        conn.remove_handler(self.handler);
+        let _ = conn.remove_match(&self.rule);
+        if let Some(ref r) = self.name_rule { let _ = conn.remove_match(r); }
+    }
+}
+
+struct SignalHandler<S, F> {
+    active: Rc<Cell<bool>>,
+    rule: MatchRule<'static>,
+    owner: Option<NameWatchCache>,
+    f: F,
+    _s: PhantomData<fn(S)>,
+}
+
+impl<S: SignalArgs, F: FnMut(S, &BusName, &Path)> MsgHandler for SignalHandler<S, F> {
+    fn handler_type(&self) -> MsgHandlerType { MsgHandlerType::MsgType(MessageType::Signal) }
+    fn handle_msg(&mut self, msg: &Message) -> Option<MsgHandlerResult> {
+        if !self.active.get() { return Some(MsgHandlerResult { handled: false, done: true, reply: Vec::new() }) }
+        let matches = match self.owner {
+            Some(ref mut w) => { w.handle_message(msg); self.rule.matches_owner(msg, w) },
+            None => self.rule.matches(msg),
+        };
+        if !matches { return None }
+        if let (Some(s), Some(sender), Some(path)) = (S::from_message(msg), msg.sender(), msg.path()) {
+            (self.f)(s, &sender, &path);
+        }
+        // The subscription might have been dropped by the callback.
+        if !self.active.get() { return Some(MsgHandlerResult { handled: false, done: true, reply: Vec::new() }) }
+        None
+    }
+}
+
+#[test]
+fn subscribe() {
+    use std::cell::RefCell;
+    use stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved as IR;
+    use testing::TestBus;
+
+    let bus = TestBus::new().unwrap();
+    let (c, c2) = (bus.connection().unwrap(), bus.connection().unwrap());
+    c2.register_name("com.example.subscribe", 0).unwrap();
+    let got = Rc::new(RefCell::new(vec!()));
+    let got2 = got.clone();
+    let sub = c.subscribe(IR::match_rule(Some(&"com.example.subscribe".into()), None), move |ir: IR, sender, path| {
+        got2.borrow_mut().push((ir.interfaces, sender.to_string(), path.to_string()));
+    }).unwrap();
+    assert_eq!(sub.match_rule(), "type='signal',sender='com.example.subscribe',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'");
+
+    let ir = IR { object: "/a".into(), interfaces: vec!("com.example.A".into()) };
+    c2.send(ir.to_emit_message(&"/hello".into())).unwrap();
+    // Not the signal we subscribed to
+    c2.send(Message::new_signal("/hello", "com.example", "Other").unwrap()).unwrap();
+    let mut n = 0;
+    for m in c.incoming(1000) { if &*m.path().unwrap() == "/hello" { n += 1; if n == 2 { break } } }
+    assert_eq!(*got.borrow(), vec!((vec!("com.example.A".to_string()), c2.unique_name(), "/hello".to_string())));
+
+    // The same signal from another connection, delivered through another match rule
+    let c3 = bus.connection().unwrap();
+    c.add_match("type='signal',path='/hello'").unwrap();
+    c3.send(ir.to_emit_message(&"/hello".into())).unwrap();
+    for m in c.incoming(1000) { if &*m.path().unwrap() == "/hello" { break } }
+    assert_eq!(got.borrow().len(), 1);
+
+    // ...until that connection takes over the name.
+    c2.release_name("com.example.subscribe").unwrap();
+    c3.register_name("com.example.subscribe", 0).unwrap();
+    c3.send(ir.to_emit_message(&"/hello".into())).unwrap();
+    for m in c.incoming(1000) { if m.path().map(|p| &*p == "/hello") == Some(true) { break } }
+    assert_eq!(got.borrow().len(), 2);
+    assert_eq!(got.borrow()[1].1, c3.unique_name());
+
+    drop(sub);
+    assert!(c.i.handlers.borrow().is_empty());
+    assert!(c.remove_match("type='signal',sender='com.example.subscribe',interface='org.freedesktop.DBus.ObjectManager',member='InterfacesRemoved'").is_err());
+    c3.send(ir.to_emit_message(&"/hello".into())).unwrap();
+    for m in c.incoming(1000) { if m.path().map(|p| &*p == "/hello") == Some(true) { break } }
+    assert_eq!(got.borrow().len(), 2);
+}
+
+#[test]
+fn handler_ids() {
+    use testing::TestBus;
+
+    // Zero sized, so boxes of it do not have distinct addresses.
+    struct Nop;
+    impl MsgHandler for Nop {
+        fn handler_type(&self) -> MsgHandlerType { MsgHandlerType::All }
+        fn handle_msg(&mut self, _: &Message) -> Option<MsgHandlerResult> { None }
+    }
+
+    let bus = TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
+    let (a, b) = (c.add_handler_id(Nop), c.add_handler_id(Nop));
+    assert!(a != b);
+    c.remove_handler(a);
+    assert_eq!(c.i.handlers.borrow().iter().map(|&(i, _)| i).collect::<Vec<_>>(), vec!(b));
+}
 
 #[test]
 fn message_reply() {
     use std::{cell, rc};
-    let c = Connection::get_private(BusType::Session).unwrap();
+    use testing::TestBus;
+    let bus = TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
     assert!(c.is_connected());
     let m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "ListNames").unwrap();
     let quit = rc::Rc::new(cell::Cell::new(false));
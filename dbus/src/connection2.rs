@@ -1,5 +1,6 @@
-use crate::{BusType, Error, Message, to_c_str, Watch};
-use std::{ptr, str};
+use crate::{BusType, Error, Message, to_c_str, Watch, Timeout};
+use crate::watch::TimeoutList;
+use std::{fmt, ptr, str};
 use std::ffi::CStr;
 use std::os::raw::{c_void};
 
@@ -30,9 +31,10 @@ impl Drop for ConnHandle {
 /// This version avoids dbus_connection_dispatch, and thus avoids
 /// callbacks from that function. Instead the same functionality needs to be
 /// implemented by these bindings somehow - this is not done yet.
-#[derive(Debug)]
 pub struct TxRx {
+    // Libdbus calls back into the timeout list until the callbacks are unset in Drop.
     handle: ConnHandle,
+    timeouts: Box<TimeoutList>,
 }
 
 impl TxRx {
@@ -47,7 +49,8 @@ impl TxRx {
         /* No, we don't want our app to suddenly quit if dbus goes down */
         unsafe { ffi::dbus_connection_set_exit_on_disconnect(ptr, 0) };
 
-        let c = TxRx { handle };
+        let timeouts = TimeoutList::from_conn(ptr, Box::new(|_| {}));
+        let c = TxRx { handle, timeouts };
 
         Ok(c)
     }
@@ -125,6 +128,11 @@ impl TxRx {
     /// Blocking: until the outgoing queue is empty.
     pub fn flush(&self) { unsafe { ffi::dbus_connection_flush(self.conn()) } }
 
+    /// Returns true if there are messages in the outgoing queue that have not been written yet.
+    pub fn has_messages_to_send(&self) -> bool {
+        unsafe { ffi::dbus_connection_has_messages_to_send(self.conn()) != 0 }
+    }
+
     /// Read and write to the connection.
     ///
     /// Incoming messages are put in the internal queue, outgoing messages are written.
@@ -154,6 +162,20 @@ impl TxRx {
         }
     }
 
+    /// Get an up-to-date list of enabled timeouts.
+    pub fn timeouts(&self) -> Vec<Timeout> { self.timeouts.get_enabled_timeouts() }
+
+    /// Call this function whenever the interval of an enabled timeout has elapsed.
+    ///
+    /// Use "read_write" and "pop_message" afterwards, like after an event on a file descriptor.
+    pub fn timeout_handle(&self, t: Timeout) { self.timeouts.timeout_handle(t.id()) }
+
+    /// Sets a callback to be called when a timeout is added, removed or toggled.
+    ///
+    /// The callback might be called from any thread using the connection, and must not call back into it.
+    /// Timeouts that already exist are not reported; get them from `timeouts`.
+    pub fn set_timeout_callback(&self, f: Box<Fn(Timeout) + Send>) { self.timeouts.set_on_update(f) }
+
     /// Get an up-to-date list of file descriptors to watch.
     ///
     /// Might be changed into something that allows for callbacks when the watch list is changed.
@@ -174,18 +196,41 @@ impl TxRx {
     }
 }
 
+impl Drop for TxRx {
+    fn drop(&mut self) {
+        // Libdbus keeps the connection (and its timeouts) alive if someone else holds a reference.
+        assert!(unsafe { ffi::dbus_connection_set_timeout_functions(self.conn(),
+            None, None, None, ptr::null_mut(), None) } != 0);
+    }
+}
+
+impl fmt::Debug for TxRx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TxRx({:?})", self.handle)
+    }
+}
+
 #[test]
 fn test_txrx_send_sync() {
     fn is_send<T: Send>(_: &T) {}
     fn is_sync<T: Sync>(_: &T) {}
-    let c = TxRx::get_private(BusType::Session).unwrap();
+    let bus = crate::testing::TestBus::new().unwrap();
+    let c = TxRx::open_private(bus.address()).unwrap();
     is_send(&c);
     is_sync(&c);
 }
 
 #[test]
 fn txrx_simple_test() {
-    let mut c = TxRx::get_private(BusType::Session).unwrap();
+    struct NoReplies;
+    impl crate::dispatcher::MessageDispatcherConfig for NoReplies {
+        type Reply = ();
+        fn call_reply(_: (), _: Message) {}
+    }
+
+    let bus = crate::testing::TestBus::new().unwrap();
+    let mut c = TxRx::open_private(bus.address()).unwrap();
+    c.register().unwrap();
     assert!(c.is_connected());
     let fds = c.watch_fds().unwrap();
     println!("{:?}", fds);
@@ -204,7 +249,7 @@ fn txrx_simple_test() {
                     if n == my_name { return; } // Hooray, we found ourselves!
                 }
                 assert!(false);
-            } else if let Some(r) = crate::MessageDispatcher::default_dispatch(&msg) {
+            } else if let Some(r) = crate::MessageDispatcher::<NoReplies>::default_dispatch(&msg) {
                 c.send(r).unwrap();
             }
         }
@@ -0,0 +1,167 @@
+//! Looking up the credentials of other connections on the bus, e g to authorize callers.
+
+use {Message, Error, SignalArgs, to_c_str};
+use connection::ConnRef;
+use stdintf::org_freedesktop_dbus::{ConnectionCredentials, DBusNameOwnerChanged};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::ptr;
+
+struct CacheInner {
+    conn: ConnRef,
+    entries: Mutex<HashMap<String, ConnectionCredentials>>,
+    watching: AtomicBool,
+}
+
+/// A per connection cache of credentials, keyed by unique name.
+///
+/// Entries are removed when the bus tells (through NameOwnerChanged) that the unique name has
+/// disconnected. Get it from `Connection::credentials_cache`; clones share the same cache.
+///
+/// The cache does not keep the connection alive; lookups fail after the connection is dropped.
+#[derive(Clone)]
+pub struct CredentialsCache(Arc<CacheInner>);
+
+const BUS_NAME: &'static str = "org.freedesktop.DBus";
+
+// Method calls are not handled while waiting, so don't wait for long.
+const TIMEOUT_MS: i32 = 5000;
+
+impl CredentialsCache {
+    pub(crate) fn new(conn: ConnRef) -> CredentialsCache {
+        CredentialsCache(Arc::new(CacheInner { conn: conn, entries: Mutex::new(HashMap::new()), watching: AtomicBool::new(false) }))
+    }
+
+    fn match_rule() -> String {
+        DBusNameOwnerChanged::match_rule(Some(&BUS_NAME.into()), None).with_arg(2, "").to_string()
+    }
+
+    /// Returns the credentials of a bus name, asking the bus if they are not cached already.
+    ///
+    /// Only unique names are cached, because the owner of a well-known name might change.
+    ///
+    /// Blocking: while waiting for the reply to GetConnectionCredentials, if not cached,
+    /// for up to five seconds.
+    pub fn get(&self, name: &str) -> Result<ConnectionCredentials, Error> {
+        if !name.starts_with(':') { return self.fetch(name) }
+        if let Some(c) = self.0.entries.lock().unwrap().get(name) { return Ok(c.clone()) }
+        if !self.0.watching.swap(true, Ordering::SeqCst) {
+            try!(self.0.conn.with(|c| {
+                // Without an error pointer, this does not wait for the reply.
+                unsafe { ffi::dbus_bus_add_match(c, to_c_str(&Self::match_rule()).as_ptr(), ptr::null_mut()) };
+                Ok(())
+            }));
+        }
+        let c = try!(self.fetch(name));
+        self.0.entries.lock().unwrap().insert(name.into(), c.clone());
+        Ok(c)
+    }
+
+    fn fetch(&self, name: &str) -> Result<ConnectionCredentials, Error> {
+        let m = Message::method_call(&BUS_NAME.into(), &"/org/freedesktop/DBus".into(), &BUS_NAME.into(),
+            &"GetConnectionCredentials".into()).append1(name);
+        let mut r = try!(self.send_with_reply_and_block(m, TIMEOUT_MS));
+        let d = try!(try!(r.as_result()).read1());
+        Ok(ConnectionCredentials::from_dict(&d))
+    }
+
+    // Like Connection::send_with_reply_and_block, for other lookups related to the caller (e g PolicyKit).
+    pub(crate) fn send_with_reply_and_block(&self, m: Message, timeout_ms: i32) -> Result<Message, Error> {
+        self.0.conn.send_with_reply_and_block(m, timeout_ms)
+    }
+
+    /// Returns the credentials of the sender of a message.
+    ///
+    /// Blocking: while waiting for the reply to GetConnectionCredentials, if not cached,
+    /// for up to five seconds.
+    pub fn get_sender(&self, m: &Message) -> Result<ConnectionCredentials, Error> {
+        match m.sender() {
+            Some(s) => self.get(&s),
+            None => Err(Error::new_custom("org.freedesktop.DBus.Error.Failed", "The message has no sender")),
+        }
+    }
+
+    /// Removes the cached credentials if the message tells that a unique name has disconnected.
+    ///
+    /// This is called for every incoming message on the connection the cache belongs to.
+    pub fn handle_message(&self, m: &Message) {
+        if !self.0.watching.load(Ordering::SeqCst) || m.sender().map(|s| &*s == BUS_NAME) != Some(true) { return }
+        if let Some(s) = DBusNameOwnerChanged::from_message(m) {
+            if s.new_owner.is_empty() { self.0.entries.lock().unwrap().remove(&s.name); }
+        }
+    }
+
+    /// Number of unique names whose credentials are currently cached.
+    pub fn len(&self) -> usize { self.0.entries.lock().unwrap().len() }
+
+    /// Whether no credentials are currently cached.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl ::std::fmt::Debug for CredentialsCache {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "CredentialsCache({} entries)", self.len())
+    }
+}
+
+#[test]
+fn test_credentials() {
+    use testing::TestBus;
+    use tree::Factory;
+    use {libc, std, MessageType};
+    use std::sync::mpsc;
+
+    let bus = TestBus::new().unwrap();
+    let c2 = bus.connection().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let server = bus.serve("com.example.creds", move || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/creds", ()).add(f.interface("com.example.creds", ())
+            .add_m(f.method("Check", (), move |m| {
+                let cred = try!(m.caller_credentials());
+                tx.send((cred.unix_user_id, cred.process_id)).unwrap();
+                Ok(vec!(m.msg.method_return()))
+            }))))
+    }).unwrap();
+
+    let call = Message::new_method_call("com.example.creds", "/creds", "com.example.creds", "Check").unwrap();
+    let reply = c2.send_with_reply_and_block(call, 2000).unwrap();
+    assert_eq!(reply.msg_type(), MessageType::MethodReturn);
+    assert_eq!(rx.recv().unwrap(), (Some(unsafe { libc::getuid() }), Some(std::process::id())));
+    assert_eq!(server.run(|c, _| c.credentials_cache().len()), 1);
+
+    // The entry goes away when the caller disconnects.
+    drop(c2);
+    assert!(server.run(|c, _| { for _ in c.incoming(500) {} c.credentials_cache().is_empty() }));
+
+    let uid = server.run(|c, _| {
+        assert!(c.connection_credentials("com.example.wellknown").is_err());
+        c.connection_credentials(&c.unique_name()).unwrap().unix_user_id
+    });
+    assert_eq!(uid, Some(unsafe { libc::getuid() }));
+
+    // The cache does not keep the connection alive.
+    let cache = server.run(|c, _| c.credentials_cache());
+    drop(server);
+    assert_eq!(cache.get("com.example.wellknown").unwrap_err().name(), Some("org.freedesktop.DBus.Error.Disconnected"));
+}
+
+#[test]
+fn drop_while_blocked() {
+    use testing::TestBus;
+    use std::{thread, time};
+
+    let bus = TestBus::new().unwrap();
+    let (c, c2) = (bus.connection().unwrap(), bus.connection().unwrap());
+    let cache = c.credentials_cache();
+    // c2 never reads its messages, so this call is not answered.
+    let m = Message::new_method_call(c2.unique_name(), "/", "com.example.nothing", "Wait").unwrap();
+    let t = thread::spawn(move || cache.send_with_reply_and_block(m, 3000));
+    thread::sleep(time::Duration::from_millis(200));
+    let now = time::Instant::now();
+    drop(c);
+    assert!(now.elapsed() < time::Duration::from_millis(1000));
+    assert!(t.join().unwrap().is_err());
+}
@@ -5,7 +5,9 @@ use std::collections::HashMap;
 
 /// [Unstable and Experimental]
 pub trait MessageDispatcherConfig {
+    /// The type of method reply stored inside the dispatcher
     type Reply;
+    /// Called when a method reply is dispatched.
     fn call_reply(_: Self::Reply, _: Message);
 }
 
@@ -14,8 +16,17 @@ pub struct MessageDispatcher<C: MessageDispatcherConfig> {
     waiting_replies: HashMap<u32, C::Reply>
 }
 
+impl<C: MessageDispatcherConfig> std::fmt::Debug for MessageDispatcher<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MessageDispatcher {{ waiting_replies: {} }}", self.waiting_replies.len())
+    }
+}
+
 impl<C: MessageDispatcherConfig> MessageDispatcher<C> {
 
+    /// Creates a new message dispatcher, without any waiting replies.
+    pub fn new() -> Self { MessageDispatcher { waiting_replies: HashMap::new() } }
+
     /// Adds a waiting reply to a method call. func will be called when a method reply is dispatched.
     pub fn add_reply(&mut self, serial: u32, func: C::Reply) {
         if let Some(_) = self.waiting_replies.insert(serial, func) {
@@ -28,6 +39,19 @@ impl<C: MessageDispatcherConfig> MessageDispatcher<C> {
         self.waiting_replies.remove(&serial)
     }
 
+    /// Number of method calls currently waiting for a reply.
+    pub fn waiting_replies(&self) -> usize { self.waiting_replies.len() }
+
+    /// Calls the waiting reply if the message is a reply to a method call added with `add_reply`.
+    ///
+    /// Returns the message if there was nothing waiting for it.
+    pub fn dispatch_reply(&mut self, m: Message) -> Option<Message> {
+        match m.get_reply_serial().and_then(|serial| self.waiting_replies.remove(&serial)) {
+            Some(r) => { C::call_reply(r, m); None },
+            None => Some(m),
+        }
+    }
+
     /// Handles what we need to be a good D-Bus citizen.
     ///
     /// Call this if you have not handled the message yourself:
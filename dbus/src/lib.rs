@@ -9,59 +9,160 @@
 //! look in the examples directory, which contains many examples and an argument guide.
 //! README.md also contain a few quick "getting started" examples.
 //!
-//! In addition to this crate, there are two companion crates, dbus-codegen for generating Rust
-//! code from D-Bus introspection data, and dbus-tokio for integrating D-Bus with [Tokio](http://tokio.rs).
+//! In addition to this crate, there are companion crates: dbus-codegen for generating Rust
+//! code from D-Bus introspection data, dbus-tokio for integrating D-Bus with [Tokio](http://tokio.rs),
+//! and dbus-derive for deriving the argument and signal traits for your own structs.
 //! However, at the time of this writing, these are far less mature than this crate. 
 
 #![warn(missing_docs)]
 
 extern crate libc;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
 
+#[cfg(feature = "libdbus-sys")]
 pub use ffi::DBusBusType as BusType;
+#[cfg(feature = "libdbus-sys")]
 pub use connection::DBusNameFlag as NameFlag;
+#[cfg(feature = "libdbus-sys")]
 pub use ffi::DBusRequestNameReply as RequestNameReply;
+#[cfg(feature = "libdbus-sys")]
 pub use ffi::DBusReleaseNameReply as ReleaseNameReply;
+#[cfg(feature = "libdbus-sys")]
 pub use ffi::DBusMessageType as MessageType;
 
+#[cfg(feature = "libdbus-sys")]
 pub use message::{Message, MessageItem, MessageItemArray, FromMessageItem, OwnedFd, ArrayError, ConnPath};
-pub use connection::{Connection, ConnectionItems, ConnectionItem, ConnMsgs, MsgHandler, MsgHandlerResult, MsgHandlerType, MessageCallback};
+#[cfg(feature = "libdbus-sys")]
+pub use connection::{Connection, ConnectionItems, ConnectionItem, ConnMsgs, MsgHandler, MsgHandlerResult, MsgHandlerType, MessageCallback, Subscription};
+#[cfg(feature = "libdbus-sys")]
 pub use prop::PropHandler;
+#[cfg(feature = "libdbus-sys")]
 pub use prop::Props;
+#[cfg(feature = "libdbus-sys")]
+pub use prop::PropertyCache;
+#[cfg(feature = "libdbus-sys")]
+pub use watch::Timeout;
 pub use watch::{Watch, WatchEvent};
+#[cfg(feature = "libdbus-sys")]
 pub use signalargs::SignalArgs;
+#[cfg(feature = "libdbus-sys")]
+pub use matchrule::MatchRule;
+#[cfg(feature = "libdbus-sys")]
+pub use objectmanager::{ObjectManagerClient, ObjectManagerCache, ObjectManagerEvent, ObjectManagerEvents, ManagedObjects, PropMap};
+#[cfg(feature = "libdbus-sys")]
+pub use reconnect::{ReconnectingConnection, ReconnectEvent};
+#[cfg(feature = "libdbus-sys")]
+pub use nameowner::{NameOwner, NameState};
+#[cfg(feature = "libdbus-sys")]
+pub use namewatcher::{NameWatcher, NameWatchCache, NameWatchEvent, NameWatchEvents};
+#[cfg(feature = "libdbus-sys")]
+pub use credentials::CredentialsCache;
+#[cfg(feature = "libdbus-sys")]
+pub use stdintf::org_freedesktop_dbus::ConnectionCredentials;
 
 /// A TypeSig describes the type of a MessageItem.
 #[deprecated(note="Use Signature instead")]
 pub type TypeSig<'a> = std::borrow::Cow<'a, str>;
 
+#[cfg(feature = "libdbus-sys")]
 use std::ffi::{CString, CStr};
+#[cfg(feature = "libdbus-sys")]
 use std::ptr;
+#[cfg(feature = "libdbus-sys")]
 use std::os::raw::c_char;
 
+#[cfg(feature = "libdbus-sys")]
 #[allow(missing_docs)]
 extern crate libdbus_sys as ffi;
+#[cfg(feature = "libdbus-sys")]
 mod message;
+#[cfg(feature = "libdbus-sys")]
 mod prop;
 mod watch;
+#[cfg(feature = "libdbus-sys")]
 mod connection;
+#[cfg(feature = "libdbus-sys")]
 mod signalargs;
-
+#[cfg(feature = "libdbus-sys")]
+mod matchrule;
+#[cfg(feature = "libdbus-sys")]
+mod objectmanager;
+#[cfg(feature = "libdbus-sys")]
+mod reconnect;
+#[cfg(feature = "libdbus-sys")]
+mod nameowner;
+#[cfg(feature = "libdbus-sys")]
+mod namewatcher;
+#[cfg(feature = "libdbus-sys")]
+mod cacheclient;
+#[cfg(feature = "libdbus-sys")]
+mod credentials;
+
+#[cfg(feature = "libdbus-sys")]
 mod connection2;
+#[cfg(feature = "libdbus-sys")]
 mod dispatcher;
+#[cfg(feature = "libdbus-sys")]
 pub use connection2::TxRx;
-pub use dispatcher::MessageDispatcher;
+#[cfg(feature = "libdbus-sys")]
+pub use dispatcher::{MessageDispatcher, MessageDispatcherConfig};
 
 mod strings;
 pub use strings::{Signature, Path, Interface, Member, ErrorName, BusName};
 
+#[cfg(feature = "libdbus-sys")]
 pub mod arg;
 
+#[cfg(feature = "libdbus-sys")]
 pub mod stdintf;
 
+#[cfg(feature = "libdbus-sys")]
 pub mod tree;
 
+pub mod marshal;
+
+#[cfg(all(feature = "libdbus-sys", any(test, feature = "testing")))]
+pub mod testing;
+
+#[cfg(feature = "native")]
+pub mod native;
+
+/// The type of a well-known bus.
+#[cfg(not(feature = "libdbus-sys"))]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BusType {
+    /// The session bus of the logged in user
+    Session = 0,
+    /// The system wide bus
+    System = 1,
+    /// The bus that started us, if any
+    Starter = 2,
+}
+
+/// The type of a message.
+#[cfg(not(feature = "libdbus-sys"))]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum MessageType {
+    /// Not a valid message type
+    Invalid = 0,
+    /// A method call
+    MethodCall = 1,
+    /// A method return
+    MethodReturn = 2,
+    /// An error reply
+    Error = 3,
+    /// A signal
+    Signal = 4,
+}
+
+#[cfg(feature = "libdbus-sys")]
 static INITDBUS: std::sync::Once = std::sync::ONCE_INIT;
 
+#[cfg(feature = "libdbus-sys")]
 fn init_dbus() {
     INITDBUS.call_once(|| {
         if unsafe { ffi::dbus_threads_init_default() } == 0 {
@@ -71,25 +172,38 @@ fn init_dbus() {
 }
 
 /// D-Bus Error wrapper.
+#[cfg(feature = "libdbus-sys")]
 pub struct Error {
     e: ffi::DBusError,
 }
 
+/// D-Bus Error wrapper.
+#[cfg(not(feature = "libdbus-sys"))]
+pub struct Error {
+    name: String,
+    message: String,
+}
+
+#[cfg(feature = "libdbus-sys")]
 unsafe impl Send for Error {}
 
 // Note! For this Sync impl to be safe, it requires that no functions that take &self,
 // actually calls into FFI. All functions that call into FFI with a ffi::DBusError
 // must take &mut self.
 
+#[cfg(feature = "libdbus-sys")]
 unsafe impl Sync for Error {}
 
+#[cfg(feature = "libdbus-sys")]
 fn c_str_to_slice(c: & *const c_char) -> Option<&str> {
     if *c == ptr::null() { None }
     else { std::str::from_utf8( unsafe { CStr::from_ptr(*c).to_bytes() }).ok() }
 }
 
+#[cfg(feature = "libdbus-sys")]
 fn to_c_str(n: &str) -> CString { CString::new(n.as_bytes()).unwrap() }
 
+#[cfg(feature = "libdbus-sys")]
 impl Error {
 
     /// Create a new custom D-Bus Error.
@@ -127,6 +241,21 @@ impl Error {
     fn get_mut(&mut self) -> &mut ffi::DBusError { &mut self.e }
 }
 
+#[cfg(not(feature = "libdbus-sys"))]
+impl Error {
+    /// Create a new custom D-Bus Error.
+    pub fn new_custom(name: &str, message: &str) -> Error {
+        Error { name: name.into(), message: message.into() }
+    }
+
+    /// Error name/type, e g 'org.freedesktop.DBus.Error.Failed'
+    pub fn name(&self) -> Option<&str> { Some(&self.name) }
+
+    /// Custom message, e g 'Could not find a matching object path'
+    pub fn message(&self) -> Option<&str> { Some(&self.message) }
+}
+
+#[cfg(feature = "libdbus-sys")]
 impl Drop for Error {
     fn drop(&mut self) {
         unsafe { ffi::dbus_error_free(&mut self.e); }
@@ -152,26 +281,30 @@ impl std::fmt::Display for Error {
     }
 }
 
+#[cfg(feature = "libdbus-sys")]
 impl From<arg::TypeMismatchError> for Error {
     fn from(t: arg::TypeMismatchError) -> Error {
         Error::new_custom("org.freedesktop.DBus.Error.Failed", &format!("{}", t))
     }
 }
 
+#[cfg(feature = "libdbus-sys")]
 impl From<tree::MethodErr> for Error {
     fn from(t: tree::MethodErr) -> Error {
         Error::new_custom(t.errorname(), t.description())
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "libdbus-sys"))]
 mod test {
-    use super::{Connection, Message, BusType, MessageItem, ConnectionItem, NameFlag,
+    use super::{Connection, Message, MessageItem, ConnectionItem, NameFlag,
         RequestNameReply, ReleaseNameReply};
+    use super::testing::TestBus;
 
     #[test]
     fn connection() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let n = c.unique_name();
         assert!(n.starts_with(":1."));
         println!("Connected to DBus, unique name: {}", n);
@@ -179,7 +312,8 @@ mod test {
 
     #[test]
     fn invalid_message() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let m = Message::new_method_call("foo.bar", "/", "foo.bar", "FooBar").unwrap();
         let e = c.send_with_reply_and_block(m, 2000).err().unwrap();
         assert!(e.name().unwrap() == "org.freedesktop.DBus.Error.ServiceUnknown");
@@ -187,7 +321,8 @@ mod test {
 
     #[test]
     fn message_listnames() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let m = Message::method_call(&"org.freedesktop.DBus".into(), &"/".into(),
             &"org.freedesktop.DBus".into(), &"ListNames".into());
         let r = c.send_with_reply_and_block(m, 2000).unwrap();
@@ -197,7 +332,8 @@ mod test {
 
     #[test]
     fn message_namehasowner() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let mut m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "NameHasOwner").unwrap();
         m.append_items(&[MessageItem::Str("org.freedesktop.DBus".to_string())]);
         let r = c.send_with_reply_and_block(m, 2000).unwrap();
@@ -210,8 +346,11 @@ mod test {
     fn object_path() {
         use  std::sync::mpsc;
         let (tx, rx) = mpsc::channel();
+        let bus = TestBus::new().unwrap();
+        let addr = bus.address().to_string();
         let thread = ::std::thread::spawn(move || {
-            let c = Connection::get_private(BusType::Session).unwrap();
+            let c = Connection::open_private(&addr).unwrap();
+            c.register().unwrap();
             c.register_object_path("/hello").unwrap();
             // println!("Waiting...");
             tx.send(c.unique_name()).unwrap();
@@ -229,7 +368,7 @@ mod test {
             c.unregister_object_path("/hello");
         });
 
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let c = bus.connection().unwrap();
         let n = rx.recv().unwrap();
         let m = Message::new_method_call(&n, "/hello", "com.example.hello", "Hello").unwrap();
         println!("Sending...");
@@ -242,7 +381,8 @@ mod test {
 
     #[test]
     fn register_name() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let n = format!("com.example.hello.test.register_name");
         assert_eq!(c.register_name(&n, NameFlag::ReplaceExisting as u32).unwrap(), RequestNameReply::PrimaryOwner);
         assert_eq!(c.release_name(&n).unwrap(), ReleaseNameReply::Released);
@@ -250,7 +390,8 @@ mod test {
 
     #[test]
     fn signal() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let iface = "com.example.signaltest";
         let mstr = format!("interface='{}',member='ThisIsASignal'", iface);
         c.add_match(&mstr).unwrap();
@@ -278,7 +419,8 @@ mod test {
 
     #[test]
     fn watch() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let d = c.watch_fds();
         assert!(d.len() > 0);
         println!("Fds to watch: {:?}", d);
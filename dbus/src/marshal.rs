@@ -0,0 +1,1228 @@
+//! Native Rust implementation of the D-Bus wire format.
+//!
+//! `RawMessage` is a message that lives entirely in Rust: it is built, marshalled and
+//! demarshalled without libdbus, which makes it usable when the crate is compiled without
+//! the `libdbus-sys` feature. Both byte orders are supported.
+//!
+//! With libdbus, this module can also serialize a `Message` into the bytes that go over the
+//! wire and parse such bytes back into a `Message`, still without asking libdbus to do the
+//! (de)marshalling. Usually you'll use `Message::marshal` and `Message::demarshal` for that,
+//! rather than the functions in here.
+//!
+//! Unix file descriptors are not part of the byte stream (they are passed out-of-band),
+//! so messages containing them cannot be marshalled.
+
+use {MessageType, Error, Path, Interface, Member, BusName, ErrorName};
+#[cfg(feature = "libdbus-sys")]
+use {ffi, Message};
+#[cfg(feature = "libdbus-sys")]
+use std::ffi::{CStr, CString};
+#[cfg(feature = "libdbus-sys")]
+use std::os::raw::{c_void, c_char, c_int};
+#[cfg(feature = "libdbus-sys")]
+use std::ptr;
+use std::{mem, str};
+
+/// Byte order of a marshalled message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    /// Little endian, marked with an 'l' as the first byte of the message.
+    Little,
+    /// Big endian, marked with a 'B' as the first byte of the message.
+    Big,
+}
+
+impl Endianness {
+    /// The byte order of the machine we're running on.
+    pub fn native() -> Endianness {
+        if cfg!(target_endian = "big") { Endianness::Big } else { Endianness::Little }
+    }
+
+    fn from_byte(b: u8) -> Option<Endianness> {
+        match b {
+            b'l' => Some(Endianness::Little),
+            b'B' => Some(Endianness::Big),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Endianness::Little => b'l',
+            Endianness::Big => b'B',
+        }
+    }
+}
+
+impl Default for Endianness {
+    fn default() -> Self { Endianness::native() }
+}
+
+const HEADER_PATH: u8 = 1;
+const HEADER_INTERFACE: u8 = 2;
+const HEADER_MEMBER: u8 = 3;
+const HEADER_ERROR_NAME: u8 = 4;
+const HEADER_REPLY_SERIAL: u8 = 5;
+const HEADER_DESTINATION: u8 = 6;
+const HEADER_SENDER: u8 = 7;
+const HEADER_SIGNATURE: u8 = 8;
+const HEADER_UNIX_FDS: u8 = 9;
+
+const FLAG_NO_REPLY_EXPECTED: u8 = 0x1;
+const FLAG_NO_AUTO_START: u8 = 0x2;
+
+const PROTOCOL_VERSION: u8 = 1;
+
+/// Size of the fixed part of the header, including the length of the header field array.
+const FIXED_HEADER_LEN: usize = 16;
+const MAX_MESSAGE_LEN: usize = 128 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 64 * 1024 * 1024;
+const MAX_SIGNATURE_LEN: usize = 255;
+const MAX_DEPTH: usize = 64;
+
+fn invalid(s: &str) -> Error { Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", s) }
+
+fn alignment(t: u8) -> usize {
+    match t {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'b' | b'i' | b'u' | b'h' | b's' | b'o' | b'a' => 4,
+        _ => 8,
+    }
+}
+
+fn is_basic(t: u8) -> bool {
+    match t {
+        b'y' | b'b' | b'n' | b'q' | b'i' | b'u' | b'x' | b't' | b'd' | b'h' | b's' | b'o' | b'g' => true,
+        _ => false,
+    }
+}
+
+/// Returns the length of the first single complete type in sig, or None if sig does not
+/// start with a valid one. Dict entries are only allowed directly inside an array.
+pub (crate) fn single_type_len(sig: &[u8], in_array: bool) -> Option<usize> {
+    let c = match sig.first() { Some(&c) => c, None => return None };
+    match c {
+        b'v' => Some(1),
+        b'a' => single_type_len(&sig[1..], true).map(|l| l + 1),
+        b'(' => {
+            let mut pos = 1;
+            loop {
+                if sig.get(pos) == Some(&b')') { return if pos > 1 { Some(pos + 1) } else { None } }
+                match single_type_len(&sig[pos..], false) {
+                    Some(l) => pos += l,
+                    None => return None,
+                }
+            }
+        },
+        b'{' if in_array => {
+            match sig.get(1) { Some(&k) if is_basic(k) => {}, _ => return None };
+            let vlen = match single_type_len(&sig[2..], false) { Some(l) => l, None => return None };
+            if sig.get(2 + vlen) == Some(&b'}') { Some(3 + vlen) } else { None }
+        },
+        c if is_basic(c) => Some(1),
+        _ => None,
+    }
+}
+
+/// Splits a signature into its single complete types.
+pub (crate) fn split_signature(mut sig: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    if sig.len() > MAX_SIGNATURE_LEN { return Err(invalid("Signature is too long")) }
+    let mut v = vec!();
+    while sig.len() > 0 {
+        let l = try!(single_type_len(sig, false).ok_or_else(|| invalid("Invalid signature")));
+        v.push(&sig[..l]);
+        sig = &sig[l..];
+    }
+    Ok(v)
+}
+
+/// A single argument of a `RawMessage`, as it is represented on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A D-Bus byte ("y")
+    Byte(u8),
+    /// A D-Bus boolean ("b")
+    Bool(bool),
+    /// A D-Bus 16-bit signed integer ("n")
+    Int16(i16),
+    /// A D-Bus 16-bit unsigned integer ("q")
+    UInt16(u16),
+    /// A D-Bus 32-bit signed integer ("i")
+    Int32(i32),
+    /// A D-Bus 32-bit unsigned integer ("u")
+    UInt32(u32),
+    /// A D-Bus 64-bit signed integer ("x")
+    Int64(i64),
+    /// A D-Bus 64-bit unsigned integer ("t")
+    UInt64(u64),
+    /// A D-Bus double ("d")
+    Double(f64),
+    /// A D-Bus string ("s"). It must not contain nul characters.
+    Str(String),
+    /// A D-Bus object path ("o")
+    ObjectPath(Path<'static>),
+    /// A D-Bus signature ("g"), which can contain any number of single complete types.
+    Signature(String),
+    /// A D-Bus array. The first field is the signature of the elements, which must all have that signature.
+    Array(String, Vec<Value>),
+    /// A D-Bus struct. It must contain at least one value.
+    Struct(Vec<Value>),
+    /// A D-Bus dict entry. It can only be an element of an array, and its key must be of a basic type.
+    DictEntry(Box<Value>, Box<Value>),
+    /// A D-Bus variant
+    Variant(Box<Value>),
+}
+
+impl Value {
+    /// The D-Bus signature of this value.
+    pub fn signature(&self) -> String {
+        match *self {
+            Value::Byte(_) => "y".into(),
+            Value::Bool(_) => "b".into(),
+            Value::Int16(_) => "n".into(),
+            Value::UInt16(_) => "q".into(),
+            Value::Int32(_) => "i".into(),
+            Value::UInt32(_) => "u".into(),
+            Value::Int64(_) => "x".into(),
+            Value::UInt64(_) => "t".into(),
+            Value::Double(_) => "d".into(),
+            Value::Str(_) => "s".into(),
+            Value::ObjectPath(_) => "o".into(),
+            Value::Signature(_) => "g".into(),
+            Value::Array(ref s, _) => format!("a{}", s),
+            Value::Struct(ref v) => format!("({})", v.iter().map(|x| x.signature()).collect::<String>()),
+            Value::DictEntry(ref k, ref v) => format!("{{{}{}}}", k.signature(), v.signature()),
+            Value::Variant(_) => "v".into(),
+        }
+    }
+}
+
+/// A D-Bus message that is built and (de)marshalled in Rust, without libdbus.
+///
+/// The arguments are kept as a list of `Value`s. Use `marshal` to turn the message into bytes
+/// and `demarshal` to parse bytes into a message. Messages sent with `native::TxRx` are of this type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawMessage {
+    msg_type: MessageType,
+    serial: u32,
+    no_reply: bool,
+    auto_start: bool,
+    path: Option<Path<'static>>,
+    interface: Option<Interface<'static>>,
+    member: Option<Member<'static>>,
+    error_name: Option<ErrorName<'static>>,
+    reply_serial: Option<u32>,
+    destination: Option<BusName<'static>>,
+    sender: Option<BusName<'static>>,
+    body: Vec<Value>,
+}
+
+impl RawMessage {
+    fn new(t: MessageType) -> RawMessage {
+        RawMessage { msg_type: t, serial: 0, no_reply: false, auto_start: true, path: None, interface: None,
+            member: None, error_name: None, reply_serial: None, destination: None, sender: None, body: vec!() }
+    }
+
+    /// Creates a new method call message.
+    pub fn new_method_call<'d, 'p, 'i, 'm, D, P, I, M>(destination: D, path: P, iface: I, method: M) -> Result<RawMessage, String>
+    where D: Into<BusName<'d>>, P: Into<Path<'p>>, I: Into<Interface<'i>>, M: Into<Member<'m>> {
+        let (d, p, i, n): (BusName, Path, Interface, Member) = (destination.into(), path.into(), iface.into(), method.into());
+        let mut m = RawMessage::new(MessageType::MethodCall);
+        m.destination = Some(d.into_static());
+        m.path = Some(p.into_static());
+        m.interface = Some(i.into_static());
+        m.member = Some(n.into_static());
+        Ok(m)
+    }
+
+    /// Creates a new signal message.
+    pub fn new_signal<P, I, M>(path: P, iface: I, name: M) -> Result<RawMessage, String>
+    where P: Into<Vec<u8>>, I: Into<Vec<u8>>, M: Into<Vec<u8>> {
+        let mut m = RawMessage::new(MessageType::Signal);
+        m.path = Some(try!(Path::new(path)));
+        m.interface = Some(try!(Interface::new(iface)));
+        m.member = Some(try!(Member::new(name)));
+        Ok(m)
+    }
+
+    fn reply(&self, t: MessageType) -> RawMessage {
+        let mut m = RawMessage::new(t);
+        m.reply_serial = Some(self.serial);
+        m.destination = self.sender.clone();
+        m.no_reply = true;
+        m
+    }
+
+    /// Creates a method return (reply) for this method call.
+    pub fn method_return(&self) -> RawMessage { self.reply(MessageType::MethodReturn) }
+
+    /// Creates a new error reply
+    pub fn error(&self, error_name: &ErrorName, error_message: &str) -> RawMessage {
+        let mut m = self.reply(MessageType::Error);
+        m.error_name = Some(error_name.clone().into_static());
+        m.body.push(Value::Str(error_message.into()));
+        m
+    }
+
+    /// Gets the MessageType of the Message.
+    pub fn msg_type(&self) -> MessageType { self.msg_type }
+
+    /// Get the D-Bus serial of a message, if one was specified.
+    pub fn get_serial(&self) -> u32 { self.serial }
+
+    /// Sets the serial. This is done by the connection when the message is sent.
+    pub fn set_serial(&mut self, serial: u32) { self.serial = serial }
+
+    /// Get the serial of the message this message is a reply to, if present.
+    pub fn get_reply_serial(&self) -> Option<u32> { self.reply_serial }
+
+    /// Returns true if the message does not expect a reply.
+    pub fn get_no_reply(&self) -> bool { self.no_reply }
+
+    /// Set whether or not the message expects a reply.
+    pub fn set_no_reply(&mut self, v: bool) { self.no_reply = v }
+
+    /// Returns true if the message can cause a service to be auto-started.
+    pub fn get_auto_start(&self) -> bool { self.auto_start }
+
+    /// Sets whether or not the message can cause a service to be auto-started.
+    pub fn set_auto_start(&mut self, v: bool) { self.auto_start = v }
+
+    /// Gets the object path this Message is being sent to.
+    pub fn path(&self) -> Option<&Path<'static>> { self.path.as_ref() }
+
+    /// Gets the interface this Message is being sent to.
+    pub fn interface(&self) -> Option<&Interface<'static>> { self.interface.as_ref() }
+
+    /// Gets the interface member being called.
+    pub fn member(&self) -> Option<&Member<'static>> { self.member.as_ref() }
+
+    /// Gets the name of the error, if this is an error message.
+    pub fn error_name(&self) -> Option<&ErrorName<'static>> { self.error_name.as_ref() }
+
+    /// Gets the destination this Message is being sent to.
+    pub fn destination(&self) -> Option<&BusName<'static>> { self.destination.as_ref() }
+
+    /// Sets the destination of this Message
+    pub fn set_destination(&mut self, dest: Option<BusName<'static>>) { self.destination = dest }
+
+    /// Gets the name of the connection that originated this message.
+    pub fn sender(&self) -> Option<&BusName<'static>> { self.sender.as_ref() }
+
+    /// The arguments of the message.
+    pub fn body(&self) -> &[Value] { &self.body }
+
+    /// Appends one argument to this message.
+    pub fn append(mut self, v: Value) -> Self { self.body.push(v); self }
+
+    /// The signature of the arguments of the message.
+    pub fn signature(&self) -> String { self.body.iter().map(|v| v.signature()).collect() }
+
+    /// Turns an error message into a D-Bus Error, or otherwise returns the message.
+    pub fn as_result(&mut self) -> Result<&mut RawMessage, Error> {
+        if self.msg_type != MessageType::Error { return Ok(self) }
+        let name = self.error_name.as_ref().map(|n| &**n).unwrap_or("org.freedesktop.DBus.Error.Failed");
+        let text = match self.body.first() { Some(&Value::Str(ref s)) => &**s, _ => "" };
+        Err(Error::new_custom(name, text))
+    }
+
+    /// Serializes this message into the D-Bus wire format, using the given byte order.
+    pub fn marshal(&self, endian: Endianness) -> Result<Vec<u8>, Error> {
+        let sig = self.signature();
+        try!(split_signature(sig.as_bytes()));
+        let mut body = Writer::new(endian);
+        for v in self.body.iter() { try!(write_value(&mut body, v, 0)) }
+
+        let mut flags = 0;
+        if self.no_reply { flags |= FLAG_NO_REPLY_EXPECTED };
+        if !self.auto_start { flags |= FLAG_NO_AUTO_START };
+        let h = Headers {
+            path: self.path.as_ref().map(|s| s.as_bytes()),
+            interface: self.interface.as_ref().map(|s| s.as_bytes()),
+            member: self.member.as_ref().map(|s| s.as_bytes()),
+            error_name: self.error_name.as_ref().map(|s| s.as_bytes()),
+            reply_serial: self.reply_serial,
+            destination: self.destination.as_ref().map(|s| s.as_bytes()),
+            sender: self.sender.as_ref().map(|s| s.as_bytes()),
+            signature: Some(sig.as_bytes()),
+            unix_fds: None,
+        };
+        write_message(endian, self.msg_type, flags, self.serial, &h, &body.buf)
+    }
+
+    /// Parses a message in the D-Bus wire format. Both byte orders are accepted.
+    ///
+    /// The buffer must contain exactly one message.
+    pub fn demarshal(buf: &[u8]) -> Result<RawMessage, Error> { parse(buf, false) }
+
+    /// Converts a libdbus backed `Message` into a `RawMessage`.
+    #[cfg(feature = "libdbus-sys")]
+    pub fn from_message(m: &Message) -> Result<RawMessage, Error> {
+        // A message that has not been sent yet has serial zero, which is fine here.
+        parse(&try!(marshal(m, Endianness::native())), true)
+    }
+
+    /// Converts this message into a libdbus backed `Message`.
+    #[cfg(feature = "libdbus-sys")]
+    pub fn to_message(&self) -> Result<Message, Error> {
+        // libdbus aborts on invalid arguments, so make sure they're all fine first.
+        try!(self.marshal(Endianness::native()));
+        let ptr = unsafe { ffi::dbus_message_new(self.msg_type as c_int) };
+        if ptr == ptr::null_mut() { panic!("D-Bus error: dbus_message_new failed") }
+        let m = Message::from_ptr(ptr, false);
+        let p = m.ptr();
+        unsafe {
+            if self.serial != 0 { ffi::dbus_message_set_serial(p, self.serial) };
+            ffi::dbus_message_set_no_reply(p, if self.no_reply { 1 } else { 0 });
+            ffi::dbus_message_set_auto_start(p, if self.auto_start { 1 } else { 0 });
+        }
+        if let Some(ref s) = self.path {
+            check("dbus_message_set_path", unsafe { ffi::dbus_message_set_path(p, s.as_cstr().as_ptr()) });
+        }
+        if let Some(ref s) = self.interface {
+            check("dbus_message_set_interface", unsafe { ffi::dbus_message_set_interface(p, s.as_cstr().as_ptr()) });
+        }
+        if let Some(ref s) = self.member {
+            check("dbus_message_set_member", unsafe { ffi::dbus_message_set_member(p, s.as_cstr().as_ptr()) });
+        }
+        if let Some(ref s) = self.error_name {
+            check("dbus_message_set_error_name", unsafe { ffi::dbus_message_set_error_name(p, s.as_cstr().as_ptr()) });
+        }
+        if let Some(s) = self.reply_serial {
+            check("dbus_message_set_reply_serial", unsafe { ffi::dbus_message_set_reply_serial(p, s) });
+        }
+        if let Some(ref s) = self.destination {
+            check("dbus_message_set_destination", unsafe { ffi::dbus_message_set_destination(p, s.as_cstr().as_ptr()) });
+        }
+        if let Some(ref s) = self.sender {
+            check("dbus_message_set_sender", unsafe { ffi::dbus_message_set_sender(p, s.as_cstr().as_ptr()) });
+        }
+        let mut i = ffi_iter();
+        unsafe { ffi::dbus_message_iter_init_append(p, &mut i) };
+        for v in self.body.iter() { append_value(&mut i, v) }
+        Ok(m)
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    endian: Endianness,
+}
+
+impl Writer {
+    fn new(endian: Endianness) -> Writer { Writer { buf: vec!(), endian: endian } }
+
+    fn pad(&mut self, align: usize) {
+        while self.buf.len() % align != 0 { self.buf.push(0) }
+    }
+
+    fn put(&mut self, align: usize, le_bytes: &[u8]) {
+        self.pad(align);
+        match self.endian {
+            Endianness::Little => self.buf.extend_from_slice(le_bytes),
+            Endianness::Big => self.buf.extend(le_bytes.iter().rev()),
+        }
+    }
+
+    fn u8(&mut self, v: u8) { self.buf.push(v) }
+    fn u16(&mut self, v: u16) { self.put(2, &v.to_le_bytes()) }
+    fn u32(&mut self, v: u32) { self.put(4, &v.to_le_bytes()) }
+    fn u64(&mut self, v: u64) { self.put(8, &v.to_le_bytes()) }
+
+    fn set_u32(&mut self, pos: usize, v: u32) {
+        let b = match self.endian {
+            Endianness::Little => v.to_le_bytes(),
+            Endianness::Big => v.to_be_bytes(),
+        };
+        self.buf[pos..pos+4].copy_from_slice(&b);
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s);
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, s: &[u8]) {
+        self.u8(s.len() as u8);
+        self.buf.extend_from_slice(s);
+        self.buf.push(0);
+    }
+
+    // Writes a placeholder for the length of an array, and returns where the array starts.
+    fn begin_array(&mut self, elem: u8) -> (usize, usize) {
+        self.pad(4);
+        let lenpos = self.buf.len();
+        self.u32(0);
+        self.pad(alignment(elem));
+        (lenpos, self.buf.len())
+    }
+
+    fn end_array(&mut self, (lenpos, start): (usize, usize)) -> Result<(), Error> {
+        let len = self.buf.len() - start;
+        if len > MAX_ARRAY_LEN { return Err(invalid("Array is too long")) }
+        self.set_u32(lenpos, len as u32);
+        Ok(())
+    }
+}
+
+/// Checks that a value is a valid message argument, i e that marshalling it would succeed.
+#[cfg(all(feature = "serde", feature = "libdbus-sys"))]
+pub (crate) fn check_value(v: &Value) -> Result<(), Error> {
+    let s = v.signature();
+    if s.len() > MAX_SIGNATURE_LEN || single_type_len(s.as_bytes(), false) != Some(s.len()) {
+        return Err(invalid("Value is not a single complete type"))
+    }
+    write_value(&mut Writer::new(Endianness::native()), v, 0)
+}
+
+fn write_value(w: &mut Writer, v: &Value, depth: usize) -> Result<(), Error> {
+    if depth > MAX_DEPTH { return Err(invalid("Message is nested too deeply")) }
+    match *v {
+        Value::Byte(x) => w.u8(x),
+        Value::Bool(x) => w.u32(x as u32),
+        Value::Int16(x) => w.u16(x as u16),
+        Value::UInt16(x) => w.u16(x),
+        Value::Int32(x) => w.u32(x as u32),
+        Value::UInt32(x) => w.u32(x),
+        Value::Int64(x) => w.u64(x as u64),
+        Value::UInt64(x) => w.u64(x),
+        Value::Double(x) => w.u64(x.to_bits()),
+        Value::Str(ref s) => {
+            if s.contains('\0') { return Err(invalid("String contains nul characters")) }
+            w.string(s.as_bytes())
+        },
+        Value::ObjectPath(ref p) => w.string(p.as_bytes()),
+        Value::Signature(ref s) => {
+            try!(split_signature(s.as_bytes()));
+            w.signature(s.as_bytes())
+        },
+        Value::Array(ref elem, ref items) => {
+            let e = elem.as_bytes();
+            if single_type_len(e, true) != Some(e.len()) { return Err(invalid("Invalid array element signature")) }
+            let a = w.begin_array(e[0]);
+            for x in items.iter() {
+                if x.signature() != *elem { return Err(invalid("Array element does not match the signature of the array")) }
+                try!(write_value(w, x, depth + 1));
+            }
+            try!(w.end_array(a));
+        },
+        Value::Struct(ref items) => {
+            if items.is_empty() { return Err(invalid("Struct must not be empty")) }
+            w.pad(8);
+            for x in items.iter() { try!(write_value(w, x, depth + 1)) }
+        },
+        Value::DictEntry(ref k, ref x) => {
+            w.pad(8);
+            try!(write_value(w, k, depth + 1));
+            try!(write_value(w, x, depth + 1));
+        },
+        Value::Variant(ref x) => {
+            let s = x.signature();
+            if s.len() > MAX_SIGNATURE_LEN || single_type_len(s.as_bytes(), false) != Some(s.len()) {
+                return Err(invalid("Variant signature must be a single complete type"))
+            }
+            w.signature(s.as_bytes());
+            try!(write_value(w, x, depth + 1));
+        },
+    }
+    Ok(())
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn ffi_iter() -> ffi::DBusMessageIter { unsafe { mem::zeroed() }}
+
+#[cfg(feature = "libdbus-sys")]
+fn iter_get_basic<T>(i: &mut ffi::DBusMessageIter) -> T {
+    unsafe {
+        let mut c: T = mem::zeroed();
+        ffi::dbus_message_iter_get_basic(i, &mut c as *mut _ as *mut c_void);
+        c
+    }
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn iter_get_str(i: &mut ffi::DBusMessageIter) -> &[u8] {
+    let p: *const c_char = iter_get_basic(i);
+    unsafe { CStr::from_ptr(p) }.to_bytes()
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn iter_signature(i: &mut ffi::DBusMessageIter) -> Vec<u8> {
+    unsafe {
+        let c = ffi::dbus_message_iter_get_signature(i);
+        assert!(c != ptr::null_mut());
+        let v = CStr::from_ptr(c).to_bytes().to_vec();
+        ffi::dbus_free(c as *mut c_void);
+        v
+    }
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn write_args(w: &mut Writer, i: &mut ffi::DBusMessageIter) -> Result<(), Error> {
+    loop {
+        let t = unsafe { ffi::dbus_message_iter_get_arg_type(i) };
+        if t == ffi::DBUS_TYPE_INVALID { return Ok(()) }
+        try!(write_arg(w, i, t));
+        unsafe { ffi::dbus_message_iter_next(i) };
+    }
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn write_arg(w: &mut Writer, i: &mut ffi::DBusMessageIter, t: c_int) -> Result<(), Error> {
+    match t {
+        ffi::DBUS_TYPE_BYTE => w.u8(iter_get_basic(i)),
+        ffi::DBUS_TYPE_INT16 | ffi::DBUS_TYPE_UINT16 => w.u16(iter_get_basic(i)),
+        ffi::DBUS_TYPE_BOOLEAN | ffi::DBUS_TYPE_INT32 | ffi::DBUS_TYPE_UINT32 => w.u32(iter_get_basic(i)),
+        ffi::DBUS_TYPE_INT64 | ffi::DBUS_TYPE_UINT64 | ffi::DBUS_TYPE_DOUBLE => w.u64(iter_get_basic(i)),
+        ffi::DBUS_TYPE_STRING | ffi::DBUS_TYPE_OBJECT_PATH => w.string(iter_get_str(i)),
+        ffi::DBUS_TYPE_SIGNATURE => w.signature(iter_get_str(i)),
+        ffi::DBUS_TYPE_UNIX_FD => return Err(invalid("Messages containing file descriptors cannot be marshalled")),
+        ffi::DBUS_TYPE_ARRAY => {
+            let sig = iter_signature(i);
+            let a = w.begin_array(sig[1]);
+            let mut sub = ffi_iter();
+            unsafe { ffi::dbus_message_iter_recurse(i, &mut sub) };
+            try!(write_args(w, &mut sub));
+            try!(w.end_array(a));
+        },
+        ffi::DBUS_TYPE_STRUCT | ffi::DBUS_TYPE_DICT_ENTRY => {
+            w.pad(8);
+            let mut sub = ffi_iter();
+            unsafe { ffi::dbus_message_iter_recurse(i, &mut sub) };
+            try!(write_args(w, &mut sub));
+        },
+        ffi::DBUS_TYPE_VARIANT => {
+            let mut sub = ffi_iter();
+            unsafe { ffi::dbus_message_iter_recurse(i, &mut sub) };
+            w.signature(&iter_signature(&mut sub));
+            try!(write_args(w, &mut sub));
+        },
+        _ => return Err(invalid(&format!("Unknown argument type {}", t))),
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Headers<'a> {
+    path: Option<&'a [u8]>,
+    interface: Option<&'a [u8]>,
+    member: Option<&'a [u8]>,
+    error_name: Option<&'a [u8]>,
+    reply_serial: Option<u32>,
+    destination: Option<&'a [u8]>,
+    sender: Option<&'a [u8]>,
+    signature: Option<&'a [u8]>,
+    unix_fds: Option<u32>,
+}
+
+fn write_header_str(w: &mut Writer, code: u8, t: u8, s: Option<&[u8]>) {
+    let s = match s { Some(s) => s, None => return };
+    w.pad(8);
+    w.u8(code);
+    w.signature(&[t]);
+    if t == b'g' { w.signature(s) } else { w.string(s) };
+}
+
+fn write_message(endian: Endianness, mtype: MessageType, flags: u8, serial: u32, h: &Headers, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut w = Writer::new(endian);
+    w.u8(endian.to_byte());
+    w.u8(mtype as u8);
+    w.u8(flags);
+    w.u8(PROTOCOL_VERSION);
+    w.u32(body.len() as u32);
+    w.u32(serial);
+    w.u32(0);
+
+    write_header_str(&mut w, HEADER_PATH, b'o', h.path);
+    write_header_str(&mut w, HEADER_INTERFACE, b's', h.interface);
+    write_header_str(&mut w, HEADER_MEMBER, b's', h.member);
+    write_header_str(&mut w, HEADER_ERROR_NAME, b's', h.error_name);
+    if let Some(r) = h.reply_serial {
+        w.pad(8);
+        w.u8(HEADER_REPLY_SERIAL);
+        w.signature(b"u");
+        w.u32(r);
+    }
+    write_header_str(&mut w, HEADER_DESTINATION, b's', h.destination);
+    write_header_str(&mut w, HEADER_SENDER, b's', h.sender);
+    write_header_str(&mut w, HEADER_SIGNATURE, b'g', h.signature.and_then(|s| if s.len() > 0 { Some(s) } else { None }));
+
+    let fields_len = w.buf.len() - FIXED_HEADER_LEN;
+    w.set_u32(FIXED_HEADER_LEN - 4, fields_len as u32);
+    w.pad(8);
+    w.buf.extend_from_slice(body);
+    if w.buf.len() > MAX_MESSAGE_LEN { return Err(invalid("Message is too long")) }
+    Ok(w.buf)
+}
+
+#[cfg(feature = "libdbus-sys")]
+unsafe fn cstr_bytes<'a>(s: *const c_char) -> Option<&'a [u8]> {
+    if s == ptr::null() { None } else { Some(CStr::from_ptr(s).to_bytes()) }
+}
+
+/// Serializes a message into the D-Bus wire format, using the given byte order.
+///
+/// Usually you'll call `Message::marshal` instead.
+#[cfg(feature = "libdbus-sys")]
+pub fn marshal(m: &Message, endian: Endianness) -> Result<Vec<u8>, Error> {
+    let msg = m.ptr();
+    let mut body = Writer::new(endian);
+    let mut i = ffi_iter();
+    if unsafe { ffi::dbus_message_iter_init(msg, &mut i) } != 0 {
+        try!(write_args(&mut body, &mut i));
+    }
+
+    let mut flags = 0;
+    if m.get_no_reply() { flags |= FLAG_NO_REPLY_EXPECTED };
+    if !m.get_auto_start() { flags |= FLAG_NO_AUTO_START };
+    let h = unsafe { Headers {
+        path: cstr_bytes(ffi::dbus_message_get_path(msg)),
+        interface: cstr_bytes(ffi::dbus_message_get_interface(msg)),
+        member: cstr_bytes(ffi::dbus_message_get_member(msg)),
+        error_name: cstr_bytes(ffi::dbus_message_get_error_name(msg)),
+        reply_serial: m.get_reply_serial(),
+        destination: cstr_bytes(ffi::dbus_message_get_destination(msg)),
+        sender: cstr_bytes(ffi::dbus_message_get_sender(msg)),
+        signature: cstr_bytes(ffi::dbus_message_get_signature(msg)),
+        unix_fds: None,
+    }};
+    write_message(endian, m.msg_type(), flags, m.get_serial(), &h, &body.buf)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    endian: Endianness,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, align: usize) -> Result<(), Error> {
+        let p = (self.pos + align - 1) / align * align;
+        if p > self.buf.len() { return Err(invalid("Message is truncated")) }
+        if self.buf[self.pos..p].iter().any(|&b| b != 0) { return Err(invalid("Alignment padding is not zero")) }
+        self.pos = p;
+        Ok(())
+    }
+
+    fn take(&mut self, align: usize, len: usize) -> Result<&'a [u8], Error> {
+        try!(self.align(align));
+        if self.buf.len() - self.pos < len { return Err(invalid("Message is truncated")) }
+        let r = &self.buf[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(r)
+    }
+
+    fn fixed<T: Copy>(&mut self, align: usize) -> Result<[u8; 8], Error> {
+        let n = mem::size_of::<T>();
+        let b = try!(self.take(align, n));
+        let mut r = [0; 8];
+        match self.endian {
+            Endianness::Little => r[..n].copy_from_slice(b),
+            Endianness::Big => for (d, s) in r[..n].iter_mut().zip(b.iter().rev()) { *d = *s },
+        }
+        Ok(r)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> { Ok(try!(self.take(1, 1))[0]) }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = try!(self.fixed::<u16>(2));
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = try!(self.fixed::<u32>(4));
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(try!(self.fixed::<u64>(8))))
+    }
+
+    fn nul_terminated(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let b = try!(self.take(1, len + 1));
+        if b[len] != 0 { return Err(invalid("String is not nul terminated")) }
+        let s = &b[..len];
+        if s.contains(&0) { return Err(invalid("String contains nul characters")) }
+        Ok(s)
+    }
+
+    fn string(&mut self) -> Result<&'a str, Error> {
+        let len = try!(self.u32()) as usize;
+        let s = try!(self.nul_terminated(len));
+        str::from_utf8(s).map_err(|_| invalid("String is not valid UTF-8"))
+    }
+
+    fn path(&mut self) -> Result<Path<'static>, Error> {
+        let s = try!(self.string());
+        Path::new(s).map_err(|e| invalid(&e))
+    }
+
+    fn signature(&mut self) -> Result<&'a [u8], Error> {
+        let len = try!(self.u8()) as usize;
+        let s = try!(self.nul_terminated(len));
+        try!(split_signature(s));
+        Ok(s)
+    }
+}
+
+// Signatures are validated when read, so they are always ASCII.
+fn sig_string(s: &[u8]) -> String { String::from_utf8_lossy(s).into_owned() }
+
+/// Reads one value of the single complete type "sig".
+fn read_value(r: &mut Reader, sig: &[u8], depth: usize) -> Result<Value, Error> {
+    if depth > MAX_DEPTH { return Err(invalid("Message is nested too deeply")) }
+    Ok(match sig[0] {
+        b'y' => Value::Byte(try!(r.u8())),
+        b'b' => match try!(r.u32()) {
+            0 => Value::Bool(false),
+            1 => Value::Bool(true),
+            _ => return Err(invalid("Boolean value is neither 0 nor 1")),
+        },
+        b'n' => Value::Int16(try!(r.u16()) as i16),
+        b'q' => Value::UInt16(try!(r.u16())),
+        b'i' => Value::Int32(try!(r.u32()) as i32),
+        b'u' => Value::UInt32(try!(r.u32())),
+        b'x' => Value::Int64(try!(r.u64()) as i64),
+        b't' => Value::UInt64(try!(r.u64())),
+        b'd' => Value::Double(f64::from_bits(try!(r.u64()))),
+        b's' => Value::Str(try!(r.string()).into()),
+        b'o' => Value::ObjectPath(try!(r.path())),
+        b'g' => Value::Signature(sig_string(try!(r.signature()))),
+        b'h' => return Err(invalid("Messages containing file descriptors cannot be demarshalled")),
+        b'a' => {
+            let len = try!(r.u32()) as usize;
+            if len > MAX_ARRAY_LEN { return Err(invalid("Array is too long")) }
+            let elem = &sig[1..];
+            try!(r.align(alignment(elem[0])));
+            if r.buf.len() - r.pos < len { return Err(invalid("Message is truncated")) }
+            let end = r.pos + len;
+            let mut v = vec!();
+            while r.pos < end { v.push(try!(read_value(r, elem, depth + 1))) }
+            if r.pos != end { return Err(invalid("Array length does not match its contents")) }
+            Value::Array(sig_string(elem), v)
+        },
+        b'(' => {
+            try!(r.align(8));
+            let mut v = vec!();
+            for s in try!(split_signature(&sig[1..sig.len()-1])) { v.push(try!(read_value(r, s, depth + 1))) }
+            Value::Struct(v)
+        },
+        b'{' => {
+            try!(r.align(8));
+            let klen = single_type_len(&sig[1..], false).unwrap_or(1);
+            let k = try!(read_value(r, &sig[1..1+klen], depth + 1));
+            let v = try!(read_value(r, &sig[1+klen..sig.len()-1], depth + 1));
+            Value::DictEntry(Box::new(k), Box::new(v))
+        },
+        b'v' => {
+            let vsig = try!(r.signature());
+            if vsig.len() == 0 || single_type_len(vsig, false) != Some(vsig.len()) {
+                return Err(invalid("Variant signature must be a single complete type"))
+            }
+            Value::Variant(Box::new(try!(read_value(r, vsig, depth + 1))))
+        },
+        _ => return Err(invalid("Invalid signature")),
+    })
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn append_basic<T>(i: &mut ffi::DBusMessageIter, t: c_int, v: &T) {
+    let r = unsafe { ffi::dbus_message_iter_append_basic(i, t, v as *const _ as *const c_void) };
+    if r == 0 { panic!("D-Bus error: 'dbus_message_iter_append_basic' failed") }
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn append_str(i: &mut ffi::DBusMessageIter, t: c_int, s: &[u8]) {
+    let c = CString::new(s).unwrap();
+    append_basic(i, t, &c.as_ptr());
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn append_container<F: FnOnce(&mut ffi::DBusMessageIter)>(i: &mut ffi::DBusMessageIter, t: c_int, sig: Option<&str>, f: F) {
+    let sig = sig.map(|s| CString::new(s).unwrap());
+    let p = sig.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
+    let mut sub = ffi_iter();
+    if unsafe { ffi::dbus_message_iter_open_container(i, t, p, &mut sub) } == 0 {
+        panic!("D-Bus error: 'dbus_message_iter_open_container' failed")
+    }
+    f(&mut sub);
+    if unsafe { ffi::dbus_message_iter_close_container(i, &mut sub) } == 0 {
+        panic!("D-Bus error: 'dbus_message_iter_close_container' failed")
+    }
+}
+
+/// Appends a value that has already been validated, e g by parsing it or by `check_value`.
+#[cfg(feature = "libdbus-sys")]
+pub (crate) fn append_value(i: &mut ffi::DBusMessageIter, v: &Value) {
+    match *v {
+        Value::Byte(x) => append_basic(i, ffi::DBUS_TYPE_BYTE, &x),
+        Value::Bool(x) => append_basic(i, ffi::DBUS_TYPE_BOOLEAN, &(x as u32)),
+        Value::Int16(x) => append_basic(i, ffi::DBUS_TYPE_INT16, &x),
+        Value::UInt16(x) => append_basic(i, ffi::DBUS_TYPE_UINT16, &x),
+        Value::Int32(x) => append_basic(i, ffi::DBUS_TYPE_INT32, &x),
+        Value::UInt32(x) => append_basic(i, ffi::DBUS_TYPE_UINT32, &x),
+        Value::Int64(x) => append_basic(i, ffi::DBUS_TYPE_INT64, &x),
+        Value::UInt64(x) => append_basic(i, ffi::DBUS_TYPE_UINT64, &x),
+        Value::Double(x) => append_basic(i, ffi::DBUS_TYPE_DOUBLE, &x),
+        Value::Str(ref s) => append_str(i, ffi::DBUS_TYPE_STRING, s.as_bytes()),
+        Value::ObjectPath(ref p) => append_str(i, ffi::DBUS_TYPE_OBJECT_PATH, p.as_bytes()),
+        Value::Signature(ref s) => append_str(i, ffi::DBUS_TYPE_SIGNATURE, s.as_bytes()),
+        Value::Array(ref s, ref items) => append_container(i, ffi::DBUS_TYPE_ARRAY, Some(&**s), |sub| {
+            for x in items.iter() { append_value(sub, x) }
+        }),
+        Value::Struct(ref items) => append_container(i, ffi::DBUS_TYPE_STRUCT, None, |sub| {
+            for x in items.iter() { append_value(sub, x) }
+        }),
+        Value::DictEntry(ref k, ref x) => append_container(i, ffi::DBUS_TYPE_DICT_ENTRY, None, |sub| {
+            append_value(sub, k);
+            append_value(sub, x);
+        }),
+        Value::Variant(ref x) => append_container(i, ffi::DBUS_TYPE_VARIANT, Some(&*x.signature()), |sub| append_value(sub, x)),
+    }
+}
+
+/// Returns the total length of the message that starts at the beginning of buf.
+///
+/// Only the first 16 bytes of the message are needed to calculate this, so this is useful when
+/// reading messages from a stream. If buf is shorter than that, `Ok(16)` is returned.
+pub fn bytes_needed(buf: &[u8]) -> Result<usize, Error> {
+    if buf.len() < FIXED_HEADER_LEN { return Ok(FIXED_HEADER_LEN) }
+    let endian = try!(Endianness::from_byte(buf[0]).ok_or_else(|| invalid("Invalid byte order")));
+    let mut r = Reader { buf: buf, pos: 4, endian: endian };
+    let body_len = try!(r.u32()) as usize;
+    try!(r.u32());
+    let fields_len = try!(r.u32()) as usize;
+    let header_len = (FIXED_HEADER_LEN + fields_len + 7) / 8 * 8;
+    let total = header_len.saturating_add(body_len);
+    if fields_len > MAX_ARRAY_LEN || total > MAX_MESSAGE_LEN { return Err(invalid("Message is too long")) }
+    Ok(total)
+}
+
+#[cfg(feature = "libdbus-sys")]
+fn check(f: &str, i: u32) { if i == 0 { panic!("D-Bus error: '{}' failed", f) }}
+
+fn read_header_field<'a>(r: &mut Reader<'a>, h: &mut Headers<'a>) -> Result<(), Error> {
+    try!(r.align(8));
+    let code = try!(r.u8());
+    let sig = try!(r.signature());
+    let expected: &[u8] = match code {
+        HEADER_PATH => b"o",
+        HEADER_INTERFACE | HEADER_MEMBER | HEADER_ERROR_NAME | HEADER_DESTINATION | HEADER_SENDER => b"s",
+        HEADER_REPLY_SERIAL | HEADER_UNIX_FDS => b"u",
+        HEADER_SIGNATURE => b"g",
+        0 => return Err(invalid("Invalid header field code")),
+        _ => {
+            // Unknown header fields must be ignored
+            if single_type_len(sig, false) != Some(sig.len()) { return Err(invalid("Invalid header field signature")) }
+            return read_value(r, sig, 1).map(|_| ());
+        }
+    };
+    if sig != expected { return Err(invalid("Header field has wrong type")) }
+    match code {
+        HEADER_PATH => h.path = Some(try!(r.string()).as_bytes()),
+        HEADER_INTERFACE => h.interface = Some(try!(r.string()).as_bytes()),
+        HEADER_MEMBER => h.member = Some(try!(r.string()).as_bytes()),
+        HEADER_ERROR_NAME => h.error_name = Some(try!(r.string()).as_bytes()),
+        HEADER_REPLY_SERIAL => h.reply_serial = Some(try!(r.u32())),
+        HEADER_DESTINATION => h.destination = Some(try!(r.string()).as_bytes()),
+        HEADER_SENDER => h.sender = Some(try!(r.string()).as_bytes()),
+        HEADER_SIGNATURE => h.signature = Some(try!(r.signature())),
+        HEADER_UNIX_FDS => h.unix_fds = Some(try!(r.u32())),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn header<T, F: FnOnce(&[u8]) -> Result<T, String>>(s: Option<&[u8]>, f: F) -> Result<Option<T>, Error> {
+    match s {
+        Some(s) => f(s).map(Some).map_err(|e| invalid(&e)),
+        None => Ok(None),
+    }
+}
+
+fn parse(buf: &[u8], allow_zero_serial: bool) -> Result<RawMessage, Error> {
+    if buf.len() < FIXED_HEADER_LEN { return Err(invalid("Message is truncated")) }
+    let total = try!(bytes_needed(buf));
+    if total != buf.len() { return Err(invalid("Buffer length does not match message length")) }
+
+    let endian = try!(Endianness::from_byte(buf[0]).ok_or_else(|| invalid("Invalid byte order")));
+    let mut r = Reader { buf: buf, pos: 1, endian: endian };
+    let mtype = try!(r.u8());
+    let flags = try!(r.u8());
+    if try!(r.u8()) != PROTOCOL_VERSION { return Err(invalid("Unsupported protocol version")) }
+    let body_len = try!(r.u32()) as usize;
+    let serial = try!(r.u32());
+    if serial == 0 && !allow_zero_serial { return Err(invalid("Message serial must not be zero")) }
+    let fields_end = FIXED_HEADER_LEN + try!(r.u32()) as usize;
+
+    let mut h = Headers::default();
+    r.buf = &buf[..fields_end];
+    while r.pos < fields_end { try!(read_header_field(&mut r, &mut h)) }
+    r.buf = buf;
+    try!(r.align(8));
+    if r.pos + body_len != buf.len() { return Err(invalid("Body length does not match message length")) }
+    if h.unix_fds.unwrap_or(0) > 0 { return Err(invalid("Messages containing file descriptors cannot be demarshalled")) }
+
+    let mtype = match mtype {
+        1 => MessageType::MethodCall,
+        2 => MessageType::MethodReturn,
+        3 => MessageType::Error,
+        4 => MessageType::Signal,
+        _ => return Err(invalid("Invalid message type")),
+    };
+    let missing = match mtype {
+        MessageType::MethodCall => h.path.is_none() || h.member.is_none(),
+        MessageType::Signal => h.path.is_none() || h.interface.is_none() || h.member.is_none(),
+        MessageType::Error => h.error_name.is_none() || h.reply_serial.is_none(),
+        MessageType::MethodReturn => h.reply_serial.is_none(),
+        MessageType::Invalid => unreachable!(),
+    };
+    if missing { return Err(invalid("Message is missing a required header field")) }
+    if h.reply_serial == Some(0) { return Err(invalid("Reply serial must not be zero")) }
+
+    let mut m = RawMessage::new(mtype);
+    m.serial = serial;
+    m.no_reply = flags & FLAG_NO_REPLY_EXPECTED != 0;
+    m.auto_start = flags & FLAG_NO_AUTO_START == 0;
+    m.path = try!(header(h.path, |s| Path::new(s)));
+    m.interface = try!(header(h.interface, |s| Interface::new(s)));
+    m.member = try!(header(h.member, |s| Member::new(s)));
+    m.error_name = try!(header(h.error_name, |s| ErrorName::new(s)));
+    m.reply_serial = h.reply_serial;
+    m.destination = try!(header(h.destination, |s| BusName::new(s)));
+    m.sender = try!(header(h.sender, |s| BusName::new(s)));
+    for s in try!(split_signature(h.signature.unwrap_or(b""))) {
+        m.body.push(try!(read_value(&mut r, s, 0)));
+    }
+    if r.pos != buf.len() { return Err(invalid("Body does not match its signature")) }
+    Ok(m)
+}
+
+/// Parses a message in the D-Bus wire format. Both byte orders are accepted.
+///
+/// The buffer must contain exactly one message. Usually you'll call `Message::demarshal` instead.
+#[cfg(feature = "libdbus-sys")]
+pub fn demarshal(buf: &[u8]) -> Result<Message, Error> {
+    try!(parse(buf, false)).to_message()
+}
+
+#[cfg(all(test, feature = "libdbus-sys"))]
+mod test {
+    use super::*;
+    use {Message, MessageItem, MessageItemArray, Signature, Path, ffi};
+    use super::{single_type_len, split_signature};
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::{ptr, slice};
+
+    /// Deterministic pseudo random generator (xorshift), so failures can be reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: u64) -> u64 { self.next() % n }
+    }
+
+    fn random_sig(r: &mut Rng, depth: u32) -> String {
+        let basic = ["y", "b", "n", "q", "i", "u", "x", "t", "d", "s", "o"];
+        let n = if depth > 3 { basic.len() as u64 } else { basic.len() as u64 + 4 };
+        match r.below(n) as usize {
+            x if x < basic.len() => basic[x].into(),
+            11 => format!("a{}", random_sig(r, depth + 1)),
+            12 => format!("a{{{}{}}}", basic[r.below(basic.len() as u64) as usize], random_sig(r, depth + 1)),
+            13 => "v".into(),
+            _ => {
+                let c = 1 + r.below(3);
+                format!("({})", (0..c).map(|_| random_sig(r, depth + 1)).collect::<String>())
+            }
+        }
+    }
+
+    fn random_str(r: &mut Rng) -> String {
+        let chars = ['a', 'Z', '0', ' ', 'å', '€', '\n', '😀'];
+        (0..r.below(12)).map(|_| chars[r.below(chars.len() as u64) as usize]).collect()
+    }
+
+    fn random_item(r: &mut Rng, sig: &[u8], depth: u32) -> MessageItem {
+        match sig[0] {
+            b'y' => MessageItem::Byte(r.next() as u8),
+            b'b' => MessageItem::Bool(r.below(2) == 1),
+            b'n' => MessageItem::Int16(r.next() as i16),
+            b'q' => MessageItem::UInt16(r.next() as u16),
+            b'i' => MessageItem::Int32(r.next() as i32),
+            b'u' => MessageItem::UInt32(r.next() as u32),
+            b'x' => MessageItem::Int64(r.next() as i64),
+            b't' => MessageItem::UInt64(r.next()),
+            b'd' => MessageItem::Double((r.next() as i32) as f64 / 7.0),
+            b's' => MessageItem::Str(random_str(r)),
+            b'o' => MessageItem::ObjectPath(Path::new(format!("/p{}/q_{}", r.below(100), r.below(100))).unwrap()),
+            b'v' => {
+                let s = random_sig(r, depth + 1);
+                MessageItem::Variant(Box::new(random_item(r, s.as_bytes(), depth + 1)))
+            },
+            b'a' => {
+                let elem = &sig[1..1 + single_type_len(&sig[1..], true).unwrap()];
+                let c = r.below(4);
+                let v = (0..c).map(|_| random_item(r, elem, depth + 1)).collect();
+                MessageItem::Array(MessageItemArray::new(v, Signature::new(&sig[..elem.len() + 1]).unwrap()).unwrap())
+            },
+            b'{' => {
+                let vlen = single_type_len(&sig[2..], false).unwrap();
+                MessageItem::DictEntry(Box::new(random_item(r, &sig[1..2], depth + 1)),
+                    Box::new(random_item(r, &sig[2..2 + vlen], depth + 1)))
+            },
+            b'(' => {
+                let l = single_type_len(sig, false).unwrap();
+                MessageItem::Struct(split_signature(&sig[1..l-1]).unwrap().into_iter()
+                    .map(|s| random_item(r, s, depth + 1)).collect())
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn random_message(r: &mut Rng) -> Message {
+        let mut m = match r.below(4) {
+            0 => Message::new_method_call("org.test.rust", "/org/test", "org.test.Iface", "Method").unwrap(),
+            1 => Message::new_signal("/org/test", "org.test.Iface", "Signal").unwrap(),
+            2 => {
+                let mut c = Message::new_method_call("org.test.rust", "/", "org.test.Iface", "M").unwrap();
+                ::message::message_set_serial(&mut c, 5);
+                c.method_return()
+            },
+            _ => {
+                let mut c = Message::new_method_call("org.test.rust", "/", "org.test.Iface", "M").unwrap();
+                ::message::message_set_serial(&mut c, 6);
+                Message::new_error(&c, "org.test.Error", "Something failed").unwrap()
+            },
+        };
+        if r.below(2) == 0 { m.set_no_reply(true) };
+        if r.below(2) == 0 { m.set_auto_start(false) };
+        let items: Vec<_> = (0..r.below(5)).map(|_| {
+            let s = random_sig(r, 0);
+            random_item(r, s.as_bytes(), 0)
+        }).collect();
+        m.append_items(&items);
+        // MessageItem cannot hold a signature
+        if r.below(2) == 0 { m = m.append1(Signature::new(random_sig(r, 0)).unwrap()) };
+        ::message::message_set_serial(&mut m, 1 + r.below(1000) as u32);
+        m
+    }
+
+    fn libdbus_marshal(m: &Message) -> Vec<u8> {
+        let mut p: *mut c_char = ptr::null_mut();
+        let mut len: c_int = 0;
+        assert!(unsafe { ffi::dbus_message_marshal(m.ptr(), &mut p, &mut len) } != 0);
+        let v = unsafe { slice::from_raw_parts(p as *const u8, len as usize) }.to_vec();
+        unsafe { ffi::dbus_free(p as *mut c_void) };
+        v
+    }
+
+    fn libdbus_demarshal(b: &[u8]) -> Message {
+        let mut e = ::Error::empty();
+        let p = unsafe { ffi::dbus_message_demarshal(b.as_ptr() as *const c_char, b.len() as c_int, e.get_mut()) };
+        assert!(p != ptr::null_mut(), "{:?}", e);
+        Message::from_ptr(p, false)
+    }
+
+    fn assert_same(a: &Message, b: &Message) {
+        assert_eq!(a.headers(), b.headers());
+        assert_eq!(a.get_serial(), b.get_serial());
+        assert_eq!(a.get_reply_serial(), b.get_reply_serial());
+        assert_eq!(a.get_no_reply(), b.get_no_reply());
+        assert_eq!(a.get_auto_start(), b.get_auto_start());
+        assert_eq!(a.destination(), b.destination());
+        assert_eq!(a.sender(), b.sender());
+        assert_eq!(body(&marshal(a, Endianness::native()).unwrap()), body(&marshal(b, Endianness::native()).unwrap()));
+    }
+
+    fn body(b: &[u8]) -> &[u8] {
+        let flen = u32::from_ne_bytes([b[12], b[13], b[14], b[15]]) as usize;
+        &b[(16 + flen + 7) / 8 * 8..]
+    }
+
+    #[test]
+    fn against_libdbus() {
+        let mut r = Rng(0x2545F4914F6CDD1D);
+        for _ in 0..500 {
+            let m = random_message(&mut r);
+            let theirs = libdbus_marshal(&m);
+            let ours = marshal(&m, Endianness::native()).unwrap();
+            // libdbus may order the header fields differently, but the body must be identical
+            assert_eq!(bytes_needed(&ours).unwrap(), ours.len());
+            assert_eq!(body(&ours), body(&theirs));
+
+            assert_same(&m, &demarshal(&theirs).unwrap());
+            assert_same(&m, &libdbus_demarshal(&ours));
+            let big = marshal(&m, Endianness::Big).unwrap();
+            assert_eq!(big[0], b'B');
+            assert_eq!(big.len(), ours.len());
+            assert_same(&m, &libdbus_demarshal(&big));
+            let m2 = demarshal(&big).unwrap();
+            assert_same(&m, &m2);
+            assert_eq!(marshal(&m2, Endianness::native()).unwrap(), ours);
+
+            let raw = RawMessage::from_message(&m).unwrap();
+            assert_eq!(raw.marshal(Endianness::native()).unwrap(), ours);
+            assert_eq!(RawMessage::demarshal(&big).unwrap(), raw);
+            assert_same(&m, &raw.to_message().unwrap());
+        }
+    }
+
+    #[test]
+    fn raw_message() {
+        let entry = Value::DictEntry(Box::new(Value::Str("a".into())), Box::new(Value::Variant(Box::new(Value::UInt32(5)))));
+        let mut m = RawMessage::new_method_call("org.test.rust", "/org/test", "org.test.Iface", "M").unwrap()
+            .append(Value::Str("Hello".into()))
+            .append(Value::Array("{sv}".into(), vec!(entry)));
+        assert_eq!(m.signature(), "sa{sv}");
+        m.set_serial(7);
+        let b = m.marshal(Endianness::Big).unwrap();
+        assert_eq!(RawMessage::demarshal(&b).unwrap(), m);
+        let m2 = m.to_message().unwrap();
+        assert_eq!(m2.get1(), Some("Hello"));
+        assert_eq!(RawMessage::from_message(&m2).unwrap(), m);
+
+        let mut r = m.error(&"org.test.Error".into(), "Oops");
+        assert_eq!(r.get_reply_serial(), Some(7));
+        assert_eq!(r.as_result().unwrap_err().message(), Some("Oops"));
+
+        // Invalid values are rejected rather than handed to libdbus or sent.
+        let bad = m.clone().append(Value::Array("s".into(), vec!(Value::UInt32(1))));
+        assert!(bad.marshal(Endianness::Little).is_err());
+        assert!(bad.to_message().is_err());
+        assert!(m.clone().append(Value::Struct(vec!())).marshal(Endianness::Little).is_err());
+        assert!(m.clone().append(Value::Str("a\0b".into())).marshal(Endianness::Little).is_err());
+        let loose = Value::DictEntry(Box::new(Value::Byte(1)), Box::new(Value::Byte(2)));
+        assert!(m.clone().append(loose).marshal(Endianness::Little).is_err());
+    }
+
+    #[test]
+    fn invalid_input() {
+        let m = Message::new_method_call("org.test.rust", "/", "org.test.Iface", "M").unwrap().append2("Hello", 5u8);
+        // Little endian, so the numbers below can be written the same way on every host.
+        let mut b = marshal(&m, Endianness::Little).unwrap();
+        assert_eq!(b[0], b'l');
+        assert!(demarshal(&b).is_err()); // serial is zero
+        b[8..12].copy_from_slice(&1u32.to_le_bytes());
+        assert!(demarshal(&b).is_ok());
+        assert!(demarshal(&b[..b.len() - 1]).is_err());
+        let mut b2 = b.clone();
+        b2.push(0);
+        assert!(demarshal(&b2).is_err());
+        let mut b3 = b.clone();
+        b3[0] = b'x';
+        assert!(demarshal(&b3).is_err());
+        // The body is the length of "Hello", "Hello\0" and the byte 5.
+        let l = b.len();
+        assert_eq!(&b[l - 11..l - 7], &5u32.to_le_bytes());
+        b[l - 11..l - 7].copy_from_slice(&200u32.to_le_bytes());
+        assert!(demarshal(&b).is_err());
+    }
+}
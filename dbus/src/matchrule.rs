@@ -0,0 +1,317 @@
+use {Message, MessageType, Error, BusName, Path, Interface, Member, NameWatchCache};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const INVALID: &'static str = "org.freedesktop.DBus.Error.MatchRuleInvalid";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+/// A match rule, as sent to `Connection::add_match` and `Connection::remove_match`.
+///
+/// Use the builder methods to create one, then `to_string` to get the rule in text form.
+///
+/// # Example
+///
+/// ```
+/// use dbus::{MatchRule, MessageType};
+/// let r = MatchRule::new().with_type(MessageType::Signal).with_interface("com.example.Foo").with_arg(0, "it's");
+/// assert_eq!(r.to_string(), "type='signal',interface='com.example.Foo',arg0='it'\\''s'");
+/// assert_eq!(MatchRule::parse(&r.to_string()).unwrap(), r);
+/// ```
+pub struct MatchRule<'a> {
+    msg_type: Option<MessageType>,
+    sender: Option<BusName<'a>>,
+    interface: Option<Interface<'a>>,
+    member: Option<Member<'a>>,
+    path: Option<Path<'a>>,
+    path_namespace: Option<Path<'a>>,
+    destination: Option<BusName<'a>>,
+    args: BTreeMap<u8, String>,
+    arg_paths: BTreeMap<u8, String>,
+    arg0namespace: Option<String>,
+    eavesdrop: bool,
+}
+
+fn type_str(t: MessageType) -> &'static str {
+    match t {
+        MessageType::Signal => "signal",
+        MessageType::MethodCall => "method_call",
+        MessageType::MethodReturn => "method_return",
+        MessageType::Error => "error",
+        MessageType::Invalid => "invalid",
+    }
+}
+
+// Argument number "n" of the message, if it is a string (or an object path, if "paths" is set).
+fn string_arg(m: &Message, n: u8, paths: bool) -> Option<String> {
+    let mut i = m.iter_init();
+    for _ in 0..n { if !i.next() { return None } }
+    i.get::<&str>().map(|s| s.to_string())
+        .or_else(|| if paths { i.get::<Path>().map(|s| s.to_string()) } else { None })
+}
+
+fn in_namespace(s: &str, ns: &str, sep: u8) -> bool {
+    s == ns || (s.starts_with(ns) && s.as_bytes().get(ns.len()) == Some(&sep))
+}
+
+fn arg_index(s: &str) -> Option<u8> {
+    if s.is_empty() || (s.len() > 1 && s.starts_with('0')) { return None }
+    s.parse::<u8>().ok().and_then(|n| if n < 64 { Some(n) } else { None })
+}
+
+impl<'a> MatchRule<'a> {
+    /// Creates a match rule that matches all messages.
+    pub fn new() -> MatchRule<'a> { Default::default() }
+
+    /// Creates a match rule for a signal.
+    pub fn new_signal<I: Into<Interface<'a>>, M: Into<Member<'a>>>(interface: I, member: M) -> MatchRule<'a> {
+        MatchRule::new().with_type(MessageType::Signal).with_interface(interface).with_member(member)
+    }
+
+    /// Matches messages of this type only.
+    pub fn with_type(mut self, t: MessageType) -> Self { self.msg_type = Some(t); self }
+
+    /// Matches messages from this sender only.
+    pub fn with_sender<S: Into<BusName<'a>>>(mut self, s: S) -> Self { self.sender = Some(s.into()); self }
+
+    /// Matches messages with this interface only.
+    pub fn with_interface<I: Into<Interface<'a>>>(mut self, i: I) -> Self { self.interface = Some(i.into()); self }
+
+    /// Matches messages with this member only.
+    pub fn with_member<M: Into<Member<'a>>>(mut self, m: M) -> Self { self.member = Some(m.into()); self }
+
+    /// Matches messages with this object path only.
+    pub fn with_path<P: Into<Path<'a>>>(mut self, p: P) -> Self { self.path = Some(p.into()); self }
+
+    /// Matches messages with this object path, or an object path below it.
+    pub fn with_path_namespace<P: Into<Path<'a>>>(mut self, p: P) -> Self { self.path_namespace = Some(p.into()); self }
+
+    /// Matches messages to this destination only.
+    pub fn with_destination<D: Into<BusName<'a>>>(mut self, d: D) -> Self { self.destination = Some(d.into()); self }
+
+    /// Matches messages whose argument number "n" is a string equal to "value".
+    ///
+    /// Panics if n is larger than 63.
+    pub fn with_arg<S: Into<String>>(mut self, n: u8, value: S) -> Self {
+        assert!(n < 64, "Match rule argument index must be less than 64");
+        self.args.insert(n, value.into());
+        self
+    }
+
+    /// Matches messages whose argument number "n" is a string or object path that is equal to "value",
+    /// or either of them ends with '/' and is a prefix of the other.
+    ///
+    /// Panics if n is larger than 63.
+    pub fn with_arg_path<S: Into<String>>(mut self, n: u8, value: S) -> Self {
+        assert!(n < 64, "Match rule argument index must be less than 64");
+        self.arg_paths.insert(n, value.into());
+        self
+    }
+
+    /// Matches messages whose first argument is a bus name or interface name in this namespace.
+    pub fn with_arg0namespace<S: Into<String>>(mut self, ns: S) -> Self { self.arg0namespace = Some(ns.into()); self }
+
+    /// Asks for messages not addressed to this connection too. Most buses require privileges for this.
+    pub fn with_eavesdrop(mut self) -> Self { self.eavesdrop = true; self }
+
+    /// The sender of the match rule, if any.
+    pub fn sender(&self) -> Option<&BusName<'a>> { self.sender.as_ref() }
+
+    /// Converts the match rule into one with a static lifetime.
+    pub fn into_static(self) -> MatchRule<'static> {
+        MatchRule {
+            msg_type: self.msg_type,
+            sender: self.sender.map(|s| s.into_static()),
+            interface: self.interface.map(|s| s.into_static()),
+            member: self.member.map(|s| s.into_static()),
+            path: self.path.map(|s| s.into_static()),
+            path_namespace: self.path_namespace.map(|s| s.into_static()),
+            destination: self.destination.map(|s| s.into_static()),
+            args: self.args,
+            arg_paths: self.arg_paths,
+            arg0namespace: self.arg0namespace,
+            eavesdrop: self.eavesdrop,
+        }
+    }
+
+    /// Parses a match rule in text form.
+    pub fn parse(s: &str) -> Result<MatchRule<'static>, Error> {
+        let e = |t: String| Error::new_custom(INVALID, &t);
+        let mut r = MatchRule::new();
+        let mut chars = s.chars().peekable();
+        loop {
+            while chars.peek() == Some(&' ') || chars.peek() == Some(&',') { chars.next(); }
+            if chars.peek().is_none() { break; }
+            let key: String = chars.by_ref().take_while(|&c| c != '=').collect();
+            let mut value = String::new();
+            let mut quoted = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\'' => quoted = !quoted,
+                    '\\' if !quoted && chars.peek() == Some(&'\'') => { value.push('\''); chars.next(); },
+                    ',' if !quoted => break,
+                    c => value.push(c),
+                }
+            }
+            if quoted { return Err(e(format!("Unterminated quote in match rule '{}'", s))) }
+            let key = key.trim();
+            let v = value.clone();
+            match key {
+                "type" => r.msg_type = Some(match &*value {
+                    "signal" => MessageType::Signal,
+                    "method_call" => MessageType::MethodCall,
+                    "method_return" => MessageType::MethodReturn,
+                    "error" => MessageType::Error,
+                    _ => return Err(e(format!("Unknown message type '{}' in match rule", value))),
+                }),
+                "sender" => r.sender = Some(try!(BusName::new(v).map_err(&e))),
+                "interface" => r.interface = Some(try!(Interface::new(v).map_err(&e))),
+                "member" => r.member = Some(try!(Member::new(v).map_err(&e))),
+                "path" => r.path = Some(try!(Path::new(v).map_err(&e))),
+                "path_namespace" => r.path_namespace = Some(try!(Path::new(v).map_err(&e))),
+                "destination" => r.destination = Some(try!(BusName::new(v).map_err(&e))),
+                "arg0namespace" => r.arg0namespace = Some(value),
+                "eavesdrop" => r.eavesdrop = value == "true",
+                k if k.starts_with("arg") && k.ends_with("path") && arg_index(&k[3..k.len()-4]).is_some() => {
+                    r.arg_paths.insert(arg_index(&k[3..k.len()-4]).unwrap(), value);
+                },
+                k if k.starts_with("arg") && arg_index(&k[3..]).is_some() => {
+                    r.args.insert(arg_index(&k[3..]).unwrap(), value);
+                },
+                k => return Err(e(format!("Unknown key '{}' in match rule", k))),
+            }
+        }
+        Ok(r)
+    }
+
+    /// Returns true if the message matches this rule.
+    ///
+    /// A bus also lets a sender match on a well-known name owned by the sender. Since that is not known
+    /// locally, a rule with a well-known sender name only matches messages which have this name as their sender.
+    pub fn matches(&self, m: &Message) -> bool {
+        let sender = m.sender();
+        self.matches_sender(m, sender.as_ref().map(|s| &**s), &[])
+    }
+
+    /// Like `matches`, but for messages that the bus has already matched against this rule.
+    ///
+    /// A well-known sender name in the rule is assumed to be owned by the message's sender.
+    pub fn matches_delivered(&self, m: &Message) -> bool {
+        let sender = m.sender();
+        let names: Vec<&str> = self.sender.iter().map(|s| &**s).filter(|s| !s.starts_with(':')).collect();
+        self.matches_sender(m, sender.as_ref().map(|s| &**s), &names)
+    }
+
+    /// Like `matches`, but a well-known sender name in the rule also matches messages from its
+    /// current owner, as known by "owner" (which should watch that name).
+    pub fn matches_owner(&self, m: &Message, owner: &NameWatchCache) -> bool {
+        let sender = m.sender();
+        let sender = sender.as_ref().map(|s| &**s);
+        let name = [&**owner.name()];
+        let names: &[&str] = if sender.is_some() && sender == owner.owner().map(|o| &**o) { &name } else { &[] };
+        self.matches_sender(m, sender, names)
+    }
+
+    /// Like `matches`, but with the sender and its well-known names supplied by the caller.
+    pub(crate) fn matches_sender(&self, m: &Message, sender: Option<&str>, sender_names: &[&str]) -> bool {
+        if let Some(t) = self.msg_type { if m.msg_type() != t { return false } }
+        if let Some(ref s) = self.sender {
+            if sender != Some(&**s) && !sender_names.contains(&&**s) { return false }
+        }
+        if let Some(ref i) = self.interface { if m.interface().as_ref() != Some(i) { return false } }
+        if let Some(ref mm) = self.member { if m.member().as_ref() != Some(mm) { return false } }
+        if let Some(ref p) = self.path { if m.path().as_ref() != Some(p) { return false } }
+        if let Some(ref ns) = self.path_namespace {
+            match m.path() {
+                Some(p) => if &**ns != "/" && !in_namespace(&p, ns, b'/') { return false },
+                None => return false,
+            }
+        }
+        if let Some(ref d) = self.destination { if m.destination().as_ref() != Some(d) { return false } }
+        for (&n, v) in self.args.iter() {
+            if string_arg(m, n, false).as_ref() != Some(v) { return false }
+        }
+        for (&n, v) in self.arg_paths.iter() {
+            let ok = string_arg(m, n, true).map(|a| {
+                a == *v || (a.ends_with('/') && v.starts_with(&*a)) || (v.ends_with('/') && a.starts_with(&**v))
+            }).unwrap_or(false);
+            if !ok { return false }
+        }
+        if let Some(ref ns) = self.arg0namespace {
+            if !string_arg(m, 0, false).map(|a| in_namespace(&a, ns, b'.')).unwrap_or(false) { return false }
+        }
+        true
+    }
+}
+
+fn quote(f: &mut fmt::Formatter, key: &str, value: &str, first: &mut bool) -> fmt::Result {
+    if !*first { try!(f.write_str(",")); }
+    *first = false;
+    write!(f, "{}='{}'", key, value.replace('\'', "'\\''"))
+}
+
+impl<'a> fmt::Display for MatchRule<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        if let Some(t) = self.msg_type { try!(quote(f, "type", type_str(t), &mut first)); }
+        if let Some(ref s) = self.sender { try!(quote(f, "sender", s, &mut first)); }
+        if let Some(ref s) = self.interface { try!(quote(f, "interface", s, &mut first)); }
+        if let Some(ref s) = self.member { try!(quote(f, "member", s, &mut first)); }
+        if let Some(ref s) = self.path { try!(quote(f, "path", s, &mut first)); }
+        if let Some(ref s) = self.path_namespace { try!(quote(f, "path_namespace", s, &mut first)); }
+        if let Some(ref s) = self.destination { try!(quote(f, "destination", s, &mut first)); }
+        for (n, v) in self.args.iter() { try!(quote(f, &format!("arg{}", n), v, &mut first)); }
+        for (n, v) in self.arg_paths.iter() { try!(quote(f, &format!("arg{}path", n), v, &mut first)); }
+        if let Some(ref s) = self.arg0namespace { try!(quote(f, "arg0namespace", s, &mut first)); }
+        if self.eavesdrop { try!(quote(f, "eavesdrop", "true", &mut first)); }
+        Ok(())
+    }
+}
+
+#[test]
+fn match_rule_parse() {
+    let r = MatchRule::parse("type='signal', sender=org.example,arg3='a,b',arg2path='/a/',arg0namespace='com.example',arg1=it\\'s").unwrap();
+    assert_eq!(r, MatchRule::new().with_type(MessageType::Signal).with_sender("org.example").with_arg(3, "a,b")
+        .with_arg_path(2, "/a/").with_arg0namespace("com.example").with_arg(1, "it's"));
+    assert_eq!(r.to_string(), "type='signal',sender='org.example',arg1='it'\\''s',arg3='a,b',arg2path='/a/',arg0namespace='com.example'");
+    assert_eq!(MatchRule::parse(&r.to_string()).unwrap(), r);
+    assert_eq!(MatchRule::parse("").unwrap(), MatchRule::new());
+    let r = MatchRule::parse("type='signal',interface='com.example',arg0='it''s',path_namespace='/a'").unwrap();
+    assert_eq!(r, MatchRule::new().with_type(MessageType::Signal).with_interface("com.example")
+        .with_arg(0, "its").with_path_namespace("/a"));
+    assert_eq!(MatchRule::parse("arg0='it'\\''s'").unwrap(), MatchRule::new().with_arg(0, "it's"));
+    for s in &["type='signal", "foo='bar'", "arg64='x'", "arg01='x'", "type='foo'", "path='no/slash'", "sender='a b'"] {
+        assert_eq!(MatchRule::parse(s).unwrap_err().name(), Some(INVALID), "{}", s);
+    }
+}
+
+#[test]
+fn match_rule_matches() {
+    let m = Message::new_signal("/com/example/a", "com.example.Foo", "Bar").unwrap()
+        .append3("com.example.Name", "/com/example/b/", Path::from("/x"));
+    let yes = |r: MatchRule| assert!(r.matches(&m), "{} should match", r);
+    let no = |r: MatchRule| assert!(!r.matches(&m), "{} should not match", r);
+    yes(MatchRule::new());
+    yes(MatchRule::new_signal("com.example.Foo", "Bar").with_path("/com/example/a"));
+    no(MatchRule::new().with_type(MessageType::MethodCall));
+    no(MatchRule::new_signal("com.example.Foo", "Baz"));
+    yes(MatchRule::new().with_path_namespace("/com/example"));
+    yes(MatchRule::new().with_path_namespace("/"));
+    no(MatchRule::new().with_path_namespace("/com/ex"));
+    yes(MatchRule::new().with_arg(0, "com.example.Name"));
+    no(MatchRule::new().with_arg(2, "/x"));
+    no(MatchRule::new().with_arg(3, "x"));
+    yes(MatchRule::new().with_arg_path(1, "/com/example/b/c"));
+    yes(MatchRule::new().with_arg_path(2, "/"));
+    no(MatchRule::new().with_arg_path(1, "/com/example"));
+    yes(MatchRule::new().with_arg0namespace("com.example"));
+    no(MatchRule::new().with_arg0namespace("com.ex"));
+    no(MatchRule::new().with_sender("com.example"));
+
+    let r = MatchRule::parse("type='signal',path_namespace='/a'").unwrap();
+    let m = Message::new_signal("/a/b", "com.example", "Sig").unwrap().append1("x");
+    assert!(r.matches_sender(&m, Some(":1.1"), &[]));
+    let m2 = Message::new_signal("/ab", "com.example", "Sig").unwrap();
+    assert!(!r.matches_sender(&m2, Some(":1.1"), &[]));
+    assert!(MatchRule::parse("sender='com.example.owner',arg0='x'").unwrap().matches_sender(&m, Some(":1.1"), &["com.example.owner"]));
+    assert!(!MatchRule::parse("arg0='y'").unwrap().matches_sender(&m, Some(":1.1"), &[]));
+}
@@ -1,7 +1,7 @@
 use std::borrow::Cow;
 use std::{fmt, mem, ptr, ops};
 use super::{ffi, Error, MessageType, Signature, libc, to_c_str, c_str_to_slice, init_dbus};
-use super::{BusName, Path, Interface, Member, ErrorName, Connection, SignalArgs};
+use super::{BusName, Path, Interface, Member, ErrorName, Connection, SignalArgs, marshal};
 use std::os::unix::io::{RawFd, AsRawFd};
 use std::ffi::CStr;
 use std::os::raw::{c_void, c_char, c_int};
@@ -925,6 +925,34 @@ impl Message {
         else { Ok(()) }
     }
 
+    /// Serializes this message into the D-Bus wire format, in the byte order of this machine.
+    ///
+    /// The (de)marshalling is done in Rust, see the `marshal` module for details.
+    pub fn marshal(&self) -> Result<Vec<u8>, Error> {
+        marshal::marshal(self, marshal::Endianness::native())
+    }
+
+    /// Serializes this message into the D-Bus wire format, in the specified byte order.
+    pub fn marshal_endian(&self, endian: marshal::Endianness) -> Result<Vec<u8>, Error> {
+        marshal::marshal(self, endian)
+    }
+
+    /// Parses a message in the D-Bus wire format. The buffer must contain exactly one message.
+    pub fn demarshal(buf: &[u8]) -> Result<Message, Error> {
+        marshal::demarshal(buf)
+    }
+
+    /// Creates a copy of this message, with the same serial number.
+    ///
+    /// May fail if out of memory.
+    pub fn duplicate(&self) -> Result<Message, String> {
+        let ptr = unsafe { ffi::dbus_message_copy(self.msg) };
+        if ptr.is_null() { return Err("D-Bus error: dbus_message_copy failed".into()) }
+        let mut m = Message::from_ptr(ptr, false);
+        unsafe { ffi::dbus_message_set_serial(m.msg, self.get_serial()) };
+        Ok(m)
+    }
+
     pub (crate) fn ptr(&self) -> *mut ffi::DBusMessage { self.msg }
 
     pub (crate) fn from_ptr(ptr: *mut ffi::DBusMessage, add_ref: bool) -> Message {
@@ -997,18 +1025,18 @@ pub fn message_set_serial(m: &mut Message, s: u32) {
 mod test {
     extern crate tempdir;
 
-    use super::super::{Connection, Message, MessageType, BusType, MessageItem, OwnedFd, libc, Path, BusName};
+    use super::super::{Message, MessageType, MessageItem, OwnedFd, libc, Path, BusName};
+    use testing::TestBus;
 
     #[test]
     fn unix_fd() {
         use std::io::prelude::*;
         use std::io::SeekFrom;
         use std::fs::OpenOptions;
-        use std::os::unix::io::AsRawFd;
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
 
-        let c = Connection::get_private(BusType::Session).unwrap();
-        c.register_object_path("/hello").unwrap();
-        let mut m = Message::new_method_call(&c.unique_name(), "/hello", "com.example.hello", "Hello").unwrap();
+        // The test bus cannot pass file descriptors, so read the fd back from the message itself.
+        let mut m = Message::new_method_call(":1.1", "/hello", "com.example.hello", "Hello").unwrap();
         let tempdir = tempdir::TempDir::new("dbus-rs-test").unwrap();
         let mut filename = tempdir.path().to_path_buf();
         filename.push("test");
@@ -1016,28 +1044,22 @@ mod test {
         let mut file = OpenOptions::new().create(true).read(true).write(true).open(&filename).unwrap();
         file.write_all(b"z").unwrap();
         file.seek(SeekFrom::Start(0)).unwrap();
-        let ofd = OwnedFd::new(file.as_raw_fd());
+        let ofd = OwnedFd::new(file.into_raw_fd());
         m.append_items(&[MessageItem::UnixFd(ofd.clone())]);
-        println!("Sending {:?}", m.get_items());
-        c.send(m).unwrap();
+        // The message has its own copy of the fd.
+        drop(ofd);
 
-        loop { for n in c.incoming(1000) {
-            if n.msg_type() == MessageType::MethodCall {
-                let z: OwnedFd = n.read1().unwrap();
-                println!("Got {:?}", z);
-                let mut q: libc::c_char = 100;
-                assert_eq!(1, unsafe { libc::read(z.as_raw_fd(), &mut q as *mut _ as *mut libc::c_void, 1) });
-                assert_eq!(q, 'z' as libc::c_char);
-                return;
-            } else {
-                println!("Got {:?}", n);
-            }
-        }}
+        let z: OwnedFd = m.read1().unwrap();
+        println!("Got {:?}", z);
+        let mut q: libc::c_char = 100;
+        assert_eq!(1, unsafe { libc::read(z.as_raw_fd(), &mut q as *mut _ as *mut libc::c_void, 1) });
+        assert_eq!(q, 'z' as libc::c_char);
     }
 
     #[test]
     fn message_types() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         c.register_object_path("/hello").unwrap();
         let mut m = Message::new_method_call(&c.unique_name(), "/hello", "com.example.hello", "Hello").unwrap();
         m.append_items(&[
@@ -1102,7 +1124,8 @@ mod test {
         println!("As MessageItem: {:?}", m);
         assert_eq!(&*m.signature(), "a{oa{sa{sv}}}");
 
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         c.register_object_path("/hello").unwrap();
         let mut msg = Message::new_method_call(&c.unique_name(), "/hello", "org.freedesktop.DBusObjectManager", "GetManagedObjects").unwrap();
         msg.append_items(&[m]);
@@ -1124,7 +1147,8 @@ mod test {
 
     #[test]
     fn issue24() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         let mut m = Message::new_method_call("org.test.rust", "/", "org.test.rust", "Test").unwrap();
 
         let a = MessageItem::from("test".to_string());
@@ -1149,4 +1173,14 @@ mod test {
         m.set_no_reply(true);
         assert!(m.get_no_reply());
     }
+
+    #[test]
+    fn duplicate() {
+        let mut m = Message::new_method_call("org.test.rust", "/", "org.test.rust", "Test").unwrap().append1(5u8);
+        super::message_set_serial(&mut m, 7);
+        let m2 = m.duplicate().unwrap();
+        assert_eq!(m2.get_serial(), 7);
+        assert_eq!(m2.get1(), Some(5u8));
+        assert_eq!(m2.method_return().get_reply_serial(), Some(7));
+    }
 }
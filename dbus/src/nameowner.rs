@@ -0,0 +1,167 @@
+use super::{Connection, Message, MessageType, Error, MsgHandler, MsgHandlerType, MsgHandlerResult, NameFlag};
+use std::{fmt, ops};
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+
+/// Whether we own a bus name.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NameState {
+    /// We are the primary owner of the name.
+    Primary,
+    /// Somebody else owns the name, and we are waiting in the queue to get it.
+    InQueue,
+    /// We don't own the name, and we are not in the queue.
+    Lost,
+}
+
+struct NameInner {
+    state: Cell<NameState>,
+    active: Cell<bool>,
+    callback: RefCell<Option<Box<FnMut(NameState)>>>,
+}
+
+impl NameInner {
+    fn set(&self, s: NameState) {
+        if self.state.get() == s { return }
+        self.state.set(s);
+        // Take the callback out while calling it, so it can call `on_change` itself.
+        let cb = self.callback.borrow_mut().take();
+        if let Some(mut cb) = cb {
+            cb(s);
+            let mut cb2 = self.callback.borrow_mut();
+            if cb2.is_none() { *cb2 = Some(cb) };
+        }
+    }
+}
+
+/// An owned (or requested) bus name, returned from `Connection::request_name_tracked`.
+///
+/// The current state is updated from the NameAcquired and NameLost signals, which are
+/// processed while reading incoming messages, e g through `Connection::incoming`.
+/// The name is released when the NameOwner is dropped.
+pub struct NameOwner<C: ops::Deref<Target = Connection>> {
+    conn: C,
+    name: String,
+    inner: Rc<NameInner>,
+}
+
+impl<C: ops::Deref<Target = Connection>> NameOwner<C> {
+    /// Requests a name on a connection, or some reference to it.
+    ///
+    /// See `Connection::request_name_tracked` for details.
+    pub fn new(conn: C, name: &str, flags: u32) -> Result<Self, Error> {
+        let m = Message::method_call(&"org.freedesktop.DBus".into(), &"/org/freedesktop/DBus".into(),
+            &"org.freedesktop.DBus".into(), &"RequestName".into()).append2(name, flags);
+        let mut r = try!(conn.send_with_reply_and_block(m, -1));
+        // NameAcquired and NameLost signals sent before the reply are already accounted for by the reply,
+        // or left over from an earlier request of the same name.
+        let serial = r.get_serial();
+        let state = match try!(try!(r.as_result()).read1::<u32>()) {
+            1 | 4 => NameState::Primary,
+            2 => NameState::InQueue,
+            3 => NameState::Lost,
+            x => return Err(Error::new_custom("org.freedesktop.DBus.Error.Failed", &format!("Invalid RequestName reply {}", x))),
+        };
+        let inner = Rc::new(NameInner { state: Cell::new(state), active: Cell::new(true), callback: RefCell::new(None) });
+        let queue = flags & NameFlag::DoNotQueue.value() == 0;
+        conn.add_handler(NameHandler { name: name.into(), queue: queue, serial: serial, inner: inner.clone() });
+        Ok(NameOwner { conn: conn, name: name.into(), inner: inner })
+    }
+
+    /// The requested name.
+    pub fn name(&self) -> &str { &self.name }
+
+    /// Whether we currently own the name, are waiting for it, or have lost it.
+    pub fn state(&self) -> NameState { self.inner.state.get() }
+
+    /// Sets a callback to be called whenever the state changes.
+    ///
+    /// This replaces any callback set earlier.
+    pub fn on_change<F: FnMut(NameState) + 'static>(&self, f: F) {
+        *self.inner.callback.borrow_mut() = Some(Box::new(f));
+    }
+}
+
+impl<C: ops::Deref<Target = Connection>> Drop for NameOwner<C> {
+    fn drop(&mut self) {
+        self.inner.active.set(false);
+        if self.inner.state.get() != NameState::Lost { let _ = self.conn.release_name(&self.name); }
+    }
+}
+
+impl<C: ops::Deref<Target = Connection>> fmt::Debug for NameOwner<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "NameOwner({}, {:?})", self.name, self.inner.state.get())
+    }
+}
+
+struct NameHandler {
+    name: String,
+    // If we lose the name to somebody else, we end up in the queue unless we asked not to.
+    queue: bool,
+    // The serial of the RequestName reply; signals with lower serials are stale.
+    serial: u32,
+    inner: Rc<NameInner>,
+}
+
+impl MsgHandler for NameHandler {
+    fn handler_type(&self) -> MsgHandlerType { MsgHandlerType::MsgType(MessageType::Signal) }
+    fn handle_msg(&mut self, msg: &Message) -> Option<MsgHandlerResult> {
+        if !self.inner.active.get() { return Some(MsgHandlerResult { handled: false, done: true, reply: Vec::new() }) }
+        if msg.sender().map(|s| &*s == "org.freedesktop.DBus") != Some(true) { return None }
+        if msg.interface().map(|i| &*i == "org.freedesktop.DBus") != Some(true) { return None }
+        if msg.get1::<&str>() != Some(&*self.name) { return None }
+        if msg.get_serial() < self.serial { return None }
+        match msg.member().as_ref().map(|m| &**m) {
+            Some("NameAcquired") => self.inner.set(NameState::Primary),
+            Some("NameLost") => self.inner.set(if self.queue { NameState::InQueue } else { NameState::Lost }),
+            _ => {},
+        }
+        None
+    }
+}
+
+#[test]
+fn test_name_owner() {
+    use testing::TestBus;
+    let bus = TestBus::new().unwrap();
+    let c1 = bus.connection().unwrap();
+    let c2 = bus.connection().unwrap();
+    let n = "com.example.nameowner";
+
+    let o1 = c1.request_name_tracked(n, NameFlag::AllowReplacement.value()).unwrap();
+    assert_eq!(o1.state(), NameState::Primary);
+    let changes = Rc::new(RefCell::new(vec!()));
+    let changes2 = changes.clone();
+    o1.on_change(move |s| changes2.borrow_mut().push(s));
+
+    let o2 = c2.request_name_tracked(n, 0).unwrap();
+    assert_eq!(o2.state(), NameState::InQueue);
+    drop(o2);
+    let o3 = c2.request_name_tracked(n, NameFlag::ReplaceExisting.value()).unwrap();
+    assert_eq!(o3.state(), NameState::Primary);
+    for _ in c1.incoming(200) {}
+    assert_eq!(o1.state(), NameState::InQueue);
+
+    // Dropping releases the name, so we get it back.
+    drop(o3);
+    for _ in c1.incoming(200) {}
+    assert_eq!(o1.state(), NameState::Primary);
+    assert_eq!(*changes.borrow(), vec!(NameState::InQueue, NameState::Primary));
+
+    let o4 = c2.request_name_tracked(n, NameFlag::DoNotQueue.value()).unwrap();
+    assert_eq!(o4.state(), NameState::Lost);
+
+    // Signals from before the request, not yet read, do not change the state.
+    drop(o1);
+    drop(o4);
+    let o5 = c2.request_name_tracked(n, 0).unwrap();
+    drop(o5);
+    let o6 = c2.request_name_tracked(n, 0).unwrap();
+    assert_eq!(o6.state(), NameState::Primary);
+    let changes2 = changes.clone();
+    o6.on_change(move |s| changes2.borrow_mut().push(s));
+    for _ in c2.incoming(200) {}
+    assert_eq!(o6.state(), NameState::Primary);
+    assert_eq!(changes.borrow().len(), 2);
+}
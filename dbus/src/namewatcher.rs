@@ -0,0 +1,189 @@
+//! Watching the owner of a bus name, like GDBus's g_bus_watch_name.
+
+use {Connection, Message, Error, BusName, SignalArgs};
+use cacheclient::{RemoteCache, CacheClient, CacheEvents};
+use stdintf::org_freedesktop_dbus::DBusNameOwnerChanged;
+
+const BUS_NAME: &'static str = "org.freedesktop.DBus";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A change to the owner of a watched name. The owners are unique names.
+pub enum NameWatchEvent {
+    /// The name got an owner.
+    Appeared(BusName<'static>),
+    /// The name lost its owner, and has no owner now.
+    Vanished(BusName<'static>),
+    /// The name was taken over by another owner.
+    OwnerChanged {
+        /// The previous owner
+        old: BusName<'static>,
+        /// The new owner
+        new: BusName<'static>,
+    },
+}
+
+#[derive(Debug)]
+/// Knows the owner of a bus name, without doing any I/O itself.
+///
+/// For main loops other than `Connection::incoming`: add `match_rule` to the bus, send
+/// `get_name_owner` and pass the reply (or error) to `handle_reply`. From then on, pass every
+/// incoming message to `handle_message`, which picks out the NameOwnerChanged signals for the name.
+pub struct NameWatchCache {
+    name: BusName<'static>,
+    owner: Option<BusName<'static>>,
+    initialized: bool,
+}
+
+impl NameWatchCache {
+    /// Creates a new cache for the owner of "name".
+    pub fn new<N: Into<BusName<'static>>>(name: N) -> NameWatchCache {
+        NameWatchCache { name: name.into(), owner: None, initialized: false }
+    }
+
+    /// The watched name.
+    pub fn name(&self) -> &BusName<'static> { &self.name }
+
+    /// The match rule needed to get the NameOwnerChanged signals for the name.
+    pub fn match_rule(&self) -> String {
+        DBusNameOwnerChanged::match_rule(Some(&BUS_NAME.into()), None).with_arg(0, &*self.name as &str).to_string()
+    }
+
+    /// Creates a GetNameOwner method call.
+    pub fn get_name_owner(&self) -> Message {
+        Message::method_call(&BUS_NAME.into(), &"/org/freedesktop/DBus".into(), &BUS_NAME.into(), &"GetNameOwner".into())
+            .append1(&*self.name as &str)
+    }
+
+    /// Sets the owner from the result of a GetNameOwner method call.
+    ///
+    /// A NameHasNoOwner error means that the name currently has no owner; other errors are returned.
+    pub fn handle_reply(&mut self, reply: Result<Message, Error>) -> Result<(), Error> {
+        self.owner = match reply {
+            Ok(mut r) => {
+                let s: &str = try!(try!(r.as_result()).read1());
+                Some(try!(BusName::new(s).map_err(|e| Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", &e))).into_static())
+            },
+            Err(ref e) if e.name() == Some("org.freedesktop.DBus.Error.NameHasNoOwner") => None,
+            Err(e) => return Err(e),
+        };
+        self.initialized = true;
+        Ok(())
+    }
+
+    // Sets the owner when it is known by other means, e g from the sender of a reply.
+    pub(crate) fn set_owner(&mut self, owner: Option<BusName<'static>>) {
+        self.owner = owner;
+        self.initialized = true;
+    }
+
+    /// The unique name of the current owner, if the name has an owner.
+    pub fn owner(&self) -> Option<&BusName<'static>> { self.owner.as_ref() }
+
+    /// Updates the owner if the message is a NameOwnerChanged signal for the name, and returns the change.
+    ///
+    /// Signals are only accepted after a reply has been given to `handle_reply`. Signals that
+    /// were sent before the GetNameOwner reply, i e where the old owner is not the current one, are ignored.
+    pub fn handle_message(&mut self, m: &Message) -> Option<NameWatchEvent> {
+        if !self.initialized || m.sender().map(|s| &*s == BUS_NAME) != Some(true) { return None }
+        let s = match DBusNameOwnerChanged::from_message(m) { Some(s) => s, None => return None };
+        if s.name != &*self.name { return None }
+        if s.old_owner != self.owner.as_ref().map(|o| &**o).unwrap_or("") { return None }
+        let to_name = |s: String| if s.is_empty() { None } else { BusName::new(s).ok() };
+        let (old, new) = (to_name(s.old_owner), to_name(s.new_owner));
+        self.owner = new.clone();
+        match (old, new) {
+            (None, Some(n)) => Some(NameWatchEvent::Appeared(n)),
+            (Some(o), None) => Some(NameWatchEvent::Vanished(o)),
+            (Some(o), Some(n)) => Some(NameWatchEvent::OwnerChanged { old: o, new: n }),
+            (None, None) => None,
+        }
+    }
+}
+
+impl RemoteCache for NameWatchCache {
+    type Event = NameWatchEvent;
+    fn rules(&self) -> Vec<String> { vec!(self.match_rule()) }
+    fn fetch_call(&self) -> Message { self.get_name_owner() }
+    fn fetched(&mut self, reply: Result<Message, Error>) -> Result<(), Error> { self.handle_reply(reply) }
+    fn update(&mut self, m: &Message) -> Option<NameWatchEvent> { self.handle_message(m) }
+}
+
+/// Watches the owner of a bus name, e g "org.freedesktop.NetworkManager", to know when
+/// a remote service appears, disappears or is replaced.
+///
+/// Asks the bus for the current owner (through GetNameOwner) when created, and after that
+/// follows the NameOwnerChanged signals the bus sends for the name. These arrive like any
+/// other message, so either pass incoming messages to `handle_message` or read them through `events`.
+///
+/// Stops listening for NameOwnerChanged when dropped.
+pub struct NameWatcher<'a>(CacheClient<'a, NameWatchCache>);
+
+impl<'a> NameWatcher<'a> {
+    /// Starts watching "name", waiting up to "timeout_ms" for the bus to tell its current owner.
+    pub fn new<N: Into<BusName<'static>>>(conn: &'a Connection, name: N, timeout_ms: i32) -> Result<NameWatcher<'a>, Error> {
+        CacheClient::new(conn, NameWatchCache::new(name), timeout_ms).map(NameWatcher)
+    }
+
+    /// Asks the bus for the current owner again, e g if NameOwnerChanged signals might have been lost.
+    pub fn refresh(&mut self, timeout_ms: i32) -> Result<(), Error> { self.0.refresh(timeout_ms) }
+
+    /// The watched name.
+    pub fn name(&self) -> &BusName<'static> { self.0.cache().name() }
+
+    /// The unique name of the current owner, or None while nobody owns the name.
+    pub fn owner(&self) -> Option<&BusName<'static>> { self.0.cache().owner() }
+
+    /// Checks whether the message is a NameOwnerChanged signal for the watched name, and if so,
+    /// records the new owner and tells how it changed.
+    pub fn handle_message(&mut self, m: &Message) -> Option<NameWatchEvent> { self.0.handle_message(m) }
+
+    /// Reads incoming messages until the owner changes, or nothing arrives for "timeout_ms".
+    ///
+    /// Messages about other things are read and thrown away, so don't use this if
+    /// the connection is used for anything else.
+    pub fn events<'b>(&'b mut self, timeout_ms: u32) -> NameWatchEvents<'b, 'a> { NameWatchEvents(self.0.events(timeout_ms)) }
+}
+
+/// The owner changes of a watched name, as they are read from the connection. See `NameWatcher::events`.
+pub struct NameWatchEvents<'b, 'a: 'b>(CacheEvents<'b, 'a, NameWatchCache>);
+
+impl<'b, 'a> Iterator for NameWatchEvents<'b, 'a> {
+    type Item = NameWatchEvent;
+    fn next(&mut self) -> Option<NameWatchEvent> { self.0.next() }
+}
+
+#[test]
+fn test_name_watcher() {
+    use testing::TestBus;
+    use NameFlag;
+
+    let bus = TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
+    let c2 = bus.connection().unwrap();
+    let c3 = bus.connection().unwrap();
+    let n = "com.example.watched";
+
+    let mut w = NameWatcher::new(&c, n, 2000).unwrap();
+    assert_eq!(w.owner(), None);
+
+    c2.register_name(n, NameFlag::AllowReplacement.value() | NameFlag::DoNotQueue.value()).unwrap();
+    assert_eq!(w.events(2000).next(), Some(NameWatchEvent::Appeared(c2.unique_name().into())));
+    assert_eq!(w.owner().map(|o| &**o), Some(&*c2.unique_name()));
+
+    c3.register_name(n, NameFlag::ReplaceExisting.value()).unwrap();
+    assert_eq!(w.events(2000).next(), Some(NameWatchEvent::OwnerChanged {
+        old: c2.unique_name().into(), new: c3.unique_name().into() }));
+
+    c3.release_name(n).unwrap();
+    assert_eq!(w.events(2000).next(), Some(NameWatchEvent::Vanished(c3.unique_name().into())));
+    assert_eq!(w.owner(), None);
+
+    // Other names are not reported.
+    c2.register_name("com.example.other", 0).unwrap();
+    assert!(w.events(200).next().is_none());
+
+    // An existing owner is picked up when the watcher is created.
+    c3.register_name(n, 0).unwrap();
+    let w2 = NameWatcher::new(&c2, n, 2000).unwrap();
+    assert_eq!(w2.owner().map(|o| &**o), Some(&*c3.unique_name()));
+}
@@ -0,0 +1,476 @@
+//! A D-Bus connection implemented in Rust [unstable / experimental]
+//!
+//! Enabled with the `native` cargo feature. The connection, authentication and
+//! (de)marshalling are done by this crate instead of by libdbus, and messages are
+//! `marshal::RawMessage`s, which do not depend on libdbus either.
+//!
+//! To build without libdbus altogether (e g for static musl binaries), turn off the default
+//! `libdbus-sys` feature: `dbus = { version = "...", default-features = false, features = ["native"] }`.
+//! What remains of the crate is this module, the `marshal` module and the string types.
+//!
+//! Only the unix socket transport is supported, with either the EXTERNAL or ANONYMOUS
+//! authentication mechanisms. Passing file descriptors is not supported.
+
+use {BusType, Error, Watch};
+use marshal::{self, Endianness, RawMessage, Value};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::os::unix::net::UnixStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::{env, str};
+
+const DEFAULT_SYSTEM_BUS_ADDRESS: &'static str = "unix:path=/var/run/dbus/system_bus_socket";
+
+fn failed(s: &str) -> Error { Error::new_custom("org.freedesktop.DBus.Error.Failed", s) }
+
+fn io_error(e: io::Error) -> Error { Error::new_custom("org.freedesktop.DBus.Error.IOError", &e.to_string()) }
+
+fn bad_address(s: &str) -> Error { Error::new_custom("org.freedesktop.DBus.Error.BadAddress", s) }
+
+/// Returns the address of a well-known bus, as found in the environment.
+pub fn bus_address(bus: BusType) -> Result<String, Error> {
+    let (var, default) = match bus {
+        BusType::Session => ("DBUS_SESSION_BUS_ADDRESS", None),
+        BusType::System => ("DBUS_SYSTEM_BUS_ADDRESS", Some(DEFAULT_SYSTEM_BUS_ADDRESS)),
+        BusType::Starter => ("DBUS_STARTER_ADDRESS", None),
+    };
+    match (env::var(var), default) {
+        (Ok(s), _) => Ok(s),
+        (Err(_), Some(d)) => Ok(d.into()),
+        (Err(_), None) => Err(bad_address(&format!("{} is not set", var))),
+    }
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, Error> {
+    let b = s.as_bytes();
+    let mut r = vec!();
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' {
+            let h = try!(b.get(i+1..i+3).and_then(|h| str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or_else(|| bad_address("Invalid escape sequence in address")));
+            r.push(h);
+            i += 3;
+        } else {
+            r.push(b[i]);
+            i += 1;
+        }
+    }
+    Ok(r)
+}
+
+fn connect_one(address: &str) -> Result<UnixStream, Error> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let colon = try!(address.find(':').ok_or_else(|| bad_address("Address has no transport")));
+    let (transport, params) = (&address[..colon], &address[colon+1..]);
+    if transport != "unix" { return Err(bad_address(&format!("Unsupported transport '{}'", transport))) }
+    for kv in params.split(',') {
+        let eq = try!(kv.find('=').ok_or_else(|| bad_address("Invalid key/value pair in address")));
+        let (k, v) = (&kv[..eq], try!(unescape(&kv[eq+1..])));
+        match k {
+            "path" => return UnixStream::connect(OsStr::from_bytes(&v)).map_err(io_error),
+            #[cfg(target_os = "linux")]
+            "abstract" => return connect_abstract(&v),
+            _ => {},
+        }
+    }
+    Err(bad_address("Address has neither path nor abstract key"))
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &[u8]) -> Result<UnixStream, Error> {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // An abstract name is a leading NUL byte followed by the name, which is not NUL terminated.
+    if name.len() >= addr.sun_path.len() { return Err(bad_address("Abstract socket name is too long")) }
+    for (d, &c) in addr.sun_path[1..].iter_mut().zip(name) { *d = c as libc::c_char; }
+    let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 { return Err(io_error(io::Error::last_os_error())) }
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    let r = unsafe { libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len as libc::socklen_t) };
+    if r < 0 { return Err(io_error(io::Error::last_os_error())) }
+    Ok(stream)
+}
+
+/// Connects to the first reachable address in a semicolon separated address list.
+fn connect(address: &str) -> Result<UnixStream, Error> {
+    let mut err = bad_address("Empty address");
+    for a in address.split(';').filter(|a| a.len() > 0) {
+        match connect_one(a) {
+            Ok(s) => return Ok(s),
+            Err(e) => err = e,
+        }
+    }
+    Err(err)
+}
+
+fn hex_encode(b: &[u8]) -> String { b.iter().map(|c| format!("{:02x}", c)).collect() }
+
+fn sasl_command(r: &mut BufReader<&UnixStream>, cmd: &str) -> Result<String, Error> {
+    let mut w: &UnixStream = *r.get_ref();
+    try!(w.write_all(cmd.as_bytes()).and_then(|_| w.write_all(b"\r\n")).map_err(io_error));
+    let mut line = String::new();
+    try!(r.read_line(&mut line).map_err(io_error));
+    if !line.ends_with("\r\n") { return Err(failed("Server closed connection during authentication")) }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// Authenticates with EXTERNAL, falling back to ANONYMOUS. Returns the server guid.
+fn authenticate(s: &UnixStream) -> Result<String, Error> {
+    try!((&*s).write_all(b"\0").map_err(io_error));
+    // The reader must never read past the final OK line, but the server does not send
+    // anything more until we've sent BEGIN.
+    let mut r = BufReader::new(s);
+    let uid = unsafe { ::libc::getuid() }.to_string();
+    let mechs = [format!("AUTH EXTERNAL {}", hex_encode(uid.as_bytes())),
+        format!("AUTH ANONYMOUS {}", hex_encode(b"dbus-rs"))];
+    for m in &mechs {
+        let reply = try!(sasl_command(&mut r, m));
+        if reply.starts_with("OK ") {
+            try!((&*s).write_all(b"BEGIN\r\n").map_err(io_error));
+            return Ok(reply[3..].into());
+        }
+        if !reply.starts_with("REJECTED") {
+            let _ = sasl_command(&mut r, "CANCEL");
+        }
+    }
+    Err(Error::new_custom("org.freedesktop.DBus.Error.AuthFailed", "No supported authentication mechanism was accepted"))
+}
+
+#[derive(Default)]
+struct Outgoing {
+    queue: VecDeque<Vec<u8>>,
+    // How much of the first message in the queue has been written
+    written: usize,
+}
+
+#[derive(Default)]
+struct Incoming {
+    buf: Vec<u8>,
+    queue: VecDeque<RawMessage>,
+}
+
+/// A connection to a D-Bus bus or peer, without libdbus' connection handling [unstable / experimental]
+///
+/// This has the same interface as `dbus::TxRx`, so code written for one works with the other.
+/// Like `TxRx`, this struct is Send + Sync.
+#[derive(Debug)]
+pub struct TxRx {
+    stream: UnixStream,
+    guid: String,
+    unique_name: Option<String>,
+    serial: AtomicU32,
+    connected: AtomicBool,
+    outgoing: Mutex<Outgoing>,
+    incoming: Mutex<Incoming>,
+}
+
+impl ::std::fmt::Debug for Outgoing {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result { write!(f, "{} queued", self.queue.len()) }
+}
+
+impl ::std::fmt::Debug for Incoming {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result { write!(f, "{} queued", self.queue.len()) }
+}
+
+impl TxRx {
+    /// Creates a new D-Bus connection.
+    ///
+    /// Blocking: until the connection is up and running.
+    pub fn get_private(bus: BusType) -> Result<TxRx, Error> {
+        let mut c = try!(Self::open_private(&try!(bus_address(bus))));
+        try!(c.register());
+        Ok(c)
+    }
+
+    /// Creates a new D-Bus connection to a remote address, and authenticates.
+    ///
+    /// Note: for all common cases (System / Session bus) you probably want "get_private" instead.
+    ///
+    /// Blocking: until the connection is established.
+    pub fn open_private(address: &str) -> Result<TxRx, Error> {
+        let stream = try!(connect(address));
+        let guid = try!(authenticate(&stream));
+        try!(stream.set_nonblocking(true).map_err(io_error));
+        Ok(TxRx {
+            stream: stream,
+            guid: guid,
+            unique_name: None,
+            serial: AtomicU32::new(1),
+            connected: AtomicBool::new(true),
+            outgoing: Default::default(),
+            incoming: Default::default(),
+        })
+    }
+
+    /// Registers a new D-Bus connection with the bus.
+    ///
+    /// Note: `get_private` does this automatically, useful with `open_private`
+    ///
+    /// Blocking: until a "Hello" response is received from the server.
+    /// Messages received before that are kept in the incoming queue.
+    pub fn register(&mut self) -> Result<(), Error> {
+        if self.unique_name.is_some() { return Ok(()) }
+        let m = RawMessage::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "Hello").unwrap();
+        let serial = try!(self.send(m).map_err(|_| failed("Sending Hello failed")));
+        loop {
+            if let Some(mut reply) = self.take_reply(serial) {
+                let name = match try!(reply.as_result()).body().first() {
+                    Some(&Value::Str(ref name)) => name.clone(),
+                    _ => return Err(failed("Invalid reply to Hello")),
+                };
+                self.unique_name = Some(name);
+                return Ok(());
+            }
+            if self.read_write(None).is_err() || !self.is_connected() {
+                return Err(Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "Connection closed before Hello reply"))
+            }
+        }
+    }
+
+    fn take_reply(&self, serial: u32) -> Option<RawMessage> {
+        let mut i = self.incoming.lock().unwrap();
+        let pos = i.queue.iter().position(|m| m.get_reply_serial() == Some(serial));
+        pos.and_then(|p| i.queue.remove(p))
+    }
+
+    /// Gets whether the connection is currently open.
+    pub fn is_connected(&self) -> bool { self.connected.load(Ordering::SeqCst) }
+
+    /// Get the connection's unique name.
+    ///
+    /// It's usually something like ":1.54"
+    pub fn unique_name(&self) -> Option<&str> { self.unique_name.as_ref().map(|s| &**s) }
+
+    /// The guid of the server we're connected to, as received during authentication.
+    pub fn server_guid(&self) -> &str { &self.guid }
+
+    /// Puts a message into the out queue. Use "flush" or "read_write" to make sure it is sent over the wire.
+    ///
+    /// Returns a serial number than can be used to match against a reply.
+    pub fn send(&self, mut msg: RawMessage) -> Result<u32, ()> {
+        if !self.is_connected() { return Err(()) }
+        let serial = self.next_serial();
+        msg.set_serial(serial);
+        let b = try!(msg.marshal(Endianness::native()).map_err(|_| ()));
+        self.outgoing.lock().unwrap().queue.push_back(b);
+        Ok(serial)
+    }
+
+    fn next_serial(&self) -> u32 {
+        loop {
+            let serial = self.serial.fetch_add(1, Ordering::SeqCst);
+            // Zero is not a valid serial, so skip it when the counter wraps around.
+            if serial != 0 { return serial }
+        }
+    }
+
+    /// Writes as much as possible without blocking. Returns true if everything was written.
+    fn write_some(&self) -> Result<bool, ()> {
+        let mut o = self.outgoing.lock().unwrap();
+        while let Some(b) = o.queue.pop_front() {
+            match (&self.stream).write(&b[o.written..]) {
+                // The socket won't take any more, so treat it like any other write error.
+                Ok(0) => { o.queue.push_front(b); self.disconnected(); return Err(()) },
+                Ok(n) => {
+                    o.written += n;
+                    if o.written < b.len() { o.queue.push_front(b); continue; }
+                    o.written = 0;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => o.queue.push_front(b),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { o.queue.push_front(b); return Ok(false) },
+                Err(_) => { o.queue.push_front(b); self.disconnected(); return Err(()) },
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads as much as possible without blocking, and parses any complete messages.
+    fn read_some(&self) -> Result<(), ()> {
+        let mut i = self.incoming.lock().unwrap();
+        let mut chunk = [0u8; 4096];
+        loop {
+            match (&self.stream).read(&mut chunk) {
+                Ok(0) => { drop(i); self.disconnected(); return Err(()) },
+                Ok(n) => i.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => { drop(i); self.disconnected(); return Err(()) },
+            }
+        }
+        loop {
+            let needed = match marshal::bytes_needed(&i.buf) {
+                Ok(n) => n,
+                Err(_) => { drop(i); self.disconnected(); return Err(()) },
+            };
+            if i.buf.len() < needed { return Ok(()) }
+            let m = RawMessage::demarshal(&i.buf[..needed]);
+            i.buf.drain(..needed);
+            match m {
+                Ok(m) => i.queue.push_back(m),
+                // A message we can't parse means we have lost track of the stream.
+                Err(_) => { drop(i); self.disconnected(); return Err(()) },
+            }
+        }
+    }
+
+    fn disconnected(&self) {
+        if !self.connected.swap(false, Ordering::SeqCst) { return }
+        let _ = self.stream.shutdown(::std::net::Shutdown::Both);
+        // Same as libdbus does, so that applications can detect the disconnection.
+        let m = RawMessage::new_signal("/org/freedesktop/DBus/Local", "org.freedesktop.DBus.Local", "Disconnected").unwrap();
+        self.incoming.lock().unwrap().queue.push_back(m);
+    }
+
+    /// Flush the queue of outgoing messages.
+    ///
+    /// Blocking: until the outgoing queue is empty.
+    pub fn flush(&self) {
+        while let Ok(false) = self.write_some() {
+            let _ = self.poll(false, true, -1);
+        }
+    }
+
+    fn poll(&self, read: bool, write: bool, timeout_ms: i32) -> Result<(bool, bool), ()> {
+        use libc;
+        let mut pfd = libc::pollfd { fd: self.stream.as_raw_fd(), revents: 0,
+            events: if read { libc::POLLIN } else { 0 } | if write { libc::POLLOUT } else { 0 } };
+        let r = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if r < 0 {
+            return if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted { Ok((false, false)) } else { Err(()) }
+        }
+        let hup = (pfd.revents & (libc::POLLERR | libc::POLLHUP)) != 0;
+        Ok(((pfd.revents & libc::POLLIN) != 0 || hup, (pfd.revents & libc::POLLOUT) != 0))
+    }
+
+    /// Read and write to the connection.
+    ///
+    /// Incoming messages are put in the internal queue, outgoing messages are written.
+    ///
+    /// Blocking: If there are no messages, for up to timeout_ms milliseconds, or forever if timeout_ms is None.
+    /// For non-blocking behaviour, set timeout_ms to Some(0).
+    pub fn read_write(&self, timeout_ms: Option<i32>) -> Result<(), ()> {
+        if !self.is_connected() { return Err(()) }
+        let all_written = try!(self.write_some());
+        let (r, w) = try!(self.poll(true, !all_written, timeout_ms.unwrap_or(-1)));
+        if w { try!(self.write_some()); }
+        if r { try!(self.read_some()); }
+        Ok(())
+    }
+
+    /// Removes a message from the incoming queue, or returns None if the queue is empty.
+    ///
+    /// Use "read_write" first, so that messages are put into the incoming queue.
+    /// For unhandled messages, please call MessageDispatcher::default_dispatch to return
+    /// default replies for method calls.
+    pub fn pop_message(&self) -> Option<RawMessage> {
+        self.incoming.lock().unwrap().queue.pop_front()
+    }
+
+    /// Get an up-to-date list of file descriptors to watch.
+    pub fn watch_fds(&mut self) -> Result<Vec<Watch>, ()> {
+        let write = self.outgoing.lock().unwrap().queue.len() > 0;
+        Ok(vec!(Watch::new(self.stream.as_raw_fd(), true, write)))
+    }
+}
+
+impl AsRawFd for TxRx {
+    fn as_raw_fd(&self) -> RawFd { self.stream.as_raw_fd() }
+}
+
+#[cfg(all(test, feature = "libdbus-sys"))]
+fn test_txrx(bus: &::testing::TestBus) -> TxRx {
+    let mut c = TxRx::open_private(bus.address()).unwrap();
+    c.register().unwrap();
+    c
+}
+
+#[cfg(feature = "libdbus-sys")]
+#[test]
+fn native_send_sync() {
+    fn is_send<T: Send>(_: &T) {}
+    fn is_sync<T: Sync>(_: &T) {}
+    let bus = ::testing::TestBus::new().unwrap();
+    let c = test_txrx(&bus);
+    is_send(&c);
+    is_sync(&c);
+}
+
+#[cfg(feature = "libdbus-sys")]
+#[test]
+fn native_listnames() {
+    let bus = ::testing::TestBus::new().unwrap();
+    let c = test_txrx(&bus);
+    assert!(c.is_connected());
+    let my_name = c.unique_name().unwrap().to_string();
+    assert!(my_name.starts_with(":"));
+    let m = RawMessage::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "ListNames").unwrap();
+    let reply = c.send(m).unwrap();
+    loop {
+        while let Some(mut msg) = c.pop_message() {
+            if msg.get_reply_serial() == Some(reply) {
+                let r = msg.as_result().unwrap();
+                match r.body().first() {
+                    Some(&Value::Array(ref sig, ref z)) if sig == "s" => {
+                        assert!(z.contains(&Value::Str(my_name)));
+                        return;
+                    }
+                    x => panic!("Unexpected ListNames reply {:?}", x),
+                }
+            }
+            assert!(msg.msg_type() != ::MessageType::MethodCall);
+        }
+        c.read_write(Some(100)).unwrap();
+    }
+}
+
+#[cfg(feature = "libdbus-sys")]
+#[test]
+fn native_large_message() {
+    let bus = ::testing::TestBus::new().unwrap();
+    let c = test_txrx(&bus);
+    let data: Vec<_> = (0..1024 * 1024).map(|_| Value::Byte(7)).collect();
+    // Send a large message to ourselves and make sure it comes back intact.
+    let m = RawMessage::new_method_call(c.unique_name().unwrap(), "/", "com.example.Test", "Big").unwrap()
+        .append(Value::Array("y".into(), data.clone()));
+    let serial = c.send(m).unwrap();
+    loop {
+        if let Some(msg) = c.pop_message() {
+            if msg.get_serial() != serial || msg.sender().map(|n| &**n) != c.unique_name() { continue; }
+            assert_eq!(msg.body(), &[Value::Array("y".into(), data)][..]);
+            break;
+        }
+        c.read_write(Some(1000)).unwrap();
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn native_abstract_address() {
+    // Nobody listens there, but the address is understood.
+    let e = TxRx::open_private("unix:abstract=dbus-rs-nonexistent-%00socket").unwrap_err();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.IOError"));
+    let e = TxRx::open_private(&format!("unix:abstract={}", "x".repeat(200))).unwrap_err();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.BadAddress"));
+}
+
+#[test]
+fn native_serial_skips_zero() {
+    let (a, _b) = UnixStream::pair().unwrap();
+    let c = TxRx { stream: a, guid: String::new(), unique_name: None, serial: AtomicU32::new(u32::max_value()),
+        connected: AtomicBool::new(true), outgoing: Default::default(), incoming: Default::default() };
+    assert_eq!(c.next_serial(), u32::max_value());
+    assert_eq!(c.next_serial(), 1);
+}
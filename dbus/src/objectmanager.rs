@@ -0,0 +1,285 @@
+//! Client side of org.freedesktop.DBus.ObjectManager.
+
+use {Connection, Message, MessageType, Error, Path, BusName, SignalArgs, NameWatchCache, NameWatchEvent};
+use cacheclient::{RemoteCache, CacheClient, CacheEvents};
+use arg::{RefArg, Variant};
+use stdintf::org_freedesktop_dbus::{ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved, PropertiesPropertiesChanged};
+use std::collections::{BTreeMap, HashMap};
+
+/// The properties of an interface, and their values.
+pub type PropMap = BTreeMap<String, Variant<Box<RefArg>>>;
+
+/// Objects managed by an object manager: their paths, interfaces, and the properties of these interfaces.
+pub type ManagedObjects = BTreeMap<Path<'static>, BTreeMap<String, PropMap>>;
+
+const OBJECT_MANAGER: &'static str = "org.freedesktop.DBus.ObjectManager";
+
+fn prop_map(h: HashMap<String, Variant<Box<RefArg>>>) -> PropMap { h.into_iter().collect() }
+
+#[derive(Debug)]
+/// A change to the objects of an object manager.
+pub enum ObjectManagerEvent {
+    /// An object was added, or got interfaces added to it.
+    InterfacesAdded(Path<'static>, BTreeMap<String, PropMap>),
+    /// Interfaces were removed from an object. If the object has no interfaces left, it has been removed.
+    InterfacesRemoved(Path<'static>, Vec<String>),
+    /// Properties of an object changed.
+    PropertiesChanged {
+        /// The object
+        path: Path<'static>,
+        /// The interface the properties belong to
+        interface: String,
+        /// The properties that changed, and their new values
+        changed: PropMap,
+        /// Properties that changed, but whose new values were not sent.
+        /// These are removed from the cached objects.
+        invalidated: Vec<String>,
+    },
+    /// The bus name of the object manager got a new owner, or lost its owner (None).
+    ///
+    /// The objects of the previous owner have been removed. `ObjectManagerClient` gets the
+    /// objects of the new owner right away; with `ObjectManagerCache`, send a new
+    /// `get_managed_objects` call and give its reply to `handle_reply`.
+    OwnerChanged(Option<BusName<'static>>),
+}
+
+#[derive(Debug)]
+/// A copy of the objects of a remote object manager, which is updated from the messages you feed it.
+///
+/// To use it from your own main loop: add all of `match_rules` to the bus, send `get_managed_objects`,
+/// and load the reply with `handle_reply`. Then pass every incoming message to `handle_message`;
+/// signals from anybody but the object manager are ignored.
+///
+/// The object manager is identified by the sender of the reply. If "dest" is a well-known name
+/// and another process takes it over, the objects are dropped and `ObjectManagerEvent::OwnerChanged`
+/// is returned; send `get_managed_objects` again to load the objects of the new owner.
+pub struct ObjectManagerCache {
+    dest: BusName<'static>,
+    path: Path<'static>,
+    owner: NameWatchCache,
+    objects: ManagedObjects,
+}
+
+impl ObjectManagerCache {
+    /// Creates a new cache for the object manager at "path" of "dest".
+    pub fn new<D: Into<BusName<'static>>, P: Into<Path<'static>>>(dest: D, path: P) -> ObjectManagerCache {
+        let dest = dest.into();
+        ObjectManagerCache { owner: NameWatchCache::new(dest.clone()), dest: dest, path: path.into(), objects: BTreeMap::new() }
+    }
+
+    /// The match rules needed to get the signals from the object manager, and changes to its owner.
+    pub fn match_rules(&self) -> Vec<String> {
+        vec!(ObjectManagerInterfacesAdded::match_str(Some(&self.dest), Some(&self.path)),
+            ObjectManagerInterfacesRemoved::match_str(Some(&self.dest), Some(&self.path)),
+            PropertiesPropertiesChanged::match_rule(Some(&self.dest), None).with_path_namespace(self.path.clone()).to_string(),
+            self.owner.match_rule())
+    }
+
+    /// Creates a GetManagedObjects method call.
+    pub fn get_managed_objects(&self) -> Message {
+        Message::method_call(&self.dest, &self.path, &OBJECT_MANAGER.into(), &"GetManagedObjects".into())
+    }
+
+    /// Replaces the objects with the ones from the reply to a GetManagedObjects method call.
+    pub fn handle_reply(&mut self, reply: &mut Message) -> Result<(), Error> {
+        let objects: HashMap<Path<'static>, HashMap<String, HashMap<String, Variant<Box<RefArg>>>>> =
+            try!(try!(reply.as_result()).read1());
+        self.objects = objects.into_iter().map(|(p, i)| (p, i.into_iter().map(|(k, v)| (k, prop_map(v))).collect())).collect();
+        self.owner.set_owner(reply.sender().map(|s| s.into_static()));
+        Ok(())
+    }
+
+    /// The objects, as currently known.
+    pub fn objects(&self) -> &ManagedObjects { &self.objects }
+
+    fn in_namespace(&self, p: &str) -> bool {
+        let ns: &str = &self.path;
+        p == ns || ns == "/" || (p.starts_with(ns) && p.as_bytes().get(ns.len()) == Some(&b'/'))
+    }
+
+    /// Updates the objects if the message is a signal from the object manager, and returns the change.
+    ///
+    /// Signals are only accepted after a reply has been given to `handle_reply`, because the
+    /// reply tells the unique name of the object manager's owner.
+    pub fn handle_message(&mut self, m: &Message) -> Option<ObjectManagerEvent> {
+        if let Some(e) = self.owner.handle_message(m) {
+            self.objects.clear();
+            return Some(ObjectManagerEvent::OwnerChanged(match e {
+                NameWatchEvent::Appeared(n) | NameWatchEvent::OwnerChanged { new: n, .. } => Some(n),
+                NameWatchEvent::Vanished(_) => None,
+            }));
+        }
+        if m.msg_type() != MessageType::Signal { return None }
+        match (m.sender(), self.owner.owner()) {
+            (Some(ref s), Some(o)) if &**s == &**o => {},
+            _ => return None,
+        }
+        let p = match m.path() { Some(p) => p, None => return None };
+        if let Some(s) = ObjectManagerInterfacesAdded::from_message(m) {
+            if &*p != &*self.path { return None }
+            let ifaces: BTreeMap<_, _> = s.interfaces.into_iter().map(|(k, v)| (k, prop_map(v))).collect();
+            let o = self.objects.entry(s.object.clone()).or_insert_with(BTreeMap::new);
+            for (k, v) in ifaces.iter() {
+                o.insert(k.clone(), v.iter().map(|(pk, pv)| (pk.clone(), Variant(pv.0.box_clone()))).collect());
+            }
+            Some(ObjectManagerEvent::InterfacesAdded(s.object, ifaces))
+        } else if let Some(s) = ObjectManagerInterfacesRemoved::from_message(m) {
+            if &*p != &*self.path { return None }
+            let empty = self.objects.get_mut(&s.object).map(|o| {
+                for i in s.interfaces.iter() { o.remove(i); }
+                o.is_empty()
+            });
+            if empty == Some(true) { self.objects.remove(&s.object); }
+            Some(ObjectManagerEvent::InterfacesRemoved(s.object, s.interfaces))
+        } else if let Some(s) = PropertiesPropertiesChanged::from_message(m) {
+            if !self.in_namespace(&p) { return None }
+            let path = p.into_static();
+            let o = match self.objects.get_mut(&path).and_then(|o| o.get_mut(&s.interface_name)) { Some(o) => o, None => return None };
+            for (k, v) in s.changed_properties.iter() { o.insert(k.clone(), Variant(v.0.box_clone())); }
+            for k in s.invalidated_properties.iter() { o.remove(k); }
+            Some(ObjectManagerEvent::PropertiesChanged { path: path, interface: s.interface_name,
+                changed: prop_map(s.changed_properties), invalidated: s.invalidated_properties })
+        } else { None }
+    }
+}
+
+impl RemoteCache for ObjectManagerCache {
+    type Event = ObjectManagerEvent;
+    fn rules(&self) -> Vec<String> { self.match_rules() }
+    fn fetch_call(&self) -> Message { self.get_managed_objects() }
+    fn fetched(&mut self, reply: Result<Message, Error>) -> Result<(), Error> { self.handle_reply(&mut try!(reply)) }
+    fn update(&mut self, m: &Message) -> Option<ObjectManagerEvent> { self.handle_message(m) }
+    fn refetch(e: &ObjectManagerEvent) -> bool {
+        if let ObjectManagerEvent::OwnerChanged(Some(_)) = *e { true } else { false }
+    }
+}
+
+/// Client side of a remote object manager, e g BlueZ or UDisks2.
+///
+/// Starts with a snapshot of all objects from GetManagedObjects, then applies the InterfacesAdded,
+/// InterfacesRemoved and PropertiesChanged signals to it as they are read through `events`
+/// (or passed to `handle_message`). When another process takes over the object manager's bus name,
+/// the snapshot is taken again from it.
+///
+/// The signals are no longer asked for once the client is dropped.
+pub struct ObjectManagerClient<'a>(CacheClient<'a, ObjectManagerCache>);
+
+impl<'a> ObjectManagerClient<'a> {
+    /// Takes the snapshot of the objects under "path" of "dest".
+    ///
+    /// The timeout is used for this, and for taking the snapshot again after an owner change.
+    pub fn new<D, P>(conn: &'a Connection, dest: D, path: P, timeout_ms: i32) -> Result<ObjectManagerClient<'a>, Error>
+    where D: Into<BusName<'static>>, P: Into<Path<'static>> {
+        CacheClient::new(conn, ObjectManagerCache::new(dest, path), timeout_ms).map(ObjectManagerClient)
+    }
+
+    /// Throws away the objects and takes a new snapshot.
+    pub fn refresh(&mut self, timeout_ms: i32) -> Result<(), Error> { self.0.refresh(timeout_ms) }
+
+    /// The objects, with the signals read so far applied.
+    pub fn objects(&self) -> &ManagedObjects { self.0.cache().objects() }
+
+    /// Applies the message to the objects if it is one of the object manager's signals, and returns what changed.
+    ///
+    /// Blocking: when the object manager got a new owner, while the new snapshot is taken.
+    /// If that fails, there are no objects until the next call to `refresh`.
+    pub fn handle_message(&mut self, m: &Message) -> Option<ObjectManagerEvent> { self.0.handle_message(m) }
+
+    /// Reads incoming messages and returns the changes to the objects, until no message has arrived for "timeout_ms".
+    ///
+    /// Every incoming message is taken, whether it is about the objects or not.
+    pub fn events<'b>(&'b mut self, timeout_ms: u32) -> ObjectManagerEvents<'b, 'a> { ObjectManagerEvents(self.0.events(timeout_ms)) }
+}
+
+/// Changes to the objects of an object manager, see `ObjectManagerClient::events`.
+pub struct ObjectManagerEvents<'b, 'a: 'b>(CacheEvents<'b, 'a, ObjectManagerCache>);
+
+impl<'b, 'a> Iterator for ObjectManagerEvents<'b, 'a> {
+    type Item = ObjectManagerEvent;
+    fn next(&mut self) -> Option<ObjectManagerEvent> { self.0.next() }
+}
+
+#[test]
+fn test_object_manager_client() {
+    use testing::TestBus;
+    use tree::{Factory, Interface, MTFn};
+
+    fn iface(v: i32) -> Interface<MTFn<()>, ()> {
+        let f = Factory::new_fn::<()>();
+        f.interface("com.example.Thing", ()).add_p(f.property::<i32,_>("Value", ()).on_get(move |i, _| { i.append(v); Ok(()) }))
+    }
+
+    let bus = TestBus::new().unwrap();
+    let server = bus.serve("com.example.objmgr", || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/mgr", ()).object_manager()).add(f.object_path("/mgr/a", ()).add(iface(1)))
+    }).unwrap();
+
+    let c = bus.connection().unwrap();
+    let mut omc = ObjectManagerClient::new(&c, "com.example.objmgr", "/mgr", 2000).unwrap();
+    {
+        let o = omc.objects();
+        assert_eq!(o.len(), 2);
+        assert!(o.contains_key(&"/mgr".into()));
+        assert_eq!(o[&"/mgr/a".into()]["com.example.Thing"]["Value"].0.as_i64(), Some(1));
+    }
+
+    server.run(|c, tree| {
+        let f = Factory::new_fn::<()>();
+        tree.insert(f.object_path("/mgr/b", ()).add(iface(2)));
+        c.register_object_path("/mgr/b").unwrap();
+    });
+    match omc.events(2000).next().unwrap() {
+        ObjectManagerEvent::InterfacesAdded(p, i) => {
+            assert_eq!(&*p, "/mgr/b");
+            assert_eq!(i["com.example.Thing"]["Value"].0.as_i64(), Some(2));
+        },
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert_eq!(omc.objects()[&"/mgr/b".into()]["com.example.Thing"]["Value"].0.as_i64(), Some(2));
+
+    server.run(|_, tree| { tree.remove(&"/mgr/a".into()); });
+    match omc.events(2000).next().unwrap() {
+        ObjectManagerEvent::InterfacesRemoved(p, mut i) => {
+            i.sort();
+            assert_eq!(&*p, "/mgr/a");
+            assert_eq!(i, vec!("com.example.Thing".to_string(), "org.freedesktop.DBus.Properties".into()));
+        },
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert!(!omc.objects().contains_key(&"/mgr/a".into()));
+
+    server.run(|c, _| {
+        let s = PropertiesPropertiesChanged { interface_name: "com.example.Thing".into(),
+            changed_properties: vec!(("Value".to_string(), Variant(Box::new(5i32) as Box<RefArg>))).into_iter().collect(),
+            invalidated_properties: vec!("Other".into()) };
+        c.send(s.to_emit_message(&"/mgr/b".into())).unwrap();
+        // Not below the object manager
+        c.send(s.to_emit_message(&"/elsewhere".into())).unwrap();
+    });
+    match omc.events(2000).next().unwrap() {
+        ObjectManagerEvent::PropertiesChanged { path, interface, changed, invalidated } => {
+            assert_eq!((&*path, &*interface), ("/mgr/b", "com.example.Thing"));
+            assert_eq!(changed["Value"].0.as_i64(), Some(5));
+            assert_eq!(invalidated, vec!("Other".to_string()));
+        },
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert_eq!(omc.objects()[&"/mgr/b".into()]["com.example.Thing"]["Value"].0.as_i64(), Some(5));
+    assert!(omc.events(200).next().is_none());
+
+    // The objects go away with the owner, and are fetched again from the new one.
+    server.run(|c, _| { c.release_name("com.example.objmgr").unwrap(); });
+    match omc.events(2000).next().unwrap() {
+        ObjectManagerEvent::OwnerChanged(None) => {},
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert!(omc.objects().is_empty());
+    server.run(|c, _| { c.register_name("com.example.objmgr", 0).unwrap(); });
+    match omc.events(2000).next().unwrap() {
+        ObjectManagerEvent::OwnerChanged(Some(_)) => {},
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert_eq!(omc.objects().len(), 2);
+    assert_eq!(omc.objects()[&"/mgr/b".into()]["com.example.Thing"]["Value"].0.as_i64(), Some(2));
+}
@@ -458,14 +458,17 @@ fn make_objpath<'a>(c: &'a Connection) -> ObjectPath<'a> {
 
 #[test]
 fn test_objpath() {
-    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let bus = super::testing::TestBus::new().unwrap();
+    let addr = bus.address().to_string();
+    let c = bus.connection().unwrap();
     let mut o = make_objpath(&c);
     o.set_registered(true).unwrap();
     let busname = format!("com.example.objpath.test.test_objpath");
     assert_eq!(c.register_name(&busname, super::NameFlag::ReplaceExisting as u32).unwrap(), super::RequestNameReply::PrimaryOwner);
 
     let thread = ::std::thread::spawn(move || {
-        let c = Connection::get_private(super::BusType::Session).unwrap();
+        let c = Connection::open_private(&addr).unwrap();
+        c.register().unwrap();
         let pr = super::Props::new(&c, &*busname, "/echo", "com.example.echo", 5000);
         assert_eq!(pr.get("EchoCount").unwrap(), 7i32.into());
         let m = pr.get_all().unwrap();
@@ -492,7 +495,8 @@ fn test_objpath() {
 /*
 #[test]
 fn test_refcount() {
-    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let bus = super::testing::TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
     let i = {
         let o = make_objpath(&c);
         o.i.clone()
@@ -503,7 +507,8 @@ fn test_refcount() {
 
 #[test]
 fn test_introspect() {
-    let c = Connection::get_private(super::BusType::Session).unwrap();
+    let bus = super::testing::TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
     let mut o = make_objpath(&c);
     o.set_registered(true).unwrap();
     let mut o2 = ObjectPath::new(&c, "/echo/subpath", true);
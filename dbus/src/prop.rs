@@ -1,5 +1,7 @@
-use super::{Connection, Message, MessageItem, Error, Path, Interface, BusName};
-use std::collections::BTreeMap;
+use super::{Connection, Message, MessageItem, MessageType, Error, Path, Interface, BusName, SignalArgs, NameWatchCache};
+use arg::{RefArg, Variant};
+use stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use std::collections::{BTreeMap, HashMap};
 
 /// Client side properties - get and set properties on a remote application.
 pub struct Props<'a> {
@@ -114,11 +116,137 @@ impl<'a> PropHandler<'a> {
 }
 
 
-/* Unfortunately org.freedesktop.DBus has no properties we can use for testing, but PolicyKit should be around on most distros. */
+/// Client side property cache, which is kept up to date through the PropertiesChanged signal.
+///
+/// All properties are fetched when the cache is created. After that, incoming messages
+/// need to be given to `handle_message`, which updates changed properties and forgets
+/// invalidated ones. Invalidated properties are fetched again when next asked for.
+///
+/// The owner of "name" is followed through NameOwnerChanged. When another process takes over
+/// the name, or it goes away, all properties are invalidated, since they belonged to the old owner.
+///
+/// The match rules are removed when the cache is dropped.
+pub struct PropertyCache<'a> {
+    conn: &'a Connection,
+    name: BusName<'a>,
+    path: Path<'a>,
+    interface: Interface<'a>,
+    timeout_ms: i32,
+    owner: NameWatchCache,
+    rule: String,
+    map: BTreeMap<String, Box<RefArg>>,
+    callbacks: BTreeMap<String, Vec<Box<FnMut(Option<&RefArg>) + 'a>>>,
+}
+
+impl<'a> PropertyCache<'a> {
+    /// Adds a match rule for PropertiesChanged, and fetches all properties of the interface.
+    pub fn new<N, P, I>(conn: &'a Connection, name: N, path: P, interface: I, timeout_ms: i32) -> Result<PropertyCache<'a>, Error>
+    where N: Into<BusName<'a>>, P: Into<Path<'a>>, I: Into<Interface<'a>> {
+        let (name, path, interface) = (name.into(), path.into(), interface.into());
+        let rule = PropertiesPropertiesChanged::match_rule(Some(&name), Some(&path)).with_arg(0, &*interface).to_string();
+        let owner = NameWatchCache::new(name.clone().into_static());
+        try!(conn.add_match(&rule));
+        if let Err(e) = conn.add_match(&owner.match_rule()) {
+            let _ = conn.remove_match(&rule);
+            return Err(e);
+        }
+        let mut c = PropertyCache { conn: conn, name: name, path: path, interface: interface, timeout_ms: timeout_ms,
+            owner: owner, rule: rule, map: BTreeMap::new(), callbacks: BTreeMap::new() };
+        try!(c.refresh());
+        Ok(c)
+    }
+
+    /// Fetches all properties again.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        let mut m = Message::method_call(&self.name, &self.path,
+            &"org.freedesktop.DBus.Properties".into(), &"GetAll".into());
+        m = m.append1(&*self.interface);
+        let mut r = try!(self.conn.send_with_reply_and_block(m, self.timeout_ms));
+        let props: HashMap<String, Variant<Box<RefArg>>> = try!(try!(r.as_result()).read1());
+        self.map = props.into_iter().map(|(k, v)| (k, v.0)).collect();
+        self.owner.set_owner(r.sender().map(|s| s.into_static()));
+        Ok(())
+    }
+
+    /// Get a single property's value, fetching it if it is not cached.
+    pub fn get(&mut self, propname: &str) -> Result<&RefArg, Error> {
+        if !self.map.contains_key(propname) {
+            let v: Box<RefArg> = try!(self.conn.with_path(self.name.clone(), self.path.clone(), self.timeout_ms)
+                .get(&self.interface, propname));
+            self.map.insert(propname.to_string(), v);
+        }
+        Ok(&**self.map.get(propname).unwrap())
+    }
+
+    /// Get a single property's value, if it is cached.
+    pub fn get_cached(&self, propname: &str) -> Option<&RefArg> { self.map.get(propname).map(|v| &**v) }
+
+    /// Registers a callback that is called when a property changes.
+    ///
+    /// The callback gets the new value, or None if the property was invalidated.
+    /// In the latter case, use `get` to fetch the new value.
+    pub fn on_change<F: FnMut(Option<&RefArg>) + 'a>(&mut self, propname: &str, f: F) {
+        self.callbacks.entry(propname.to_string()).or_insert_with(Vec::new).push(Box::new(f));
+    }
+
+    /// Updates the cache if the message is a PropertiesChanged signal for this interface, or
+    /// invalidates all properties if the message tells that the name has a new owner.
+    ///
+    /// Returns true if the message was handled.
+    pub fn handle_message(&mut self, m: &Message) -> bool {
+        if self.owner.handle_message(m).is_some() {
+            let map = ::std::mem::replace(&mut self.map, BTreeMap::new());
+            for k in map.keys() {
+                if let Some(cbs) = self.callbacks.get_mut(k) {
+                    for cb in cbs.iter_mut() { cb(None); }
+                }
+            }
+            return true;
+        }
+        if m.msg_type() != MessageType::Signal { return false }
+        match (m.sender(), self.owner.owner()) {
+            (Some(ref s), Some(o)) if &**s == &**o => {},
+            _ => return false,
+        }
+        if m.path().as_ref() != Some(&self.path) { return false }
+        let s = match PropertiesPropertiesChanged::from_message(m) { Some(s) => s, None => return false };
+        if &*s.interface_name != &*self.interface { return false }
+        for (k, v) in s.changed_properties {
+            if let Some(cbs) = self.callbacks.get_mut(&k) {
+                for cb in cbs.iter_mut() { cb(Some(&*v.0)); }
+            }
+            self.map.insert(k, v.0);
+        }
+        for k in s.invalidated_properties {
+            self.map.remove(&k);
+            if let Some(cbs) = self.callbacks.get_mut(&k) {
+                for cb in cbs.iter_mut() { cb(None); }
+            }
+        }
+        true
+    }
+}
+
+impl<'a> Drop for PropertyCache<'a> {
+    fn drop(&mut self) {
+        let _ = self.conn.remove_match(&self.rule);
+        let _ = self.conn.remove_match(&self.owner.match_rule());
+    }
+}
+
+
 #[test]
-fn test_get_policykit_version() {
-    use super::BusType;
-    let c = Connection::get_private(BusType::System).unwrap();
+fn test_props() {
+    use testing::TestBus;
+    use tree::Factory;
+
+    let bus = TestBus::new().unwrap();
+    let _server = bus.serve("org.freedesktop.PolicyKit1", || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/org/freedesktop/PolicyKit1/Authority", ()).add(f.interface("org.freedesktop.PolicyKit1.Authority", ())
+            .add_p(f.property::<&str,_>("BackendVersion", ()).on_get(|i, _| { i.append("0.105"); Ok(()) }))))
+    }).unwrap();
+    let c = bus.connection().unwrap();
     let p = Props::new(&c, "org.freedesktop.PolicyKit1", "/org/freedesktop/PolicyKit1/Authority",
         "org.freedesktop.PolicyKit1.Authority", 10000);
 
@@ -129,8 +257,77 @@ fn test_get_policykit_version() {
 
     assert_eq!(&v, &*v2);
     match v {
-        MessageItem::Str(ref s) => { println!("Policykit Backend version is {}", s); }
+        MessageItem::Str(ref s) => { assert_eq!(s, "0.105"); }
         _ => { panic!("Invalid Get: {:?}", v); }
     };
 }
 
+
+#[test]
+fn test_property_cache() {
+    use testing::TestBus;
+    use tree::Factory;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    let bus = TestBus::new().unwrap();
+    let server = bus.serve("com.example.propcache", || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/thing", ()).add(f.interface("com.example.Thing", ())
+            .add_p(f.property::<i32,_>("Count", ()).on_get(|i, _| { i.append(3i32); Ok(()) }))
+            .add_p(f.property::<&str,_>("Name", ()).on_get(|i, _| { i.append("Three"); Ok(()) }))))
+    }).unwrap();
+
+    let c = bus.connection().unwrap();
+    let changes = Rc::new(RefCell::new(vec!()));
+    let mut pc = PropertyCache::new(&c, "com.example.propcache", "/thing", "com.example.Thing", 2000).unwrap();
+    assert_eq!(pc.get_cached("Count").unwrap().as_i64(), Some(3));
+    assert_eq!(pc.get("Name").unwrap().as_str(), Some("Three"));
+    let changes2 = changes.clone();
+    pc.on_change("Count", move |v| changes2.borrow_mut().push(v.and_then(|v| v.as_i64())));
+    let changes2 = changes.clone();
+    pc.on_change("Name", move |v| changes2.borrow_mut().push(v.and_then(|v| v.as_i64())));
+
+    server.run(|c, _| {
+        let mut s = PropertiesPropertiesChanged { interface_name: "com.example.Other".into(),
+            changed_properties: HashMap::new(), invalidated_properties: vec!("Count".into()) };
+        c.send(s.to_emit_message(&"/thing".into())).unwrap();
+        s.interface_name = "com.example.Thing".into();
+        s.changed_properties.insert("Count".into(), Variant(Box::new(4i32) as Box<RefArg>));
+        s.invalidated_properties = vec!("Name".into());
+        c.send(s.to_emit_message(&"/thing".into())).unwrap();
+    });
+    let mut handled = 0;
+    for m in c.incoming(1000) {
+        if pc.handle_message(&m) { handled += 1; break; }
+    }
+    assert_eq!(handled, 1);
+    assert_eq!(*changes.borrow(), vec!(Some(4), None));
+    assert_eq!(pc.get_cached("Count").unwrap().as_i64(), Some(4));
+    assert!(pc.get_cached("Name").is_none());
+    assert_eq!(pc.get("Name").unwrap().as_str(), Some("Three"));
+
+    // Everything is invalidated when the owner goes away
+    changes.borrow_mut().clear();
+    drop(server);
+    assert!(c.incoming(1000).any(|m| pc.handle_message(&m)));
+    assert_eq!(*changes.borrow(), vec!(None, None));
+    assert!(pc.get_cached("Count").is_none());
+
+    // ...and signals from the new owner are picked up
+    let server = bus.serve("com.example.propcache", || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/thing", ()).add(f.interface("com.example.Thing", ())
+            .add_p(f.property::<i32,_>("Count", ()).on_get(|i, _| { i.append(5i32); Ok(()) }))))
+    }).unwrap();
+    assert!(c.incoming(1000).any(|m| pc.handle_message(&m)));
+    assert_eq!(pc.get("Count").unwrap().as_i64(), Some(5));
+    server.run(|c, _| {
+        let mut s = PropertiesPropertiesChanged { interface_name: "com.example.Thing".into(),
+            changed_properties: HashMap::new(), invalidated_properties: vec!() };
+        s.changed_properties.insert("Count".into(), Variant(Box::new(6i32) as Box<RefArg>));
+        c.send(s.to_emit_message(&"/thing".into())).unwrap();
+    });
+    assert!(c.incoming(1000).any(|m| pc.handle_message(&m)));
+    assert_eq!(pc.get_cached("Count").unwrap().as_i64(), Some(6));
+}
@@ -0,0 +1,333 @@
+use super::{Connection, Message, MessageType, Error, BusType, RequestNameReply, ReleaseNameReply, MsgHandler};
+use super::{MatchRule, SignalArgs, BusName, Path};
+use connection::Attached;
+use tree::{Tree, TreeConn, MethodType, DataType};
+use std::{cmp, fmt, mem, thread};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+enum Target {
+    Bus(BusType),
+    Address(String),
+}
+
+impl Target {
+    fn connect(&self) -> Result<Connection, Error> {
+        match *self {
+            Target::Bus(bus) => Connection::get_private(bus),
+            Target::Address(ref a) => {
+                let c = try!(Connection::open_private(a));
+                try!(c.register());
+                Ok(c)
+            }
+        }
+    }
+}
+
+/// Something that happened on a ReconnectingConnection.
+#[derive(Debug)]
+pub enum ReconnectEvent {
+    /// An incoming message
+    Message(Message),
+    /// The connection to the bus was lost. Reconnection attempts start with the next call to `next_event`.
+    Disconnected,
+    /// A new connection was made, and the match rules, names, object paths and handlers were restored.
+    Reconnected,
+}
+
+struct Resubscription {
+    id: usize,
+    attached: Attached,
+    attach: Box<Fn(&Connection) -> Result<Attached, Error>>,
+}
+
+impl fmt::Debug for Resubscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Resubscription({:?})", self.attached) }
+}
+
+/// A Connection wrapper that reconnects when the connection to the bus is lost, e g because the bus restarted.
+///
+/// Match rules, subscriptions, names, object paths, trees and message handlers that are added through
+/// this wrapper are remembered, and added to the new connection after a reconnect. Anything done directly
+/// on the underlying `connection()` is not remembered.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use dbus::{BusType, ReconnectingConnection, ReconnectEvent};
+///
+/// let mut c = ReconnectingConnection::get_private(BusType::Session).unwrap();
+/// c.register_name("com.example.reconnect", 0).unwrap();
+/// c.add_match("interface='com.example.reconnect'").unwrap();
+/// loop {
+///     match c.next_event(1000) {
+///         Some(ReconnectEvent::Message(m)) => println!("Got {:?}", m),
+///         Some(ReconnectEvent::Disconnected) => println!("Lost the bus, reconnecting..."),
+///         Some(ReconnectEvent::Reconnected) => println!("Reconnected as {}", c.connection().unique_name()),
+///         None => {},
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ReconnectingConnection {
+    conn: Connection,
+    target: Target,
+    connected: bool,
+    matches: Vec<String>,
+    names: Vec<(String, u32)>,
+    paths: Vec<String>,
+    trees: Vec<TreeConn>,
+    subscriptions: Vec<Resubscription>,
+    next_id: usize,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+}
+
+impl ReconnectingConnection {
+    fn new(target: Target) -> Result<Self, Error> {
+        let c = try!(target.connect());
+        let min = Duration::from_millis(100);
+        Ok(ReconnectingConnection { conn: c, target: target, connected: true, matches: vec!(), names: vec!(), paths: vec!(),
+            trees: vec!(), subscriptions: vec!(), next_id: 0, min_backoff: min, max_backoff: Duration::from_secs(10), backoff: min })
+    }
+
+    /// Connects to a message bus, and reconnects to the same bus when needed.
+    pub fn get_private(bus: BusType) -> Result<Self, Error> { Self::new(Target::Bus(bus)) }
+
+    /// Connects to a remote address and registers with the bus there, and reconnects to the same address when needed.
+    pub fn open_private(address: &str) -> Result<Self, Error> { Self::new(Target::Address(address.into())) }
+
+    /// Sets the time to wait after a failed reconnection attempt.
+    ///
+    /// The wait starts at "min" and is doubled after every failed attempt, up to "max".
+    /// The default is 100 ms up to 10 seconds.
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self.backoff = min;
+        self
+    }
+
+    /// The current underlying connection.
+    ///
+    /// Note that this is a new connection after every reconnect.
+    pub fn connection(&self) -> &Connection { &self.conn }
+
+    /// Whether we're currently connected.
+    pub fn is_connected(&self) -> bool { self.connected && self.conn.is_connected() }
+
+    /// Adds a match rule, which is added again after a reconnect.
+    pub fn add_match(&mut self, rule: &str) -> Result<(), Error> {
+        if self.connected { try!(self.conn.add_match(rule)); }
+        self.matches.push(rule.into());
+        Ok(())
+    }
+
+    /// Removes a match rule added with `add_match`.
+    pub fn remove_match(&mut self, rule: &str) -> Result<(), Error> {
+        if let Some(i) = self.matches.iter().position(|r| r == rule) { self.matches.remove(i); }
+        if self.connected { self.conn.remove_match(rule) } else { Ok(()) }
+    }
+
+    /// Listens to a signal, like `Connection::subscribe`. The subscription is made again on the new connection after a reconnect.
+    ///
+    /// Returns an id that can be given to `unsubscribe`.
+    pub fn subscribe<S, F>(&mut self, rule: MatchRule, f: F) -> Result<usize, Error>
+    where S: SignalArgs + 'static, F: FnMut(S, &BusName, &Path) + 'static {
+        let rule = rule.into_static();
+        let f = Rc::new(RefCell::new(f));
+        let attach = move |c: &Connection| {
+            let f = f.clone();
+            Attached::new(c, rule.clone(), move |s: S, sender: &BusName, path: &Path| (&mut *f.borrow_mut())(s, sender, path))
+        };
+        let a = try!(attach(&self.conn));
+        self.next_id += 1;
+        self.subscriptions.push(Resubscription { id: self.next_id, attached: a, attach: Box::new(attach) });
+        Ok(self.next_id)
+    }
+
+    /// Removes a subscription made with `subscribe`.
+    pub fn unsubscribe(&mut self, id: usize) {
+        if let Some(i) = self.subscriptions.iter().position(|s| s.id == id) {
+            self.subscriptions.remove(i).attached.detach(&self.conn);
+        }
+    }
+
+    /// Requests a name, which is requested again (with the same flags) after a reconnect.
+    pub fn register_name(&mut self, name: &str, flags: u32) -> Result<RequestNameReply, Error> {
+        let r = try!(self.conn.register_name(name, flags));
+        self.names.push((name.into(), flags));
+        Ok(r)
+    }
+
+    /// Releases a name requested with `register_name`.
+    pub fn release_name(&mut self, name: &str) -> Result<ReleaseNameReply, Error> {
+        self.names.retain(|&(ref n, _)| n != name);
+        self.conn.release_name(name)
+    }
+
+    /// Registers an object path, which is registered again after a reconnect.
+    pub fn register_object_path(&mut self, path: &str) -> Result<(), Error> {
+        try!(self.conn.register_object_path(path));
+        self.paths.push(path.into());
+        Ok(())
+    }
+
+    /// Unregisters an object path registered with `register_object_path` or `register_tree`.
+    pub fn unregister_object_path(&mut self, path: &str) {
+        self.paths.retain(|p| p != path);
+        self.conn.unregister_object_path(path);
+    }
+
+    /// Registers all object paths in the tree, like `Tree::set_registered`. They are registered again after a reconnect,
+    /// and the tree is moved over to the new connection, so that its signals and caller credentials keep working.
+    ///
+    /// Object paths added to the tree later need to be registered with `register_object_path`.
+    pub fn register_tree<M: MethodType<D>, D: DataType>(&mut self, tree: &Tree<M, D>) -> Result<(), Error> {
+        try!(tree.set_registered(&self.conn, true));
+        for p in tree.iter() { self.paths.push(p.get_name().to_string()); }
+        self.trees.push(tree.tree_conn());
+        Ok(())
+    }
+
+    /// Adds a MsgHandler to the connection. All handlers are moved over to the new connection after a reconnect.
+    pub fn add_handler<H: MsgHandler + 'static>(&self, h: H) { self.conn.add_handler(h) }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        let c = try!(self.target.connect());
+        for r in &self.matches { try!(c.add_match(r)); }
+        let mut attached = vec!();
+        for s in &self.subscriptions { attached.push(try!((s.attach)(&c))); }
+        for &(ref n, flags) in &self.names { try!(c.register_name(n, flags)); }
+        for p in &self.paths { try!(c.register_object_path(p)); }
+        for t in &self.trees { t.set(&c); }
+        // The old handlers of the subscriptions must not be moved along with the others.
+        for (s, a) in self.subscriptions.iter_mut().zip(attached) { mem::replace(&mut s.attached, a).detach(&self.conn); }
+        self.conn.move_handlers(&c);
+        if let Some(cb) = self.conn.replace_message_callback(None) { c.replace_message_callback(Some(cb)); }
+        self.conn = c;
+        Ok(())
+    }
+
+    /// Waits for the next incoming message, or handles a lost connection.
+    ///
+    /// Blocking: for up to timeout_ms milliseconds while connected. While disconnected,
+    /// one reconnection attempt is made; if it fails, this blocks for the current backoff time.
+    pub fn next_event(&mut self, timeout_ms: u32) -> Option<ReconnectEvent> {
+        if !self.connected {
+            return match self.reconnect() {
+                Ok(()) => {
+                    self.connected = true;
+                    self.backoff = self.min_backoff;
+                    Some(ReconnectEvent::Reconnected)
+                }
+                Err(_) => {
+                    thread::sleep(self.backoff);
+                    self.backoff = cmp::min(self.backoff * 2, self.max_backoff);
+                    None
+                }
+            };
+        }
+        match self.conn.incoming(timeout_ms).next() {
+            Some(ref m) if is_disconnected(m) => {},
+            Some(m) => return Some(ReconnectEvent::Message(m)),
+            None => if self.conn.is_connected() { return None },
+        }
+        self.connected = false;
+        Some(ReconnectEvent::Disconnected)
+    }
+}
+
+fn is_disconnected(m: &Message) -> bool {
+    m.msg_type() == MessageType::Signal &&
+        m.interface().map(|i| &*i == "org.freedesktop.DBus.Local").unwrap_or(false) &&
+        m.member().map(|mm| &*mm == "Disconnected").unwrap_or(false)
+}
+
+#[test]
+fn test_reconnect() {
+    use testing::TestBus;
+    use tree::Factory;
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use {MsgHandlerType, MatchRule, SignalArgs, BusName, Path, arg};
+
+    #[derive(Default)]
+    struct Ping;
+    impl SignalArgs for Ping {
+        const NAME: &'static str = "Ping";
+        const INTERFACE: &'static str = "com.example.subscribed";
+        fn append(&self, _: &mut arg::IterAppend) {}
+        fn get(&mut self, _: &mut arg::Iter) -> Result<(), arg::TypeMismatchError> { Ok(()) }
+    }
+
+    struct Counter(Rc<Cell<u32>>);
+    impl MsgHandler for Counter {
+        fn handler_type(&self) -> MsgHandlerType { MsgHandlerType::MsgType(MessageType::Signal) }
+        fn handle_msg(&mut self, m: &Message) -> Option<::MsgHandlerResult> {
+            if m.interface().map(|i| &*i == "com.example.reconnect").unwrap_or(false) { self.0.set(self.0.get() + 1); }
+            None
+        }
+    }
+
+    let mut bus = TestBus::new().unwrap();
+    let mut c = ReconnectingConnection::open_private(bus.address()).unwrap()
+        .with_backoff(Duration::from_millis(10), Duration::from_millis(50));
+    let f = Factory::new_fn::<()>();
+    let mut tree = f.tree(()).add(f.object_path("/hello", ()).introspectable().object_manager());
+    c.register_tree(&tree).unwrap();
+    c.register_name("com.example.reconnect", 0).unwrap();
+    c.add_match("interface='com.example.reconnect'").unwrap();
+    let count = Rc::new(Cell::new(0));
+    c.add_handler(Counter(count.clone()));
+    let pings = Rc::new(Cell::new(0));
+    let pings2 = pings.clone();
+    let rule = MatchRule::new_signal(Ping::INTERFACE, Ping::NAME);
+    let id = c.subscribe(rule, move |_: Ping, _: &BusName, _: &Path| pings2.set(pings2.get() + 1)).unwrap();
+
+    bus.restart().unwrap();
+    let mut events = vec!();
+    for _ in 0..100 {
+        if c.is_connected() && !events.is_empty() { break; }
+        match c.next_event(100) {
+            Some(ReconnectEvent::Message(_)) | None => {},
+            Some(e) => events.push(format!("{:?}", e)),
+        }
+    }
+    assert_eq!(events, vec!("Disconnected", "Reconnected"));
+    assert_eq!(c.connection().list_registered_object_paths("/"), vec!("hello"));
+
+    // The name and the match rule are back, and so is the handler.
+    let c2 = bus.connection().unwrap();
+    let m = Message::new_method_call("org.freedesktop.DBus", "/", "org.freedesktop.DBus", "GetNameOwner").unwrap()
+        .append1("com.example.reconnect");
+    let r = c2.send_with_reply_and_block(m, 2000).unwrap();
+    assert_eq!(r.get1::<&str>(), Some(&*c.connection().unique_name()));
+    c2.send(Message::new_signal("/", "com.example.reconnect", "Ping").unwrap()).unwrap();
+    c2.send(Message::new_signal("/", "com.example.subscribed", "Ping").unwrap()).unwrap();
+    let is_ping = |m: &Message, iface: &str| m.interface().map(|i| &*i == iface).unwrap_or(false);
+    let mut got = 0;
+    for _ in 0..20 {
+        if let Some(ReconnectEvent::Message(m)) = c.next_event(100) {
+            if is_ping(&m, "com.example.reconnect") || is_ping(&m, "com.example.subscribed") { got += 1; }
+        }
+        if got == 2 { break; }
+    }
+    assert_eq!(got, 2);
+    assert_eq!(count.get(), 1);
+    assert_eq!(pings.get(), 1);
+
+    // The tree sends its signals on the new connection
+    c2.add_match("interface='org.freedesktop.DBus.ObjectManager'").unwrap();
+    tree.insert_notify(f.object_path("/hello/world", ()).introspectable()).unwrap();
+    let added = c2.incoming(1000).find(|m| m.member().map(|mm| &*mm == "InterfacesAdded").unwrap_or(false)).unwrap();
+    assert_eq!(added.sender().map(|s| s.to_string()), Some(c.connection().unique_name()));
+
+    c.unsubscribe(id);
+    c2.send(Message::new_signal("/", "com.example.subscribed", "Ping").unwrap()).unwrap();
+    for _ in 0..3 { c.next_event(100); }
+    assert_eq!(pings.get(), 1);
+}
@@ -1,5 +1,5 @@
 use arg;
-use {Message, MessageType, BusName, Path, Interface, Member};
+use {Message, MessageType, MatchRule, BusName, Path, Interface, Member};
 
 /// Helper methods for structs representing a Signal
 ///
@@ -65,18 +65,27 @@ pub trait SignalArgs: Default {
     ///
     /// If sender and/or path is None, matches all senders and/or paths.
     fn match_str(sender: Option<&BusName>, path: Option<&Path>) -> String {
-        let mut r = format!("type='signal',interface='{}',member='{}'", Self::INTERFACE, Self::NAME);
-        sender.map(|s| r += &format!(",sender='{}'", s));
-        path.map(|s| r += &format!(",path='{}'", s));
+        Self::match_rule(sender, path).to_string()
+    }
+
+    /// Returns a match rule matching this signal.
+    ///
+    /// If sender and/or path is None, matches all senders and/or paths. Use the builder methods of
+    /// `MatchRule` to narrow it down further.
+    fn match_rule<'a>(sender: Option<&BusName<'a>>, path: Option<&Path<'a>>) -> MatchRule<'a> {
+        let mut r = MatchRule::new_signal(Self::INTERFACE, Self::NAME);
+        if let Some(s) = sender { r = r.with_sender(s.clone()); }
+        if let Some(p) = path { r = r.with_path(p.clone()); }
         r
     }
 }
 
 #[test]
 fn intf_removed() {
-    use {Connection, BusType};
+    use testing::TestBus;
     use stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved as IR;
-    let c = Connection::get_private(BusType::Session).unwrap();
+    let bus = TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
     let mstr = IR::match_str(Some(&c.unique_name().into()), Some(&"/hello".into()));
     println!("Match str: {}", mstr);
     c.add_match(&mstr).unwrap();
@@ -24,6 +24,8 @@ pub use self::org_freedesktop_dbus::Properties as OrgFreedesktopDBusProperties;
 
 pub use self::org_freedesktop_dbus::ObjectManager as OrgFreedesktopDBusObjectManager;
 
+pub use self::org_freedesktop_dbus::DBus as OrgFreedesktopDBus;
+
 pub mod org_freedesktop_dbus {
 
 use arg;
@@ -208,5 +210,395 @@ impl<'a, C: ::std::ops::Deref<Target=::Connection>> Peer for ::ConnPath<'a, C> {
     }
 }
 
+/// Methods of the [org.freedesktop.DBus](https://dbus.freedesktop.org/doc/dbus-specification.html#message-bus-messages) interface,
+/// which is implemented by the message bus itself.
+///
+/// Use it with a ConnPath for "org.freedesktop.DBus" and "/org/freedesktop/DBus".
+pub trait DBus {
+    type Err;
+    fn hello(&self) -> Result<String, Self::Err>;
+    fn request_name(&self, name: &str, flags: u32) -> Result<::RequestNameReply, Self::Err>;
+    fn release_name(&self, name: &str) -> Result<::ReleaseNameReply, Self::Err>;
+    fn start_service_by_name(&self, name: &str, flags: u32) -> Result<StartServiceReply, Self::Err>;
+    fn update_activation_environment(&self, environment: ::std::collections::HashMap<&str, &str>) -> Result<(), Self::Err>;
+    fn name_has_owner(&self, name: &str) -> Result<bool, Self::Err>;
+    fn list_names(&self) -> Result<Vec<String>, Self::Err>;
+    fn list_activatable_names(&self) -> Result<Vec<String>, Self::Err>;
+    fn add_match(&self, rule: &str) -> Result<(), Self::Err>;
+    fn remove_match(&self, rule: &str) -> Result<(), Self::Err>;
+    fn get_name_owner(&self, name: &str) -> Result<String, Self::Err>;
+    fn list_queued_owners(&self, name: &str) -> Result<Vec<String>, Self::Err>;
+    fn get_connection_unix_user(&self, bus_name: &str) -> Result<u32, Self::Err>;
+    fn get_connection_unix_process_id(&self, bus_name: &str) -> Result<u32, Self::Err>;
+    fn get_adt_audit_session_data(&self, bus_name: &str) -> Result<Vec<u8>, Self::Err>;
+    fn get_connection_selinux_security_context(&self, bus_name: &str) -> Result<Vec<u8>, Self::Err>;
+    fn reload_config(&self) -> Result<(), Self::Err>;
+    fn get_id(&self) -> Result<String, Self::Err>;
+    fn get_connection_credentials(&self, bus_name: &str) -> Result<ConnectionCredentials, Self::Err>;
+}
+
+impl<'a, C: ::std::ops::Deref<Target=::Connection>> DBus for ::ConnPath<'a, C> {
+    type Err = ::Error;
+
+    fn hello(&self) -> Result<String, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"Hello".into(), |_| {
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let unique_name: String = try!(i.read());
+        Ok(unique_name)
+    }
+
+    fn request_name(&self, name: &str, flags: u32) -> Result<::RequestNameReply, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"RequestName".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(name);
+            i.append(flags);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let reply: u32 = try!(i.read());
+        match reply {
+            1 => Ok(::RequestNameReply::PrimaryOwner),
+            2 => Ok(::RequestNameReply::InQueue),
+            3 => Ok(::RequestNameReply::Exists),
+            4 => Ok(::RequestNameReply::AlreadyOwner),
+            _ => Err(invalid_reply("RequestName", reply)),
+        }
+    }
+
+    fn release_name(&self, name: &str) -> Result<::ReleaseNameReply, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"ReleaseName".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let reply: u32 = try!(i.read());
+        match reply {
+            1 => Ok(::ReleaseNameReply::Released),
+            2 => Ok(::ReleaseNameReply::NonExistent),
+            3 => Ok(::ReleaseNameReply::NotOwner),
+            _ => Err(invalid_reply("ReleaseName", reply)),
+        }
+    }
+
+    fn start_service_by_name(&self, name: &str, flags: u32) -> Result<StartServiceReply, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"StartServiceByName".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(name);
+            i.append(flags);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let reply: u32 = try!(i.read());
+        match reply {
+            1 => Ok(StartServiceReply::Success),
+            2 => Ok(StartServiceReply::AlreadyRunning),
+            _ => Err(invalid_reply("StartServiceByName", reply)),
+        }
+    }
+
+    fn update_activation_environment(&self, environment: ::std::collections::HashMap<&str, &str>) -> Result<(), Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"UpdateActivationEnvironment".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(environment);
+        }));
+        try!(m.as_result());
+        Ok(())
+    }
+
+    fn name_has_owner(&self, name: &str) -> Result<bool, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"NameHasOwner".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let has_owner: bool = try!(i.read());
+        Ok(has_owner)
+    }
+
+    fn list_names(&self) -> Result<Vec<String>, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"ListNames".into(), |_| {
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let names: Vec<String> = try!(i.read());
+        Ok(names)
+    }
+
+    fn list_activatable_names(&self) -> Result<Vec<String>, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"ListActivatableNames".into(), |_| {
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let names: Vec<String> = try!(i.read());
+        Ok(names)
+    }
+
+    fn add_match(&self, rule: &str) -> Result<(), Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"AddMatch".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(rule);
+        }));
+        try!(m.as_result());
+        Ok(())
+    }
+
+    fn remove_match(&self, rule: &str) -> Result<(), Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"RemoveMatch".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(rule);
+        }));
+        try!(m.as_result());
+        Ok(())
+    }
+
+    fn get_name_owner(&self, name: &str) -> Result<String, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetNameOwner".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let unique_name: String = try!(i.read());
+        Ok(unique_name)
+    }
+
+    fn list_queued_owners(&self, name: &str) -> Result<Vec<String>, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"ListQueuedOwners".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let unique_names: Vec<String> = try!(i.read());
+        Ok(unique_names)
+    }
+
+    fn get_connection_unix_user(&self, bus_name: &str) -> Result<u32, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetConnectionUnixUser".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(bus_name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let unix_user_id: u32 = try!(i.read());
+        Ok(unix_user_id)
+    }
+
+    fn get_connection_unix_process_id(&self, bus_name: &str) -> Result<u32, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetConnectionUnixProcessID".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(bus_name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let unix_process_id: u32 = try!(i.read());
+        Ok(unix_process_id)
+    }
+
+    fn get_adt_audit_session_data(&self, bus_name: &str) -> Result<Vec<u8>, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetAdtAuditSessionData".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(bus_name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let audit_data: Vec<u8> = try!(i.read());
+        Ok(audit_data)
+    }
+
+    fn get_connection_selinux_security_context(&self, bus_name: &str) -> Result<Vec<u8>, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetConnectionSELinuxSecurityContext".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(bus_name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let security_context: Vec<u8> = try!(i.read());
+        Ok(security_context)
+    }
+
+    fn reload_config(&self) -> Result<(), Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"ReloadConfig".into(), |_| {
+        }));
+        try!(m.as_result());
+        Ok(())
+    }
+
+    fn get_id(&self) -> Result<String, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetId".into(), |_| {
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let id: String = try!(i.read());
+        Ok(id)
+    }
+
+    fn get_connection_credentials(&self, bus_name: &str) -> Result<ConnectionCredentials, Self::Err> {
+        let mut m = try!(self.method_call_with_args(&"org.freedesktop.DBus".into(), &"GetConnectionCredentials".into(), |msg| {
+            let mut i = arg::IterAppend::new(msg);
+            i.append(bus_name);
+        }));
+        try!(m.as_result());
+        let mut i = m.iter_init();
+        let credentials: ::std::collections::HashMap<String, arg::Variant<Box<arg::RefArg>>> = try!(i.read());
+        Ok(ConnectionCredentials::from_dict(&credentials))
+    }
+}
+
+fn invalid_reply(method: &str, reply: u32) -> ::Error {
+    ::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", &format!("Unknown {} reply {}", method, reply))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// The reply to StartServiceByName.
+pub enum StartServiceReply {
+    /// The service was started.
+    Success = 1,
+    /// The service was already running.
+    AlreadyRunning = 2,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The credentials of a connection, as returned by
+/// [GetConnectionCredentials](https://dbus.freedesktop.org/doc/dbus-specification.html#bus-messages-get-connection-credentials).
+///
+/// Which credentials are known depends on the platform and the message bus.
+pub struct ConnectionCredentials {
+    /// The numeric uid of the process.
+    pub unix_user_id: Option<u32>,
+    /// The numeric gids of the process, both primary and supplementary.
+    pub unix_group_ids: Option<Vec<u32>>,
+    /// The numeric process id.
+    pub process_id: Option<u32>,
+    /// The Windows security identifier.
+    pub windows_sid: Option<String>,
+    /// The security label of the process, e g a SELinux context or an AppArmor label.
+    pub linux_security_label: Option<Vec<u8>>,
+}
+
+impl ConnectionCredentials {
+    /// Reads the credentials from the dictionary returned by GetConnectionCredentials.
+    ///
+    /// Unknown keys, and keys with an unexpected type, are ignored.
+    pub fn from_dict(d: &::std::collections::HashMap<String, arg::Variant<Box<arg::RefArg>>>) -> ConnectionCredentials {
+        use arg::RefArg;
+        let u32s = |v: &arg::Variant<Box<RefArg>>| v.0.as_iter().map(|i| i.filter_map(|x| x.as_u64().map(|x| x as u32)).collect::<Vec<_>>());
+        ConnectionCredentials {
+            unix_user_id: d.get("UnixUserID").and_then(|v| v.0.as_u64()).map(|x| x as u32),
+            unix_group_ids: d.get("UnixGroupIDs").and_then(|v| u32s(v)),
+            process_id: d.get("ProcessID").and_then(|v| v.0.as_u64()).map(|x| x as u32),
+            windows_sid: d.get("WindowsSID").and_then(|v| v.0.as_str()).map(|s| s.to_string()),
+            linux_security_label: d.get("LinuxSecurityLabel").and_then(|v| u32s(v)).map(|v| v.into_iter().map(|x| x as u8).collect()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Struct to send/receive the NameOwnerChanged signal of the
+/// [org.freedesktop.DBus](https://dbus.freedesktop.org/doc/dbus-specification.html#message-bus-messages) interface.
+///
+/// Broadcast when the owner of a name changes. An empty old or new owner means that the name had, or has, no owner.
+pub struct DBusNameOwnerChanged {
+    pub name: String,
+    pub old_owner: String,
+    pub new_owner: String,
+}
+
+impl ::SignalArgs for DBusNameOwnerChanged {
+    const NAME: &'static str = "NameOwnerChanged";
+    const INTERFACE: &'static str = "org.freedesktop.DBus";
+    fn append(&self, i: &mut arg::IterAppend) {
+        (&self.name as &arg::RefArg).append(i);
+        (&self.old_owner as &arg::RefArg).append(i);
+        (&self.new_owner as &arg::RefArg).append(i);
+    }
+    fn get(&mut self, i: &mut arg::Iter) -> Result<(), arg::TypeMismatchError> {
+        self.name = try!(i.read());
+        self.old_owner = try!(i.read());
+        self.new_owner = try!(i.read());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+/// Struct to send/receive the NameLost signal of the
+/// [org.freedesktop.DBus](https://dbus.freedesktop.org/doc/dbus-specification.html#message-bus-messages) interface.
+///
+/// Sent to a connection when it loses a name.
+pub struct DBusNameLost {
+    pub name: String,
+}
+
+impl ::SignalArgs for DBusNameLost {
+    const NAME: &'static str = "NameLost";
+    const INTERFACE: &'static str = "org.freedesktop.DBus";
+    fn append(&self, i: &mut arg::IterAppend) {
+        (&self.name as &arg::RefArg).append(i);
+    }
+    fn get(&mut self, i: &mut arg::Iter) -> Result<(), arg::TypeMismatchError> {
+        self.name = try!(i.read());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+/// Struct to send/receive the NameAcquired signal of the
+/// [org.freedesktop.DBus](https://dbus.freedesktop.org/doc/dbus-specification.html#message-bus-messages) interface.
+///
+/// Sent to a connection when it gets a name.
+pub struct DBusNameAcquired {
+    pub name: String,
+}
+
+impl ::SignalArgs for DBusNameAcquired {
+    const NAME: &'static str = "NameAcquired";
+    const INTERFACE: &'static str = "org.freedesktop.DBus";
+    fn append(&self, i: &mut arg::IterAppend) {
+        (&self.name as &arg::RefArg).append(i);
+    }
+    fn get(&mut self, i: &mut arg::Iter) -> Result<(), arg::TypeMismatchError> {
+        self.name = try!(i.read());
+        Ok(())
+    }
+}
+
+
+}
+
+#[test]
+fn test_bus_proxy() {
+    use self::org_freedesktop_dbus::{DBus, DBusNameOwnerChanged};
+    use testing::TestBus;
+    use {SignalArgs, libc, std};
+
+    let bus = TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
+    let c2 = bus.connection().unwrap();
+    let p = c.with_path("org.freedesktop.DBus", "/org/freedesktop/DBus", 2000);
+    c.add_match(&DBusNameOwnerChanged::match_str(None, None)).unwrap();
+
+    assert_eq!(p.request_name("com.example.stdintf", 0).unwrap(), ::RequestNameReply::PrimaryOwner);
+    assert_eq!(p.request_name("com.example.stdintf", 0).unwrap(), ::RequestNameReply::AlreadyOwner);
+    assert_eq!(p.release_name("com.example.nonexistent").unwrap(), ::ReleaseNameReply::NonExistent);
+    assert!(p.name_has_owner("com.example.stdintf").unwrap());
+    assert_eq!(p.get_name_owner("com.example.stdintf").unwrap(), c.unique_name());
+    assert!(p.list_names().unwrap().contains(&c2.unique_name()));
+    assert_eq!(p.get_connection_unix_user(&c2.unique_name()).unwrap(), unsafe { libc::getuid() });
+    assert_eq!(p.get_connection_unix_process_id(&c2.unique_name()).unwrap(), std::process::id());
+
+    let cred = p.get_connection_credentials(&c2.unique_name()).unwrap();
+    assert_eq!(cred.unix_user_id, Some(unsafe { libc::getuid() }));
+    assert_eq!(cred.process_id, Some(std::process::id()));
+    assert_eq!(cred.windows_sid, None);
+    let e = p.get_connection_credentials("com.example.nonexistent").unwrap_err();
+    assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.NameHasNoOwner"));
 
+    let s = c.incoming(2000).filter_map(|m| DBusNameOwnerChanged::from_message(&m)).next().unwrap();
+    assert_eq!(&*s.name, "com.example.stdintf");
+    assert_eq!(&*s.old_owner, "");
+    assert_eq!(s.new_owner, c.unique_name());
 }
@@ -5,11 +5,62 @@ use std::ffi::{CStr, CString};
 use std::borrow::{Borrow, Cow};
 use std::os::raw::c_char;
 
-#[cfg(not(feature = "no-string-validation"))]
+#[cfg(all(not(feature = "no-string-validation"), feature = "libdbus-sys"))]
 use Error;
-#[cfg(not(feature = "no-string-validation"))]
+#[cfg(all(not(feature = "no-string-validation"), feature = "libdbus-sys"))]
 use ffi;
 
+// The same checks as libdbus does, for when we're built without it.
+#[cfg(all(not(feature = "no-string-validation"), not(feature = "libdbus-sys")))]
+mod validate {
+    use marshal::single_type_len;
+
+    const MAX_NAME_LEN: usize = 255;
+
+    fn element(e: &[u8], dash: bool, digit_first: bool) -> bool {
+        e.len() > 0 && (digit_first || !e[0].is_ascii_digit()) &&
+            e.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_' || (dash && c == b'-'))
+    }
+
+    fn dotted(s: &[u8], dash: bool, digit_first: bool) -> bool {
+        s.len() <= MAX_NAME_LEN && s.contains(&b'.') && s.split(|&c| c == b'.').all(|e| element(e, dash, digit_first))
+    }
+
+    fn check(ok: bool, what: &str, s: &[u8]) -> Result<(), String> {
+        if ok { Ok(()) } else { Err(format!("{} was not valid: '{}'", what, String::from_utf8_lossy(s))) }
+    }
+
+    pub fn dbus_signature_validate_single(s: &[u8]) -> Result<(), String> {
+        check(s.len() <= MAX_NAME_LEN && single_type_len(s, false) == Some(s.len()), "Signature", s)
+    }
+
+    pub fn dbus_validate_path(s: &[u8]) -> Result<(), String> {
+        let ok = s == b"/" || (s.first() == Some(&b'/') && s[1..].split(|&c| c == b'/').all(|e| element(e, false, true)));
+        check(ok, "Object path", s)
+    }
+
+    pub fn dbus_validate_member(s: &[u8]) -> Result<(), String> {
+        check(s.len() <= MAX_NAME_LEN && element(s, false, false), "Member name", s)
+    }
+
+    pub fn dbus_validate_interface(s: &[u8]) -> Result<(), String> {
+        check(dotted(s, false, false), "Interface name", s)
+    }
+
+    pub fn dbus_validate_error_name(s: &[u8]) -> Result<(), String> {
+        check(dotted(s, false, false), "Error name", s)
+    }
+
+    pub fn dbus_validate_bus_name(s: &[u8]) -> Result<(), String> {
+        // Unique names start with a colon, and their elements may start with a digit.
+        let ok = match s.first() {
+            Some(&b':') => s.len() <= MAX_NAME_LEN && dotted(&s[1..], true, true),
+            _ => dotted(s, true, false),
+        };
+        check(ok, "Bus name", s)
+    }
+}
+
 macro_rules! cstring_wrapper {
     ($t: ident, $s: ident) => {
 
@@ -17,13 +68,18 @@ impl<'m> $t<'m> {
     #[cfg(feature = "no-string-validation")]
     fn check_valid(_: *const c_char) -> Result<(), String> { Ok(()) }
 
-    #[cfg(not(feature = "no-string-validation"))]
+    #[cfg(all(not(feature = "no-string-validation"), feature = "libdbus-sys"))]
     fn check_valid(c: *const c_char) -> Result<(), String> {
         let mut e = Error::empty();
         let b = unsafe { ffi::$s(c, e.get_mut()) };
         if b != 0 { Ok(()) } else { Err(e.message().unwrap().into()) }
     }
 
+    #[cfg(all(not(feature = "no-string-validation"), not(feature = "libdbus-sys")))]
+    fn check_valid(c: *const c_char) -> Result<(), String> {
+        validate::$s(unsafe { CStr::from_ptr(c) }.to_bytes())
+    }
+
     /// Creates a new instance of this struct.
     ///
     /// Note: If the no-string-validation feature is activated, this string
@@ -133,6 +189,7 @@ pub struct Signature<'a>(Cow<'a, CStr>);
 
 cstring_wrapper!(Signature, dbus_signature_validate_single);
 
+#[cfg(feature = "libdbus-sys")]
 impl Signature<'static> {
     /// Makes a D-Bus signature that corresponds to A. 
     pub fn make<A: super::arg::Arg>() -> Signature<'static> { A::signature() }
@@ -204,6 +261,7 @@ fn reborrow_path() {
     assert_eq!(p1, p2);
 }
 
+#[cfg(feature = "libdbus-sys")]
 #[test]
 fn make_sig() {
     assert_eq!(&*Signature::make::<(&str, u8)>(), "(sy)");
@@ -0,0 +1,671 @@
+//! An in-process message bus, for tests that should not depend on a running session bus.
+//!
+//! Only available with the "testing" feature, which is meant to be enabled from dev-dependencies.
+//!
+//! # Example
+//! ```
+//! use dbus::testing::TestBus;
+//! let bus = TestBus::new().unwrap();
+//! let c = bus.connection().unwrap();
+//! assert!(c.unique_name().starts_with(":1."));
+//! ```
+//!
+//! The bus implements the parts of `org.freedesktop.DBus` that are needed to get clients
+//! connected and talking to each other: Hello, RequestName, ReleaseName, GetNameOwner,
+//! NameHasOwner, ListNames, GetId, AddMatch, RemoveMatch, GetConnectionUnixUser,
+//! GetConnectionUnixProcessID and GetConnectionCredentials, and emits NameOwnerChanged,
+//! NameAcquired and NameLost. Unicast messages are routed to their destination and broadcast
+//! signals to all connections with a matching match rule.
+//!
+//! For the server side of a test, `TestBus::serve` runs a tree on its own connection and thread.
+//!
+//! It does not enforce any security policy, does not activate services and cannot pass
+//! file descriptors.
+
+use {ffi, marshal, Connection, Error, Message, MessageType, MatchRule, RequestNameReply, ReleaseNameReply};
+use tree::{Tree, MethodType, DataType};
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fmt, fs, process};
+
+const BUS_NAME: &'static str = "org.freedesktop.DBus";
+const BUS_PATH: &'static str = "/org/freedesktop/DBus";
+
+static BUS_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn io_error(e: io::Error) -> Error { Error::new_custom("org.freedesktop.DBus.Error.IOError", &e.to_string()) }
+
+/// A message bus running in a background thread, listening on a temporary unix socket.
+///
+/// The bus is shut down and the socket removed when this struct is dropped.
+#[derive(Debug)]
+pub struct TestBus {
+    address: String,
+    path: PathBuf,
+    wakeup: UnixStream,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestBus {
+    /// Starts a new bus.
+    pub fn new() -> Result<TestBus, Error> {
+        let n = BUS_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("dbus-rs-testbus-{}-{}", process::id(), n));
+        let address = format!("unix:path={}", path.display());
+        let (wakeup, thread) = try!(Self::start(&path));
+        Ok(TestBus { address: address, path: path, wakeup: wakeup, thread: Some(thread) })
+    }
+
+    fn start(path: &PathBuf) -> Result<(UnixStream, JoinHandle<()>), Error> {
+        let _ = fs::remove_file(path);
+        let listener = try!(UnixListener::bind(path).map_err(io_error));
+        try!(listener.set_nonblocking(true).map_err(io_error));
+        let (wakeup, wakeup_rx) = try!(UnixStream::pair().map_err(io_error));
+        let n = BUS_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let guid = format!("{:08x}{:08x}{:016x}", process::id(), nanos, n);
+        let thread = try!(thread::Builder::new().name("dbus-testbus".into()).spawn(move || {
+            Bus::new(listener, wakeup_rx, guid).run()
+        }).map_err(io_error));
+        Ok((wakeup, thread))
+    }
+
+    fn stop(&mut self) {
+        let _ = (&self.wakeup).write_all(b"q");
+        if let Some(t) = self.thread.take() { let _ = t.join(); }
+        let _ = fs::remove_file(&self.path);
+    }
+
+    /// Shuts the bus down, disconnecting all connections, and starts it again on the same address.
+    ///
+    /// Useful for testing how clients deal with a bus restart.
+    pub fn restart(&mut self) -> Result<(), Error> {
+        self.stop();
+        let (wakeup, thread) = try!(Self::start(&self.path));
+        self.wakeup = wakeup;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// The address of the bus, to use with e g `Connection::open_private`.
+    pub fn address(&self) -> &str { &self.address }
+
+    /// Opens a new connection to the bus and registers it (i e, calls Hello).
+    pub fn connection(&self) -> Result<Connection, Error> {
+        let c = try!(Connection::open_private(&self.address));
+        try!(c.register());
+        Ok(c)
+    }
+
+    /// Serves a tree on a new connection in a background thread, under the well-known name "name".
+    ///
+    /// The tree is created by "f", in that thread, and registered with the connection. This returns
+    /// once the tree is ready to take method calls. The thread stops when the `TestServer` is dropped.
+    pub fn serve<M, D, F>(&self, name: &str, f: F) -> Result<TestServer<M, D>, Error>
+    where M: MethodType<D> + 'static, D: DataType + 'static, F: FnOnce() -> Tree<M, D> + Send + 'static {
+        let (address, name) = (self.address.clone(), name.to_string());
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (tx, rx) = mpsc::channel::<ServerCmd<M, D>>();
+        let thread = try!(thread::Builder::new().name("dbus-testserver".into()).spawn(move || {
+            let setup = Connection::open_private(&address).and_then(|c| {
+                try!(c.register());
+                try!(c.register_name(&name, 0));
+                let tree = f();
+                try!(tree.set_registered(&c, true));
+                Ok((c, tree))
+            });
+            let (c, mut tree) = match setup {
+                Ok(x) => { let _ = ready_tx.send(Ok(())); x },
+                Err(e) => { let _ = ready_tx.send(Err(e)); return },
+            };
+            loop {
+                for m in c.incoming(50) {
+                    if let Some(r) = tree.handle(&m) { for r in r { let _ = c.send(r); } }
+                }
+                loop {
+                    match rx.try_recv() {
+                        Ok(mut cmd) => cmd(&c, &mut tree),
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => return,
+                    }
+                }
+            }
+        }).map_err(io_error));
+        let server = TestServer { cmds: Some(tx), thread: Some(thread) };
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(server),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::new_custom("org.freedesktop.DBus.Error.Failed", "Test server thread panicked")),
+        }
+    }
+}
+
+type ServerCmd<M, D> = Box<FnMut(&Connection, &mut Tree<M, D>) + Send>;
+
+/// A tree served in a background thread, see `TestBus::serve`.
+///
+/// The thread is stopped when this struct is dropped.
+pub struct TestServer<M: MethodType<D>, D: DataType> {
+    cmds: Option<mpsc::Sender<ServerCmd<M, D>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<M: MethodType<D>, D: DataType> TestServer<M, D> {
+    /// Calls "f" in the server's thread, with its connection and tree, and returns the result.
+    ///
+    /// Useful for changing the tree, or sending signals, at a point decided by the test.
+    pub fn run<R, F>(&self, f: F) -> R
+    where R: Send + 'static, F: FnOnce(&Connection, &mut Tree<M, D>) -> R + Send + 'static {
+        let (tx, rx) = mpsc::channel();
+        let mut f = Some(f);
+        self.cmds.as_ref().unwrap().send(Box::new(move |c: &Connection, t: &mut Tree<M, D>| {
+            if let Some(f) = f.take() { let _ = tx.send(f(c, t)); }
+        })).expect("Test server thread has stopped");
+        rx.recv().expect("Test server thread panicked")
+    }
+}
+
+impl<M: MethodType<D>, D: DataType> Drop for TestServer<M, D> {
+    fn drop(&mut self) {
+        self.cmds.take();
+        if let Some(t) = self.thread.take() { let _ = t.join(); }
+    }
+}
+
+impl<M: MethodType<D>, D: DataType> fmt::Debug for TestServer<M, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "TestServer") }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) { self.stop() }
+}
+
+enum Auth {
+    Waiting,
+    WaitingForData,
+    Authenticated,
+    Running,
+}
+
+struct Client {
+    stream: UnixStream,
+    auth: Auth,
+    inbuf: Vec<u8>,
+    outbuf: VecDeque<u8>,
+    name: Option<String>,
+    matches: Vec<MatchRule<'static>>,
+    // The uid and pid of the peer process, if the platform tells us.
+    creds: Option<(u32, u32)>,
+    dead: bool,
+}
+
+impl Client {
+    fn write_line(&mut self, s: &str) {
+        self.outbuf.extend(s.as_bytes());
+        self.outbuf.extend(b"\r\n");
+    }
+
+    /// Handles the SASL exchange. Returns when authentication is done or more data is needed.
+    fn authenticate(&mut self, guid: &str) {
+        loop {
+            if let Auth::Running = self.auth { return }
+            if self.inbuf.first() == Some(&0) { self.inbuf.remove(0); }
+            let pos = match self.inbuf.windows(2).position(|w| w == b"\r\n") {
+                Some(p) => p,
+                None => {
+                    if self.inbuf.len() > 16384 { self.dead = true; }
+                    return;
+                },
+            };
+            let line: Vec<u8> = self.inbuf.drain(..pos+2).take(pos).collect();
+            let line = String::from_utf8_lossy(&line).into_owned();
+            let mut words = line.split(' ');
+            match (words.next().unwrap_or(""), words.next(), words.next().is_some()) {
+                ("AUTH", Some("EXTERNAL"), true) |
+                ("AUTH", Some("ANONYMOUS"), _) => { self.write_line(&format!("OK {}", guid)); self.auth = Auth::Authenticated; },
+                ("AUTH", Some("EXTERNAL"), false) => { self.write_line("DATA"); self.auth = Auth::WaitingForData; },
+                ("AUTH", _, _) => self.write_line("REJECTED EXTERNAL ANONYMOUS"),
+                ("DATA", _, _) => match self.auth {
+                    Auth::WaitingForData => { self.write_line(&format!("OK {}", guid)); self.auth = Auth::Authenticated; },
+                    _ => self.write_line("ERROR"),
+                },
+                ("CANCEL", _, _) => { self.write_line("REJECTED EXTERNAL ANONYMOUS"); self.auth = Auth::Waiting },
+                ("BEGIN", _, _) => match self.auth {
+                    Auth::Authenticated => self.auth = Auth::Running,
+                    _ => { self.dead = true; return },
+                },
+                // We cannot pass file descriptors.
+                ("NEGOTIATE_UNIX_FD", _, _) => self.write_line("ERROR"),
+                _ => self.write_line("ERROR"),
+            }
+        }
+    }
+
+    fn read(&mut self) {
+        let mut buf = [0u8; 4096];
+        loop {
+            match (&self.stream).read(&mut buf) {
+                Ok(0) => { self.dead = true; return },
+                Ok(n) => self.inbuf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(_) => { self.dead = true; return },
+            }
+        }
+    }
+
+    fn write(&mut self) {
+        while self.outbuf.len() > 0 {
+            let r = {
+                let (a, _) = self.outbuf.as_slices();
+                (&self.stream).write(a)
+            };
+            match r {
+                Ok(n) => { self.outbuf.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(_) => { self.dead = true; return },
+            }
+        }
+    }
+
+    /// Takes the next complete message out of the input buffer.
+    fn next_message(&mut self) -> Option<Message> {
+        if let Auth::Running = self.auth {} else { return None };
+        let needed = match marshal::bytes_needed(&self.inbuf) {
+            Ok(n) => n,
+            Err(_) => { self.dead = true; return None },
+        };
+        if self.inbuf.len() < needed { return None }
+        let m = marshal::demarshal(&self.inbuf[..needed]);
+        self.inbuf.drain(..needed);
+        match m {
+            Ok(m) => Some(m),
+            Err(_) => { self.dead = true; None },
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_creds(s: &UnixStream) -> Option<(u32, u32)> {
+    use libc;
+    let mut c = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let r = unsafe { libc::getsockopt(s.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut c as *mut _ as *mut _, &mut len) };
+    if r < 0 { None } else { Some((c.uid, c.pid as u32)) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_creds(_: &UnixStream) -> Option<(u32, u32)> { None }
+
+fn error_reply(m: &Message, name: &str, text: &str) -> Message {
+    Message::new_error(m, name, text).unwrap()
+}
+
+struct Bus {
+    listener: UnixListener,
+    wakeup: UnixStream,
+    guid: String,
+    clients: BTreeMap<usize, Client>,
+    // Well-known names: the first entry is the primary owner, the rest is the queue.
+    names: BTreeMap<String, Vec<(usize, u32)>>,
+    next_id: usize,
+    serial: u32,
+}
+
+impl Bus {
+    fn new(listener: UnixListener, wakeup: UnixStream, guid: String) -> Bus {
+        Bus { listener: listener, wakeup: wakeup, guid: guid, clients: BTreeMap::new(),
+            names: BTreeMap::new(), next_id: 1, serial: 1 }
+    }
+
+    fn run(mut self) {
+        use libc;
+        loop {
+            let ids: Vec<usize> = self.clients.keys().cloned().collect();
+            let mut fds = vec!(
+                libc::pollfd { fd: self.wakeup.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: self.listener.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            );
+            for id in &ids {
+                let c = &self.clients[id];
+                let out = if c.outbuf.len() > 0 { libc::POLLOUT } else { 0 };
+                fds.push(libc::pollfd { fd: c.stream.as_raw_fd(), events: libc::POLLIN | out, revents: 0 });
+            }
+            let r = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if r < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted { continue }
+                panic!("poll failed: {}", io::Error::last_os_error());
+            }
+            if fds[0].revents != 0 { return }
+            if fds[1].revents != 0 { self.accept() }
+            for (id, pfd) in ids.iter().zip(fds[2..].iter()) {
+                if pfd.revents == 0 { continue }
+                let c = self.clients.get_mut(id).unwrap();
+                if pfd.revents & libc::POLLOUT != 0 { c.write() }
+                if pfd.revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0 { c.read() }
+            }
+            for id in ids { self.process(id) }
+            let dead: Vec<usize> = self.clients.iter().filter(|&(_, c)| c.dead).map(|(&id, _)| id).collect();
+            for id in dead { self.disconnect(id) }
+        }
+    }
+
+    fn accept(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_err() { continue }
+            let id = self.next_id;
+            self.next_id += 1;
+            let creds = peer_creds(&stream);
+            self.clients.insert(id, Client { stream: stream, auth: Auth::Waiting, inbuf: vec!(), outbuf: VecDeque::new(),
+                name: None, matches: vec!(), creds: creds, dead: false });
+        }
+    }
+
+    fn process(&mut self, id: usize) {
+        loop {
+            let msg = {
+                let guid = &self.guid;
+                // A client that has hung up might still have sent complete messages before that.
+                let c = match self.clients.get_mut(&id) { Some(c) => c, None => return };
+                c.authenticate(guid);
+                let m = c.next_message();
+                c.write();
+                match m { Some(m) => m, None => return }
+            };
+            self.handle(id, msg);
+        }
+    }
+
+    fn unique_name(id: usize) -> String { format!(":1.{}", id) }
+
+    fn client_id(&self, name: &str) -> Option<usize> {
+        if name.starts_with(':') {
+            self.clients.iter().find(|&(_, c)| c.name.as_ref().map(|n| n == name).unwrap_or(false)).map(|(&id, _)| id)
+        } else {
+            self.names.get(name).map(|q| q[0].0)
+        }
+    }
+
+    fn owned_names(&self, id: usize) -> Vec<&str> {
+        self.names.iter().filter(|&(_, q)| q[0].0 == id).map(|(n, _)| &**n).collect()
+    }
+
+    /// Sends a message to a client. The message must already have its serial and sender set.
+    fn deliver(&mut self, id: usize, m: &Message) {
+        let b = match m.marshal() { Ok(b) => b, Err(_) => return };
+        if let Some(c) = self.clients.get_mut(&id) {
+            c.outbuf.extend(b);
+            c.write();
+        }
+    }
+
+    /// Sends a message from the bus itself.
+    fn send_from_bus(&mut self, dest: Option<usize>, m: Message) {
+        let s = CString::new(BUS_NAME).unwrap();
+        unsafe {
+            ffi::dbus_message_set_sender(m.ptr(), s.as_ptr());
+            ffi::dbus_message_set_serial(m.ptr(), self.serial);
+        }
+        self.serial += 1;
+        match dest {
+            Some(id) => {
+                if let Some(n) = self.clients.get(&id).and_then(|c| c.name.clone()) {
+                    let mut m = m;
+                    m.set_destination(Some(n.into()));
+                    self.deliver(id, &m);
+                }
+            },
+            None => self.broadcast(m, BUS_NAME),
+        }
+    }
+
+    fn broadcast(&mut self, m: Message, sender: &str) {
+        let sender_names: Vec<String> = self.client_id(sender).map(|id| self.owned_names(id).iter().map(|s| s.to_string()).collect()).unwrap_or(vec!());
+        let sender_names: Vec<&str> = sender_names.iter().map(|s| &**s).collect();
+        let targets: Vec<usize> = self.clients.iter()
+            .filter(|&(_, c)| c.matches.iter().any(|r| r.matches_sender(&m, Some(sender), &sender_names)))
+            .map(|(&id, _)| id).collect();
+        for id in targets { self.deliver(id, &m) }
+    }
+
+    fn signal(member: &str) -> Message {
+        Message::new_signal(BUS_PATH, BUS_NAME, member).unwrap()
+    }
+
+    fn name_owner_changed(&mut self, name: &str, old: &str, new: &str) {
+        self.send_from_bus(None, Self::signal("NameOwnerChanged").append3(name, old, new));
+    }
+
+    fn handle(&mut self, id: usize, m: Message) {
+        let registered = self.clients[&id].name.clone();
+        let sender = match registered {
+            Some(n) => n,
+            None => {
+                if m.msg_type() == MessageType::MethodCall && m.destination().map(|d| &*d == BUS_NAME).unwrap_or(false)
+                    && m.member().map(|n| &*n == "Hello").unwrap_or(false) {
+                    return self.hello(id, &m);
+                }
+                // Clients must call Hello before doing anything else.
+                self.clients.get_mut(&id).unwrap().dead = true;
+                return;
+            },
+        };
+        let s = CString::new(&*sender).unwrap();
+        unsafe { ffi::dbus_message_set_sender(m.ptr(), s.as_ptr()) };
+
+        let dest = m.destination().map(|d| d.to_string());
+        match dest {
+            Some(ref d) if d == BUS_NAME => {
+                if m.msg_type() != MessageType::MethodCall { return }
+                let reply = self.bus_method(id, &m);
+                if !m.get_no_reply() { self.send_from_bus(Some(id), reply) }
+            },
+            Some(d) => match self.client_id(&d) {
+                Some(target) => self.deliver(target, &m),
+                None => if m.msg_type() == MessageType::MethodCall && !m.get_no_reply() {
+                    let e = error_reply(&m, "org.freedesktop.DBus.Error.ServiceUnknown",
+                        &format!("The name {} was not provided by any .service files", d));
+                    self.send_from_bus(Some(id), e);
+                },
+            },
+            None => self.broadcast(m, &sender),
+        }
+    }
+
+    fn hello(&mut self, id: usize, m: &Message) {
+        let name = Self::unique_name(id);
+        self.clients.get_mut(&id).unwrap().name = Some(name.clone());
+        self.send_from_bus(Some(id), m.method_return().append1(&*name));
+        self.send_from_bus(Some(id), Self::signal("NameAcquired").append1(&*name));
+        self.name_owner_changed(&name, "", &name);
+    }
+
+    fn bus_method(&mut self, id: usize, m: &Message) -> Message {
+        let member = m.member().map(|s| s.to_string()).unwrap_or(String::new());
+        let iface = m.interface().map(|s| s.to_string());
+        if iface.as_ref().map(|i| i == "org.freedesktop.DBus.Peer").unwrap_or(false) {
+            return if member == "Ping" { m.method_return() }
+                else { error_reply(m, "org.freedesktop.DBus.Error.UnknownMethod", "Unknown method") }
+        }
+        if iface.as_ref().map(|i| i != BUS_NAME).unwrap_or(false) {
+            return error_reply(m, "org.freedesktop.DBus.Error.UnknownInterface", "Unknown interface");
+        }
+        let invalid = || error_reply(m, "org.freedesktop.DBus.Error.InvalidArgs", "Invalid arguments");
+        match &*member {
+            "Hello" => error_reply(m, "org.freedesktop.DBus.Error.Failed", "Already handled an Hello message"),
+            "RequestName" => match m.read2::<&str, u32>() {
+                Ok((name, flags)) => {
+                    if name.starts_with(':') || ::BusName::new(name).is_err() { return invalid() }
+                    let r = self.request_name(id, name, flags);
+                    m.method_return().append1(r)
+                },
+                Err(_) => invalid(),
+            },
+            "ReleaseName" => match m.read1::<&str>() {
+                Ok(name) => {
+                    let r = self.release_name(id, name);
+                    m.method_return().append1(r)
+                },
+                Err(_) => invalid(),
+            },
+            "ListNames" => {
+                let mut v = vec!(BUS_NAME.to_string());
+                v.extend(self.clients.values().filter_map(|c| c.name.clone()));
+                v.extend(self.names.keys().cloned());
+                m.method_return().append1(v)
+            },
+            "NameHasOwner" => match m.read1::<&str>() {
+                Ok(name) => m.method_return().append1(name == BUS_NAME || self.client_id(name).is_some()),
+                Err(_) => invalid(),
+            },
+            "GetNameOwner" => match m.read1::<&str>() {
+                Ok(name) if name == BUS_NAME => m.method_return().append1(BUS_NAME),
+                Ok(name) => match self.client_id(name) {
+                    Some(owner) => m.method_return().append1(Self::unique_name(owner)),
+                    None => error_reply(m, "org.freedesktop.DBus.Error.NameHasNoOwner",
+                        &format!("Could not get owner of name '{}': no such name", name)),
+                },
+                Err(_) => invalid(),
+            },
+            "GetId" => m.method_return().append1(&*self.guid),
+            "GetConnectionUnixUser" | "GetConnectionUnixProcessID" | "GetConnectionCredentials" => match m.read1::<&str>() {
+                Ok(name) => match self.client_id(name).map(|id| self.clients[&id].creds) {
+                    None => error_reply(m, "org.freedesktop.DBus.Error.NameHasNoOwner",
+                        &format!("Could not get credentials of name '{}': no such name", name)),
+                    Some(None) => error_reply(m, "org.freedesktop.DBus.Error.Failed", "Could not determine credentials"),
+                    Some(Some((uid, pid))) => match &*member {
+                        "GetConnectionUnixUser" => m.method_return().append1(uid),
+                        "GetConnectionUnixProcessID" => m.method_return().append1(pid),
+                        _ => m.method_return().append1(::arg::Dict::new(vec!(
+                            ("UnixUserID", ::arg::Variant(uid)), ("ProcessID", ::arg::Variant(pid))))),
+                    },
+                },
+                Err(_) => invalid(),
+            },
+            "AddMatch" => match m.read1::<&str>().map(MatchRule::parse) {
+                Ok(Ok(r)) => {
+                    self.clients.get_mut(&id).unwrap().matches.push(r);
+                    m.method_return()
+                },
+                Ok(Err(e)) => error_reply(m, "org.freedesktop.DBus.Error.MatchRuleInvalid", e.message().unwrap_or("")),
+                Err(_) => invalid(),
+            },
+            "RemoveMatch" => match m.read1::<&str>().map(MatchRule::parse) {
+                Ok(Ok(r)) => {
+                    let matches = &mut self.clients.get_mut(&id).unwrap().matches;
+                    match matches.iter().position(|rr| *rr == r) {
+                        Some(p) => { matches.remove(p); m.method_return() },
+                        None => error_reply(m, "org.freedesktop.DBus.Error.MatchRuleNotFound",
+                            "The given match rule wasn't found and can't be removed"),
+                    }
+                },
+                Ok(Err(e)) => error_reply(m, "org.freedesktop.DBus.Error.MatchRuleInvalid", e.message().unwrap_or("")),
+                Err(_) => invalid(),
+            },
+            _ => error_reply(m, "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("org.freedesktop.DBus does not understand message {}", member)),
+        }
+    }
+
+    fn request_name(&mut self, id: usize, name: &str, flags: u32) -> u32 {
+        let (old, r) = {
+            let q = self.names.entry(name.into()).or_insert(vec!());
+            if q.len() == 0 {
+                q.push((id, flags));
+                (None, RequestNameReply::PrimaryOwner)
+            } else if q[0].0 == id {
+                q[0].1 = flags;
+                return RequestNameReply::AlreadyOwner as u32;
+            } else if flags & ffi::DBUS_NAME_FLAG_REPLACE_EXISTING as u32 != 0 && q[0].1 & ffi::DBUS_NAME_FLAG_ALLOW_REPLACEMENT as u32 != 0 {
+                q.retain(|&(i, _)| i != id);
+                let old = q.remove(0);
+                q.insert(0, (id, flags));
+                if old.1 & ffi::DBUS_NAME_FLAG_DO_NOT_QUEUE as u32 == 0 { q.insert(1, old) }
+                (Some(old.0), RequestNameReply::PrimaryOwner)
+            } else if flags & ffi::DBUS_NAME_FLAG_DO_NOT_QUEUE as u32 != 0 {
+                q.retain(|&(i, _)| i != id);
+                return RequestNameReply::Exists as u32;
+            } else {
+                match q.iter_mut().find(|&&mut (i, _)| i == id) {
+                    Some(e) => e.1 = flags,
+                    None => q.push((id, flags)),
+                }
+                return RequestNameReply::InQueue as u32;
+            }
+        };
+        let new_name = Self::unique_name(id);
+        let old_name = old.map(Self::unique_name).unwrap_or(String::new());
+        if let Some(old) = old { self.send_from_bus(Some(old), Self::signal("NameLost").append1(name)) }
+        self.send_from_bus(Some(id), Self::signal("NameAcquired").append1(name));
+        self.name_owner_changed(name, &old_name, &new_name);
+        r as u32
+    }
+
+    fn release_name(&mut self, id: usize, name: &str) -> u32 {
+        let new_owner = {
+            let q = match self.names.get_mut(name) {
+                Some(q) => q,
+                None => return ReleaseNameReply::NonExistent as u32,
+            };
+            match q.iter().position(|&(i, _)| i == id) {
+                None => return ReleaseNameReply::NotOwner as u32,
+                Some(0) => { q.remove(0); q.first().map(|x| x.0) },
+                Some(p) => { q.remove(p); return ReleaseNameReply::Released as u32 },
+            }
+        };
+        if new_owner.is_none() { self.names.remove(name); }
+        self.send_from_bus(Some(id), Self::signal("NameLost").append1(name));
+        if let Some(n) = new_owner { self.send_from_bus(Some(n), Self::signal("NameAcquired").append1(name)) }
+        let new_name = new_owner.map(Self::unique_name).unwrap_or(String::new());
+        self.name_owner_changed(name, &Self::unique_name(id), &new_name);
+        ReleaseNameReply::Released as u32
+    }
+
+    fn disconnect(&mut self, id: usize) {
+        let names: Vec<String> = self.names.iter().filter(|&(_, q)| q.iter().any(|x| x.0 == id)).map(|(n, _)| n.clone()).collect();
+        for n in names { self.release_name(id, &n); }
+        let c = self.clients.remove(&id).unwrap();
+        if let Some(n) = c.name { self.name_owner_changed(&n, &n, "") }
+    }
+}
+
+#[test]
+fn testbus_names() {
+    use NameFlag;
+    let bus = TestBus::new().unwrap();
+    let c1 = bus.connection().unwrap();
+    let c2 = bus.connection().unwrap();
+    assert!(c1.unique_name() != c2.unique_name());
+    let n = "com.example.dbusrs.testbus";
+    assert_eq!(c1.register_name(n, NameFlag::AllowReplacement as u32).unwrap(), RequestNameReply::PrimaryOwner);
+    assert_eq!(c1.register_name(n, NameFlag::AllowReplacement as u32).unwrap(), RequestNameReply::AlreadyOwner);
+    assert_eq!(c2.register_name(n, NameFlag::DoNotQueue as u32).unwrap(), RequestNameReply::Exists);
+    assert_eq!(c2.register_name(n, 0).unwrap(), RequestNameReply::InQueue);
+    assert_eq!(c2.release_name(n).unwrap(), ReleaseNameReply::Released);
+    assert_eq!(c2.register_name(n, NameFlag::ReplaceExisting as u32).unwrap(), RequestNameReply::PrimaryOwner);
+    assert_eq!(c1.release_name(n).unwrap(), ReleaseNameReply::Released);
+    assert_eq!(c1.release_name(n).unwrap(), ReleaseNameReply::NotOwner);
+    assert_eq!(c1.release_name("com.example.nonexistent").unwrap(), ReleaseNameReply::NonExistent);
+
+    let m = Message::new_method_call(BUS_NAME, "/", BUS_NAME, "GetNameOwner").unwrap().append1(n);
+    let r = c1.send_with_reply_and_block(m, 2000).unwrap();
+    assert_eq!(r.get1::<&str>(), Some(&*c2.unique_name()));
+    drop(c2);
+    // The bus notices the disconnection asynchronously.
+    for i in 0.. {
+        let m = Message::new_method_call(BUS_NAME, "/", BUS_NAME, "NameHasOwner").unwrap().append1(n);
+        let r = c1.send_with_reply_and_block(m, 2000).unwrap();
+        if r.get1() == Some(false) { break; }
+        assert!(i < 100);
+        thread::sleep(::std::time::Duration::from_millis(10));
+    }
+}
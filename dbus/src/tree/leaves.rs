@@ -33,6 +33,7 @@ pub struct Method<M: MethodType<D>, D: DataType> {
     i_args: Vec<Argument>,
     o_args: Vec<Argument>,
     anns: Annotations,
+    polkit_action: Option<String>,
 }
 
 impl<M: MethodType<D>, D: DataType> Method<M, D> {
@@ -62,8 +63,18 @@ impl<M: MethodType<D>, D: DataType> Method<M, D> {
     /// Builder method that adds an annotation that this entity is deprecated.
     pub fn deprecated(self) -> Self { self.annotate("org.freedesktop.DBus.Deprecated", "true") }
 
+    /// Builder method that requires the caller to be authorized by PolicyKit for an action
+    /// before the method handler is called.
+    ///
+    /// Callers that are not authorized get an org.freedesktop.DBus.Error.AccessDenied error reply.
+    /// Note that the check blocks, see `MethodInfo::check_polkit` for details.
+    pub fn require_polkit<S: Into<String>>(mut self, action_id: S) -> Self { self.polkit_action = Some(action_id.into()); self }
+
     /// Call the Method
-    pub fn call(&self, minfo: &MethodInfo<M, D>) -> MethodResult { M::call_method(&self.cb.0, minfo) }
+    pub fn call(&self, minfo: &MethodInfo<M, D>) -> MethodResult {
+        if let Some(ref a) = self.polkit_action { try!(minfo.check_polkit(a)); }
+        M::call_method(&self.cb.0, minfo)
+    }
 
     /// Get method name
     pub fn get_name(&self) -> &Member<'static> { &self.name }
@@ -85,7 +96,7 @@ impl<M: MethodType<D>, D: DataType> Introspect for Method<M, D> {
 }
 
 pub fn new_method<M: MethodType<D>, D: DataType>(n: Member<'static>, data: D::Method, cb: Box<M::Method>) -> Method<M, D> {
-    Method { name: n, i_args: vec!(), o_args: vec!(), anns: Annotations::new(), cb: DebugMethod(cb), data: data }
+    Method { name: n, i_args: vec!(), o_args: vec!(), anns: Annotations::new(), cb: DebugMethod(cb), data: data, polkit_action: None }
 }
 
 
@@ -440,6 +451,27 @@ impl<D: DataType> Property<MTSync<D>, D> {
 }
 
 
+impl<M: MethodType<D>, D: DataType> Property<M, D> {
+    /// Sets the callback for getting a property - usually you'll use "on_get" instead.
+    ///
+    /// This is useful for being able to create properties in code which is generic over methodtype.
+    pub fn on_get_sync<H>(mut self, handler: H) -> Self
+        where H: Fn(&mut arg::IterAppend, &PropInfo<M, D>) -> Result<(), MethodErr> + Send + Sync + 'static {
+        self.get_cb = Some(DebugGetProp(M::make_getprop(handler)));
+        self
+    }
+
+    /// Sets the callback for setting a property - usually you'll use "on_set" instead.
+    ///
+    /// This is useful for being able to create properties in code which is generic over methodtype.
+    pub fn on_set_sync<H>(mut self, handler: H) -> Self
+        where H: Fn(&mut arg::Iter, &PropInfo<M, D>) -> Result<(), MethodErr> + Send + Sync + 'static {
+        self.set_cb = Some(DebugSetProp(M::make_setprop(handler)));
+        self
+    }
+}
+
+
 impl<M: MethodType<D>, D: DataType> Property<M, D> where D::Property: arg::Append + Clone {
     /// Adds a "standard" get handler.
     pub fn default_get(mut self) -> Self {
@@ -1,7 +1,7 @@
 // Methods and method types. Glue to make stuff generic over MFn, MFnMut and MSync
 
 use std::fmt;
-use {ErrorName, Message, stdintf};
+use {ErrorName, Message, ConnectionCredentials, stdintf};
 use arg::{Iter, IterAppend, TypeMismatchError};
 use std::marker::PhantomData;
 use super::{Method, Interface, Property, ObjectPath, Tree};
@@ -127,6 +127,9 @@ pub trait MethodType<D: DataType>: Sized + Default {
     fn make_getprop<H>(h: H) -> Box<Self::GetProp>
     where H: Fn(&mut IterAppend, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static;
     /// For internal use.
+    fn make_setprop<H>(h: H) -> Box<Self::SetProp>
+    where H: Fn(&mut Iter, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static;
+    /// For internal use.
     fn make_method<H>(h: H) -> Box<Self::Method>
     where H: Fn(&MethodInfo<Self,D>) -> MethodResult + Send + Sync + 'static;
 }
@@ -150,6 +153,8 @@ impl<D: DataType> MethodType<D> for MTFn<D> {
 
     fn make_getprop<H>(h: H) -> Box<Self::GetProp>
     where H: Fn(&mut IterAppend, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static { Box::new(h) }
+    fn make_setprop<H>(h: H) -> Box<Self::SetProp>
+    where H: Fn(&mut Iter, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static { Box::new(h) }
     fn make_method<H>(h: H) -> Box<Self::Method>
     where H: Fn(&MethodInfo<Self,D>) -> MethodResult + Send + Sync + 'static { Box::new(h) }
 }
@@ -172,6 +177,8 @@ impl<D: DataType> MethodType<D> for MTFnMut<D> {
 
     fn make_getprop<H>(h: H) -> Box<Self::GetProp>
     where H: Fn(&mut IterAppend, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static { Box::new(RefCell::new(h)) }
+    fn make_setprop<H>(h: H) -> Box<Self::SetProp>
+    where H: Fn(&mut Iter, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static { Box::new(RefCell::new(h)) }
     fn make_method<H>(h: H) -> Box<Self::Method>
     where H: Fn(&MethodInfo<Self,D>) -> MethodResult + Send + Sync + 'static { Box::new(RefCell::new(h)) }
 
@@ -195,6 +202,8 @@ impl<D: DataType> MethodType<D> for MTSync<D> {
 
     fn make_getprop<H>(h: H) -> Box<Self::GetProp>
     where H: Fn(&mut IterAppend, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static  { Box::new(h) }
+    fn make_setprop<H>(h: H) -> Box<Self::SetProp>
+    where H: Fn(&mut Iter, &PropInfo<Self,D>) -> Result<(), MethodErr> + Send + Sync + 'static  { Box::new(h) }
     fn make_method<H>(h: H) -> Box<Self::Method>
     where H: Fn(&MethodInfo<Self,D>) -> MethodResult + Send + Sync + 'static { Box::new(h) }
 }
@@ -221,6 +230,30 @@ impl<'a, M: 'a + MethodType<D>, D: 'a + DataType> MethodInfo<'a, M, D> {
     pub fn to_prop_info(&self, iface: &'a Interface<M, D>, prop: &'a Property<M, D>) -> PropInfo<'a, M, D> {
         PropInfo { msg: self.msg, method: self.method, iface: iface, prop: prop, path: self.path, tree: self.tree }
     }
+
+    /// Gets the credentials (uid, pid etc) of the caller, e g to decide whether the call is allowed.
+    ///
+    /// The credentials are looked up in the tree's credentials cache, which is set when the tree
+    /// is registered with `Tree::set_registered`, or served by dbus-tokio's `ATreeServer`.
+    /// Trees served by dbus-futures have no cache, unless one is set with `Tree::set_credentials_cache`.
+    ///
+    /// Blocking: while waiting for the reply from the bus, if not cached.
+    pub fn caller_credentials(&self) -> Result<ConnectionCredentials, MethodErr> {
+        match self.tree.credentials_cache() {
+            Some(c) => c.get_sender(self.msg).map_err(|e| e.into()),
+            None => Err(MethodErr::failed(&"Cannot look up caller credentials: the tree is not registered with a connection")),
+        }
+    }
+
+    /// Asks PolicyKit whether the caller is authorized for an action, e g "org.example.foo.configure".
+    ///
+    /// Returns an org.freedesktop.DBus.Error.AccessDenied error if not, or if PolicyKit could not be asked.
+    /// No user interaction is allowed, so callers are only authorized if PolicyKit can decide right away.
+    /// Like `caller_credentials`, this needs the tree to have a credentials cache, see there.
+    ///
+    /// Blocking: while waiting for the reply from PolicyKit, for up to five seconds. No other messages
+    /// are handled in the meantime.
+    pub fn check_polkit(&self, action_id: &str) -> Result<(), MethodErr> { super::polkit::check_authorization(self, action_id) }
 }
 
 
@@ -27,9 +27,11 @@ mod methodtype;
 mod leaves;
 mod objectpath;
 mod factory;
+mod polkit;
 
 pub use self::utils::{Argument, Iter};
 pub use self::methodtype::{MethodErr, MethodInfo, PropInfo, MethodResult, MethodType, DataType, MTFn, MTFnMut, MTSync};
 pub use self::leaves::{Method, Signal, Property, Access, EmitsChangedSignal};
 pub use self::objectpath::{Interface, ObjectPath, Tree, TreeServer};
+pub(crate) use self::objectpath::TreeConn;
 pub use self::factory::Factory;
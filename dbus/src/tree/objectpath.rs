@@ -1,12 +1,15 @@
 use super::utils::{ArcMap, Iter, IterE, Annotations, Introspect};
 use super::{Factory, MethodType, MethodInfo, MethodResult, MethodErr, DataType, Property, Method, Signal, methodtype};
 use std::sync::{Arc, Mutex};
-use {Member, Message, Path, Signature, MessageType, Connection, ConnectionItem, Error, arg, MsgHandler, MsgHandlerType, MsgHandlerResult};
+use {Member, Message, Path, Signature, MessageType, Connection, ConnectionItem, Error, arg, MsgHandler, MsgHandlerType, MsgHandlerResult, CredentialsCache};
+use connection::ConnRef;
 use Interface as IfaceName;
 use std::fmt;
 use std::ffi::CStr;
 use super::leaves::prop_append_dict;
 
+const OBJECT_MANAGER: &'static str = "org.freedesktop.DBus.ObjectManager";
+
 fn introspect_map<I: fmt::Display, T: Introspect>
     (h: &ArcMap<I, T>, indent: &str) -> String {
 
@@ -271,11 +274,13 @@ where <D as DataType>::Interface: Default, <D as DataType>::Method: Default
 
     /// Adds ObjectManager support for this object path.
     ///
-    /// It is not possible to add/remove interfaces while the object path belongs to a tree,
-    /// hence no InterfacesAdded / InterfacesRemoved signals are sent.
+    /// When object paths below this one are inserted into or removed from the tree, or get
+    /// interfaces added or removed through `Tree::add_interface` and `Tree::remove_interface`,
+    /// the tree sends InterfacesAdded / InterfacesRemoved signals on the connection it is
+    /// registered with, see `Tree::set_registered`.
     pub fn object_manager(mut self) -> Self {
         use arg::{Variant, Dict};
-        let ifname = IfaceName::from("org.freedesktop.DBus.ObjectManager");
+        let ifname = IfaceName::from(OBJECT_MANAGER);
         if self.ifaces.contains_key(&ifname) { return self };
         let z = self.ifacecache.get(ifname, |i| {
             i.add_m(super::leaves::new_method("GetManagedObjects".into(), Default::default(),
@@ -321,6 +326,29 @@ pub fn new_objectpath<M: MethodType<D>, D: DataType>(n: Path<'static>, d: D::Obj
 pub struct Tree<M: MethodType<D>, D: DataType> {
     paths: ArcMap<Arc<Path<'static>>, ObjectPath<M, D>>,
     data: D::Tree,
+    conn: Arc<Mutex<Option<ConnRef>>>,
+    creds: Arc<Mutex<Option<CredentialsCache>>>,
+}
+
+/// The connection a registered tree sends signals on and takes credentials from,
+/// shared with the tree so that it can be moved over to a new connection.
+#[derive(Debug, Clone)]
+pub(crate) struct TreeConn(Arc<Mutex<Option<ConnRef>>>, Arc<Mutex<Option<CredentialsCache>>>);
+
+impl TreeConn {
+    /// Points the tree at another connection, unless it has been unregistered.
+    pub(crate) fn set(&self, c: &Connection) {
+        let mut conn = self.0.lock().unwrap();
+        if conn.is_none() { return }
+        *conn = Some(c.conn_ref());
+        *self.1.lock().unwrap() = Some(c.credentials_cache());
+    }
+}
+
+// The parent of an object path, e g "/a" for "/a/b", and "/" for "/a".
+fn parent_path(p: &str) -> Option<&str> {
+    if p == "/" { return None }
+    p.rfind('/').map(|i| if i == 0 { "/" } else { &p[..i] })
 }
 
 impl<M: MethodType<D>, D: DataType> Tree<M, D> {
@@ -329,7 +357,8 @@ impl<M: MethodType<D>, D: DataType> Tree<M, D> {
     /// Note: This does not register a path with the connection, so if the tree is currently registered,
     /// you might want to call Connection::register_object_path to add the path manually.
     pub fn add<I: Into<Arc<ObjectPath<M, D>>>>(mut self, s: I) -> Self {
-        self.insert(s);
+        let m = s.into();
+        self.paths.insert(m.name.clone(), m);
         self
     }
 
@@ -343,26 +372,145 @@ impl<M: MethodType<D>, D: DataType> Tree<M, D> {
 
     /// Non-builder function that adds an object path to this tree.
     ///
+    /// If an ancestor of the path has ObjectManager support, and the tree is registered with a
+    /// connection, an InterfacesAdded signal is sent. Use `insert_notify` to find out if that fails.
+    ///
     /// Note: This does not register a path with the connection, so if the tree is currently registered,
     /// you might want to call Connection::register_object_path to add the path manually.
     pub fn insert<I: Into<Arc<ObjectPath<M, D>>>>(&mut self, s: I) {
+        let _ = self.insert_notify(s);
+    }
+
+    /// Like `insert`, but returns an error if getting the properties for the InterfacesAdded
+    /// signal, or sending it, fails. The path is inserted anyway.
+    pub fn insert_notify<I: Into<Arc<ObjectPath<M, D>>>>(&mut self, s: I) -> Result<(), Error> {
         let m = s.into();
-        self.paths.insert(m.name.clone(), m);
+        let old = self.paths.insert(m.name.clone(), m.clone());
+        let old = old.map(|o| o.ifaces.keys().cloned().collect()).unwrap_or(vec!());
+        self.send_changes(&m.name, false, old)
     }
 
 
     /// Remove a object path from the Tree. Returns the object path removed, or None if not found.
     ///
+    /// If an ancestor of the path has ObjectManager support, and the tree is registered with a
+    /// connection, an InterfacesRemoved signal is sent.
+    ///
     /// Note: This does not unregister a path with the connection, so if the tree is currently registered,
     /// you might want to call Connection::unregister_object_path to remove the path manually.
     pub fn remove(&mut self, p: &Path<'static>) -> Option<Arc<ObjectPath<M, D>>> {
         // There is no real reason p needs to have a static lifetime; but
         // the borrow checker doesn't agree. :-(
-        self.paths.remove(p)
+        let r = self.paths.remove(p);
+        // Sending only fails if the connection is gone, in which case nobody is listening anyway.
+        if let Some(ref o) = r { let _ = self.send_changes(p, false, o.ifaces.keys().cloned().collect()); };
+        r
+    }
+
+    /// Removes an interface from an object path in the tree.
+    ///
+    /// If the object path, or an ancestor of it, has ObjectManager support, and the tree is
+    /// registered with a connection, an InterfacesRemoved signal is sent.
+    ///
+    /// Fails if the object path is not found, or if it is shared (i e, there are other
+    /// references to its `Arc`), or if sending the signal fails.
+    pub fn remove_interface(&mut self, p: &Path<'static>, iface: &IfaceName<'static>) -> Result<Option<Arc<Interface<M, D>>>, Error> {
+        let (old, r) = {
+            let o = try!(self.get_mut(p));
+            let old = o.ifaces.keys().cloned().collect();
+            (old, o.ifaces.remove(iface))
+        };
+        try!(self.send_changes(p, true, old));
+        Ok(r)
+    }
+
+    fn get_mut(&mut self, p: &Path<'static>) -> Result<&mut ObjectPath<M, D>, Error> {
+        let o = try!(self.paths.get_mut(p).ok_or_else(||
+            Error::new_custom("org.freedesktop.DBus.Error.UnknownObject", &format!("Object path {} not found", p))));
+        Arc::get_mut(o).ok_or_else(||
+            Error::new_custom("org.freedesktop.DBus.Error.Failed", &format!("Object path {} is in use", p)))
+    }
+
+    // The nearest object path at or above p that has ObjectManager support.
+    fn object_manager_for(&self, p: &str, include_self: bool) -> Option<&ObjectPath<M, D>> {
+        let ifname = IfaceName::from(OBJECT_MANAGER);
+        let mut s = if include_self { Some(p) } else { parent_path(p) };
+        while let Some(pp) = s {
+            let o = Path::new(pp).ok().and_then(|pp| self.paths.get(&pp));
+            if let Some(o) = o { if o.ifaces.contains_key(&ifname) { return Some(o) } }
+            s = parent_path(pp);
+        }
+        None
+    }
+
+    // Sends InterfacesAdded / InterfacesRemoved signals for the difference between the old
+    // interfaces of p, and the ones in the tree now.
+    fn send_changes(&self, p: &Path<'static>, include_self: bool, old: Vec<Arc<IfaceName<'static>>>) -> Result<(), Error> {
+        let conn = match *self.conn.lock().unwrap() { Some(ref c) => c.clone(), None => return Ok(()) };
+        let mgr = match self.object_manager_for(p, include_self) { Some(mgr) => mgr, None => return Ok(()) };
+        let (mut v, mut err) = (vec!(), None);
+        let o = self.paths.get(p);
+        let removed: Vec<&str> = old.iter().filter(|i| o.map(|o| !o.ifaces.contains_key(*i)).unwrap_or(true))
+            .map(|i| &***i).collect();
+        if !removed.is_empty() {
+            v.push(Message::signal(&mgr.name, &OBJECT_MANAGER.into(), &"InterfacesRemoved".into())
+                .append2(p, arg::Array::new(removed)));
+        }
+        if let Some(o) = o {
+            let added: Vec<_> = o.ifaces.iter().filter(|&(k, _)| !old.contains(k)).map(|(_, v)| v).collect();
+            if !added.is_empty() {
+                v.push(match self.interfaces_added(mgr, o, &added, true) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        // Still announce the interfaces; clients can ask for the properties themselves.
+                        err = Some(e);
+                        try!(self.interfaces_added(mgr, o, &added, false))
+                    }
+                });
+            }
+        }
+        for m in v { try!(conn.send(m)); }
+        err.map_or(Ok(()), Err)
+    }
+
+    fn interfaces_added(&self, mgr: &ObjectPath<M, D>, o: &ObjectPath<M, D>, ifaces: &[&Arc<Interface<M, D>>], props: bool) -> Result<Message, Error> {
+        use arg::{Dict, Variant};
+        let method = try!(mgr.ifaces.get(&IfaceName::from(OBJECT_MANAGER))
+            .and_then(|i| i.methods.get(&Member::from("GetManagedObjects")))
+            .ok_or_else(|| Error::new_custom("org.freedesktop.DBus.Error.Failed", "No ObjectManager method found")));
+        // The property getters need a message; they get one looking like the signal.
+        let msg = Message::signal(&mgr.name, &OBJECT_MANAGER.into(), &"InterfacesAdded".into());
+        let mut r = Message::signal(&mgr.name, &OBJECT_MANAGER.into(), &"InterfacesAdded".into());
+        let mut result = Ok(());
+        {
+            let mut i = arg::IterAppend::new(&mut r);
+            i.append(&*o.name);
+            i.append_dict(&Signature::make::<&str>(), &Signature::make::<Dict<&str,Variant<()>,()>>(), |ii| {
+                for iface in ifaces {
+                    let minfo = MethodInfo { msg: &msg, path: o, iface: iface, tree: self, method: method };
+                    ii.append_dict_entry(|e| {
+                        e.append(&**iface.name);
+                        result = if props { prop_append_dict(e, iface.properties.values().map(|v| &**v), &minfo) }
+                            else { prop_append_dict(e, ::std::iter::empty::<&Property<M, D>>(), &minfo) };
+                    });
+                    if result.is_err() { break; }
+                }
+            });
+        }
+        match result {
+            Ok(()) => Ok(r),
+            Err(e) => Err(Error::new_custom(e.errorname(), &format!("Getting properties of {} for InterfacesAdded failed: {}", o.name, e.description()))),
+        }
     }
 
     /// Registers or unregisters all object paths in the tree.
+    ///
+    /// Registering also makes the connection's credentials cache available to method handlers,
+    /// see `MethodInfo::caller_credentials`, and makes the tree send ObjectManager signals on the
+    /// connection when it changes, see `ObjectPath::object_manager`.
     pub fn set_registered(&self, c: &Connection, b: bool) -> Result<(), Error> {
+        if b { self.set_credentials_cache(Some(c.credentials_cache())) }
+        *self.conn.lock().unwrap() = if b { Some(c.conn_ref()) } else { None };
         let mut regd_paths = Vec::new();
         for p in self.paths.keys() {
             if b {
@@ -382,6 +530,16 @@ impl<M: MethodType<D>, D: DataType> Tree<M, D> {
         Ok(())
     }
 
+    /// Sets the cache that `MethodInfo::caller_credentials` looks up credentials in.
+    ///
+    /// This is done by `set_registered`, so you only need it if you dispatch method calls some other way.
+    pub fn set_credentials_cache(&self, c: Option<CredentialsCache>) { *self.creds.lock().unwrap() = c }
+
+    /// The cache that `MethodInfo::caller_credentials` looks up credentials in, if any.
+    pub fn credentials_cache(&self) -> Option<CredentialsCache> { self.creds.lock().unwrap().clone() }
+
+    pub(crate) fn tree_conn(&self) -> TreeConn { TreeConn(self.conn.clone(), self.creds.clone()) }
+
     /// This method takes an `ConnectionItem` iterator (you get it from `Connection::iter()`)
     /// and handles all matching items. Non-matching items (e g signals) are passed through.
     pub fn run<'a, I: Iterator<Item=ConnectionItem>>(&'a self, c: &'a Connection, i: I) -> TreeServer<'a, I, M, D> {
@@ -416,8 +574,32 @@ impl<M: MethodType<D>, D: DataType> Tree<M, D> {
 
 }
 
+impl<M: MethodType<D>, D: DataType> Tree<M, D>
+where <D as DataType>::Interface: Default, <D as DataType>::Method: Default
+{
+    /// Adds an interface to an object path in the tree.
+    ///
+    /// If the object path, or an ancestor of it, has ObjectManager support, and the tree is
+    /// registered with a connection, an InterfacesAdded signal is sent.
+    ///
+    /// Fails if the object path is not found, or if it is shared (i e, there are other
+    /// references to its `Arc`). Also fails if getting the properties for the signal, or sending
+    /// it, fails; the interface is added anyway.
+    pub fn add_interface<I: Into<Arc<Interface<M, D>>>>(&mut self, p: &Path<'static>, i: I) -> Result<(), Error> {
+        let old = {
+            let o = try!(self.get_mut(p));
+            let old = o.ifaces.keys().cloned().collect();
+            let i = i.into();
+            if !i.properties.is_empty() { o.add_property_handler(); }
+            o.ifaces.insert(i.name.clone(), i);
+            old
+        };
+        self.send_changes(p, true, old)
+    }
+}
+
 pub fn new_tree<M: MethodType<D>, D: DataType>(d: D::Tree) -> Tree<M, D> {
-    Tree { paths: ArcMap::new(), data: d }
+    Tree { paths: ArcMap::new(), data: d, conn: Default::default(), creds: Default::default() }
 }
 
 impl<M: MethodType<D>, D: DataType> MsgHandler for Tree<M, D> {
@@ -534,3 +716,75 @@ fn test_introspection() {
     assert_eq!(expected_result, actual_result);   
 }
 
+
+#[test]
+fn test_object_manager_signals() {
+    use stdintf::org_freedesktop_dbus::{ObjectManagerInterfacesAdded as IA, ObjectManagerInterfacesRemoved as IR};
+    use {SignalArgs, arg::RefArg};
+    use testing::TestBus;
+
+    let bus = TestBus::new().unwrap();
+    let c = bus.connection().unwrap();
+    let c2 = bus.connection().unwrap();
+    c2.add_match(&format!("interface='{}'", OBJECT_MANAGER)).unwrap();
+    let signals = || c2.incoming(300).filter(|m| m.msg_type() == MessageType::Signal &&
+        m.interface().map(|i| &*i == OBJECT_MANAGER).unwrap_or(false)).collect::<Vec<_>>();
+
+    let f = super::Factory::new_fn::<()>();
+    let iface = |name: &'static str, v: i32| f.interface(name, ())
+        .add_p(f.property::<i32,_>("Value", ()).on_get(move |i, _| { i.append(v); Ok(()) }));
+    let mut t = f.tree(()).add(f.object_path("/mgr", ()).object_manager())
+        .add(f.object_path("/mgr/a", ()).add(iface("com.example.A", 1)));
+
+    // Not registered, so nothing is sent
+    t.insert(f.object_path("/mgr/x", ()).add(iface("com.example.X", 1)));
+    t.set_registered(&c, true).unwrap();
+
+    // Outside of the object manager
+    t.insert_notify(f.object_path("/other", ()).add(iface("com.example.A", 1))).unwrap();
+    assert_eq!(signals().len(), 0);
+
+    t.insert_notify(f.object_path("/mgr/a/b", ()).add(iface("com.example.B", 2))).unwrap();
+    let s = signals();
+    assert_eq!(s.len(), 1);
+    assert_eq!(&*s[0].path().unwrap(), "/mgr");
+    let ia = IA::from_message(&s[0]).unwrap();
+    assert_eq!(&*ia.object, "/mgr/a/b");
+    assert_eq!(ia.interfaces.len(), 2);
+    assert_eq!(ia.interfaces["com.example.B"]["Value"].0.as_i64(), Some(2));
+    assert!(ia.interfaces.contains_key("org.freedesktop.DBus.Properties"));
+
+    let b: Path<'static> = "/mgr/a/b".into();
+    t.add_interface(&b, iface("com.example.C", 3)).unwrap();
+    let s = signals();
+    let ia = IA::from_message(&s[0]).unwrap();
+    assert_eq!(ia.interfaces.keys().collect::<Vec<_>>(), vec!("com.example.C"));
+    assert_eq!(ia.interfaces["com.example.C"]["Value"].0.as_i64(), Some(3));
+
+    assert!(t.remove_interface(&b, &"com.example.B".into()).unwrap().is_some());
+    let s = signals();
+    assert_eq!(s.len(), 1);
+    let ir = IR::from_message(&s[0]).unwrap();
+    assert_eq!((&*ir.object, ir.interfaces), ("/mgr/a/b", vec!("com.example.B".to_string())));
+
+    // Shared object paths cannot be changed
+    let shared = t.get(&b).unwrap().clone();
+    assert!(t.add_interface(&b, iface("com.example.D", 4)).is_err());
+    assert!(t.add_interface(&"/nothere".into(), iface("com.example.D", 4)).is_err());
+    drop(shared);
+
+    // A failing getter is reported, but the interface is still announced
+    let broken = f.interface("com.example.Broken", ())
+        .add_p(f.property::<i32,_>("Value", ()).on_get(|_, _| Err(MethodErr::failed(&"broken"))));
+    assert!(t.add_interface(&b, broken).is_err());
+    let s = signals();
+    let ia = IA::from_message(&s[0]).unwrap();
+    assert!(ia.interfaces["com.example.Broken"].is_empty());
+
+    assert!(t.remove(&b).is_some());
+    let s = signals();
+    let mut ir = IR::from_message(&s[0]).unwrap();
+    ir.interfaces.sort();
+    assert_eq!(ir.interfaces, vec!("com.example.Broken".to_string(), "com.example.C".into(), "org.freedesktop.DBus.Properties".into()));
+    assert_eq!(signals().len(), 0);
+}
@@ -0,0 +1,87 @@
+// Authorizing method calls through PolicyKit, see MethodInfo::check_polkit.
+
+use super::{MethodInfo, MethodType, DataType, MethodErr};
+use Message;
+use arg::Variant;
+use std::collections::HashMap;
+
+const AUTHORITY: &'static str = "org.freedesktop.PolicyKit1";
+const AUTHORITY_PATH: &'static str = "/org/freedesktop/PolicyKit1/Authority";
+const AUTHORITY_IFACE: &'static str = "org.freedesktop.PolicyKit1.Authority";
+
+// Method calls are not handled while waiting, so don't wait for long.
+const TIMEOUT_MS: i32 = 5000;
+
+fn denied<T: ::std::fmt::Display>(s: T) -> MethodErr { ("org.freedesktop.DBus.Error.AccessDenied", s.to_string()).into() }
+
+pub fn check_authorization<M: MethodType<D>, D: DataType>(minfo: &MethodInfo<M, D>, action_id: &str) -> Result<(), MethodErr> {
+    let cache = try!(minfo.tree.credentials_cache().ok_or_else(||
+        denied("Cannot check authorization: the tree is not registered with a connection")));
+    let sender = try!(minfo.msg.sender().ok_or_else(|| denied("Cannot check authorization: the message has no sender")));
+
+    // The subject is ("system-bus-name", {"name": <sender>}), details are empty, and flags 0 means no user interaction.
+    let mut subject = HashMap::new();
+    subject.insert("name", Variant(&*sender));
+    let details: HashMap<&str, &str> = HashMap::new();
+    let m = Message::method_call(&AUTHORITY.into(), &AUTHORITY_PATH.into(), &AUTHORITY_IFACE.into(), &"CheckAuthorization".into())
+        .append3(("system-bus-name", subject), action_id, details).append2(0u32, "");
+    let failed = |e: ::Error| denied(format!("Cannot check authorization for {}: {}", action_id, e.message().unwrap_or("PolicyKit failed")));
+    let mut r = try!(cache.send_with_reply_and_block(m, TIMEOUT_MS).map_err(&failed));
+    let r = try!(r.as_result().map_err(&failed));
+    let (authorized, challenge, _): (bool, bool, HashMap<String, String>) = try!(r.read1().map_err(|e|
+        denied(format!("Cannot check authorization for {}: invalid reply from PolicyKit ({})", action_id, e))));
+    if authorized { Ok(()) }
+    else if challenge { Err(denied(format!("Authentication is required for {}", action_id))) }
+    else { Err(denied(format!("Not authorized for {}", action_id))) }
+}
+
+#[test]
+fn test_require_polkit() {
+    use testing::TestBus;
+    use tree::Factory;
+    use std::sync::mpsc;
+
+    let bus = TestBus::new().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    // A fake authority, which only allows "com.example.allowed", and asks for authentication for "com.example.challenge".
+    let authority = bus.serve(AUTHORITY, move || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path(AUTHORITY_PATH, ()).add(f.interface(AUTHORITY_IFACE, ())
+            .add_m(f.method("CheckAuthorization", (), move |m| {
+                let mut i = m.msg.iter_init();
+                let (kind, details): (String, HashMap<String, Variant<String>>) = try!(i.read());
+                let action: String = try!(i.read());
+                tx.send(format!("{} {} {}", kind, details["name"].0, action)).unwrap();
+                if action == "com.example.malformed" { return Ok(vec!(m.msg.method_return().append1(true))) }
+                let r = (action == "com.example.allowed", action == "com.example.challenge", HashMap::<String, String>::new());
+                Ok(vec!(m.msg.method_return().append1(r)))
+            }))))
+    }).unwrap();
+
+    let _server = bus.serve("com.example.polkit", || {
+        let f = Factory::new_fn::<()>();
+        f.tree(()).add(f.object_path("/polkit", ()).add(f.interface("com.example.polkit", ())
+            .add_m(f.method("Allowed", (), |m| Ok(vec!(m.msg.method_return()))).require_polkit("com.example.allowed"))
+            .add_m(f.method("Challenge", (), |m| Ok(vec!(m.msg.method_return()))).require_polkit("com.example.challenge"))
+            .add_m(f.method("Denied", (), |_| panic!("Handler called without authorization")).require_polkit("com.example.denied"))
+            .add_m(f.method("Malformed", (), |_| panic!("Handler called without authorization")).require_polkit("com.example.malformed"))
+        ))
+    }).unwrap();
+
+    let c = bus.connection().unwrap();
+    let call = |name: &str| {
+        let m = Message::new_method_call("com.example.polkit", "/polkit", "com.example.polkit", name).unwrap();
+        c.send_with_reply_and_block(m, 10000).map(|_| ()).map_err(|e| e.name().unwrap().to_string())
+    };
+
+    assert_eq!(call("Allowed"), Ok(()));
+    assert_eq!(rx.recv().unwrap(), format!("system-bus-name {} com.example.allowed", c.unique_name()));
+    assert_eq!(call("Challenge"), Err("org.freedesktop.DBus.Error.AccessDenied".into()));
+    assert_eq!(call("Denied"), Err("org.freedesktop.DBus.Error.AccessDenied".into()));
+    assert_eq!(call("Malformed"), Err("org.freedesktop.DBus.Error.AccessDenied".into()));
+
+    // Without an authority on the bus, nobody is authorized.
+    drop(authority);
+    assert_eq!(call("Allowed"), Err("org.freedesktop.DBus.Error.AccessDenied".into()));
+}
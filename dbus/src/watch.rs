@@ -1,11 +1,19 @@
+#[cfg(feature = "libdbus-sys")]
 use ffi;
 use libc;
+#[cfg(feature = "libdbus-sys")]
 use super::Connection;
 
+#[cfg(feature = "libdbus-sys")]
 use std::mem;
+#[cfg(feature = "libdbus-sys")]
+use std::time::Duration;
+#[cfg(feature = "libdbus-sys")]
 use std::sync::{Mutex, RwLock};
 use std::os::unix::io::{RawFd, AsRawFd};
-use std::os::raw::{c_void, c_uint};
+#[cfg(feature = "libdbus-sys")]
+use std::os::raw::{c_void, c_int};
+use std::os::raw::c_uint;
 
 /// A file descriptor to watch for incoming events (for async I/O).
 ///
@@ -40,14 +48,15 @@ use std::os::raw::{c_void, c_uint};
 ///
 /// It should really be bitflags instead.
 pub enum WatchEvent {
+    // The values are the DBUS_WATCH_* flags of libdbus.
     /// The fd is readable
-    Readable = ffi::DBUS_WATCH_READABLE as isize,
+    Readable = 1,
     /// The fd is writable
-    Writable = ffi::DBUS_WATCH_WRITABLE as isize,
+    Writable = 2,
     /// An error occured on the fd
-    Error = ffi::DBUS_WATCH_ERROR as isize,
+    Error = 4,
     /// The fd received a hangup.
-    Hangup = ffi::DBUS_WATCH_HANGUP as isize,
+    Hangup = 8,
 }
 
 impl WatchEvent {
@@ -84,6 +93,10 @@ impl Watch {
         }
     }
 
+    #[cfg(feature = "native")]
+    pub (crate) fn new(fd: RawFd, read: bool, write: bool) -> Self { Watch { fd: fd, read: read, write: write } }
+
+    #[cfg(feature = "libdbus-sys")]
     pub (crate) unsafe fn from_raw(watch: *mut ffi::DBusWatch) -> Self {
         let mut w = Watch { fd: ffi::dbus_watch_get_unix_fd(watch), read: false, write: false};
         let enabled = ffi::dbus_watch_get_enabled(watch) != 0;
@@ -101,6 +114,31 @@ impl AsRawFd for Watch {
     fn as_raw_fd(&self) -> RawFd { self.fd }
 }
 
+#[cfg(feature = "libdbus-sys")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// A timer that libdbus wants to be told about when it expires (for async I/O).
+///
+/// Libdbus uses these for e g pending method calls and authentication. Once the interval
+/// has elapsed, call `Connection::timeout_handle`. A timeout that is still enabled after
+/// it has been handled should be rearmed with the same interval.
+pub struct Timeout {
+    id: usize,
+    interval: c_int,
+    enabled: bool,
+}
+
+#[cfg(feature = "libdbus-sys")]
+impl Timeout {
+    /// An identifier for this timeout, unique among the timeouts of a connection
+    /// that are currently alive.
+    pub fn id(&self) -> usize { self.id }
+    /// The time after which the timeout expires
+    pub fn interval(&self) -> Duration { Duration::from_millis(if self.interval > 0 { self.interval as u64 } else { 0 }) }
+    /// If false, the timeout was disabled or removed and should no longer be waited for
+    pub fn enabled(&self) -> bool { self.enabled }
+}
+
+#[cfg(feature = "libdbus-sys")]
 /// Note - internal struct, not to be used outside API. Moving it outside its box will break things.
 pub struct WatchList {
     watches: RwLock<Vec<*mut ffi::DBusWatch>>,
@@ -108,6 +146,7 @@ pub struct WatchList {
     on_update: Mutex<Box<Fn(Watch) + Send>>,
 }
 
+#[cfg(feature = "libdbus-sys")]
 impl WatchList {
     pub fn new(c: &Connection, on_update: Box<Fn(Watch) + Send>) -> Box<WatchList> {
         let w = Box::new(WatchList { on_update: Mutex::new(on_update), watches: RwLock::new(vec!()), enabled_fds: Mutex::new(vec!()) });
@@ -176,6 +215,7 @@ impl WatchList {
     }
 }
 
+#[cfg(feature = "libdbus-sys")]
 extern "C" fn add_watch_cb(watch: *mut ffi::DBusWatch, data: *mut c_void) -> u32 {
     let wlist: &WatchList = unsafe { mem::transmute(data) };
     // println!("Add watch {:?}", watch);
@@ -184,6 +224,7 @@ extern "C" fn add_watch_cb(watch: *mut ffi::DBusWatch, data: *mut c_void) -> u32
     1
 }
 
+#[cfg(feature = "libdbus-sys")]
 extern "C" fn remove_watch_cb(watch: *mut ffi::DBusWatch, data: *mut c_void) {
     let wlist: &WatchList = unsafe { mem::transmute(data) };
     // println!("Removed watch {:?}", watch);
@@ -191,20 +232,101 @@ extern "C" fn remove_watch_cb(watch: *mut ffi::DBusWatch, data: *mut c_void) {
     wlist.update(watch);
 }
 
+#[cfg(feature = "libdbus-sys")]
 extern "C" fn toggled_watch_cb(watch: *mut ffi::DBusWatch, data: *mut c_void) {
     let wlist: &WatchList = unsafe { mem::transmute(data) };
     // println!("Toggled watch {:?}", watch);
     wlist.update(watch);
 }
 
-#[cfg(test)]
+#[cfg(feature = "libdbus-sys")]
+/// Note - internal struct, not to be used outside API. Moving it outside its box will break things.
+pub struct TimeoutList {
+    timeouts: RwLock<Vec<*mut ffi::DBusTimeout>>,
+    on_update: Mutex<Box<Fn(Timeout) + Send>>,
+}
+
+// Libdbus timeouts can be handled from any thread, see dbus_threads_init_default.
+#[cfg(feature = "libdbus-sys")]
+unsafe impl Send for TimeoutList {}
+#[cfg(feature = "libdbus-sys")]
+unsafe impl Sync for TimeoutList {}
+
+#[cfg(feature = "libdbus-sys")]
+impl TimeoutList {
+    pub fn new(c: &Connection, on_update: Box<Fn(Timeout) + Send>) -> Box<TimeoutList> {
+        Self::from_conn(super::connection::conn_handle(c), on_update)
+    }
+
+    pub (crate) fn from_conn(c: *mut ffi::DBusConnection, on_update: Box<Fn(Timeout) + Send>) -> Box<TimeoutList> {
+        let t = Box::new(TimeoutList { on_update: Mutex::new(on_update), timeouts: RwLock::new(vec!()) });
+        if unsafe { ffi::dbus_connection_set_timeout_functions(c,
+            Some(add_timeout_cb), Some(remove_timeout_cb), Some(toggled_timeout_cb), &*t as *const _ as *mut _, None) } == 0 {
+            panic!("dbus_connection_set_timeout_functions failed");
+        }
+        t
+    }
+
+    pub fn set_on_update(&self, on_update: Box<Fn(Timeout) + Send>) { *self.on_update.lock().unwrap() = on_update; }
+
+    pub fn timeout_handle(&self, id: usize) {
+        // Libdbus might remove the timeout while handling it, so don't hold the lock.
+        let q = self.timeouts.read().unwrap().iter().map(|&q| q).find(|&q| q as usize == id);
+        let q = match q { Some(q) => q, None => return };
+        if unsafe { ffi::dbus_timeout_get_enabled(q) } == 0 { return };
+        if unsafe { ffi::dbus_timeout_handle(q) } == 0 {
+            panic!("dbus_timeout_handle failed");
+        }
+    }
+
+    pub fn get_enabled_timeouts(&self) -> Vec<Timeout> {
+        let t = self.timeouts.read().unwrap();
+        t.iter().map(|&q| self.get_timeout(q, &t)).filter(|t| t.enabled).collect()
+    }
+
+    fn get_timeout(&self, timeout: *mut ffi::DBusTimeout, list: &[*mut ffi::DBusTimeout]) -> Timeout {
+        let enabled = list.contains(&timeout) && unsafe { ffi::dbus_timeout_get_enabled(timeout) != 0 };
+        Timeout { id: timeout as usize, interval: unsafe { ffi::dbus_timeout_get_interval(timeout) }, enabled: enabled }
+    }
+
+    fn update(&self, timeout: *mut ffi::DBusTimeout) {
+        let t = self.get_timeout(timeout, &self.timeouts.read().unwrap());
+        let func = self.on_update.lock().unwrap();
+        (*func)(t);
+    }
+}
+
+#[cfg(feature = "libdbus-sys")]
+extern "C" fn add_timeout_cb(timeout: *mut ffi::DBusTimeout, data: *mut c_void) -> u32 {
+    let tlist: &TimeoutList = unsafe { mem::transmute(data) };
+    tlist.timeouts.write().unwrap().push(timeout);
+    tlist.update(timeout);
+    1
+}
+
+#[cfg(feature = "libdbus-sys")]
+extern "C" fn remove_timeout_cb(timeout: *mut ffi::DBusTimeout, data: *mut c_void) {
+    let tlist: &TimeoutList = unsafe { mem::transmute(data) };
+    tlist.timeouts.write().unwrap().retain(|t| *t != timeout);
+    tlist.update(timeout);
+}
+
+#[cfg(feature = "libdbus-sys")]
+extern "C" fn toggled_timeout_cb(timeout: *mut ffi::DBusTimeout, data: *mut c_void) {
+    let tlist: &TimeoutList = unsafe { mem::transmute(data) };
+    tlist.update(timeout);
+}
+
+#[cfg(all(test, feature = "libdbus-sys"))]
 mod test {
     use libc;
-    use super::super::{Connection, Message, BusType, WatchEvent, ConnectionItem, MessageType};
+    use super::super::{Message, WatchEvent, ConnectionItem, MessageType};
+    use testing::TestBus;
 
     #[test]
     fn async() {
-        let c = Connection::get_private(BusType::Session).unwrap();
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
         c.register_object_path("/test").unwrap();
         let m = Message::new_method_call(&c.unique_name(), "/test", "com.example.asynctest", "AsyncTest").unwrap();
         let serial = c.send(m).unwrap();
@@ -251,4 +373,47 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn timeouts() {
+        use std::{ptr, thread};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use ffi;
+
+        let bus = TestBus::new().unwrap();
+        let c = bus.connection().unwrap();
+        let updates = Arc::new(Mutex::new(vec!()));
+        let u2 = updates.clone();
+        c.set_timeout_callback(Box::new(move |t| u2.lock().unwrap().push(t)));
+        assert!(c.timeouts().is_empty());
+
+        // Nobody will answer this one, so the pending call times out.
+        let m = Message::new_method_call(&c.unique_name(), "/test", "com.example.timeouttest", "Hang").unwrap();
+        let mut pending = ptr::null_mut();
+        assert!(unsafe { ffi::dbus_connection_send_with_reply(::connection::conn_handle(&c), m.ptr(), &mut pending, 100) } != 0);
+        assert!(!pending.is_null());
+
+        let t = c.timeouts();
+        assert_eq!(t.len(), 1);
+        assert!(t[0].enabled());
+        assert_eq!(t[0].interval(), Duration::from_millis(100));
+        assert_eq!(&*updates.lock().unwrap(), &t);
+
+        thread::sleep(t[0].interval());
+        for _ in c.timeout_handle(t[0]) {}
+        assert!(c.timeouts().is_empty());
+        {
+            let u = updates.lock().unwrap();
+            assert_eq!(u.len(), 2);
+            assert_eq!(u[1].id(), t[0].id());
+            assert!(!u[1].enabled());
+        }
+
+        let reply = unsafe { ffi::dbus_pending_call_steal_reply(pending) };
+        assert!(!reply.is_null());
+        let mut reply = Message::from_ptr(reply, false);
+        unsafe { ffi::dbus_pending_call_unref(pending) };
+        assert_eq!(reply.as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.NoReply"));
+    }
 }
@@ -165,6 +165,7 @@ extern "C" {
     pub fn dbus_connection_dispatch(conn: *mut DBusConnection) -> DBusDispatchStatus;
     pub fn dbus_connection_flush(conn: *mut DBusConnection);
     pub fn dbus_connection_open_private(address: *const c_char, error: *mut DBusError) -> *mut DBusConnection;
+    pub fn dbus_connection_ref(conn: *mut DBusConnection) -> *mut DBusConnection;
     pub fn dbus_connection_unref(conn: *mut DBusConnection);
     pub fn dbus_connection_get_is_connected(conn: *mut DBusConnection) -> u32;
     pub fn dbus_connection_set_exit_on_disconnect(conn: *mut DBusConnection, enable: u32);
@@ -206,6 +207,7 @@ extern "C" {
     pub fn dbus_set_error(error: *mut DBusError, name: *const c_char, message: *const c_char, ...);
     pub fn dbus_set_error_from_message(error: *mut DBusError, message: *mut DBusMessage) -> u32;
 
+    pub fn dbus_message_new(message_type: c_int) -> *mut DBusMessage;
     pub fn dbus_message_new_method_call(destination: *const c_char, path: *const c_char,
         iface: *const c_char, method: *const c_char) -> *mut DBusMessage;
     pub fn dbus_message_new_method_return(message: *mut DBusMessage) -> *mut DBusMessage;
@@ -215,6 +217,7 @@ extern "C" {
         iface: *const c_char, name: *const c_char) -> *mut DBusMessage;
     pub fn dbus_message_ref(message: *mut DBusMessage) -> *mut DBusMessage;
     pub fn dbus_message_unref(message: *mut DBusMessage);
+    pub fn dbus_message_copy(message: *const DBusMessage) -> *mut DBusMessage;
     pub fn dbus_message_get_type(message: *mut DBusMessage) -> c_int;
     pub fn dbus_message_is_method_call(message: *mut DBusMessage, iface: *const c_char, method: *const c_char) -> u32;
     pub fn dbus_message_is_signal(message: *mut DBusMessage, iface: *const c_char, signal_name: *const c_char) -> u32;
@@ -225,8 +228,16 @@ extern "C" {
     pub fn dbus_message_get_destination(message: *mut DBusMessage) -> *const c_char;
     pub fn dbus_message_get_member(message: *mut DBusMessage) -> *const c_char;
     pub fn dbus_message_get_sender(message: *mut DBusMessage) -> *const c_char;
+    pub fn dbus_message_get_error_name(message: *mut DBusMessage) -> *const c_char;
+    pub fn dbus_message_get_signature(message: *mut DBusMessage) -> *const c_char;
     pub fn dbus_message_set_serial(message: *mut DBusMessage, serial: u32);
+    pub fn dbus_message_set_reply_serial(message: *mut DBusMessage, reply_serial: u32) -> u32;
     pub fn dbus_message_set_destination(message: *mut DBusMessage, destination: *const c_char) -> u32;
+    pub fn dbus_message_set_sender(message: *mut DBusMessage, sender: *const c_char) -> u32;
+    pub fn dbus_message_set_path(message: *mut DBusMessage, object_path: *const c_char) -> u32;
+    pub fn dbus_message_set_interface(message: *mut DBusMessage, iface: *const c_char) -> u32;
+    pub fn dbus_message_set_member(message: *mut DBusMessage, member: *const c_char) -> u32;
+    pub fn dbus_message_set_error_name(message: *mut DBusMessage, name: *const c_char) -> u32;
     pub fn dbus_message_get_no_reply(message: *mut DBusMessage) -> u32;
     pub fn dbus_message_set_no_reply(message: *mut DBusMessage, no_reply: u32);
     pub fn dbus_message_get_auto_start(message: *mut DBusMessage) -> u32;
@@ -248,6 +259,7 @@ extern "C" {
     pub fn dbus_message_iter_open_container(iter: *mut DBusMessageIter, _type: c_int,
         contained_signature: *const c_char, sub: *mut DBusMessageIter) -> u32;
     pub fn dbus_message_iter_close_container(iter: *mut DBusMessageIter, sub: *mut DBusMessageIter) -> u32;
+    pub fn dbus_message_iter_abandon_container(iter: *mut DBusMessageIter, sub: *mut DBusMessageIter);
 
     pub fn dbus_free(memory: *mut c_void);
     pub fn dbus_free_string_array(str_array: *mut *mut c_char) -> c_void;